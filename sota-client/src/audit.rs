@@ -0,0 +1,93 @@
+use chan::Receiver;
+use chrono::Utc;
+use json;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use datatype::{Error, Event};
+
+
+/// The number of rotated audit log files to keep.
+const ROTATIONS: u32 = 2;
+
+#[derive(Serialize)]
+struct AuditEntry<'e> {
+    timestamp: String,
+    event:     &'e Event,
+}
+
+
+/// Append each received `Event` as a timestamped line of JSON to a log file,
+/// rotating it to `<path>.1` and `<path>.2` once it grows beyond `max_size` bytes.
+pub struct AuditLog {
+    pub path:     String,
+    pub max_size: u64,
+}
+
+impl AuditLog {
+    /// Write every event to the audit log until the sender closes.
+    pub fn run(&self, erx: Receiver<Event>) {
+        info!("Writing audit log to {}", self.path);
+        while let Some(event) = erx.recv() {
+            self.write(&event).unwrap_or_else(|err| error!("couldn't write to audit log: {}", err));
+        }
+    }
+
+    /// Append a single event to the audit log.
+    pub fn write(&self, event: &Event) -> Result<(), Error> {
+        let entry = AuditEntry { timestamp: Utc::now().to_rfc3339(), event: event };
+        let mut line = json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let current = fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0);
+        if current > 0 && current + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Shift each log file along by one, dropping the oldest.
+    fn rotate(&self) -> Result<(), Error> {
+        for n in (1..ROTATIONS).rev() {
+            let from = format!("{}.{}", self.path, n);
+            if Path::new(&from).exists() {
+                fs::rename(&from, format!("{}.{}", self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pacman::test::TestDir;
+    use datatype::Util;
+
+
+    #[test]
+    fn test_audit_log_rotation() {
+        let dir = TestDir::new("sota-audit-log");
+        let log = AuditLog { path: format!("{}/audit.log", dir.0), max_size: 100 };
+        log.write(&Event::Authenticated).unwrap();
+        log.write(&Event::NotAuthenticated).unwrap();
+        log.write(&Event::NoUpdateRequests).unwrap();
+        log.write(&Event::SystemInfoSent).unwrap();
+
+        let latest = Util::read_text(&log.path).unwrap();
+        assert_eq!(latest.lines().count(), 1);
+        let entry = json::from_str::<json::Value>(latest.trim()).unwrap();
+        assert_eq!(entry["event"], json::Value::String("SystemInfoSent".into()));
+        assert!(entry["timestamp"].is_string());
+
+        assert!(Util::read_text(&format!("{}.1", log.path)).unwrap().contains("NoUpdateRequests"));
+        assert!(Util::read_text(&format!("{}.2", log.path)).unwrap().contains("NotAuthenticated"));
+        assert!(!Path::new(&format!("{}.3", log.path)).exists());
+    }
+}
@@ -1,7 +1,7 @@
 use json;
 
-use datatype::{AccessToken, Error, Url};
-use http::{Client, Response};
+use datatype::{AccessToken, Error, Method, Url};
+use http::{Client, Request, Response};
 
 
 #[derive(Serialize)]
@@ -23,10 +23,12 @@ pub fn pkcs12(client: &Client, server: Url, payload: &RegistrationPayload) -> Re
 }
 
 
-/// Authenticate with the specified `OAuth2` server to retrieve a new `AccessToken`.
-pub fn oauth2(server: Url, client: &Client) -> Result<AccessToken, Error> {
+/// Authenticate with the specified `OAuth2` server to retrieve a new `AccessToken`,
+/// failing when the response is larger than `max_bytes`.
+pub fn oauth2(server: Url, client: &Client, max_bytes: u64) -> Result<AccessToken, Error> {
     info!("OAuth2 authentication server: {}", server);
-    let rx = client.post(server, Some(br#"grant_type=client_credentials"#.to_vec()));
+    let body = br#"grant_type=client_credentials"#.to_vec();
+    let rx = client.send_request(Request::new(Method::Post, server, Some(body)).max_bytes(max_bytes));
     match rx.recv().expect("no authenticate response received") {
         Response::Success(data) => Ok(json::from_slice(&data.body)?),
         Response::Failed(data)  => Err(data.into()),
@@ -68,12 +70,21 @@ mod tests {
             expires_in:   10,
             scope:        "scope1 scope2".to_string()
         };
-        assert_eq!(expect, oauth2(test_server(), &client).unwrap());
+        assert_eq!(expect, oauth2(test_server(), &client, 1024).unwrap());
     }
 
     #[test]
     fn test_oauth2_bad_json() {
         let client = TestClient::from(vec![br#"{"apa": 1}"#.to_vec()]);
-        assert!(oauth2(test_server(), &client).is_err());
+        assert!(oauth2(test_server(), &client, 1024).is_err());
+    }
+
+    #[test]
+    fn test_oauth2_too_large() {
+        let client = TestClient::from(vec![br#"{"access_token": "token"}"#.to_vec()]);
+        match oauth2(test_server(), &client, 10) {
+            Err(Error::TooLarge(_)) => (),
+            other => panic!("expected response too large, got {:?}", other)
+        }
     }
 }
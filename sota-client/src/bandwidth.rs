@@ -0,0 +1,334 @@
+use chrono::{self, DateTime, Datelike, TimeZone, Timelike, Utc};
+use json;
+use serde::de::{Deserialize, Deserializer, Error as SerdeError};
+use serde::ser::{Serialize, Serializer};
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use datatype::{Clock, DeviceConfig, Error, SystemClock, Util};
+
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+
+/// A daily window in UTC when downloads may start, either at full speed or
+/// limited to a number of bytes per second. Written as `00:00-06:00=full` or
+/// `06:00-24:00=65536`, where a window ending before it starts crosses midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BandwidthRule {
+    pub start: u32,
+    pub end:   u32,
+    pub limit: Option<u64>,
+}
+
+impl BandwidthRule {
+    /// Whether this window includes the time of day in minutes after midnight.
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Parse a comma-separated list of rules.
+    pub fn parse_list(rules: &str) -> Result<Vec<BandwidthRule>, Error> {
+        rules.split(',').map(str::trim).filter(|rule| !rule.is_empty()).map(str::parse).collect()
+    }
+}
+
+impl FromStr for BandwidthRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BandwidthRule, Error> {
+        let mut parts = s.splitn(2, '=');
+        let window = parts.next().unwrap_or("");
+        let limit = match parts.next().map(str::trim) {
+            None | Some("full") => None,
+            Some(limit) => match limit.parse::<u64>() {
+                Ok(limit) if limit > 0 => Some(limit),
+                _ => return Err(Error::Parse(format!("invalid bandwidth limit in {}: {}", s, limit)))
+            }
+        };
+        let mut times = window.splitn(2, '-');
+        let start = parse_time(times.next().unwrap_or(""))?;
+        let end = parse_time(times.next().unwrap_or(""))?;
+        if start == end || start == MINUTES_PER_DAY {
+            return Err(Error::Parse(format!("empty bandwidth window: {}", s)));
+        }
+        Ok(BandwidthRule { start: start, end: end % MINUTES_PER_DAY, limit: limit })
+    }
+}
+
+impl Display for BandwidthRule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let end = if self.end == 0 { MINUTES_PER_DAY } else { self.end };
+        write!(f, "{:02}:{:02}-{:02}:{:02}=", self.start / 60, self.start % 60, end / 60, end % 60)?;
+        match self.limit {
+            Some(limit) => write!(f, "{}", limit),
+            None => write!(f, "full")
+        }
+    }
+}
+
+impl Serialize for BandwidthRule {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        ser.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for BandwidthRule {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<BandwidthRule, D::Error> {
+        let s: String = Deserialize::deserialize(de)?;
+        s.parse().map_err(|err| SerdeError::custom(format!("invalid bandwidth rule: {}", err)))
+    }
+}
+
+/// Parse a time of day as minutes after midnight, allowing `24:00`.
+fn parse_time(time: &str) -> Result<u32, Error> {
+    let mut parts = time.trim().splitn(2, ':');
+    let hours = parts.next().and_then(|hours| hours.parse::<u32>().ok());
+    let minutes = parts.next().and_then(|minutes| minutes.parse::<u32>().ok());
+    match (hours, minutes) {
+        (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => Ok(hours * 60 + minutes),
+        (Some(24), Some(0)) => Ok(MINUTES_PER_DAY),
+        _ => Err(Error::Parse(format!("invalid time of day: {}", time)))
+    }
+}
+
+
+/// The bytes downloaded in a calendar month, saved so that the monthly cap
+/// holds across restarts.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DownloadUsage {
+    pub month: String,
+    pub bytes: u64,
+}
+
+impl DownloadUsage {
+    /// Read the usage for the month of `now`, starting from zero in a new month.
+    pub fn load(path: &str, now: DateTime<Utc>) -> Result<Self, Error> {
+        let month = now.format("%Y-%m").to_string();
+        let usage = if Path::new(path).exists() {
+            json::from_slice::<DownloadUsage>(&Util::read_file(path)?)?
+        } else {
+            DownloadUsage { month: month.clone(), bytes: 0 }
+        };
+        if usage.month == month {
+            Ok(usage)
+        } else {
+            Ok(DownloadUsage { month: month, bytes: 0 })
+        }
+    }
+
+    /// Add downloaded bytes to the usage saved at `path`.
+    pub fn add(path: &str, bytes: u64, now: DateTime<Utc>) -> Result<Self, Error> {
+        let mut usage = Self::load(path, now)?;
+        usage.bytes += bytes;
+        Util::write_file(path, &json::to_vec(&usage)?)?;
+        Ok(usage)
+    }
+}
+
+
+/// The download windows and monthly cap set in the device config.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bandwidth {
+    pub rules:      Vec<BandwidthRule>,
+    pub cap:        Option<u64>,
+    pub usage_file: Option<String>,
+}
+
+impl Bandwidth {
+    /// Read the `download_*` device config, failing on invalid rules or a
+    /// monthly cap without a file to track usage in.
+    pub fn from_config(device: &DeviceConfig) -> Result<Self, Error> {
+        let rules = match device.download_rules {
+            Some(ref rules) => BandwidthRule::parse_list(rules)?,
+            None => Vec::new()
+        };
+        if device.download_monthly_cap.is_some() && device.download_usage_file.is_none() {
+            return Err(Error::Config("device.download_monthly_cap requires device.download_usage_file".into()));
+        }
+        Ok(Bandwidth { rules: rules, cap: device.download_monthly_cap, usage_file: device.download_usage_file.clone() })
+    }
+
+    /// Returns the bytes downloaded so far this month.
+    pub fn used(&self, now: DateTime<Utc>) -> Result<u64, Error> {
+        match self.usage_file {
+            Some(ref path) => DownloadUsage::load(path, now).map(|usage| usage.bytes),
+            None => Ok(0)
+        }
+    }
+
+    /// Returns the speed limit for a download starting at `now`, or the
+    /// reason it should be deferred. Downloads run at full speed when no rules
+    /// are set.
+    pub fn check(&self, now: DateTime<Utc>) -> Result<Result<Option<u64>, String>, Error> {
+        if let Some(cap) = self.cap {
+            let used = self.used(now)?;
+            if used >= cap {
+                return Ok(Err(format!("monthly download cap reached ({} of {} bytes)", used, cap)));
+            }
+        }
+        if self.rules.is_empty() {
+            return Ok(Ok(None));
+        }
+        let minute = now.hour() * 60 + now.minute();
+        match self.rules.iter().find(|rule| rule.contains(minute)) {
+            Some(rule) => Ok(Ok(rule.limit)),
+            None => {
+                let windows = self.rules.iter().map(|rule| rule.to_string()).collect::<Vec<_>>();
+                Ok(Err(format!("outside the download windows {}", windows.join(","))))
+            }
+        }
+    }
+
+    /// Returns the earliest time from `now` that a deferred download may
+    /// start: the next month once the cap is reached, otherwise the start of
+    /// the next download window.
+    pub fn next_start(&self, now: DateTime<Utc>) -> Result<DateTime<Utc>, Error> {
+        if let Some(cap) = self.cap {
+            if self.used(now)? >= cap {
+                let (year, month) = if now.month() == 12 { (now.year() + 1, 1) } else { (now.year(), now.month() + 1) };
+                return Ok(Utc.ymd(year, month, 1).and_hms(0, 0, 0));
+            }
+        }
+        let minute = now.hour() * 60 + now.minute();
+        let wait = self.rules.iter()
+            .map(|rule| if rule.contains(minute) { 0 } else { (rule.start + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY })
+            .min();
+        match wait {
+            Some(0) | None => Ok(now),
+            Some(wait) => {
+                let minute_start = now.with_second(0).and_then(|now| now.with_nanosecond(0)).unwrap_or(now);
+                Ok(minute_start + chrono::Duration::minutes(i64::from(wait)))
+            }
+        }
+    }
+}
+
+
+/// Checks the bandwidth rules and counts downloaded bytes against the monthly
+/// cap using the same clock, for downloads made outside of `Sota`.
+#[derive(Clone)]
+pub struct DownloadMeter {
+    pub bandwidth: Bandwidth,
+    pub clock:     Arc<Clock>,
+}
+
+impl Default for DownloadMeter {
+    fn default() -> Self {
+        DownloadMeter { bandwidth: Bandwidth::default(), clock: Arc::new(SystemClock) }
+    }
+}
+
+impl DownloadMeter {
+    /// Fail with `Error::DownloadDeferred` outside the download windows or
+    /// once the monthly cap is reached. This is for downloads such as ostree
+    /// pulls that can't be rate limited, so they run at full speed in any
+    /// window.
+    pub fn allow_download(&self) -> Result<(), Error> {
+        match self.bandwidth.check(self.clock.now())? {
+            Ok(None) => Ok(()),
+            Ok(Some(limit)) => {
+                warn!("downloading at full speed as the limit of {} bytes/sec can't be applied", limit);
+                Ok(())
+            }
+            Err(reason) => Err(Error::DownloadDeferred(reason))
+        }
+    }
+
+    /// Add downloaded bytes to the monthly usage, logging any failure to save it.
+    pub fn add(&self, bytes: u64) {
+        if let Some(ref path) = self.bandwidth.usage_file {
+            if let Err(err) = DownloadUsage::add(path, bytes, self.clock.now()) {
+                error!("couldn't record download usage in {}: {}", path, err);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datatype::FakeClock;
+    use pacman::test::TestDir;
+
+
+    #[test]
+    fn test_download_windows() {
+        let rules = BandwidthRule::parse_list("00:00-06:00=full, 06:00-24:00=65536").expect("parse rules");
+        assert_eq!(rules, vec![
+            BandwidthRule { start: 0, end: 360, limit: None },
+            BandwidthRule { start: 360, end: 0, limit: Some(65536) },
+        ]);
+        assert_eq!(rules[1].to_string(), "06:00-24:00=65536");
+        assert!("22:00-06:00".parse::<BandwidthRule>().expect("overnight").contains(120));
+        assert!("06:00-06:00".parse::<BandwidthRule>().is_err());
+        assert!("06:00-25:00".parse::<BandwidthRule>().is_err());
+        assert!("06:00-07:00=0".parse::<BandwidthRule>().is_err());
+
+        let bandwidth = Bandwidth { rules: rules, cap: None, usage_file: None };
+        assert_eq!(bandwidth.check(Utc.ymd(2017, 1, 1).and_hms(3, 0, 0)).unwrap(), Ok(None));
+        assert_eq!(bandwidth.check(Utc.ymd(2017, 1, 1).and_hms(12, 0, 0)).unwrap(), Ok(Some(65536)));
+
+        let night = Bandwidth { rules: BandwidthRule::parse_list("22:00-06:00").unwrap(), cap: None, usage_file: None };
+        assert_eq!(night.check(Utc.ymd(2017, 1, 1).and_hms(23, 30, 0)).unwrap(), Ok(None));
+        match night.check(Utc.ymd(2017, 1, 1).and_hms(12, 0, 0)).unwrap() {
+            Err(reason) => assert_eq!(reason, "outside the download windows 22:00-06:00=full"),
+            other => panic!("expected a deferred download, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_monthly_cap() {
+        let dir = TestDir::new("sota-bandwidth-cap");
+        let path = format!("{}/usage.json", dir.0);
+        let bandwidth = Bandwidth { rules: Vec::new(), cap: Some(100), usage_file: Some(path.clone()) };
+        let now = Utc.ymd(2017, 1, 31).and_hms(12, 0, 0);
+
+        DownloadUsage::add(&path, 60, now).expect("add usage");
+        assert_eq!(bandwidth.check(now).unwrap(), Ok(None));
+        DownloadUsage::add(&path, 40, now).expect("add usage");
+        match bandwidth.check(now).unwrap() {
+            Err(reason) => assert_eq!(reason, "monthly download cap reached (100 of 100 bytes)"),
+            other => panic!("expected a deferred download, got {:?}", other)
+        }
+        assert_eq!(bandwidth.check(Utc.ymd(2017, 2, 1).and_hms(0, 0, 0)).unwrap(), Ok(None));
+        assert_eq!(bandwidth.next_start(now).unwrap(), Utc.ymd(2017, 2, 1).and_hms(0, 0, 0));
+    }
+
+    #[test]
+    fn test_next_start() {
+        let bandwidth = Bandwidth { rules: BandwidthRule::parse_list("01:00-02:00,22:00-23:00=1024").unwrap(), cap: None, usage_file: None };
+        let inside = Utc.ymd(2017, 1, 1).and_hms(1, 30, 15);
+        assert_eq!(bandwidth.next_start(inside).unwrap(), inside);
+        assert_eq!(bandwidth.next_start(Utc.ymd(2017, 1, 1).and_hms(12, 0, 30)).unwrap(), Utc.ymd(2017, 1, 1).and_hms(22, 0, 0));
+        assert_eq!(bandwidth.next_start(Utc.ymd(2017, 1, 1).and_hms(23, 10, 0)).unwrap(), Utc.ymd(2017, 1, 2).and_hms(1, 0, 0));
+    }
+
+    #[test]
+    fn test_meter_allow_download() {
+        let dir = TestDir::new("sota-bandwidth-meter");
+        let clock = FakeClock::new(Utc.ymd(2017, 1, 1).and_hms(20, 0, 0));
+        let bandwidth = Bandwidth {
+            rules:      BandwidthRule::parse_list("00:00-06:00=full,06:00-18:00=1024").unwrap(),
+            cap:        Some(100),
+            usage_file: Some(format!("{}/usage.json", dir.0)),
+        };
+        let meter = DownloadMeter { bandwidth: bandwidth, clock: Arc::new(clock.clone()) };
+        match meter.allow_download() {
+            Err(Error::DownloadDeferred(reason)) => assert!(reason.starts_with("outside the download windows")),
+            other => panic!("expected a deferred download, got {:?}", other)
+        }
+        clock.advance(chrono::Duration::hours(12));
+        assert!(meter.allow_download().is_ok());
+        meter.add(100);
+        assert_eq!(meter.bandwidth.used(clock.now()).unwrap(), 100);
+        assert!(meter.allow_download().is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use chan::{Sender, Receiver};
+use serde::de::{Deserialize, Deserializer, Error as SerdeError};
+use serde::ser::{Serialize, Serializer};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use datatype::Error;
+
+
+/// The action taken when a message arrives at a full `BoundedQueue`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Stop receiving so that senders block until there is space.
+    Block,
+    /// Discard the oldest waiting message that isn't critical to make space.
+    DropOldest,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<QueuePolicy, Error> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(QueuePolicy::Block),
+            "drop_oldest" | "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            _ => Err(Error::Parse(format!("unknown queue policy: {}", s)))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for QueuePolicy {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<QueuePolicy, D::Error> {
+        let s: String = Deserialize::deserialize(de)?;
+        s.parse().map_err(|err| SerdeError::custom(format!("invalid queue policy: {}", err)))
+    }
+}
+
+impl Serialize for QueuePolicy {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            QueuePolicy::Block      => ser.serialize_str("block"),
+            QueuePolicy::DropOldest => ser.serialize_str("drop_oldest"),
+        }
+    }
+}
+
+
+/// Marks the messages that a full `BoundedQueue` must never drop.
+pub trait Critical {
+    fn is_critical(&self) -> bool;
+}
+
+
+/// Forward messages in order while holding at most `capacity` waiting
+/// messages, applying the `QueuePolicy` when full.
+pub struct BoundedQueue<A: Clone + Debug + Critical> {
+    capacity: usize,
+    policy:   QueuePolicy,
+    waiting:  VecDeque<A>,
+}
+
+impl<A: Clone + Debug + Critical> BoundedQueue<A> {
+    /// Create a new queue holding at least one waiting message.
+    pub fn new(capacity: usize, policy: QueuePolicy) -> BoundedQueue<A> {
+        BoundedQueue { capacity: if capacity > 0 { capacity } else { 1 }, policy: policy, waiting: VecDeque::new() }
+    }
+
+    /// Start forwarding messages from `rx` to `tx` until `rx` is closed.
+    pub fn start(&mut self, rx: Receiver<A>, tx: Sender<A>) {
+        loop {
+            if self.waiting.is_empty() {
+                match rx.recv() {
+                    Some(msg) => self.push(msg),
+                    None => break
+                }
+            } else if ! self.is_receiving() {
+                tx.send(self.waiting.pop_front().expect("front of queue"));
+            } else {
+                let next = self.waiting.front().cloned().expect("front of queue");
+                let mut closed = false;
+                chan_select! {
+                    rx.recv() -> msg => {
+                        match msg {
+                            Some(msg) => self.push(msg),
+                            None => closed = true
+                        }
+                    },
+                    tx.send(next) => { self.waiting.pop_front(); },
+                }
+                if closed { break }
+            }
+        }
+
+        for msg in self.waiting.drain(..) {
+            tx.send(msg);
+        }
+    }
+
+    /// Add a message to the back of the queue. When full with a `DropOldest`
+    /// policy the oldest non-critical message is dropped, which may be the new
+    /// one. Critical messages are kept even when that exceeds the capacity.
+    pub fn push(&mut self, msg: A) {
+        if self.is_full() && self.policy == QueuePolicy::DropOldest {
+            match self.waiting.iter().position(|waiting| ! waiting.is_critical()) {
+                Some(index) => {
+                    let dropped = self.waiting.remove(index);
+                    warn!("queue full ({} waiting), dropping oldest: {:?}", self.capacity, dropped);
+                }
+                None if ! msg.is_critical() => {
+                    warn!("queue full ({} waiting) of critical messages, dropping: {:?}", self.capacity, msg);
+                    return;
+                }
+                None => warn!("queue full ({} waiting) of critical messages, keeping: {:?}", self.capacity, msg)
+            }
+        }
+        self.waiting.push_back(msg);
+    }
+
+    /// Returns true if the queue holds `capacity` waiting messages.
+    pub fn is_full(&self) -> bool {
+        self.waiting.len() >= self.capacity
+    }
+
+    /// Returns false while a full queue with a `Block` policy stops receiving.
+    pub fn is_receiving(&self) -> bool {
+        ! (self.is_full() && self.policy == QueuePolicy::Block)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chan;
+
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Msg {
+        Normal(u32),
+        Critical(u32),
+    }
+
+    impl Critical for Msg {
+        fn is_critical(&self) -> bool {
+            match *self {
+                Msg::Normal(_)   => false,
+                Msg::Critical(_) => true,
+            }
+        }
+    }
+
+    fn push_all(queue: &mut BoundedQueue<Msg>, msgs: &[Msg]) {
+        for msg in msgs {
+            queue.push(*msg);
+        }
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("block".parse::<QueuePolicy>().unwrap(), QueuePolicy::Block);
+        assert_eq!("drop_oldest".parse::<QueuePolicy>().unwrap(), QueuePolicy::DropOldest);
+        assert!("drop_newest".parse::<QueuePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_forwards_in_order() {
+        let (in_tx, in_rx) = chan::async::<Msg>();
+        let (out_tx, out_rx) = chan::async::<Msg>();
+        for n in 1..5 {
+            in_tx.send(Msg::Normal(n));
+        }
+        drop(in_tx);
+        BoundedQueue::new(2, QueuePolicy::Block).start(in_rx, out_tx);
+        let expected = (1..5).map(Msg::Normal).collect::<Vec<_>>();
+        assert_eq!(out_rx.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_full_queue_blocks() {
+        let mut queue = BoundedQueue::new(2, QueuePolicy::Block);
+        queue.push(Msg::Normal(1));
+        assert!(queue.is_receiving());
+        queue.push(Msg::Normal(2));
+        assert!(! queue.is_receiving());
+        assert!(BoundedQueue::<Msg>::new(0, QueuePolicy::Block).is_receiving());
+    }
+
+    #[test]
+    fn test_push_drops_oldest() {
+        let mut queue = BoundedQueue::new(2, QueuePolicy::DropOldest);
+        push_all(&mut queue, &[Msg::Normal(1), Msg::Normal(2), Msg::Normal(3), Msg::Normal(4)]);
+        assert_eq!(queue.waiting, VecDeque::from(vec![Msg::Normal(3), Msg::Normal(4)]));
+        assert!(queue.is_receiving());
+    }
+
+    #[test]
+    fn test_push_keeps_critical() {
+        let mut queue = BoundedQueue::new(2, QueuePolicy::DropOldest);
+        push_all(&mut queue, &[Msg::Critical(1), Msg::Normal(2), Msg::Normal(3)]);
+        assert_eq!(queue.waiting, VecDeque::from(vec![Msg::Critical(1), Msg::Normal(3)]));
+        push_all(&mut queue, &[Msg::Critical(4), Msg::Normal(5)]);
+        assert_eq!(queue.waiting, VecDeque::from(vec![Msg::Critical(1), Msg::Critical(4)]));
+        queue.push(Msg::Critical(6));
+        assert_eq!(queue.waiting, VecDeque::from(vec![Msg::Critical(1), Msg::Critical(4), Msg::Critical(6)]));
+    }
+}
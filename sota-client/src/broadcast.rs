@@ -1,21 +1,36 @@
 use chan::{self, Sender, Receiver};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 
 
 /// Receive a message and broadcast to all current peers.
 pub struct Broadcast<A: Clone> {
-    rx:    Receiver<A>,
-    peers: Vec<Sender<A>>,
+    rx:      Receiver<A>,
+    peers:   Vec<Sender<A>>,
+    history: History<A>,
 }
 
 impl<A: Clone> Broadcast<A> {
     /// Instantiate a new broadcaster for the given `Receiver`.
     pub fn new(rx: Receiver<A>) -> Broadcast<A> {
-        Broadcast { rx: rx, peers: Vec::new() }
+        Self::with_history(rx, 0)
+    }
+
+    /// Instantiate a new broadcaster that also remembers the last `capacity`
+    /// messages sent.
+    pub fn with_history(rx: Receiver<A>, capacity: usize) -> Broadcast<A> {
+        Broadcast { rx: rx, peers: Vec::new(), history: History::new(capacity) }
+    }
+
+    /// Returns a handle to the recently broadcast messages.
+    pub fn history(&self) -> History<A> {
+        self.history.clone()
     }
 
     /// Start forwarding received messages to every peer.
     pub fn start(&self) {
         while let Some(msg) = self.rx.recv() {
+            self.history.push(msg.clone());
             for peer in &self.peers {
                 peer.send(msg.clone());
             }
@@ -31,6 +46,46 @@ impl<A: Clone> Broadcast<A> {
 }
 
 
+/// The most recently broadcast messages, up to a fixed capacity. Clones share
+/// the same messages.
+#[derive(Clone)]
+pub struct History<A> {
+    capacity: usize,
+    recent:   Arc<Mutex<VecDeque<A>>>,
+}
+
+impl<A: Clone> Default for History<A> {
+    fn default() -> Self {
+        History::new(0)
+    }
+}
+
+impl<A: Clone> History<A> {
+    pub fn new(capacity: usize) -> Self {
+        History { capacity: capacity, recent: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))) }
+    }
+
+    /// Remember a message, forgetting the oldest when full.
+    pub fn push(&self, msg: A) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == self.capacity {
+            let _ = recent.pop_front();
+        }
+        recent.push_back(msg);
+    }
+
+    /// Returns up to the last `count` messages, oldest first.
+    pub fn last(&self, count: usize) -> Vec<A> {
+        let recent = self.recent.lock().unwrap();
+        let skip = recent.len().saturating_sub(count);
+        recent.iter().skip(skip).cloned().collect()
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use chan;
@@ -52,4 +107,21 @@ mod tests {
         assert_eq!(123, one.recv().unwrap());
         assert_eq!(123, two.recv().unwrap());
     }
+
+    #[test]
+    fn test_history() {
+        let (tx, rx) = chan::sync(0);
+        let mut bc = Broadcast::with_history(rx, 3);
+        let history = bc.history();
+        let peer = bc.subscribe();
+        thread::spawn(move || bc.start());
+
+        for msg in 1..6 {
+            tx.send(msg);
+            assert_eq!(Some(msg), peer.recv());
+        }
+        assert_eq!(history.last(2), vec![4, 5]);
+        assert_eq!(history.last(10), vec![3, 4, 5]);
+        assert!(history.last(0).is_empty());
+    }
 }
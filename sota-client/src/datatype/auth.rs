@@ -1,4 +1,6 @@
+use std::cmp;
 use std::fmt::{self, Debug, Display, Formatter};
+use std::time::Duration;
 
 
 /// An enumeration of all authentication types.
@@ -44,3 +46,63 @@ pub struct AccessToken {
     pub expires_in:   i32,
     pub scope:        String
 }
+
+impl AccessToken {
+    /// Return each of the space-separated scopes granted to this token.
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scope.split_whitespace().collect()
+    }
+}
+
+
+/// Delays repeated authentication attempts, doubling the delay after each
+/// consecutive failure up to `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuthBackoff {
+    pub initial:  Duration,
+    pub max:      Duration,
+    pub failures: u32,
+}
+
+impl AuthBackoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        AuthBackoff { initial: initial, max: max, failures: 0 }
+    }
+
+    /// Record a failed attempt, returning how long to wait before the next.
+    pub fn next_attempt(&mut self) -> Duration {
+        let factor = 1u32.checked_shl(self.failures).unwrap_or(u32::max_value());
+        self.failures = self.failures.saturating_add(1);
+        cmp::min(self.initial.checked_mul(factor).unwrap_or(self.max), self.max)
+    }
+
+    /// Forget any previous failures after authenticating successfully.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_scopes() {
+        let token = AccessToken { scope: " scope1  scope2 ".into(), ..AccessToken::default() };
+        assert_eq!(token.scopes(), vec!["scope1", "scope2"]);
+        assert!(AccessToken::default().scopes().is_empty());
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let mut backoff = AuthBackoff::new(Duration::from_secs(10), Duration::from_secs(60));
+        let delays = (0..5).map(|_| backoff.next_attempt().as_secs()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![10, 20, 40, 60, 60]);
+        backoff.failures = 40;
+        assert_eq!(backoff.next_attempt(), Duration::from_secs(60));
+        backoff.reset();
+        assert_eq!(backoff.next_attempt(), Duration::from_secs(10));
+    }
+}
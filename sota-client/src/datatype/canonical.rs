@@ -1,5 +1,6 @@
 use itoa;
 use json;
+use std::cmp;
 use std::collections::BTreeMap;
 
 use datatype::Error;
@@ -14,6 +15,74 @@ impl CanonicalJson {
         json.write(&mut buf)?;
         Ok(buf)
     }
+
+    /// Compare JSON bytes with their canonical form, returning a description
+    /// of the first difference when they aren't already canonical.
+    pub fn diff(received: &[u8]) -> Result<Option<String>, Error> {
+        let canonical = Self::convert(json::from_slice(received)?)?;
+        let offset = match received.iter().zip(canonical.iter()).position(|(a, b)| a != b) {
+            Some(offset) => offset,
+            None if received.len() == canonical.len() => return Ok(None),
+            None => cmp::min(received.len(), canonical.len())
+        };
+        Ok(Some(format!("differs from canonical json at byte {}: received `{}`, canonical `{}`",
+                        offset, excerpt(received, offset), excerpt(&canonical, offset))))
+    }
+
+    /// Returns the raw bytes of a top-level field's value in a JSON object.
+    pub fn raw_field<'a>(json: &'a [u8], field: &str) -> Option<&'a [u8]> {
+        let key = format!("\"{}\"", field);
+        let (mut depth, mut in_str, mut escaped) = (0, false, false);
+        let (mut key_start, mut value_start) = (None, None);
+        for (pos, &byte) in json.iter().enumerate() {
+            if in_str {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_str = false,
+                    _ => ()
+                }
+                continue;
+            }
+            match byte {
+                b'"' => {
+                    in_str = true;
+                    if depth == 1 && value_start.is_none() {
+                        key_start = Some(pos);
+                    }
+                }
+                b':' if depth == 1 && value_start.is_none() => {
+                    let name = key_start.map(|start| trim_ascii(&json[start..pos]));
+                    if name == Some(key.as_bytes()) {
+                        value_start = Some(pos + 1);
+                    }
+                }
+                b'{' | b'[' => depth += 1,
+                b'}' | b']' if depth == 1 => return value_start.map(|start| trim_ascii(&json[start..pos])),
+                b'}' | b']' => depth -= 1,
+                b',' if depth == 1 => if let Some(start) = value_start {
+                    return Some(trim_ascii(&json[start..pos]));
+                },
+                _ => ()
+            }
+        }
+        None
+    }
+}
+
+/// Returns the bytes around an offset for display.
+fn excerpt(bytes: &[u8], offset: usize) -> String {
+    let start = offset.saturating_sub(16);
+    let end = cmp::min(offset + 24, bytes.len());
+    String::from_utf8_lossy(&bytes[start..end]).into_owned()
+}
+
+/// Returns the slice without leading or trailing ASCII whitespace.
+fn trim_ascii(bytes: &[u8]) -> &[u8] {
+    let text = |byte: &u8| match *byte { b' ' | b'\t' | b'\n' | b'\r' => false, _ => true };
+    let start = bytes.iter().position(&text).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(&text).map_or(start, |end| end + 1);
+    &bytes[start..end]
 }
 
 
@@ -140,4 +209,21 @@ mod test {
         input.write(&mut buf).expect("write failed");
         assert_eq!(&buf, &"{\"some key\":[\"some val array\",-1]}".as_bytes());
     }
+
+    #[test]
+    fn canonical_diff() {
+        assert_eq!(CanonicalJson::diff(br#"{"a":[1,"b"],"c":true}"#).unwrap(), None);
+        let diff = CanonicalJson::diff(br#"{"c":true, "a":[1,"b"]}"#).unwrap().expect("not canonical");
+        assert_eq!(diff, r#"differs from canonical json at byte 2: received `{"c":true, "a":[1,"b"]}`, canonical `{"a":[1,"b"],"c":true}`"#);
+        let diff = CanonicalJson::diff(br#"{"a":1} "#).unwrap().expect("trailing space");
+        assert!(diff.starts_with("differs from canonical json at byte 7:"), "{}", diff);
+    }
+
+    #[test]
+    fn raw_signed_field() {
+        let input = br#"{"signatures": [{"sig": "a,b}"}], "signed" : {"_type": "Targets", "x": [1, 2]} }"#;
+        assert_eq!(CanonicalJson::raw_field(input, "signed"), Some(&br#"{"_type": "Targets", "x": [1, 2]}"#[..]));
+        assert_eq!(CanonicalJson::raw_field(input, "sig"), None);
+        assert_eq!(CanonicalJson::raw_field(input, "missing"), None);
+    }
 }
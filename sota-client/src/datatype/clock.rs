@@ -0,0 +1,68 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::{Arc, Mutex};
+
+
+/// A source of the current time, so that time-dependent behavior such as
+/// expiry checks can be tested without waiting.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+
+/// Reads the current system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+
+/// A clock that only moves when advanced. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct FakeClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl FakeClock {
+    /// Create a new clock stopped at the given time.
+    pub fn new(now: DateTime<Utc>) -> FakeClock {
+        FakeClock { now: Arc::new(Mutex::new(now)) }
+    }
+
+    /// Move the clock forward by this duration.
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + by;
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> FakeClock {
+        FakeClock::new(Utc::now())
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_fake_clock() {
+        let clock = FakeClock::default();
+        let shared = clock.clone();
+        let start = clock.now();
+        clock.advance(Duration::seconds(90));
+        assert_eq!(shared.now(), start + Duration::seconds(90));
+    }
+}
@@ -1,10 +1,14 @@
+use chrono::{DateTime, Utc};
 use std::fmt::{self, Display, Formatter};
 use std::str::FromStr;
 use uuid::Uuid;
 
+use bandwidth::BandwidthRule;
 use datatype::{Auth, ClientCredentials, Error, InstallCode, InstallReport,
-               InstallResult, InstalledSoftware, Manifests, Package};
-use uptane::Verified;
+               InstallResult, InstalledSoftware, Manifests, OstreeRemote, Package, RoleName};
+#[cfg(any(test, feature = "debug"))]
+use pacman::PacMan;
+use uptane::{Service, Verified};
 
 
 /// System-wide commands that are sent to the interpreter.
@@ -12,35 +16,187 @@ use uptane::Verified;
 pub enum Command {
     /// Authenticate with the auth server.
     Authenticate(Auth),
+    /// Authenticate again with the configured credentials, reporting any failure.
+    ReAuthenticate,
     /// Shutdown the client immediately.
     Shutdown,
+    /// Turn automatic downloading of pending updates on or off.
+    SetAutoDownload(bool),
+    /// Stop starting downloads and installs until resumed.
+    Pause,
+    /// Start downloads and installs again, including any deferred while paused.
+    Resume,
+    /// Replace the running package manager (for development and testing only).
+    #[cfg(any(test, feature = "debug"))]
+    SetPackageManager(PacMan),
+    /// Return the current `RUST_LOG`-style logging filter.
+    GetLogLevel,
+    /// Replace the logging filter with directives such as `debug` or `info,sota::uptane=trace`.
+    SetLogLevel(String),
+    /// Approve or decline an update that is waiting for confirmation.
+    ConfirmUpdate { update_id: Uuid, approved: bool },
 
     /// Check for any pending or in-flight updates.
     GetUpdateRequests,
+    /// List the available updates without downloading or installing them.
+    CheckUpdates,
 
     /// List the installed packages on the system.
     ListInstalledPackages,
     /// List the system information.
     ListSystemInfo,
+    /// List the free space in each storage directory.
+    GetStorageInfo,
+    /// Ask the package manager to check the installed files for corruption.
+    VerifySystemIntegrity,
 
     /// Start downloading an update.
     StartDownload(Uuid),
+    /// Abort all active transfers and remove their partial images.
+    AbortAllTransfers,
+    /// List the download windows, the monthly cap and the bytes downloaded this month.
+    ListBandwidthRules,
+    /// Replace the download windows, removing them all when empty.
+    SetBandwidthRules(Vec<BandwidthRule>),
+    /// Abort an update canceled by the server, removing its artifacts.
+    ServerAbort(Uuid),
+    /// Remove downloaded update artifacts, optionally only those older than this many seconds.
+    CleanupArtifacts { older_than_secs: Option<u64> },
     /// Start installing an update.
     StartInstall(Uuid),
+    /// Download an update now and install it at a future time.
+    ScheduleInstall { update_id: Uuid, at: DateTime<Utc> },
+    /// Verify and install an Uptane update bundle from a local directory.
+    InstallLocalBundle { path: String },
 
     /// Send a list of installed packages.
     SendInstalledPackages(Vec<Package>),
     /// Send a list of installed packages and firmware.
     SendInstalledSoftware(InstalledSoftware),
+    /// Gather and send the installed software to the RVI backend.
+    SendInstalledSoftwareNow,
     /// Send a hardware report.
     SendSystemInfo,
     /// Send an installation report.
     SendInstallReport(InstallReport),
+    /// Re-query the package manager and send the installed packages.
+    SyncInstalledPackages,
+    /// List the installation reports waiting in the spool to be sent.
+    GetPendingReports,
+    /// Discard every installation report waiting in the spool.
+    ClearPendingReports,
+    /// Try to send every installation report waiting in the spool.
+    SendPendingReports,
+    /// Return up to this many of the most recent events to the caller.
+    ReplayEvents(usize),
+    /// Write a support bundle of the current state, with secrets redacted, to this path.
+    ExportDiagnostics { path: String },
 
     /// Send signed reports from ECUs to the Director server.
     UptaneSendManifest(Option<Manifests>),
+    /// Build and send the current manifest to the Director server now.
+    UptaneSendManifestNow,
+    /// Build and sign the manifest for the Director server without sending it.
+    UptaneExportManifest,
     /// Install the verified targets.json metadata to their respective ECUs.
     UptaneStartInstall(Box<Verified>),
+    /// Replace the primary ECU's private key with the one at this path, using
+    /// the public key file to derive the new key id.
+    UptaneRotateKey { new_key_path: String, new_pub_key_path: String },
+    /// List the trusted Uptane metadata version of each role.
+    UptaneMetadataVersions,
+    /// List the primary and secondary ECUs with their current versions.
+    UptaneListEcus,
+    /// Return the raw metadata of a role without verifying it again.
+    UptaneGetRawMetadata { service: Service, role: RoleName },
+    /// Check that a target in the Director's targets.json is for one of this device's ECUs.
+    UptaneVerifyTarget(String),
+    /// Install a target from the Director's targets.json even if it isn't new.
+    UptaneForceInstall(String),
+
+    /// List the configured OSTree remotes.
+    OstreeListRemotes,
+    /// Add (or replace) an OSTree remote.
+    OstreeAddRemote(OstreeRemote),
+    /// Delete the OSTree remote with this name.
+    OstreeDeleteRemote(String),
+}
+
+impl Command {
+    /// Returns true for commands that a full command queue must never drop,
+    /// as they authenticate the client, install updates or report results.
+    pub fn is_critical(&self) -> bool {
+        match *self {
+            Command::Authenticate(_)           |
+            Command::ReAuthenticate            |
+            Command::Shutdown                  |
+            Command::ConfirmUpdate { .. }      |
+            Command::StartInstall(_)           |
+            Command::ScheduleInstall { .. }    |
+            Command::InstallLocalBundle { .. } |
+            Command::SendInstallReport(_)      |
+            Command::UptaneSendManifest(_)     |
+            Command::UptaneStartInstall(_)     |
+            Command::UptaneForceInstall(_)     => true,
+            _ => false
+        }
+    }
+
+    /// Returns the name of the command variant, as used in allow-lists.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::Authenticate(_)             => "Authenticate",
+            Command::ReAuthenticate              => "ReAuthenticate",
+            Command::Shutdown                    => "Shutdown",
+            Command::SetAutoDownload(_)          => "SetAutoDownload",
+            Command::Pause                       => "Pause",
+            Command::Resume                      => "Resume",
+            #[cfg(any(test, feature = "debug"))]
+            Command::SetPackageManager(_)        => "SetPackageManager",
+            Command::GetLogLevel                 => "GetLogLevel",
+            Command::SetLogLevel(_)              => "SetLogLevel",
+            Command::ConfirmUpdate { .. }        => "ConfirmUpdate",
+            Command::GetUpdateRequests           => "GetUpdateRequests",
+            Command::CheckUpdates                => "CheckUpdates",
+            Command::ListInstalledPackages       => "ListInstalledPackages",
+            Command::ListSystemInfo              => "ListSystemInfo",
+            Command::GetStorageInfo              => "GetStorageInfo",
+            Command::VerifySystemIntegrity       => "VerifySystemIntegrity",
+            Command::StartDownload(_)            => "StartDownload",
+            Command::AbortAllTransfers           => "AbortAllTransfers",
+            Command::ListBandwidthRules          => "ListBandwidthRules",
+            Command::SetBandwidthRules(_)        => "SetBandwidthRules",
+            Command::ServerAbort(_)              => "ServerAbort",
+            Command::CleanupArtifacts { .. }     => "CleanupArtifacts",
+            Command::StartInstall(_)             => "StartInstall",
+            Command::ScheduleInstall { .. }      => "ScheduleInstall",
+            Command::InstallLocalBundle { .. }   => "InstallLocalBundle",
+            Command::SendInstalledPackages(_)    => "SendInstalledPackages",
+            Command::SendInstalledSoftware(_)    => "SendInstalledSoftware",
+            Command::SendInstalledSoftwareNow    => "SendInstalledSoftwareNow",
+            Command::SendSystemInfo              => "SendSystemInfo",
+            Command::SendInstallReport(_)        => "SendInstallReport",
+            Command::SyncInstalledPackages       => "SyncInstalledPackages",
+            Command::GetPendingReports           => "GetPendingReports",
+            Command::ClearPendingReports         => "ClearPendingReports",
+            Command::SendPendingReports          => "SendPendingReports",
+            Command::ReplayEvents(_)             => "ReplayEvents",
+            Command::ExportDiagnostics { .. }    => "ExportDiagnostics",
+            Command::UptaneSendManifest(_)       => "UptaneSendManifest",
+            Command::UptaneSendManifestNow       => "UptaneSendManifestNow",
+            Command::UptaneExportManifest        => "UptaneExportManifest",
+            Command::UptaneStartInstall(_)       => "UptaneStartInstall",
+            Command::UptaneRotateKey { .. }      => "UptaneRotateKey",
+            Command::UptaneMetadataVersions      => "UptaneMetadataVersions",
+            Command::UptaneListEcus              => "UptaneListEcus",
+            Command::UptaneGetRawMetadata { .. } => "UptaneGetRawMetadata",
+            Command::UptaneVerifyTarget(_)       => "UptaneVerifyTarget",
+            Command::UptaneForceInstall(_)       => "UptaneForceInstall",
+            Command::OstreeListRemotes           => "OstreeListRemotes",
+            Command::OstreeAddRemote(_)          => "OstreeAddRemote",
+            Command::OstreeDeleteRemote(_)       => "OstreeDeleteRemote",
+        }
+    }
 }
 
 impl FromStr for Command {
@@ -52,6 +208,17 @@ impl FromStr for Command {
         let args = args.collect::<Vec<_>>();
 
         match cmd {
+            "AbortAllTransfers" => match args.len() {
+                0 => Ok(Command::AbortAllTransfers),
+                _ => Err(Error::Command(format!("unexpected AbortAllTransfers args: {:?}", args))),
+            },
+
+            "CleanupArtifacts" => match args.len() {
+                0 => Ok(Command::CleanupArtifacts { older_than_secs: None }),
+                1 => Ok(Command::CleanupArtifacts { older_than_secs: Some(args[0].parse()?) }),
+                _ => Err(Error::Command(format!("unexpected CleanupArtifacts args: {:?}", args))),
+            },
+
             "Authenticate" => match args.len() {
                 0 => Err(Error::Command("usage: Authenticate <type> | Authenticate <client-id> <client-secret>".to_string())),
                 1 if args[0] == "none" => Ok(Command::Authenticate(Auth::None)),
@@ -63,11 +230,58 @@ impl FromStr for Command {
                 _ => Err(Error::Command(format!("unexpected Authenticate args: {:?}", args))),
             },
 
+            "ReAuthenticate" => match args.len() {
+                0 => Ok(Command::ReAuthenticate),
+                _ => Err(Error::Command(format!("unexpected ReAuthenticate args: {:?}", args))),
+            },
+
+            "CheckUpdates" => match args.len() {
+                0 => Ok(Command::CheckUpdates),
+                _ => Err(Error::Command(format!("unexpected CheckUpdates args: {:?}", args))),
+            },
+
+            "ClearPendingReports" => match args.len() {
+                0 => Ok(Command::ClearPendingReports),
+                _ => Err(Error::Command(format!("unexpected ClearPendingReports args: {:?}", args))),
+            },
+
+            "ExportDiagnostics" => match args.len() {
+                0 => Err(Error::Command("usage: ExportDiagnostics <path>".to_string())),
+                1 => Ok(Command::ExportDiagnostics { path: args[0].into() }),
+                _ => Err(Error::Command(format!("unexpected ExportDiagnostics args: {:?}", args))),
+            },
+
+            "GetLogLevel" => match args.len() {
+                0 => Ok(Command::GetLogLevel),
+                _ => Err(Error::Command(format!("unexpected GetLogLevel args: {:?}", args))),
+            },
+
+            "GetPendingReports" => match args.len() {
+                0 => Ok(Command::GetPendingReports),
+                _ => Err(Error::Command(format!("unexpected GetPendingReports args: {:?}", args))),
+            },
+
             "GetUpdateRequests" => match args.len() {
                 0 => Ok(Command::GetUpdateRequests),
                 _ => Err(Error::Command(format!("unexpected GetUpdateRequests args: {:?}", args))),
             },
 
+            "GetStorageInfo" => match args.len() {
+                0 => Ok(Command::GetStorageInfo),
+                _ => Err(Error::Command(format!("unexpected GetStorageInfo args: {:?}", args))),
+            },
+
+            "InstallLocalBundle" => match args.len() {
+                0 => Err(Error::Command("usage: InstallLocalBundle <path>".to_string())),
+                1 => Ok(Command::InstallLocalBundle { path: args[0].into() }),
+                _ => Err(Error::Command(format!("unexpected InstallLocalBundle args: {:?}", args))),
+            },
+
+            "ListBandwidthRules" => match args.len() {
+                0 => Ok(Command::ListBandwidthRules),
+                _ => Err(Error::Command(format!("unexpected ListBandwidthRules args: {:?}", args))),
+            },
+
             "ListInstalledPackages" => match args.len() {
                 0 => Ok(Command::ListInstalledPackages),
                 _ => Err(Error::Command(format!("unexpected ListInstalledPackages args: {:?}", args))),
@@ -78,6 +292,68 @@ impl FromStr for Command {
                 _ => Err(Error::Command(format!("unexpected ListSystemInfo args: {:?}", args))),
             },
 
+            "VerifySystemIntegrity" => match args.len() {
+                0 => Ok(Command::VerifySystemIntegrity),
+                _ => Err(Error::Command(format!("unexpected VerifySystemIntegrity args: {:?}", args))),
+            },
+
+            "OstreeAddRemote" => match args.len() {
+                0 | 1 => Err(Error::Command("usage: OstreeAddRemote <name> <url> [<setting>=<value>...]".to_string())),
+                _ => {
+                    let mut remote = OstreeRemote::new(args[0].into(), args[1].into());
+                    for setting in &args[2..] {
+                        remote.set(setting).map_err(|err| Error::Command(format!("{}", err)))?;
+                    }
+                    Ok(Command::OstreeAddRemote(remote))
+                }
+            },
+
+            "OstreeDeleteRemote" => match args.len() {
+                0 => Err(Error::Command("usage: OstreeDeleteRemote <name>".to_string())),
+                1 => Ok(Command::OstreeDeleteRemote(args[0].into())),
+                _ => Err(Error::Command(format!("unexpected OstreeDeleteRemote args: {:?}", args))),
+            },
+
+            "OstreeListRemotes" => match args.len() {
+                0 => Ok(Command::OstreeListRemotes),
+                _ => Err(Error::Command(format!("unexpected OstreeListRemotes args: {:?}", args))),
+            },
+
+            "Pause" => match args.len() {
+                0 => Ok(Command::Pause),
+                _ => Err(Error::Command(format!("unexpected Pause args: {:?}", args))),
+            },
+
+            "ReplayEvents" => match args.len() {
+                0 => Err(Error::Command("usage: ReplayEvents <count>".to_string())),
+                1 => Ok(Command::ReplayEvents(args[0].parse()?)),
+                _ => Err(Error::Command(format!("unexpected ReplayEvents args: {:?}", args))),
+            },
+
+            "Resume" => match args.len() {
+                0 => Ok(Command::Resume),
+                _ => Err(Error::Command(format!("unexpected Resume args: {:?}", args))),
+            },
+
+            "ScheduleInstall" => match args.len() {
+                0 | 1 => Err(Error::Command("usage: ScheduleInstall <id> <time>".to_string())),
+                2 => {
+                    let uuid = args[0].parse::<Uuid>().map_err(|err| Error::Command(format!("couldn't parse UpdateResultId: {}", err)))?;
+                    let at = args[1].parse::<DateTime<Utc>>().map_err(|err| Error::Command(format!("couldn't parse install time: {}", err)))?;
+                    Ok(Command::ScheduleInstall { update_id: uuid, at: at })
+                }
+                _ => Err(Error::Command(format!("unexpected ScheduleInstall args: {:?}", args))),
+            },
+
+            "ServerAbort" => match args.len() {
+                0 => Err(Error::Command("usage: ServerAbort <id>".to_string())),
+                1 => {
+                    let uuid = args[0].parse::<Uuid>().map_err(|err| Error::Command(format!("couldn't parse UpdateResultId: {}", err)))?;
+                    Ok(Command::ServerAbort(uuid))
+                }
+                _ => Err(Error::Command(format!("unexpected ServerAbort args: {:?}", args))),
+            },
+
             "SendInstalledPackages" => match args.len() {
                 0 | 1 => Err(Error::Command("usage: SendInstalledPackages (<name> <version>)+".to_string())),
                 n if n % 2 == 0 => {
@@ -94,6 +370,16 @@ impl FromStr for Command {
                 _ => Err(Error::Command(format!("unexpected SendInstalledSoftware args: {:?}", args))),
             },
 
+            "SendInstalledSoftwareNow" => match args.len() {
+                0 => Ok(Command::SendInstalledSoftwareNow),
+                _ => Err(Error::Command(format!("unexpected SendInstalledSoftwareNow args: {:?}", args))),
+            },
+
+            "SendPendingReports" => match args.len() {
+                0 => Ok(Command::SendPendingReports),
+                _ => Err(Error::Command(format!("unexpected SendPendingReports args: {:?}", args))),
+            },
+
             "SendSystemInfo" => match args.len() {
                 0 => Ok(Command::SendSystemInfo),
                 _ => Err(Error::Command(format!("unexpected SendSystemInfo args: {:?}", args))),
@@ -108,6 +394,46 @@ impl FromStr for Command {
                 _ => Err(Error::Command(format!("unexpected SendInstallReport args: {:?}", args))),
             },
 
+            "ConfirmUpdate" => match args.len() {
+                0 | 1 => Err(Error::Command("usage: ConfirmUpdate <id> <true|false>".to_string())),
+                2 => {
+                    let uuid = args[0].parse::<Uuid>().map_err(|err| Error::Command(format!("couldn't parse UpdateResultId: {}", err)))?;
+                    let approved = args[1].parse::<bool>().map_err(|err| Error::Command(format!("couldn't parse bool: {}", err)))?;
+                    Ok(Command::ConfirmUpdate { update_id: uuid, approved: approved })
+                }
+                _ => Err(Error::Command(format!("unexpected ConfirmUpdate args: {:?}", args))),
+            },
+
+            "SetAutoDownload" => match args.len() {
+                0 => Err(Error::Command("usage: SetAutoDownload <true|false>".to_string())),
+                1 => {
+                    let enabled = args[0].parse::<bool>().map_err(|err| Error::Command(format!("couldn't parse bool: {}", err)))?;
+                    Ok(Command::SetAutoDownload(enabled))
+                }
+                _ => Err(Error::Command(format!("unexpected SetAutoDownload args: {:?}", args))),
+            },
+
+            "SetBandwidthRules" => {
+                let rules = args.iter()
+                    .map(|arg| arg.parse::<BandwidthRule>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|err| Error::Command(format!("usage: SetBandwidthRules [<HH:MM-HH:MM=full|bytes-per-sec>...]: {}", err)))?;
+                Ok(Command::SetBandwidthRules(rules))
+            }
+
+            "SetLogLevel" => match args.len() {
+                0 => Err(Error::Command("usage: SetLogLevel <level>".to_string())),
+                1 => Ok(Command::SetLogLevel(args[0].into())),
+                _ => Err(Error::Command(format!("unexpected SetLogLevel args: {:?}", args))),
+            },
+
+            #[cfg(any(test, feature = "debug"))]
+            "SetPackageManager" => match args.len() {
+                0 => Err(Error::Command("usage: SetPackageManager <package-manager>".to_string())),
+                1 => Ok(Command::SetPackageManager(args[0].parse()?)),
+                _ => Err(Error::Command(format!("unexpected SetPackageManager args: {:?}", args))),
+            },
+
             "Shutdown" => match args.len() {
                 0 => Ok(Command::Shutdown),
                 _ => Err(Error::Command(format!("unexpected Shutdown args: {:?}", args))),
@@ -131,11 +457,60 @@ impl FromStr for Command {
                 _ => Err(Error::Command(format!("unexpected StartInstall args: {:?}", args))),
             },
 
+            "SyncInstalledPackages" => match args.len() {
+                0 => Ok(Command::SyncInstalledPackages),
+                _ => Err(Error::Command(format!("unexpected SyncInstalledPackages args: {:?}", args))),
+            },
+
+            "UptaneExportManifest" => match args.len() {
+                0 => Ok(Command::UptaneExportManifest),
+                _ => Err(Error::Command(format!("unexpected UptaneExportManifest args: {:?}", args))),
+            },
+
+            "UptaneMetadataVersions" => match args.len() {
+                0 => Ok(Command::UptaneMetadataVersions),
+                _ => Err(Error::Command(format!("unexpected UptaneMetadataVersions args: {:?}", args))),
+            },
+
+            "UptaneListEcus" => match args.len() {
+                0 => Ok(Command::UptaneListEcus),
+                _ => Err(Error::Command(format!("unexpected UptaneListEcus args: {:?}", args))),
+            },
+
+            "UptaneGetRawMetadata" => match args.len() {
+                0 | 1 => Err(Error::Command("usage: UptaneGetRawMetadata <director|repo> <role>".to_string())),
+                2 => Ok(Command::UptaneGetRawMetadata { service: args[0].parse()?, role: args[1].parse()? }),
+                _ => Err(Error::Command(format!("unexpected UptaneGetRawMetadata args: {:?}", args))),
+            },
+
+            "UptaneRotateKey" => match args.len() {
+                0 | 1 => Err(Error::Command("usage: UptaneRotateKey <new-key-path> <new-pub-key-path>".to_string())),
+                2 => Ok(Command::UptaneRotateKey { new_key_path: args[0].into(), new_pub_key_path: args[1].into() }),
+                _ => Err(Error::Command(format!("unexpected UptaneRotateKey args: {:?}", args))),
+            },
+
             "UptaneSendManifest" => match args.len() {
                 // FIXME(PRO-1160): args
                 _ => Err(Error::Command(format!("unexpected UptaneSendManifest args: {:?}", args))),
             },
 
+            "UptaneSendManifestNow" => match args.len() {
+                0 => Ok(Command::UptaneSendManifestNow),
+                _ => Err(Error::Command(format!("unexpected UptaneSendManifestNow args: {:?}", args))),
+            },
+
+            "UptaneVerifyTarget" => match args.len() {
+                0 => Err(Error::Command("usage: UptaneVerifyTarget <refname>".to_string())),
+                1 => Ok(Command::UptaneVerifyTarget(args[0].into())),
+                _ => Err(Error::Command(format!("unexpected UptaneVerifyTarget args: {:?}", args))),
+            },
+
+            "UptaneForceInstall" => match args.len() {
+                0 => Err(Error::Command("usage: UptaneForceInstall <refname>".to_string())),
+                1 => Ok(Command::UptaneForceInstall(args[0].into())),
+                _ => Err(Error::Command(format!("unexpected UptaneForceInstall args: {:?}", args))),
+            },
+
             "UptaneStartInstall" => match args.len() {
                 _ => Err(Error::Command(format!("unexpected UptaneStartInstall args: {:?}", args))),
             },
@@ -162,11 +537,25 @@ impl Display for Command {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use datatype::{Auth, Command, ClientCredentials, Package, InstallCode};
+    use datatype::{Auth, Command, ClientCredentials, Package, InstallCode, OstreeRemote};
 
 
     const DEFAULT_UUID: &'static str = "00000000-0000-0000-0000-000000000000";
 
+    #[test]
+    fn abort_all_transfers_test() {
+        assert_eq!("AbortAllTransfers".parse::<Command>().unwrap(), Command::AbortAllTransfers);
+        assert!("AbortAllTransfers now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn cleanup_artifacts_test() {
+        assert_eq!("CleanupArtifacts".parse::<Command>().unwrap(), Command::CleanupArtifacts { older_than_secs: None });
+        assert_eq!("CleanupArtifacts 3600".parse::<Command>().unwrap(), Command::CleanupArtifacts { older_than_secs: Some(3600) });
+        assert!("CleanupArtifacts old".parse::<Command>().is_err());
+        assert!("CleanupArtifacts 1 2".parse::<Command>().is_err());
+    }
+
     #[test]
     fn authenticate_test() {
         assert_eq!("Authenticate none".parse::<Command>().unwrap(), Command::Authenticate(Auth::None));
@@ -181,6 +570,30 @@ mod tests {
         assert!("Authenticate one two three".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn reauthenticate_test() {
+        assert_eq!("ReAuthenticate".parse::<Command>().unwrap(), Command::ReAuthenticate);
+        assert!("ReAuthenticate now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn check_updates_test() {
+        assert_eq!("CheckUpdates".parse::<Command>().unwrap(), Command::CheckUpdates);
+        assert!("CheckUpdates now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn clear_pending_reports_test() {
+        assert_eq!("ClearPendingReports".parse::<Command>().unwrap(), Command::ClearPendingReports);
+        assert!("ClearPendingReports all".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn get_pending_reports_test() {
+        assert_eq!("GetPendingReports".parse::<Command>().unwrap(), Command::GetPendingReports);
+        assert!("GetPendingReports all".parse::<Command>().is_err());
+    }
+
     #[test]
     fn get_update_requests_test() {
         assert_eq!("GetUpdateRequests".parse::<Command>().unwrap(), Command::GetUpdateRequests);
@@ -193,12 +606,120 @@ mod tests {
         assert!("ListInstalledPackages some".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn get_storage_info_test() {
+        assert_eq!("GetStorageInfo".parse::<Command>().unwrap(), Command::GetStorageInfo);
+        assert!("GetStorageInfo /tmp".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn install_local_bundle_test() {
+        assert_eq!("InstallLocalBundle /media/usb/update".parse::<Command>().unwrap(),
+                   Command::InstallLocalBundle { path: "/media/usb/update".into() });
+        assert!("InstallLocalBundle".parse::<Command>().is_err());
+        assert!("InstallLocalBundle /media/usb/update extra".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn list_bandwidth_rules_test() {
+        assert_eq!("ListBandwidthRules".parse::<Command>().unwrap(), Command::ListBandwidthRules);
+        assert!("ListBandwidthRules all".parse::<Command>().is_err());
+    }
+
     #[test]
     fn list_system_info_test() {
         assert_eq!("ListSystemInfo".parse::<Command>().unwrap(), Command::ListSystemInfo);
         assert!("ListSystemInfo please".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn verify_system_integrity_test() {
+        assert_eq!("VerifySystemIntegrity".parse::<Command>().unwrap(), Command::VerifySystemIntegrity);
+        assert!("VerifySystemIntegrity now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn ostree_add_remote_test() {
+        let mut remote = OstreeRemote::new("treehub".into(), "https://treehub.local".into());
+        assert_eq!("OstreeAddRemote treehub https://treehub.local".parse::<Command>().unwrap(),
+                   Command::OstreeAddRemote(remote.clone()));
+        remote.gpg_verify = true;
+        remote.tls_ca_path = Some("/etc/ca.pem".into());
+        assert_eq!("OstreeAddRemote treehub https://treehub.local gpg-verify=true tls-ca-path=/etc/ca.pem".parse::<Command>().unwrap(),
+                   Command::OstreeAddRemote(remote));
+        assert!("OstreeAddRemote treehub".parse::<Command>().is_err());
+        assert!("OstreeAddRemote treehub https://treehub.local gpg-verify=maybe".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn ostree_delete_remote_test() {
+        assert_eq!("OstreeDeleteRemote treehub".parse::<Command>().unwrap(), Command::OstreeDeleteRemote("treehub".into()));
+        assert!("OstreeDeleteRemote".parse::<Command>().is_err());
+        assert!("OstreeDeleteRemote treehub backup".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn ostree_list_remotes_test() {
+        assert_eq!("OstreeListRemotes".parse::<Command>().unwrap(), Command::OstreeListRemotes);
+        assert!("OstreeListRemotes all".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn resume_test() {
+        assert_eq!("Resume".parse::<Command>().unwrap(), Command::Resume);
+        assert!("Resume now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn schedule_install_test() {
+        let at = "2030-01-01T02:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(format!("ScheduleInstall {} 2030-01-01T02:00:00Z", DEFAULT_UUID).parse::<Command>().unwrap(),
+                   Command::ScheduleInstall { update_id: Uuid::default(), at: at });
+        assert!(format!("ScheduleInstall {}", DEFAULT_UUID).parse::<Command>().is_err());
+        assert!(format!("ScheduleInstall {} tomorrow", DEFAULT_UUID).parse::<Command>().is_err());
+        assert!(format!("ScheduleInstall {} 2030-01-01T02:00:00Z extra", DEFAULT_UUID).parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn pause_test() {
+        assert_eq!("Pause".parse::<Command>().unwrap(), Command::Pause);
+        assert!("Pause now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn export_diagnostics_test() {
+        assert_eq!("ExportDiagnostics /tmp/sota-diagnostics.json".parse::<Command>().unwrap(),
+                   Command::ExportDiagnostics { path: "/tmp/sota-diagnostics.json".into() });
+        assert!("ExportDiagnostics".parse::<Command>().is_err());
+        assert!("ExportDiagnostics /tmp/a.json /tmp/b.json".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn log_level_test() {
+        assert_eq!("GetLogLevel".parse::<Command>().unwrap(), Command::GetLogLevel);
+        assert!("GetLogLevel debug".parse::<Command>().is_err());
+        assert_eq!("SetLogLevel info,sota::uptane=debug".parse::<Command>().unwrap(),
+                   Command::SetLogLevel("info,sota::uptane=debug".into()));
+        assert!("SetLogLevel".parse::<Command>().is_err());
+        assert!("SetLogLevel debug trace".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn replay_events_test() {
+        assert_eq!("ReplayEvents 10".parse::<Command>().unwrap(), Command::ReplayEvents(10));
+        assert!("ReplayEvents".parse::<Command>().is_err());
+        assert!("ReplayEvents all".parse::<Command>().is_err());
+        assert!("ReplayEvents 10 20".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn server_abort_test() {
+        assert_eq!(format!("ServerAbort {}", DEFAULT_UUID).parse::<Command>().unwrap(),
+                   Command::ServerAbort(Uuid::default()));
+        assert!("ServerAbort".parse::<Command>().is_err());
+        assert!(format!("ServerAbort {} extra", DEFAULT_UUID).parse::<Command>().is_err());
+    }
+
     #[test]
     fn send_install_report_test() {
         assert_eq!("SendInstallReport id 0".parse::<Command>().unwrap(),
@@ -226,12 +747,65 @@ mod tests {
         assert!("SendInstalledSoftware some".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn send_installed_software_now_test() {
+        assert_eq!("SendInstalledSoftwareNow".parse::<Command>().unwrap(), Command::SendInstalledSoftwareNow);
+        assert!("SendInstalledSoftwareNow some".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn send_pending_reports_test() {
+        assert_eq!("SendPendingReports".parse::<Command>().unwrap(), Command::SendPendingReports);
+        assert!("SendPendingReports all".parse::<Command>().is_err());
+    }
+
     #[test]
     fn send_system_info_test() {
         assert_eq!("SendSystemInfo".parse::<Command>().unwrap(), Command::SendSystemInfo);
         assert!("SendSystemInfo please".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn confirm_update_test() {
+        assert_eq!(format!("ConfirmUpdate {} true", DEFAULT_UUID).parse::<Command>().unwrap(),
+                   Command::ConfirmUpdate { update_id: Uuid::default(), approved: true });
+        assert_eq!(format!("ConfirmUpdate {} false", DEFAULT_UUID).parse::<Command>().unwrap(),
+                   Command::ConfirmUpdate { update_id: Uuid::default(), approved: false });
+        assert!(format!("ConfirmUpdate {}", DEFAULT_UUID).parse::<Command>().is_err());
+        assert!(format!("ConfirmUpdate {} maybe", DEFAULT_UUID).parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn set_auto_download_test() {
+        assert_eq!("SetAutoDownload true".parse::<Command>().unwrap(), Command::SetAutoDownload(true));
+        assert_eq!("SetAutoDownload false".parse::<Command>().unwrap(), Command::SetAutoDownload(false));
+        assert!("SetAutoDownload".parse::<Command>().is_err());
+        assert!("SetAutoDownload maybe".parse::<Command>().is_err());
+        assert!("SetAutoDownload true false".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn set_bandwidth_rules_test() {
+        let rules = vec![
+            BandwidthRule { start: 0, end: 360, limit: None },
+            BandwidthRule { start: 360, end: 0, limit: Some(65536) },
+        ];
+        assert_eq!("SetBandwidthRules 00:00-06:00=full 06:00-24:00=65536".parse::<Command>().unwrap(), Command::SetBandwidthRules(rules));
+        assert_eq!("SetBandwidthRules".parse::<Command>().unwrap(), Command::SetBandwidthRules(Vec::new()));
+        assert!("SetBandwidthRules 06:00-06:00".parse::<Command>().is_err());
+        assert!("SetBandwidthRules 00:00-06:00=fast".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn set_package_manager_test() {
+        assert_eq!("SetPackageManager deb".parse::<Command>().unwrap(), Command::SetPackageManager(PacMan::Deb));
+        assert_eq!("SetPackageManager test:/tmp/tpm".parse::<Command>().unwrap(),
+                   Command::SetPackageManager(PacMan::Test { filename: "/tmp/tpm".into(), succeeds: true }));
+        assert!("SetPackageManager".parse::<Command>().is_err());
+        assert!("SetPackageManager apt".parse::<Command>().is_err());
+        assert!("SetPackageManager deb rpm".parse::<Command>().is_err());
+    }
+
     #[test]
     fn shutdown_test() {
         assert_eq!("Shutdown".parse::<Command>().unwrap(), Command::Shutdown);
@@ -254,11 +828,85 @@ mod tests {
         assert!(format!("StartInstall {} extra", DEFAULT_UUID).parse::<Command>().is_err());
     }
 
+    #[test]
+    fn sync_installed_packages_test() {
+        assert_eq!("SyncInstalledPackages".parse::<Command>().unwrap(), Command::SyncInstalledPackages);
+        assert!("SyncInstalledPackages now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn uptane_export_manifest_test() {
+        assert_eq!("UptaneExportManifest".parse::<Command>().unwrap(), Command::UptaneExportManifest);
+        assert!("UptaneExportManifest now".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn uptane_metadata_versions_test() {
+        assert_eq!("UptaneMetadataVersions".parse::<Command>().unwrap(), Command::UptaneMetadataVersions);
+        assert!("UptaneMetadataVersions director".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn uptane_list_ecus_test() {
+        assert_eq!("UptaneListEcus".parse::<Command>().unwrap(), Command::UptaneListEcus);
+        assert!("UptaneListEcus primary".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn uptane_get_raw_metadata_test() {
+        assert_eq!("UptaneGetRawMetadata director snapshot".parse::<Command>().unwrap(),
+                   Command::UptaneGetRawMetadata { service: Service::Director, role: RoleName::Snapshot });
+        assert_eq!("UptaneGetRawMetadata repo root".parse::<Command>().unwrap(),
+                   Command::UptaneGetRawMetadata { service: Service::Repo, role: RoleName::Root });
+        assert!("UptaneGetRawMetadata director".parse::<Command>().is_err());
+        assert!("UptaneGetRawMetadata image snapshot".parse::<Command>().is_err());
+        assert!("UptaneGetRawMetadata director manifest".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn critical_commands_test() {
+        assert!(Command::StartInstall(DEFAULT_UUID.parse().unwrap()).is_critical());
+        assert!(Command::ReAuthenticate.is_critical());
+        assert!(! Command::GetUpdateRequests.is_critical());
+        assert!(! Command::ListInstalledPackages.is_critical());
+    }
+
+    #[test]
+    fn uptane_rotate_key_test() {
+        assert_eq!("UptaneRotateKey /tmp/new.der /tmp/new.pub".parse::<Command>().unwrap(),
+                   Command::UptaneRotateKey { new_key_path: "/tmp/new.der".into(), new_pub_key_path: "/tmp/new.pub".into() });
+        assert!("UptaneRotateKey".parse::<Command>().is_err());
+        assert!("UptaneRotateKey /tmp/new.der".parse::<Command>().is_err());
+        assert!("UptaneRotateKey /tmp/new.der /tmp/new.pub extra".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn uptane_verify_target_test() {
+        assert_eq!("UptaneVerifyTarget /file.img".parse::<Command>().unwrap(),
+                   Command::UptaneVerifyTarget("/file.img".into()));
+        assert!("UptaneVerifyTarget".parse::<Command>().is_err());
+        assert!("UptaneVerifyTarget /file.img extra".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn uptane_force_install_test() {
+        assert_eq!("UptaneForceInstall /file.img".parse::<Command>().unwrap(),
+                   Command::UptaneForceInstall("/file.img".into()));
+        assert!("UptaneForceInstall".parse::<Command>().is_err());
+        assert!("UptaneForceInstall /file.img extra".parse::<Command>().is_err());
+    }
+
     #[test]
     fn uptane_send_manifest_test() {
         assert!("UptaneSendManifest".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn uptane_send_manifest_now_test() {
+        assert_eq!("UptaneSendManifestNow".parse::<Command>().unwrap(), Command::UptaneSendManifestNow);
+        assert!("UptaneSendManifestNow now".parse::<Command>().is_err());
+    }
+
     #[test]
     fn uptane_start_install_test() {
         assert!("UptaneStartInstall".parse::<Command>().is_err());
@@ -1,14 +1,17 @@
+use std::collections::HashMap;
 use std::ops::Deref;
 use toml;
 use uuid::Uuid;
 
-use datatype::{Auth, ClientCredentials, Error, SocketAddrV4, Url, Util};
-use http::TlsData;
+use datatype::{Auth, ClientCredentials, Error, KeyIdDigest, Servers, SocketAddr, SocketAddrV4,
+               SystemInfo, Url, Util};
+use bounded::QueuePolicy;
+use http::{SigningAlgorithm, TlsData};
 use pacman::PacMan;
 
 
 /// A container for all parsed configs.
-#[derive(Deserialize, Default, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Debug, Clone)]
 pub struct Config {
     pub auth:    Option<AuthConfig>,
     pub core:    CoreConfig,
@@ -64,6 +67,17 @@ impl Config {
             }
         }
     }
+
+    /// Return the static headers attached to every outgoing HTTP request,
+    /// with any configured `network.http_headers` taking precedence.
+    pub fn http_headers(&self, version: Option<&str>) -> HashMap<String, String> {
+        let mut headers = hashmap!{
+            "User-Agent".to_string()    => format!("sota-client/{}", version.unwrap_or("unknown")),
+            "x-device-uuid".to_string() => format!("{}", self.device.uuid),
+        };
+        headers.extend(self.network.http_headers.clone());
+        headers
+    }
 }
 
 
@@ -131,85 +145,117 @@ trait Defaultify<T: Default> {
 
 
 /// The [auth] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct AuthConfig {
-    pub server:        Url,
-    pub client_id:     String,
-    pub client_secret: String
+    pub server:          Servers,
+    pub client_id:       String,
+    pub client_secret:   String,
+    pub required_scope:  Option<String>,
+    pub token_cache:     Option<String>,
+    pub token_cache_key: Option<String>,
 }
 
 impl Default for AuthConfig {
     fn default() -> Self {
         AuthConfig {
-            server:        "http://127.0.0.1:9001".parse().unwrap(),
-            client_id:     "client-id".to_string(),
-            client_secret: "client-secret".to_string()
+            server:          "http://127.0.0.1:9001".parse().unwrap(),
+            client_id:       "client-id".to_string(),
+            client_secret:   "client-secret".to_string(),
+            required_scope:  None,
+            token_cache:     None,
+            token_cache_key: None,
         }
     }
 }
 
 #[derive(Deserialize, Default)]
 struct ParsedAuthConfig {
-    server:        Option<Url>,
-    client_id:     Option<String>,
-    client_secret: Option<String>
+    server:          Option<Servers>,
+    client_id:       Option<String>,
+    client_secret:   Option<String>,
+    required_scope:  Option<String>,
+    token_cache:     Option<String>,
+    token_cache_key: Option<String>,
 }
 
 impl Defaultify<AuthConfig> for ParsedAuthConfig {
     fn defaultify(self) -> AuthConfig {
         let default = AuthConfig::default();
         AuthConfig {
-            server:        self.server.unwrap_or(default.server),
-            client_id:     self.client_id.unwrap_or(default.client_id),
-            client_secret: self.client_secret.unwrap_or(default.client_secret)
+            server:          self.server.unwrap_or(default.server),
+            client_id:       self.client_id.unwrap_or(default.client_id),
+            client_secret:   self.client_secret.unwrap_or(default.client_secret),
+            required_scope:  self.required_scope.or(default.required_scope),
+            token_cache:     self.token_cache.or(default.token_cache),
+            token_cache_key: self.token_cache_key.or(default.token_cache_key),
         }
     }
 }
 
 
 /// The [core] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct CoreConfig {
-    pub server:      Url,
-    pub polling:     bool,
-    pub polling_sec: u64,
-    pub ca_file:     Option<String>,
+    pub server:               Servers,
+    pub polling:              bool,
+    pub polling_sec:          u64,
+    pub ca_file:              Option<String>,
+    pub startup_jitter_sec:   u64,
+    pub auth_backoff_sec:     u64,
+    pub auth_backoff_max_sec: u64,
+    pub max_updates_per_poll: usize,
+    pub poll_telemetry:       bool,
 }
 
 impl Default for CoreConfig {
     fn default() -> CoreConfig {
         CoreConfig {
-            server:      "http://127.0.0.1:8080".parse().unwrap(),
-            polling:     true,
-            polling_sec: 10,
-            ca_file:     None,
+            server:               "http://127.0.0.1:8080".parse().unwrap(),
+            polling:              true,
+            polling_sec:          10,
+            ca_file:              None,
+            startup_jitter_sec:   0,
+            auth_backoff_sec:     1,
+            auth_backoff_max_sec: 300,
+            max_updates_per_poll: 100,
+            poll_telemetry:       false,
         }
     }
 }
 
 #[derive(Deserialize, Default)]
 struct ParsedCoreConfig {
-    server:      Option<Url>,
-    polling:     Option<bool>,
-    polling_sec: Option<u64>,
-    ca_file:     Option<String>,
+    server:               Option<Servers>,
+    polling:              Option<bool>,
+    polling_sec:          Option<u64>,
+    ca_file:              Option<String>,
+    startup_jitter_sec:   Option<u64>,
+    auth_backoff_sec:     Option<u64>,
+    auth_backoff_max_sec: Option<u64>,
+    max_updates_per_poll: Option<usize>,
+    poll_telemetry:       Option<bool>,
 }
 
 impl Defaultify<CoreConfig> for ParsedCoreConfig {
     fn defaultify(self) -> CoreConfig {
         let default = CoreConfig::default();
         CoreConfig {
-            server:      self.server.unwrap_or(default.server),
-            polling:     self.polling.unwrap_or(default.polling),
-            polling_sec: self.polling_sec.unwrap_or(default.polling_sec),
-            ca_file:     self.ca_file.or(default.ca_file),
+            server:               self.server.unwrap_or(default.server),
+            polling:              self.polling.unwrap_or(default.polling),
+            polling_sec:          self.polling_sec.unwrap_or(default.polling_sec),
+            ca_file:              self.ca_file.or(default.ca_file),
+            startup_jitter_sec:   self.startup_jitter_sec.unwrap_or(default.startup_jitter_sec),
+            auth_backoff_sec:     self.auth_backoff_sec.unwrap_or(default.auth_backoff_sec),
+            auth_backoff_max_sec: self.auth_backoff_max_sec.unwrap_or(default.auth_backoff_max_sec),
+            max_updates_per_poll: self.max_updates_per_poll.unwrap_or(default.max_updates_per_poll),
+            poll_telemetry:       self.poll_telemetry.unwrap_or(default.poll_telemetry),
         }
     }
 }
 
 
 /// The [dbus] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct DBusConfig {
     pub name:                  String,
     pub path:                  String,
@@ -258,58 +304,229 @@ impl Defaultify<DBusConfig> for ParsedDBusConfig {
 
 
 /// The [device] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct DeviceConfig {
-    pub uuid:            Uuid,
-    pub packages_dir:    String,
-    pub package_manager: PacMan,
-    pub auto_download:   bool,
-    pub system_info:     Option<String>,
+    pub uuid:                    Uuid,
+    pub packages_dir:            String,
+    pub package_manager:         PacMan,
+    pub auto_download:           bool,
+    pub system_info:             Option<SystemInfo>,
+    pub continue_on_error:       bool,
+    pub install_timeout:         u64,
+    pub disk_headroom:           u64,
+    pub audit_log:               Option<String>,
+    pub audit_log_size:          u64,
+    pub verify_signature:        bool,
+    pub signature_key:           Option<String>,
+    pub report_dedup_size:       usize,
+    pub report_dedup_ttl:        u64,
+    pub command_queue_size:      usize,
+    pub command_queue_policy:    QueuePolicy,
+    pub report_spool_dir:        Option<String>,
+    pub deb_packages_dir:        Option<String>,
+    pub rpm_packages_dir:        Option<String>,
+    pub ostree_packages_dir:     Option<String>,
+    pub ostree_repo:             String,
+    pub chunks_dir:              Option<String>,
+    pub staging_dir:             Option<String>,
+    pub package_snapshot:        Option<String>,
+    pub package_full_every:      u64,
+    pub post_install_health_cmd: Option<String>,
+    pub rollback_cmd:            Option<String>,
+    pub cleanup_after_install:   bool,
+    pub max_report_output_bytes: u64,
+    pub install_schedule_path:   String,
+    pub reboot_marker_file:      Option<String>,
+    pub reboot_after_install:    bool,
+    pub reboot_cmd:              String,
+    pub lock_file:               Option<String>,
+    pub install_marker_file:     Option<String>,
+    pub check_content_type:      bool,
+    pub expected_content_type:   Option<String>,
+    pub download_rules:          Option<String>,
+    pub download_monthly_cap:    Option<u64>,
+    pub download_usage_file:     Option<String>,
 }
 
 impl Default for DeviceConfig {
     fn default() -> DeviceConfig {
         DeviceConfig {
-            uuid:            Uuid::default(),
-            packages_dir:    "/tmp".into(),
-            package_manager: PacMan::Off,
-            auto_download:   true,
-            system_info:     None,
+            uuid:                    Uuid::default(),
+            packages_dir:            "/tmp".into(),
+            package_manager:         PacMan::Off,
+            auto_download:           true,
+            system_info:             None,
+            continue_on_error:       false,
+            install_timeout:         3600,
+            disk_headroom:           0,
+            audit_log:               None,
+            audit_log_size:          10 * 1024 * 1024,
+            verify_signature:        false,
+            signature_key:           None,
+            report_dedup_size:       64,
+            report_dedup_ttl:        30,
+            command_queue_size:      100,
+            command_queue_policy:    QueuePolicy::Block,
+            report_spool_dir:        None,
+            deb_packages_dir:        None,
+            rpm_packages_dir:        None,
+            ostree_packages_dir:     None,
+            ostree_repo:             "/sysroot/ostree/repo".into(),
+            chunks_dir:              None,
+            staging_dir:             None,
+            package_snapshot:        None,
+            package_full_every:      24,
+            post_install_health_cmd: None,
+            rollback_cmd:            None,
+            cleanup_after_install:   false,
+            max_report_output_bytes: 64 * 1024,
+            install_schedule_path:   "/var/sota/install_schedule.json".into(),
+            reboot_marker_file:      None,
+            reboot_after_install:    false,
+            reboot_cmd:              "shutdown -r +1".into(),
+            lock_file:               None,
+            install_marker_file:     None,
+            check_content_type:      false,
+            expected_content_type:   None,
+            download_rules:          None,
+            download_monthly_cap:    None,
+            download_usage_file:     None,
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// Return the download directory configured for this package manager's
+    /// updates, if any.
+    pub fn download_dir(&self, pacman: &PacMan) -> Option<&str> {
+        let dir = match *pacman {
+            PacMan::Deb => self.deb_packages_dir.as_ref(),
+            PacMan::Rpm => self.rpm_packages_dir.as_ref(),
+            PacMan::Ostree | PacMan::Uptane => self.ostree_packages_dir.as_ref(),
+            _ => None
+        };
+        dir.map(|dir| dir.as_str())
+    }
+
+    /// Returns each download directory that is configured in addition to the
+    /// `packages_dir`.
+    pub fn download_dirs(&self) -> Vec<&str> {
+        let dirs = [&self.deb_packages_dir, &self.rpm_packages_dir, &self.ostree_packages_dir, &self.chunks_dir, &self.staging_dir];
+        dirs.iter().filter_map(|dir| dir.as_ref().map(String::as_str)).collect()
+    }
+
+    /// Check that each configured download directory exists and is writable.
+    pub fn check_download_dirs(&self) -> Result<(), Error> {
+        for dir in self.download_dirs() {
+            Util::check_writable(dir).map_err(|err| Error::Config(format!("download directory {}: {}", dir, err)))?;
         }
+        Ok(())
     }
 }
 
 #[derive(Deserialize, Default)]
 struct ParsedDeviceConfig {
-    pub uuid:              Option<Uuid>,
-    pub packages_dir:      Option<String>,
-    pub package_manager:   Option<PacMan>,
-    pub auto_download:     Option<bool>,
-    pub system_info:       Option<String>,
-    pub polling_interval:  Option<u64>,
-    pub certificates_path: Option<String>,
+    pub uuid:                    Option<Uuid>,
+    pub packages_dir:            Option<String>,
+    pub package_manager:         Option<PacMan>,
+    pub auto_download:           Option<bool>,
+    pub system_info:             Option<SystemInfo>,
+    pub continue_on_error:       Option<bool>,
+    pub install_timeout:         Option<u64>,
+    pub disk_headroom:           Option<u64>,
+    pub audit_log:               Option<String>,
+    pub audit_log_size:          Option<u64>,
+    pub verify_signature:        Option<bool>,
+    pub signature_key:           Option<String>,
+    pub report_dedup_size:       Option<usize>,
+    pub report_dedup_ttl:        Option<u64>,
+    pub command_queue_size:      Option<usize>,
+    pub command_queue_policy:    Option<QueuePolicy>,
+    pub report_spool_dir:        Option<String>,
+    pub deb_packages_dir:        Option<String>,
+    pub rpm_packages_dir:        Option<String>,
+    pub ostree_packages_dir:     Option<String>,
+    pub ostree_repo:             Option<String>,
+    pub chunks_dir:              Option<String>,
+    pub staging_dir:             Option<String>,
+    pub package_snapshot:        Option<String>,
+    pub package_full_every:      Option<u64>,
+    pub post_install_health_cmd: Option<String>,
+    pub rollback_cmd:            Option<String>,
+    pub cleanup_after_install:   Option<bool>,
+    pub max_report_output_bytes: Option<u64>,
+    pub install_schedule_path:   Option<String>,
+    pub reboot_marker_file:      Option<String>,
+    pub reboot_after_install:    Option<bool>,
+    pub reboot_cmd:              Option<String>,
+    pub lock_file:               Option<String>,
+    pub install_marker_file:     Option<String>,
+    pub check_content_type:      Option<bool>,
+    pub expected_content_type:   Option<String>,
+    pub download_rules:          Option<String>,
+    pub download_monthly_cap:    Option<u64>,
+    pub download_usage_file:     Option<String>,
+    pub polling_interval:        Option<u64>,
+    pub certificates_path:       Option<String>,
 }
 
 impl Defaultify<DeviceConfig> for ParsedDeviceConfig {
     fn defaultify(self) -> DeviceConfig {
         let default = DeviceConfig::default();
         DeviceConfig {
-            uuid:            self.uuid.unwrap_or(default.uuid),
-            packages_dir:    self.packages_dir.unwrap_or(default.packages_dir),
-            package_manager: self.package_manager.unwrap_or(default.package_manager),
-            auto_download:   self.auto_download.unwrap_or(default.auto_download),
-            system_info:     self.system_info.or(default.system_info),
+            uuid:                    self.uuid.unwrap_or(default.uuid),
+            packages_dir:            self.packages_dir.unwrap_or(default.packages_dir),
+            package_manager:         self.package_manager.unwrap_or(default.package_manager),
+            auto_download:           self.auto_download.unwrap_or(default.auto_download),
+            system_info:             self.system_info.or(default.system_info),
+            continue_on_error:       self.continue_on_error.unwrap_or(default.continue_on_error),
+            install_timeout:         self.install_timeout.unwrap_or(default.install_timeout),
+            disk_headroom:           self.disk_headroom.unwrap_or(default.disk_headroom),
+            audit_log:               self.audit_log.or(default.audit_log),
+            audit_log_size:          self.audit_log_size.unwrap_or(default.audit_log_size),
+            verify_signature:        self.verify_signature.unwrap_or(default.verify_signature),
+            signature_key:           self.signature_key.or(default.signature_key),
+            report_dedup_size:       self.report_dedup_size.unwrap_or(default.report_dedup_size),
+            report_dedup_ttl:        self.report_dedup_ttl.unwrap_or(default.report_dedup_ttl),
+            command_queue_size:      self.command_queue_size.unwrap_or(default.command_queue_size),
+            command_queue_policy:    self.command_queue_policy.unwrap_or(default.command_queue_policy),
+            report_spool_dir:        self.report_spool_dir.or(default.report_spool_dir),
+            deb_packages_dir:        self.deb_packages_dir.or(default.deb_packages_dir),
+            rpm_packages_dir:        self.rpm_packages_dir.or(default.rpm_packages_dir),
+            ostree_packages_dir:     self.ostree_packages_dir.or(default.ostree_packages_dir),
+            ostree_repo:             self.ostree_repo.unwrap_or(default.ostree_repo),
+            chunks_dir:              self.chunks_dir.or(default.chunks_dir),
+            staging_dir:             self.staging_dir.or(default.staging_dir),
+            package_snapshot:        self.package_snapshot.or(default.package_snapshot),
+            package_full_every:      self.package_full_every.unwrap_or(default.package_full_every),
+            post_install_health_cmd: self.post_install_health_cmd.or(default.post_install_health_cmd),
+            rollback_cmd:            self.rollback_cmd.or(default.rollback_cmd),
+            cleanup_after_install:   self.cleanup_after_install.unwrap_or(default.cleanup_after_install),
+            max_report_output_bytes: self.max_report_output_bytes.unwrap_or(default.max_report_output_bytes),
+            install_schedule_path:   self.install_schedule_path.unwrap_or(default.install_schedule_path),
+            reboot_marker_file:      self.reboot_marker_file.or(default.reboot_marker_file),
+            reboot_after_install:    self.reboot_after_install.unwrap_or(default.reboot_after_install),
+            reboot_cmd:              self.reboot_cmd.unwrap_or(default.reboot_cmd),
+            lock_file:               self.lock_file.or(default.lock_file),
+            install_marker_file:     self.install_marker_file.or(default.install_marker_file),
+            check_content_type:      self.check_content_type.unwrap_or(default.check_content_type),
+            expected_content_type:   self.expected_content_type.or(default.expected_content_type),
+            download_rules:          self.download_rules.or(default.download_rules),
+            download_monthly_cap:    self.download_monthly_cap.or(default.download_monthly_cap),
+            download_usage_file:     self.download_usage_file.or(default.download_usage_file),
         }
     }
 }
 
 
 /// The [[ecu]] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct EcuConfig {
     pub ecu_serial:      String,
     pub public_key_path: String,
     pub manifest_path:   String,
+    pub version_cmd:     Option<String>,
+    pub install_cmd:     Option<String>,
 }
 
 impl Default for EcuConfig {
@@ -318,6 +535,8 @@ impl Default for EcuConfig {
             ecu_serial:      "my-serial".into(),
             public_key_path: "/tmp/my-serial.pub".into(),
             manifest_path:   "/tmp/my-serial.manifest".into(),
+            version_cmd:     None,
+            install_cmd:     None,
         }
     }
 }
@@ -327,6 +546,8 @@ struct ParsedEcuConfig {
     ecu_serial:      Option<String>,
     public_key_path: Option<String>,
     manifest_path:   Option<String>,
+    version_cmd:     Option<String>,
+    install_cmd:     Option<String>,
 }
 
 impl Defaultify<EcuConfig> for ParsedEcuConfig {
@@ -336,55 +557,130 @@ impl Defaultify<EcuConfig> for ParsedEcuConfig {
             ecu_serial:      self.ecu_serial.unwrap_or(default.ecu_serial),
             public_key_path: self.public_key_path.unwrap_or(default.public_key_path),
             manifest_path:   self.manifest_path.unwrap_or(default.manifest_path),
+            version_cmd:     self.version_cmd.or(default.version_cmd),
+            install_cmd:     self.install_cmd.or(default.install_cmd),
         }
     }
 }
 
 
 /// The [gateway] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct GatewayConfig {
-    pub console:   bool,
-    pub dbus:      bool,
-    pub http:      bool,
-    pub rvi:       bool,
-    pub socket:    bool,
-    pub websocket: bool,
+    pub console:            bool,
+    pub dbus:               bool,
+    pub http:               bool,
+    pub rvi:                bool,
+    pub socket:             bool,
+    pub websocket:          bool,
+    pub dbus_commands:      Vec<String>,
+    pub http_commands:      Vec<String>,
+    pub socket_commands:    Vec<String>,
+    pub websocket_commands: Vec<String>,
+    pub event_history:      usize,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> GatewayConfig {
+        GatewayConfig {
+            console:            false,
+            dbus:               false,
+            http:               false,
+            rvi:                false,
+            socket:             false,
+            websocket:          false,
+            dbus_commands:      Self::session_commands(),
+            http_commands:      Self::network_commands(),
+            socket_commands:    Self::network_commands(),
+            websocket_commands: Self::network_commands(),
+            event_history:      32,
+        }
+    }
+}
+
+impl GatewayConfig {
+    /// The commands allowed by default from gateways reachable by other
+    /// processes, limited to checking for and installing updates and
+    /// replaying recent events.
+    pub fn network_commands() -> Vec<String> {
+        ["CheckUpdates", "ConfirmUpdate", "GetPendingReports", "GetStorageInfo", "GetUpdateRequests",
+         "ListInstalledPackages", "ListSystemInfo", "ReplayEvents", "StartDownload", "StartInstall"]
+            .iter().map(|cmd| cmd.to_string()).collect()
+    }
+
+    /// The commands allowed by default from the dbus session gateway, which
+    /// also reports installation results and the installed software.
+    pub fn session_commands() -> Vec<String> {
+        let mut commands = Self::network_commands();
+        commands.extend(["AbortAllTransfers", "ScheduleInstall", "SendInstallReport", "SendInstalledPackages",
+                         "SendInstalledSoftware", "SendSystemInfo", "SetAutoDownload", "SyncInstalledPackages",
+                         "UptaneSendManifestNow"]
+                        .iter().map(|cmd| cmd.to_string()));
+        commands
+    }
 }
 
 #[derive(Deserialize, Default)]
 struct ParsedGatewayConfig {
-    console:   Option<bool>,
-    dbus:      Option<bool>,
-    http:      Option<bool>,
-    rvi:       Option<bool>,
-    socket:    Option<bool>,
-    websocket: Option<bool>,
+    console:            Option<bool>,
+    dbus:               Option<bool>,
+    http:               Option<bool>,
+    rvi:                Option<bool>,
+    socket:             Option<bool>,
+    websocket:          Option<bool>,
+    dbus_commands:      Option<Vec<String>>,
+    http_commands:      Option<Vec<String>>,
+    socket_commands:    Option<Vec<String>>,
+    websocket_commands: Option<Vec<String>>,
+    event_history:      Option<usize>,
 }
 
 impl Defaultify<GatewayConfig> for ParsedGatewayConfig {
     fn defaultify(self) -> GatewayConfig {
         let default = GatewayConfig::default();
         GatewayConfig {
-            console:   self.console.unwrap_or(default.console),
-            dbus:      self.dbus.unwrap_or(default.dbus),
-            http:      self.http.unwrap_or(default.http),
-            rvi:       self.rvi.unwrap_or(default.rvi),
-            socket:    self.socket.unwrap_or(default.socket),
-            websocket: self.websocket.unwrap_or(default.websocket)
+            console:            self.console.unwrap_or(default.console),
+            dbus:               self.dbus.unwrap_or(default.dbus),
+            http:               self.http.unwrap_or(default.http),
+            rvi:                self.rvi.unwrap_or(default.rvi),
+            socket:             self.socket.unwrap_or(default.socket),
+            websocket:          self.websocket.unwrap_or(default.websocket),
+            dbus_commands:      self.dbus_commands.unwrap_or(default.dbus_commands),
+            http_commands:      self.http_commands.unwrap_or(default.http_commands),
+            socket_commands:    self.socket_commands.unwrap_or(default.socket_commands),
+            websocket_commands: self.websocket_commands.unwrap_or(default.websocket_commands),
+            event_history:      self.event_history.unwrap_or(default.event_history),
         }
     }
 }
 
 
 /// The [network] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct NetworkConfig {
-    pub http_server:          SocketAddrV4,
-    pub rvi_edge_server:      SocketAddrV4,
+    pub http_server:          SocketAddr,
+    pub rvi_edge_server:      SocketAddr,
     pub socket_commands_path: String,
     pub socket_events_path:   String,
-    pub websocket_server:     String
+    pub websocket_server:     String,
+    pub http_proxy:           Option<Url>,
+    pub https_proxy:          Option<Url>,
+    pub no_proxy:             Option<String>,
+    pub http_headers:         HashMap<String, String>,
+    pub max_response_bytes:   u64,
+    pub max_target_bytes:     u64,
+    pub pool_max_idle:        usize,
+    pub pool_idle_sec:        u64,
+    pub signing_key:          Option<String>,
+    pub signing_algorithm:    SigningAlgorithm,
+    pub signing_header:       String,
+    pub signing_canonical:    String,
+    pub max_redirects:        u32,
+    pub redirect_same_host:   bool,
+    pub redirect_keep_auth:   bool,
+    pub basic_auth:           Option<(String, String)>,
+    pub basic_auth_hosts:     Option<String>,
+    pub token_header:         String,
 }
 
 impl Default for NetworkConfig {
@@ -394,18 +690,54 @@ impl Default for NetworkConfig {
             rvi_edge_server:      "127.0.0.1:9999".parse().unwrap(),
             socket_commands_path: "/tmp/sota-commands.socket".to_string(),
             socket_events_path:   "/tmp/sota-events.socket".to_string(),
-            websocket_server:     "127.0.0.1:3012".to_string()
+            websocket_server:     "127.0.0.1:3012".to_string(),
+            http_proxy:           None,
+            https_proxy:          None,
+            no_proxy:             None,
+            http_headers:         HashMap::new(),
+            max_response_bytes:   10 * 1024 * 1024,
+            max_target_bytes:     4 * 1024 * 1024 * 1024,
+            pool_max_idle:        5,
+            pool_idle_sec:        30,
+            signing_key:          None,
+            signing_algorithm:    SigningAlgorithm::HmacSha256,
+            signing_header:       "X-Signature".to_string(),
+            signing_canonical:    "{method}\n{path}\n{body_sha256}".to_string(),
+            max_redirects:        5,
+            redirect_same_host:   false,
+            redirect_keep_auth:   false,
+            basic_auth:           None,
+            basic_auth_hosts:     None,
+            token_header:         "X-Authorization".to_string(),
         }
     }
 }
 
 #[derive(Deserialize, Default)]
 struct ParsedNetworkConfig {
-    http_server:          Option<SocketAddrV4>,
-    rvi_edge_server:      Option<SocketAddrV4>,
+    http_server:          Option<SocketAddr>,
+    rvi_edge_server:      Option<SocketAddr>,
     socket_commands_path: Option<String>,
     socket_events_path:   Option<String>,
-    websocket_server:     Option<String>
+    websocket_server:     Option<String>,
+    http_proxy:           Option<Url>,
+    https_proxy:          Option<Url>,
+    no_proxy:             Option<String>,
+    http_headers:         Option<HashMap<String, String>>,
+    max_response_bytes:   Option<u64>,
+    max_target_bytes:     Option<u64>,
+    pool_max_idle:        Option<usize>,
+    pool_idle_sec:        Option<u64>,
+    signing_key:          Option<String>,
+    signing_algorithm:    Option<SigningAlgorithm>,
+    signing_header:       Option<String>,
+    signing_canonical:    Option<String>,
+    max_redirects:        Option<u32>,
+    redirect_same_host:   Option<bool>,
+    redirect_keep_auth:   Option<bool>,
+    basic_auth:           Option<(String, String)>,
+    basic_auth_hosts:     Option<String>,
+    token_header:         Option<String>,
 }
 
 impl Defaultify<NetworkConfig> for ParsedNetworkConfig {
@@ -416,142 +748,208 @@ impl Defaultify<NetworkConfig> for ParsedNetworkConfig {
             rvi_edge_server:      self.rvi_edge_server.unwrap_or(default.rvi_edge_server),
             socket_commands_path: self.socket_commands_path.unwrap_or(default.socket_commands_path),
             socket_events_path:   self.socket_events_path.unwrap_or(default.socket_events_path),
-            websocket_server:     self.websocket_server.unwrap_or(default.websocket_server)
+            websocket_server:     self.websocket_server.unwrap_or(default.websocket_server),
+            http_proxy:           self.http_proxy.or(default.http_proxy),
+            https_proxy:          self.https_proxy.or(default.https_proxy),
+            no_proxy:             self.no_proxy.or(default.no_proxy),
+            http_headers:         self.http_headers.unwrap_or(default.http_headers),
+            max_response_bytes:   self.max_response_bytes.unwrap_or(default.max_response_bytes),
+            max_target_bytes:     self.max_target_bytes.unwrap_or(default.max_target_bytes),
+            pool_max_idle:        self.pool_max_idle.unwrap_or(default.pool_max_idle),
+            pool_idle_sec:        self.pool_idle_sec.unwrap_or(default.pool_idle_sec),
+            signing_key:          self.signing_key.or(default.signing_key),
+            signing_algorithm:    self.signing_algorithm.unwrap_or(default.signing_algorithm),
+            signing_header:       self.signing_header.unwrap_or(default.signing_header),
+            signing_canonical:    self.signing_canonical.unwrap_or(default.signing_canonical),
+            max_redirects:        self.max_redirects.unwrap_or(default.max_redirects),
+            redirect_same_host:   self.redirect_same_host.unwrap_or(default.redirect_same_host),
+            redirect_keep_auth:   self.redirect_keep_auth.unwrap_or(default.redirect_keep_auth),
+            basic_auth:           self.basic_auth.or(default.basic_auth),
+            basic_auth_hosts:     self.basic_auth_hosts.or(default.basic_auth_hosts),
+            token_header:         self.token_header.unwrap_or(default.token_header),
         }
     }
 }
 
 
 /// The [rvi] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct RviConfig {
-    pub client:      Url,
-    pub storage_dir: String,
-    pub timeout:     Option<u64>,
+    pub client:               Url,
+    pub storage_dir:          String,
+    pub timeout:              Option<u64>,
+    pub hmac_secret:          Option<String>,
+    pub max_active_transfers: usize,
 }
 
 impl Default for RviConfig {
     fn default() -> RviConfig {
         RviConfig {
-            client:      "http://127.0.0.1:8901".parse().unwrap(),
-            storage_dir: "/usr/local/etc/sota/rvi".to_string(),
-            timeout:     None,
+            client:               "http://127.0.0.1:8901".parse().unwrap(),
+            storage_dir:          "/usr/local/etc/sota/rvi".to_string(),
+            timeout:              None,
+            hmac_secret:          None,
+            max_active_transfers: 10,
         }
     }
 }
 
 #[derive(Deserialize, Default)]
 struct ParsedRviConfig {
-    client:      Option<Url>,
-    storage_dir: Option<String>,
-    timeout:     Option<u64>,
+    client:               Option<Url>,
+    storage_dir:          Option<String>,
+    timeout:              Option<u64>,
+    hmac_secret:          Option<String>,
+    max_active_transfers: Option<usize>,
 }
 
 impl Defaultify<RviConfig> for ParsedRviConfig {
     fn defaultify(self) -> RviConfig {
         let default = RviConfig::default();
         RviConfig {
-            client:      self.client.unwrap_or(default.client),
-            storage_dir: self.storage_dir.unwrap_or(default.storage_dir),
-            timeout:     self.timeout.or(default.timeout)
+            client:               self.client.unwrap_or(default.client),
+            storage_dir:          self.storage_dir.unwrap_or(default.storage_dir),
+            timeout:              self.timeout.or(default.timeout),
+            hmac_secret:          self.hmac_secret.or(default.hmac_secret),
+            max_active_transfers: self.max_active_transfers.unwrap_or(default.max_active_transfers),
         }
     }
 }
 
 
 /// The [tls] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct TlsConfig {
-    pub server:    Url,
-    pub ca_file:   String,
-    pub cert_file: String,
-    pub pkey_file: String,
+    pub server:           Url,
+    pub ca_file:          String,
+    pub cert_file:        String,
+    pub pkey_file:        String,
+    pub expiry_warn_days: u64,
+    pub expiry_check_sec: u64,
 }
 
 impl Default for TlsConfig {
     fn default() -> Self {
         TlsConfig {
-            server:    "http://localhost:8000".parse().unwrap(),
-            ca_file:   "/usr/local/etc/sota/ca.crt".to_string(),
-            cert_file: "/usr/local/etc/sota/device.crt".to_string(),
-            pkey_file: "/usr/local/etc/sota/device.pem".to_string(),
+            server:           "http://localhost:8000".parse().unwrap(),
+            ca_file:          "/usr/local/etc/sota/ca.crt".to_string(),
+            cert_file:        "/usr/local/etc/sota/device.crt".to_string(),
+            pkey_file:        "/usr/local/etc/sota/device.pem".to_string(),
+            expiry_warn_days: 30,
+            expiry_check_sec: 86400,
         }
     }
 }
 
 #[derive(Deserialize, Default)]
 struct ParsedTlsConfig {
-    server:    Option<Url>,
-    ca_file:   Option<String>,
-    cert_file: Option<String>,
-    pkey_file: Option<String>,
+    server:           Option<Url>,
+    ca_file:          Option<String>,
+    cert_file:        Option<String>,
+    pkey_file:        Option<String>,
+    expiry_warn_days: Option<u64>,
+    expiry_check_sec: Option<u64>,
 }
 
 impl Defaultify<TlsConfig> for ParsedTlsConfig {
     fn defaultify(self) -> TlsConfig {
         let default = TlsConfig::default();
         TlsConfig {
-            server:    self.server.unwrap_or(default.server),
-            ca_file:   self.ca_file.unwrap_or(default.ca_file),
-            cert_file: self.cert_file.unwrap_or(default.cert_file),
-            pkey_file: self.pkey_file.unwrap_or(default.pkey_file),
+            server:           self.server.unwrap_or(default.server),
+            ca_file:          self.ca_file.unwrap_or(default.ca_file),
+            cert_file:        self.cert_file.unwrap_or(default.cert_file),
+            pkey_file:        self.pkey_file.unwrap_or(default.pkey_file),
+            expiry_warn_days: self.expiry_warn_days.unwrap_or(default.expiry_warn_days),
+            expiry_check_sec: self.expiry_check_sec.unwrap_or(default.expiry_check_sec),
         }
     }
 }
 
 
 /// The [uptane] configuration section.
-#[derive(Deserialize, PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct UptaneConfig {
-    pub director_server:    Url,
-    pub repo_server:        Url,
-    pub primary_ecu_serial: String,
-    pub metadata_path:      String,
-    pub private_key_path:   String,
-    pub public_key_path:    String,
-    pub atomic_primary:     SocketAddrV4,
-    pub atomic_timeout_sec: u64,
+    pub director_server:      Url,
+    pub repo_server:          Url,
+    pub primary_ecu_serial:   String,
+    pub metadata_path:        String,
+    pub images_dir:           String,
+    pub private_key_path:     String,
+    pub public_key_path:      String,
+    pub keyid_digest:         KeyIdDigest,
+    pub atomic_primary:       SocketAddrV4,
+    pub atomic_timeout_sec:   u64,
+    pub manifest_retries:     u32,
+    pub max_clock_skew_sec:   u64,
+    pub timeserver_server:    Option<Url>,
+    pub timeserver_key_path:  Option<String>,
+    pub trust_device_clock:   bool,
+    pub debug_canonical_json: bool,
 }
 
 impl Default for UptaneConfig {
     fn default() -> UptaneConfig {
         UptaneConfig {
-            director_server:    "http://localhost:8001/director".parse().unwrap(),
-            repo_server:        "http://localhost:8002/repo".parse().unwrap(),
-            primary_ecu_serial: "primary-serial".to_string(),
-            metadata_path:      "/usr/local/etc/sota/metadata".to_string(),
-            private_key_path:   "/usr/local/etc/sota/ecuprimary.pem".to_string(),
-            public_key_path:    "/usr/local/etc/sota/ecuprimary.pub".to_string(),
-            atomic_primary:     "127.0.0.1:2310".parse().unwrap(),
-            atomic_timeout_sec: 300,
+            director_server:      "http://localhost:8001/director".parse().unwrap(),
+            repo_server:          "http://localhost:8002/repo".parse().unwrap(),
+            primary_ecu_serial:   "primary-serial".to_string(),
+            metadata_path:        "/usr/local/etc/sota/metadata".to_string(),
+            images_dir:           "/var/sota/images".to_string(),
+            private_key_path:     "/usr/local/etc/sota/ecuprimary.pem".to_string(),
+            public_key_path:      "/usr/local/etc/sota/ecuprimary.pub".to_string(),
+            keyid_digest:         KeyIdDigest::Sha256,
+            atomic_primary:       "127.0.0.1:2310".parse().unwrap(),
+            atomic_timeout_sec:   300,
+            manifest_retries:     3,
+            max_clock_skew_sec:   3600,
+            timeserver_server:    None,
+            timeserver_key_path:  None,
+            trust_device_clock:   true,
+            debug_canonical_json: false,
         }
     }
 }
 
 #[derive(Deserialize, Default)]
 struct ParsedUptaneConfig {
-    director_server:    Option<Url>,
-    repo_server:        Option<Url>,
-    primary_ecu_serial: Option<String>,
-    metadata_path:      Option<String>,
-    private_key_path:   Option<String>,
-    public_key_path:    Option<String>,
-    atomic_primary:     Option<SocketAddrV4>,
-    atomic_timeout_sec: Option<u64>,
+    director_server:      Option<Url>,
+    repo_server:          Option<Url>,
+    primary_ecu_serial:   Option<String>,
+    metadata_path:        Option<String>,
+    images_dir:           Option<String>,
+    private_key_path:     Option<String>,
+    public_key_path:      Option<String>,
+    keyid_digest:         Option<KeyIdDigest>,
+    atomic_primary:       Option<SocketAddrV4>,
+    atomic_timeout_sec:   Option<u64>,
+    manifest_retries:     Option<u32>,
+    max_clock_skew_sec:   Option<u64>,
+    timeserver_server:    Option<Url>,
+    timeserver_key_path:  Option<String>,
+    trust_device_clock:   Option<bool>,
+    debug_canonical_json: Option<bool>,
 }
 
 impl Defaultify<UptaneConfig> for ParsedUptaneConfig {
     fn defaultify(self) -> UptaneConfig {
         let default = UptaneConfig::default();
         UptaneConfig {
-            director_server:    self.director_server.unwrap_or(default.director_server),
-            repo_server:        self.repo_server.unwrap_or(default.repo_server),
-            primary_ecu_serial: self.primary_ecu_serial.unwrap_or(default.primary_ecu_serial),
-            metadata_path:      self.metadata_path.unwrap_or(default.metadata_path),
-            private_key_path:   self.private_key_path.unwrap_or(default.private_key_path),
-            public_key_path:    self.public_key_path.unwrap_or(default.public_key_path),
-            atomic_primary:     self.atomic_primary.unwrap_or(default.atomic_primary),
-            atomic_timeout_sec: self.atomic_timeout_sec.unwrap_or(default.atomic_timeout_sec),
+            director_server:      self.director_server.unwrap_or(default.director_server),
+            repo_server:          self.repo_server.unwrap_or(default.repo_server),
+            primary_ecu_serial:   self.primary_ecu_serial.unwrap_or(default.primary_ecu_serial),
+            metadata_path:        self.metadata_path.unwrap_or(default.metadata_path),
+            images_dir:           self.images_dir.unwrap_or(default.images_dir),
+            private_key_path:     self.private_key_path.unwrap_or(default.private_key_path),
+            public_key_path:      self.public_key_path.unwrap_or(default.public_key_path),
+            keyid_digest:         self.keyid_digest.unwrap_or(default.keyid_digest),
+            atomic_primary:       self.atomic_primary.unwrap_or(default.atomic_primary),
+            atomic_timeout_sec:   self.atomic_timeout_sec.unwrap_or(default.atomic_timeout_sec),
+            manifest_retries:     self.manifest_retries.unwrap_or(default.manifest_retries),
+            max_clock_skew_sec:   self.max_clock_skew_sec.unwrap_or(default.max_clock_skew_sec),
+            timeserver_server:    self.timeserver_server.or(default.timeserver_server),
+            timeserver_key_path:  self.timeserver_key_path.or(default.timeserver_key_path),
+            trust_device_clock:   self.trust_device_clock.unwrap_or(default.trust_device_clock),
+            debug_canonical_json: self.debug_canonical_json.unwrap_or(default.debug_canonical_json),
         }
     }
 }
@@ -576,6 +974,11 @@ mod tests {
         server = "http://127.0.0.1:8080"
         polling = true
         polling_sec = 10
+        startup_jitter_sec = 0
+        auth_backoff_sec = 1
+        auth_backoff_max_sec = 300
+        max_updates_per_poll = 100
+        poll_telemetry = false
         "#;
 
     const DBUS_CONFIG: &'static str =
@@ -606,6 +1009,11 @@ mod tests {
         rvi = false
         socket = false
         websocket = false
+        dbus_commands = ["CheckUpdates", "ConfirmUpdate", "GetPendingReports", "GetStorageInfo", "GetUpdateRequests", "ListInstalledPackages", "ListSystemInfo", "ReplayEvents", "StartDownload", "StartInstall", "AbortAllTransfers", "ScheduleInstall", "SendInstallReport", "SendInstalledPackages", "SendInstalledSoftware", "SendSystemInfo", "SetAutoDownload", "SyncInstalledPackages", "UptaneSendManifestNow"]
+        http_commands = ["CheckUpdates", "ConfirmUpdate", "GetPendingReports", "GetStorageInfo", "GetUpdateRequests", "ListInstalledPackages", "ListSystemInfo", "ReplayEvents", "StartDownload", "StartInstall"]
+        socket_commands = ["CheckUpdates", "ConfirmUpdate", "GetPendingReports", "GetStorageInfo", "GetUpdateRequests", "ListInstalledPackages", "ListSystemInfo", "ReplayEvents", "StartDownload", "StartInstall"]
+        websocket_commands = ["CheckUpdates", "ConfirmUpdate", "GetPendingReports", "GetStorageInfo", "GetUpdateRequests", "ListInstalledPackages", "ListSystemInfo", "ReplayEvents", "StartDownload", "StartInstall"]
+        event_history = 32
         "#;
 
     const NETWORK_CONFIG: &'static str =
@@ -616,6 +1024,17 @@ mod tests {
         socket_commands_path = "/tmp/sota-commands.socket"
         socket_events_path = "/tmp/sota-events.socket"
         websocket_server = "127.0.0.1:3012"
+        max_response_bytes = 10485760
+        max_target_bytes = 4294967296
+        pool_max_idle = 5
+        pool_idle_sec = 30
+        signing_algorithm = "hmac-sha256"
+        signing_header = "X-Signature"
+        signing_canonical = "{method}\n{path}\n{body_sha256}"
+        max_redirects = 5
+        redirect_same_host = false
+        redirect_keep_auth = false
+        token_header = "X-Authorization"
         "#;
 
     const RVI_CONFIG: &'static str =
@@ -632,6 +1051,8 @@ mod tests {
         ca_file = "/usr/local/etc/sota/ca.crt"
         cert_file = "/usr/local/etc/sota/device.crt"
         pkey_file = "/usr/local/etc/sota/device.pem"
+        expiry_warn_days = 30
+        expiry_check_sec = 86400
         "#;
 
     const UPTANE_CONFIG: &'static str =
@@ -641,10 +1062,15 @@ mod tests {
         repo_server = "http://localhost:8002/repo"
         primary_ecu_serial = "primary-serial"
         metadata_path = "/usr/local/etc/sota/metadata"
+        images_dir = "/var/sota/images"
         private_key_path = "/usr/local/etc/sota/ecuprimary.pem"
         public_key_path = "/usr/local/etc/sota/ecuprimary.pub"
         atomic_primary = "127.0.0.1:2310"
         atomic_timeout_sec = 300
+        manifest_retries = 3
+        max_clock_skew_sec = 3600
+        trust_device_clock = true
+        debug_canonical_json = false
         "#;
 
 
@@ -679,10 +1105,84 @@ mod tests {
         assert_eq!(Config::load("tests/config/auth.toml").unwrap(), Config::parse(&configs).unwrap());
     }
 
+    #[test]
+    fn server_list_config() {
+        let config = Config::parse(r#"
+            [core]
+            server = ["http://one.local:8080", "http://two.local:8080"]
+            "#).unwrap();
+        assert_eq!(config.core.server, "http://one.local:8080,http://two.local:8080".parse().unwrap());
+        assert_eq!(Config::parse(CORE_CONFIG).unwrap().core.server.urls.len(), 1);
+        assert!(Config::parse("[core]\nserver = []").is_err());
+    }
+
+    #[test]
+    fn network_addresses_config() {
+        let config = Config::parse(r#"
+            [network]
+            http_server = "[::]:8888"
+            websocket_server = "localhost:3012"
+            "#).unwrap();
+        assert!(config.network.http_server.is_ipv6());
+        assert_eq!(config.network.websocket_server, "localhost:3012");
+        assert!(Config::parse("[network]\nhttp_server = \"localhost:8888\"").is_err());
+    }
+
+    #[test]
+    fn download_dirs_config() {
+        let config = Config::parse(r#"
+            [device]
+            packages_dir = "/tmp"
+            deb_packages_dir = "/var/sota/debs"
+            ostree_packages_dir = "/var/sota/ostree"
+            "#).unwrap();
+        assert_eq!(config.device.download_dir(&PacMan::Deb), Some("/var/sota/debs"));
+        assert_eq!(config.device.download_dir(&PacMan::Ostree), Some("/var/sota/ostree"));
+        assert_eq!(config.device.download_dir(&PacMan::Uptane), Some("/var/sota/ostree"));
+        assert_eq!(config.device.download_dir(&PacMan::Rpm), None);
+        assert_eq!(config.device.download_dirs(), vec!["/var/sota/debs", "/var/sota/ostree"]);
+        assert!(config.device.check_download_dirs().is_err());
+
+        let mut config = Config::default();
+        config.device.deb_packages_dir = Some("/tmp".into());
+        assert!(config.device.check_download_dirs().is_ok());
+    }
+
+    #[test]
+    fn http_headers_config() {
+        let mut config = Config::parse(r#"
+            [network.http_headers]
+            User-Agent = "custom-agent"
+            x-fleet = "test-fleet"
+            "#).unwrap();
+        config.device.uuid = "00000000-0000-0000-0000-000000000001".parse().unwrap();
+        let headers = config.http_headers(Some("1.0"));
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers["User-Agent"], "custom-agent");
+        assert_eq!(headers["x-fleet"], "test-fleet");
+        assert_eq!(headers["x-device-uuid"], "00000000-0000-0000-0000-000000000001");
+        assert_eq!(Config::default().http_headers(Some("1.0"))["User-Agent"], "sota-client/1.0");
+    }
+
     #[test]
     fn backwards_compatible_config() {
         let config = Config::load("tests/config/old.toml").unwrap();
         assert_eq!(config.core.polling, true);
         assert_eq!(config.core.polling_sec, 10);
+        assert_eq!(config.device.system_info, Some(SystemInfo::Command("system_info.sh".into())));
+    }
+
+    #[test]
+    fn system_info_sources_config() {
+        let config = Config::parse(r#"
+            [device]
+            system_info = ["/usr/bin/lshw", "/usr/local/bin/custom"]
+            "#).unwrap();
+        assert_eq!(config.device.system_info, Some(SystemInfo::Commands(vec!["/usr/bin/lshw".into(), "/usr/local/bin/custom".into()])));
+        let config = Config::parse(r#"
+            [device]
+            system_info = "/etc/sota/info.json"
+            "#).unwrap();
+        assert_eq!(config.device.system_info, Some(SystemInfo::File("/etc/sota/info.json".into())));
     }
 }
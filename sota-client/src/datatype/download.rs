@@ -1,6 +1,12 @@
+use base64;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
 use uuid::Uuid;
 
+use datatype::{Error, SignatureType, Util};
+
 
 /// Details of a package for downloading.
 #[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
@@ -16,6 +22,39 @@ impl Display for Package {
 }
 
 
+/// The changes to the installed packages since a previous list was reported.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct PackageDiff {
+    pub added:   Vec<Package>,
+    pub removed: Vec<Package>,
+    pub changed: Vec<Package>,
+}
+
+impl PackageDiff {
+    /// Compare two lists of packages by name. A package with a new version is
+    /// reported in `changed` with the new version.
+    pub fn new(old: &[Package], new: &[Package]) -> PackageDiff {
+        let old_versions = old.iter().map(|pkg| (&pkg.name, &pkg.version)).collect::<HashMap<_, _>>();
+        let new_versions = new.iter().map(|pkg| (&pkg.name, &pkg.version)).collect::<HashMap<_, _>>();
+        let mut diff = PackageDiff::default();
+        for pkg in new {
+            match old_versions.get(&pkg.name) {
+                None => diff.added.push(pkg.clone()),
+                Some(version) if **version != pkg.version => diff.changed.push(pkg.clone()),
+                Some(_) => ()
+            }
+        }
+        diff.removed = old.iter().filter(|pkg| ! new_versions.contains_key(&pkg.name)).cloned().collect();
+        diff
+    }
+
+    /// Whether there were no changes between the package lists.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+
 /// A request for the device to install a new update.
 #[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
 #[allow(non_snake_case)]
@@ -25,6 +64,10 @@ pub struct UpdateRequest {
     pub packageId:  Package,
     pub installPos: i32,
     pub createdAt:  String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum:   Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature:  Option<String>,
 }
 
 /// The current status of an `UpdateRequest`.
@@ -56,9 +99,76 @@ pub struct DownloadComplete {
     pub signature:    String
 }
 
+impl DownloadComplete {
+    /// Verify the base64-encoded RSASSA-PSS signature over the hex-encoded
+    /// sha256 hash of the downloaded image with the DER-encoded public key.
+    pub fn verify_signature(&self, pub_key: &[u8]) -> Result<(), Error> {
+        let mut hasher = Sha256::new();
+        hasher.input(&Util::read_file(&self.update_image)?);
+        let sig = base64::decode(&self.signature)?;
+        if SignatureType::RsaSsaPss.verify_msg(hasher.result_str().as_bytes(), pub_key, &sig) {
+            Ok(())
+        } else {
+            Err(Error::Image(format!("signature verification failed for {}", self.update_image)))
+        }
+    }
+}
+
 /// A notification to an external package manager that the package download failed.
 #[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
 pub struct DownloadFailed {
     pub update_id: Uuid,
     pub reason:    String
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    use pacman::test::TestDir;
+
+
+    fn pkg(name: &str, version: &str) -> Package {
+        Package { name: name.into(), version: version.into() }
+    }
+
+    #[test]
+    fn test_package_diff() {
+        let old = vec![pkg("one", "1.0"), pkg("two", "2.0"), pkg("three", "3.0")];
+        assert!(PackageDiff::new(&old, &old).is_empty());
+
+        let new = vec![pkg("one", "1.0"), pkg("two", "2.1"), pkg("four", "4.0")];
+        assert_eq!(PackageDiff::new(&old, &new), PackageDiff {
+            added:   vec![pkg("four", "4.0")],
+            removed: vec![pkg("three", "3.0")],
+            changed: vec![pkg("two", "2.1")],
+        });
+        assert_eq!(PackageDiff::new(&[], &old).added, old);
+        assert_eq!(PackageDiff::new(&old, &[]).removed, old);
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let dir = TestDir::new("sota-download-signature");
+        let update_image = format!("{}/image", dir.0);
+        File::create(&update_image).unwrap().write_all(b"image data").unwrap();
+        let mut hasher = Sha256::new();
+        hasher.input(b"image data");
+
+        let pri_key = Util::read_file("tests/keys/rsa.der").expect("rsa.der");
+        let pub_key = Util::read_pem("tests/keys/rsa.pub").expect("rsa.pub");
+        let sig = SignatureType::RsaSsaPss.sign_msg(hasher.result_str().as_bytes(), &pri_key).expect("sign");
+        let mut complete = DownloadComplete {
+            update_id:    Uuid::default(),
+            update_image: update_image,
+            signature:    base64::encode(&sig),
+        };
+        assert!(complete.verify_signature(&pub_key).is_ok());
+
+        complete.signature = base64::encode(b"spoofed");
+        assert!(complete.verify_signature(&pub_key).is_err());
+    }
+}
@@ -31,6 +31,7 @@ use interpreter::CommandExec;
 /// System-wide errors that are returned from `Result` type failures.
 #[derive(Debug)]
 pub enum Error {
+    Aborted(String),
     Addr(AddrParseError),
     AtomicAbort(String),
     AtomicOffline(String),
@@ -44,7 +45,10 @@ pub enum Error {
     Client(String),
     Command(String),
     Config(String),
+    Connect(String),
     DateTime(ChronoParseError),
+    DiskSpace,
+    DownloadDeferred(String),
     FromUtf8(FromUtf8Error),
     Hex(FromHexError),
     Http(ResponseData),
@@ -70,16 +74,20 @@ pub enum Error {
     Socket(String),
     SystemInfo(String),
     Toml(TomlError),
+    TooLarge(String),
     TufKeyId(String),
     TufKeyType(String),
     TufRole(String),
     TufSigType(String),
     UptaneExpired,
+    UptaneManifestConflict(u32),
+    UptaneMetaMismatch(String),
     UptaneMissingKeys,
     UptaneMissingRoles,
     UptaneRole(String),
     UptaneTargets(String),
     UptaneThreshold(String),
+    UptaneTime(String),
     UptaneVersion,
     UrlParse(UrlParseError),
     Utf8(Utf8Error),
@@ -90,6 +98,7 @@ pub enum Error {
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let inner: String = match *self {
+            Error::Aborted(ref err)     => format!("Aborted by the server: {}", err),
             Error::Addr(ref err)        => format!("Address parse error: {}", err),
             Error::AtomicAbort(ref err) => format!("Atomic transaction aborted: {}", err),
             Error::AtomicOffline(ref serial) => format!("Secondary offline: {}", serial),
@@ -103,7 +112,10 @@ impl Display for Error {
             Error::Client(ref err)      => format!("Http client error: {}", err),
             Error::Command(ref err)     => format!("Unknown Command: {}", err),
             Error::Config(ref err)      => format!("Bad Config: {}", err),
+            Error::Connect(ref err)     => format!("Connection error: {}", err),
             Error::DateTime(ref err)    => format!("DateTime parse error: {}", err),
+            Error::DiskSpace            => "insufficient disk space".into(),
+            Error::DownloadDeferred(ref err) => format!("Download deferred: {}", err),
             Error::FromUtf8(ref err)    => format!("From utf8 error: {}", err),
             Error::Hex(ref err)         => format!("Not valid hex data: {}", err),
             Error::Http(ref err)        => format!("HTTP client error: {}", err),
@@ -129,16 +141,20 @@ impl Display for Error {
             Error::Socket(ref err)      => format!("Socket error: {}", err),
             Error::SystemInfo(ref err)  => format!("System info error: {}", err),
             Error::Toml(ref err)        => format!("TOML error: {:?}", err),
+            Error::TooLarge(ref err)    => format!("Response too large: {}", err),
             Error::TufKeyId(ref err)    => format!("Invalid TUF key id: {}", err),
             Error::TufKeyType(ref err)  => format!("Invalid TUF key type: {}", err),
             Error::TufRole(ref err)     => format!("Invalid TUF role: {}", err),
             Error::TufSigType(ref err)  => format!("Invalid TUF signature type: {}", err),
             Error::UptaneExpired        => "Uptane: metadata has expired".into(),
+            Error::UptaneManifestConflict(retries) => format!("Uptane: manifest still conflicting after {} retries", retries),
+            Error::UptaneMetaMismatch(ref err) => format!("Uptane metadata mismatch: {}", err),
             Error::UptaneMissingKeys    => "Uptane: missing `keys` field".into(),
             Error::UptaneMissingRoles   => "Uptane: missing `roles` field".into(),
             Error::UptaneRole(ref err)  => format!("Uptane role: {}", err),
             Error::UptaneTargets(ref err) => format!("Uptane targets: {}", err),
             Error::UptaneThreshold(ref err) => format!("Uptane metadata: {}", err),
+            Error::UptaneTime(ref err)  => format!("Uptane signed time: {}", err),
             Error::UptaneVersion        => "Uptane: metadata version older than current".into(),
             Error::UrlParse(ref err)    => format!("Url parse error: {}", err),
             Error::Utf8(ref err)        => format!("Utf8 error: {}", err),
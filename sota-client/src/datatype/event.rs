@@ -1,10 +1,15 @@
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
 use uuid::Uuid;
 
-use datatype::{DownloadComplete, InstallReport, InstallResult, Manifests, OstreePackage,
-               Package, TufMeta, UpdateAvailable, UpdateRequest};
-use uptane::Verified;
+use bandwidth::BandwidthRule;
+use datatype::{DownloadComplete, EcuVersion, InstallReport, InstallResult, Manifests, OstreePackage,
+               OstreeRemote, Package, ReportSummary, RoleName, TufMeta, UpdateAvailable,
+               UpdateRequest};
+#[cfg(any(test, feature = "debug"))]
+use pacman::PacMan;
+use uptane::{Service, Verified};
 
 
 /// System-wide events that are broadcast to all interested parties.
@@ -14,8 +19,40 @@ pub enum Event {
     Authenticated,
     /// An operation failed because we are not currently authenticated.
     NotAuthenticated,
+    /// Authenticating again with `ReAuthenticate` failed for this reason.
+    AuthenticationFailed(String),
+    /// Authentication failed repeatedly so the next attempt is delayed.
+    AuthenticationBackoff { next_attempt_secs: u64 },
     /// General error event with a printable representation for debugging.
     Error(String),
+    /// An HTTP request failed with this status code.
+    HttpError { status: u16, url: String, body_excerpt: String },
+    /// A gateway rejected a command that isn't on its allow-list.
+    CommandRejected(String),
+    /// The most recent events, oldest first, returned only to the caller of
+    /// `Command::ReplayEvents`.
+    RecentEvents(Vec<Event>),
+    /// A support bundle of the current state was written to this path.
+    DiagnosticsExported { path: String },
+    /// Automatic downloading of pending updates was turned on or off.
+    AutoDownloadChanged(bool),
+    /// Downloads and installs won't be started until resumed.
+    Paused,
+    /// Downloads and installs will be started again.
+    Resumed,
+    /// A command starting a download or install will run when resumed.
+    CommandDeferred(String),
+    /// The running package manager was replaced (for development and testing only).
+    #[cfg(any(test, feature = "debug"))]
+    PackageManagerChanged(PacMan),
+    /// The current logging filter, returned to the caller of `Command::GetLogLevel`.
+    LogLevel(String),
+    /// The logging filter was replaced with these directives.
+    LogLevelChanged(String),
+    /// The TLS client certificate will expire within the configured threshold.
+    CertificateExpiringSoon { days_remaining: i64 },
+    /// The TLS client certificate has expired.
+    CertificateExpired,
 
     /// A notification from Core of pending or in-flight updates.
     UpdatesReceived(Vec<UpdateRequest>),
@@ -23,11 +60,26 @@ pub enum Event {
     UpdateAvailable(UpdateAvailable),
     /// There are no outstanding update requests.
     NoUpdateRequests,
+    /// Which core or Director server was polled for updates, how long it took
+    /// to respond and the size of the response, sent after failed polls too.
+    PollTelemetry { server: String, latency_ms: u64, bytes: u64, updates_found: usize },
+    /// An update waiting for confirmation was approved.
+    UpdateApproved(Uuid),
+    /// An update waiting for confirmation was declined.
+    UpdateDenied(Uuid),
+    /// An update canceled by the server was aborted.
+    UpdateAborted(Uuid),
 
     /// The following packages are installed on the device.
     FoundInstalledPackages(Vec<Package>),
     /// An update on the system information was received.
     FoundSystemInfo(String),
+    /// The free bytes available in each storage directory, including each
+    /// download directory configured per package type.
+    StorageInfo { packages_dir_free: u64, images_dir_free: u64, metadata_dir_free: u64,
+                  download_dirs_free: HashMap<String, u64> },
+    /// The problems found by the package manager's integrity check.
+    IntegrityReport { issues: Vec<String> },
 
     /// Downloading an update.
     DownloadingUpdate(Uuid),
@@ -35,15 +87,48 @@ pub enum Event {
     DownloadComplete(DownloadComplete),
     /// Downloading an update failed.
     DownloadFailed(Uuid, String),
+    /// An update wasn't downloaded again as its verified artifact is on disk.
+    DownloadSkipped(Uuid),
+    /// A download wasn't started outside the download windows or after the
+    /// monthly cap was reached, for this reason.
+    DownloadDeferred(Uuid, String),
+    /// The download windows, the monthly cap and the bytes downloaded this month.
+    BandwidthRules { rules: Vec<BandwidthRule>, monthly_cap: Option<u64>, used_bytes: u64 },
+    /// The download windows were replaced with these rules.
+    BandwidthRulesChanged(Vec<BandwidthRule>),
+    /// A new transfer was rejected as the maximum number are already active.
+    TransfersThrottled(usize),
+    /// All transfers were aborted, counting the orphaned partial downloads removed.
+    TransfersAborted(usize),
+    /// Downloaded update artifacts were removed.
+    ArtifactsCleaned { freed_bytes: u64, count: usize },
 
     /// Installing an update.
     InstallingUpdate(Uuid),
+    /// An update is waiting on earlier updates in the install queue.
+    InstallQueued(Uuid),
+    /// A downloaded update will be installed at this time.
+    InstallScheduled { update_id: Uuid, at: DateTime<Utc> },
+    /// The install of a scheduled update is starting.
+    InstallStarting(Uuid),
     /// An update was installed.
     InstallComplete(InstallResult),
     /// The installation of an update failed.
     InstallFailed(InstallResult),
+    /// An installed update failed the health check and was rolled back.
+    InstallRolledBack(Uuid),
+    /// An installed update only takes effect after the device is rebooted.
+    RebootRequired(Uuid),
     /// An installation report was sent.
     InstallReportSent(InstallReport),
+    /// A duplicate installation report for this update id was not sent.
+    InstallReportSkipped(String),
+    /// All updates in the install queue were processed.
+    InstallQueueComplete(Vec<InstallResult>),
+    /// The installation reports waiting in the spool to be sent.
+    PendingReports(Vec<ReportSummary>),
+    /// These spooled installation reports were discarded.
+    PendingReportsCleared(Vec<ReportSummary>),
 
     /// An event requesting an update on all installed packages.
     InstalledPackagesNeeded,
@@ -74,6 +159,33 @@ pub enum Event {
     UptaneManifestNeeded,
     /// A manifest was sent to the Director server.
     UptaneManifestSent,
+    /// The signed manifest that would be sent to the Director server, and the
+    /// canonical JSON that was signed.
+    UptaneManifest { manifest: String, canonical_json: String },
+    /// The primary ECU's private key was replaced with the one with this key id.
+    UptaneKeyRotated(String),
+    /// The local clock differs from the server's by more than the allowed skew.
+    ClockSkewDetected { skew_sec: i64 },
+    /// An image didn't match stale targets metadata so was fetched again.
+    UptaneTargetStale(String),
+    /// An image didn't match the hash in the latest targets metadata.
+    UptaneTargetCorrupt { refname: String, expected: String, actual: String },
+    /// Whether a target is authorized for one of this device's ECUs, and why.
+    UptaneTargetAuthorized { authorized: bool, reason: String },
+    /// The trusted metadata version of each role from the Director and Repo.
+    UptaneMetadataVersions { director: HashMap<String, u64>, repo: HashMap<String, u64> },
+    /// The current version of the primary ECU followed by each secondary ECU.
+    UptaneEcus(Vec<EcuVersion>),
+    /// The metadata of a role, marked as verified when read from the local copy
+    /// or unverified when fetched directly from the server.
+    UptaneRawMetadata { service: Service, role: RoleName, json: String, verified: bool },
+
+    /// The configured OSTree remotes.
+    OstreeRemotes(Vec<OstreeRemote>),
+    /// An OSTree remote was added.
+    OstreeRemoteAdded(String),
+    /// An OSTree remote was deleted.
+    OstreeRemoteDeleted(String),
 }
 
 impl Display for Event {
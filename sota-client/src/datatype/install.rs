@@ -1,7 +1,14 @@
+use json;
 use serde::{Serialize, Serializer};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use uuid::Uuid;
 
-use datatype::Error;
+use datatype::{Error, Package, Util};
 
 
 /// The installation outcome from a package manager.
@@ -32,6 +39,17 @@ impl InstallOutcome {
         Self::new(InstallCode::GENERAL_ERROR, "".into(), stderr)
     }
 
+    /// Was the installation successful?
+    pub fn is_success(&self) -> bool {
+        self.code.is_success()
+    }
+
+    /// Limit stdout and stderr to `max_bytes` each, keeping the tail of the
+    /// output where errors are usually reported.
+    pub fn truncate(self, max_bytes: u64) -> InstallOutcome {
+        InstallOutcome { code: self.code, stdout: keep_tail(self.stdout, max_bytes), stderr: keep_tail(self.stderr, max_bytes) }
+    }
+
     /// Convert an `InstallOutcome` into a `InstallResult
     pub fn into_result(self, id: String) -> InstallResult {
         InstallResult::new(id, self.code, format!("stdout: {}\nstderr: {}\n", self.stdout, self.stderr))
@@ -39,18 +57,38 @@ impl InstallOutcome {
 }
 
 
+fn keep_tail(text: String, max_bytes: u64) -> String {
+    if text.len() as u64 <= max_bytes {
+        return text;
+    }
+    let mut start = text.len() - max_bytes as usize;
+    while ! text.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("...truncated {} bytes\n{}", start, &text[start..])
+}
+
+
 /// An encodable response of the installation outcome.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
 pub struct InstallResult {
-    pub id:          String,
-    pub result_code: InstallCode,
-    pub result_text: String,
+    pub id:              String,
+    pub result_code:     InstallCode,
+    pub result_text:     String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms:     Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempt:         Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reboot_required: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forced:          Option<bool>,
 }
 
 impl InstallResult {
     /// Create a new installation result.
     pub fn new(id: String, result_code: InstallCode, result_text: String) -> InstallResult {
-        InstallResult { id, result_code, result_text }
+        InstallResult { id, result_code, result_text, duration_ms: None, attempt: None, reboot_required: None, forced: None }
     }
 
     /// Convert a single installation result to an `InstallReport`.
@@ -75,6 +113,198 @@ impl InstallReport {
 }
 
 
+/// A summary of an installation report waiting in the spool to be sent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReportSummary {
+    pub update_id: String,
+    pub age_secs:  u64,
+}
+
+
+/// An ordered queue of updates that must be installed strictly one after another.
+#[derive(Debug, Default)]
+pub struct InstallQueue {
+    pub halted: bool,
+    order:      VecDeque<Uuid>,
+    ready:      HashSet<Uuid>,
+    results:    Vec<InstallResult>,
+    attempts:   HashMap<Uuid, u32>,
+    checksums:  HashMap<Uuid, String>,
+    signatures: HashMap<Uuid, String>,
+}
+
+impl InstallQueue {
+    /// Add an update to the back of the queue.
+    pub fn push(&mut self, id: Uuid) {
+        if ! self.order.contains(&id) {
+            self.order.push_back(id);
+        }
+    }
+
+    /// Remove an update from the queue without installing it.
+    pub fn remove(&mut self, id: &Uuid) {
+        self.order.retain(|queued| queued != id);
+        self.ready.remove(id);
+    }
+
+    /// Forget everything about an update aborted by the server.
+    pub fn abort(&mut self, id: Uuid) {
+        self.remove(&id);
+        self.checksums.remove(&id);
+        self.signatures.remove(&id);
+        self.attempts.remove(&id);
+    }
+
+    /// Returns true if the update is waiting in the queue.
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.order.contains(id)
+    }
+
+    /// Returns the updates waiting in the queue, in installation order.
+    pub fn queued(&self) -> Vec<Uuid> {
+        self.order.iter().cloned().collect()
+    }
+
+    /// Returns true if there are no more updates waiting in the queue.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Mark a queued update as ready for installation.
+    pub fn set_ready(&mut self, id: Uuid) {
+        self.ready.insert(id);
+    }
+
+    /// Pop the update at the front of the queue if it is ready for installation.
+    pub fn next_ready(&mut self) -> Option<Uuid> {
+        if self.order.front().map_or(false, |id| self.ready.contains(id)) {
+            let id = self.order.pop_front().expect("front of queue");
+            self.ready.remove(&id);
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Record the expected sha256 checksum of an update's package.
+    pub fn set_checksum(&mut self, id: Uuid, checksum: String) {
+        self.checksums.insert(id, checksum);
+    }
+
+    /// Return the expected sha256 checksum of an update's package.
+    pub fn checksum(&self, id: &Uuid) -> Option<&str> {
+        self.checksums.get(id).map(|sum| sum.as_str())
+    }
+
+    /// Record the base64-encoded signature of an update's package.
+    pub fn set_signature(&mut self, id: Uuid, signature: String) {
+        self.signatures.insert(id, signature);
+    }
+
+    /// Return the base64-encoded signature of an update's package.
+    pub fn signature(&self, id: &Uuid) -> Option<&str> {
+        self.signatures.get(id).map(|sig| sig.as_str())
+    }
+
+    /// Increment and return the number of installation attempts for an update.
+    pub fn next_attempt(&mut self, id: Uuid) -> u32 {
+        let attempt = self.attempts.entry(id).or_insert(0);
+        *attempt += 1;
+        *attempt
+    }
+
+    /// Forget the installation attempts of an update once it succeeds.
+    pub fn clear_attempts(&mut self, id: &Uuid) {
+        self.attempts.remove(id);
+    }
+
+    /// Record the installation result of a queued update.
+    pub fn add_result(&mut self, result: InstallResult) {
+        self.results.push(result);
+    }
+
+    /// Reset the queue state, returning the results of the finished batch.
+    pub fn finish(&mut self) -> Vec<InstallResult> {
+        self.halted = false;
+        mem::replace(&mut self.results, Vec::new())
+    }
+}
+
+
+/// The maximum number of aborted updates remembered.
+const MAX_ABORTED: usize = 256;
+
+/// Updates canceled by the server. A cancellation is flagged as soon as it is
+/// seen so that in-flight downloads and installs stop early, and the most
+/// recently aborted updates are saved to `path` when set so that they aren't
+/// started again after a restart. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct AbortedUpdates {
+    path:     Option<String>,
+    aborted:  Arc<Mutex<VecDeque<Uuid>>>,
+    canceled: Arc<Mutex<HashSet<Uuid>>>,
+    flags:    Arc<Mutex<HashMap<Uuid, Weak<AtomicBool>>>>,
+}
+
+impl AbortedUpdates {
+    /// Load the aborted updates saved at `path`, starting empty when there aren't any.
+    pub fn load(path: Option<String>) -> Result<Self, Error> {
+        let aborted = match path {
+            Some(ref path) if Path::new(path).exists() => json::from_slice(&Util::read_file(path)?)?,
+            _ => VecDeque::new()
+        };
+        Ok(AbortedUpdates { path: path, aborted: Arc::new(Mutex::new(aborted)), canceled: Arc::default(), flags: Arc::default() })
+    }
+
+    /// Flag an update canceled by the server so that any in-flight work stops.
+    pub fn cancel(&self, id: Uuid) {
+        self.canceled.lock().unwrap().insert(id);
+        if let Some(flag) = self.flags.lock().unwrap().get(&id).and_then(Weak::upgrade) {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns a flag that is set once the update is canceled, for checking
+    /// while a download of it is in progress.
+    pub fn flag(&self, id: Uuid) -> Arc<AtomicBool> {
+        let mut flags = self.flags.lock().unwrap();
+        flags.retain(|_, flag| flag.upgrade().is_some());
+        let flag = Arc::new(AtomicBool::new(self.is_canceled(&id)));
+        flags.insert(id, Arc::downgrade(&flag));
+        flag
+    }
+
+    /// Returns true if the update was canceled by the server, whether or not
+    /// the abort has finished.
+    pub fn is_canceled(&self, id: &Uuid) -> bool {
+        self.canceled.lock().unwrap().contains(id) || self.is_aborted(id)
+    }
+
+    /// Returns true if the abort of the update has finished.
+    pub fn is_aborted(&self, id: &Uuid) -> bool {
+        self.aborted.lock().unwrap().contains(id)
+    }
+
+    /// Record that the abort of an update has finished, forgetting the oldest
+    /// once `MAX_ABORTED` are remembered.
+    pub fn add(&self, id: Uuid) -> Result<(), Error> {
+        self.canceled.lock().unwrap().remove(&id);
+        let mut aborted = self.aborted.lock().unwrap();
+        if aborted.contains(&id) {
+            return Ok(());
+        }
+        while aborted.len() >= MAX_ABORTED {
+            let _ = aborted.pop_front();
+        }
+        aborted.push_back(id);
+        match self.path {
+            Some(ref path) => Util::write_file(path, &json::to_vec(&*aborted)?),
+            None => Ok(())
+        }
+    }
+}
+
+
 /// Enumerate the possible outcomes when trying to install a package.
 #[allow(non_camel_case_types)]
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
@@ -191,6 +421,18 @@ pub struct InstalledPackage {
     pub last_modified: u64
 }
 
+impl InstalledPackage {
+    /// Describe a package reported by the package manager.
+    pub fn from_package(pkg: Package) -> InstalledPackage {
+        InstalledPackage {
+            package_id:    format!("{}", pkg),
+            name:          pkg.name,
+            description:   "".into(),
+            last_modified: 0,
+        }
+    }
+}
+
 /// An encodable list of packages and firmwares to send to RVI.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
 pub struct InstalledSoftware {
@@ -204,3 +446,115 @@ impl InstalledSoftware {
         InstalledSoftware { packages, firmwares }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use json;
+
+    use pacman::test::TestDir;
+
+
+    fn new_id(n: u8) -> Uuid {
+        format!("00000000-0000-0000-0000-00000000000{}", n).parse().unwrap()
+    }
+
+    #[test]
+    fn install_queue_order() {
+        let mut queue = InstallQueue::default();
+        queue.push(new_id(1));
+        queue.push(new_id(2));
+        queue.set_ready(new_id(2));
+        assert_eq!(queue.next_ready(), None);
+        queue.set_ready(new_id(1));
+        assert_eq!(queue.next_ready(), Some(new_id(1)));
+        assert_eq!(queue.next_ready(), Some(new_id(2)));
+        assert_eq!(queue.next_ready(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn install_queue_remove() {
+        let mut queue = InstallQueue::default();
+        queue.push(new_id(1));
+        queue.push(new_id(2));
+        queue.set_ready(new_id(2));
+        queue.remove(&new_id(1));
+        assert_eq!(queue.next_ready(), Some(new_id(2)));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn install_outcome_truncated() {
+        let stdout = format!("{}error: dependency problems", "unpacking...\n".repeat(1000));
+        let result = InstallOutcome::new(InstallCode::INSTALL_FAILED, stdout.clone(), "short".into())
+            .truncate(26)
+            .into_result("id".into());
+        let expected = format!("stdout: ...truncated {} bytes\nerror: dependency problems\nstderr: short\n", stdout.len() - 26);
+        assert_eq!(result.result_text, expected);
+
+        let result = InstallOutcome::new(InstallCode::OK, "∂∂∂".into(), "".into()).truncate(4).into_result("id".into());
+        assert_eq!(result.result_text, "stdout: ...truncated 6 bytes\n∂\nstderr: \n");
+    }
+
+    #[test]
+    fn install_result_optional_fields() {
+        let mut result = InstallResult::new("id".into(), InstallCode::OK, "".into());
+        let json = json::to_string(&result).unwrap();
+        assert_eq!(json, r#"{"id":"id","result_code":0,"result_text":""}"#);
+
+        result.duration_ms = Some(1200);
+        result.attempt = Some(2);
+        let json = json::to_string(&result).unwrap();
+        assert_eq!(json, r#"{"id":"id","result_code":0,"result_text":"","duration_ms":1200,"attempt":2}"#);
+    }
+
+    #[test]
+    fn install_queue_attempts() {
+        let mut queue = InstallQueue::default();
+        assert_eq!(queue.next_attempt(new_id(1)), 1);
+        assert_eq!(queue.next_attempt(new_id(1)), 2);
+        queue.clear_attempts(&new_id(1));
+        assert_eq!(queue.next_attempt(new_id(1)), 1);
+    }
+
+    #[test]
+    fn aborted_updates_saved() {
+        let dir = TestDir::new("sota-aborted-updates");
+        let path = Some(format!("{}/aborted.json", dir.0));
+        let aborted = AbortedUpdates::load(path.clone()).expect("load");
+        aborted.cancel(new_id(1));
+        assert!(aborted.is_canceled(&new_id(1)));
+        assert!(! aborted.is_aborted(&new_id(1)));
+        assert!(! AbortedUpdates::load(path.clone()).expect("reload").is_canceled(&new_id(1)));
+
+        aborted.add(new_id(1)).expect("add");
+        let reloaded = AbortedUpdates::load(path).expect("reload");
+        assert!(reloaded.is_aborted(&new_id(1)));
+        assert!(reloaded.is_canceled(&new_id(1)));
+        assert!(! reloaded.is_canceled(&new_id(2)));
+    }
+
+    #[test]
+    fn aborted_updates_flag() {
+        let aborted = AbortedUpdates::default();
+        let flag = aborted.flag(new_id(1));
+        assert!(! flag.load(Ordering::SeqCst));
+        aborted.cancel(new_id(1));
+        assert!(flag.load(Ordering::SeqCst));
+        assert!(aborted.flag(new_id(1)).load(Ordering::SeqCst));
+        assert!(! aborted.flag(new_id(2)).load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn aborted_updates_bounded() {
+        let aborted = AbortedUpdates::default();
+        for _ in 0..MAX_ABORTED {
+            aborted.add(Uuid::new_v4()).expect("add");
+        }
+        aborted.add(new_id(1)).expect("add");
+        assert_eq!(aborted.aborted.lock().unwrap().len(), MAX_ABORTED);
+        assert!(aborted.is_aborted(&new_id(1)));
+    }
+}
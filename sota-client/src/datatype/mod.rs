@@ -1,5 +1,6 @@
 pub mod auth;
 pub mod canonical;
+pub mod clock;
 pub mod command;
 pub mod config;
 pub mod download;
@@ -9,24 +10,29 @@ pub mod install;
 pub mod network;
 pub mod ostree;
 pub mod signature;
+pub mod system_info;
 pub mod tuf;
 pub mod util;
 
-pub use self::auth::{AccessToken, Auth, ClientCredentials};
+pub use self::auth::{AccessToken, Auth, AuthBackoff, ClientCredentials};
 pub use self::canonical::CanonicalJson;
+pub use self::clock::{Clock, FakeClock, SystemClock};
 pub use self::command::Command;
 pub use self::config::{AuthConfig, CoreConfig, Config, DBusConfig, DeviceConfig,
-                       EcuConfig, GatewayConfig, RviConfig, TlsConfig, UptaneConfig};
-pub use self::download::{DownloadComplete, DownloadFailed, Package, RequestStatus,
+                       EcuConfig, GatewayConfig, NetworkConfig, RviConfig, TlsConfig,
+                       UptaneConfig};
+pub use self::download::{DownloadComplete, DownloadFailed, Package, PackageDiff, RequestStatus,
                          UpdateAvailable, UpdateRequest};
 pub use self::error::Error;
 pub use self::event::Event;
-pub use self::install::{InstallCode, InstallOutcome, InstallReport, InstallResult,
-                        InstalledFirmware, InstalledPackage, InstalledSoftware};
-pub use self::network::{Method, SocketAddrV4, Url};
-pub use self::ostree::OstreePackage;
+pub use self::install::{AbortedUpdates, InstallCode, InstallOutcome, InstallQueue, InstallReport,
+                        InstallResult, InstalledFirmware, InstalledPackage, InstalledSoftware,
+                        ReportSummary};
+pub use self::network::{Method, Servers, SocketAddr, SocketAddrV4, Url};
+pub use self::ostree::{Ostree, OstreePackage, OstreeRemote};
 pub use self::signature::{Signature, SignatureType};
-pub use self::tuf::{EcuCustom, EcuManifests, EcuVersion, Key, KeyType, KeyValue,
-                    Manifests, PrivateKey, RoleData, RoleName, RoleMeta, TufCustom,
+pub use self::system_info::SystemInfo;
+pub use self::tuf::{EcuCustom, EcuManifests, EcuVersion, Key, KeyIdDigest, KeyType, KeyValue,
+                    Manifests, PrivateKey, RoleData, RoleName, RoleMeta, SignedTime, TufCustom,
                     TufImage, TufMeta, TufSigned};
 pub use self::util::Util;
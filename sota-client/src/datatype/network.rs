@@ -2,9 +2,11 @@ use hyper::method::Method as HyperMethod;
 use serde::de::{Deserialize, Deserializer, Error as SerdeError};
 use serde::ser::{Serialize, Serializer};
 use std::fmt::{self, Display, Formatter};
-use std::net::{SocketAddrV4 as NetSocketAddrV4};
+use std::net::{SocketAddr as NetSocketAddr, SocketAddrV4 as NetSocketAddrV4};
 use std::ops::Deref;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use url;
 
 use datatype::Error;
@@ -47,6 +49,43 @@ impl Display for SocketAddrV4 {
 }
 
 
+/// Encapsulate an IPv4 or IPv6 socket address for implementing additional traits.
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SocketAddr(pub NetSocketAddr);
+
+impl FromStr for SocketAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match NetSocketAddr::from_str(s) {
+            Ok(addr) => Ok(SocketAddr(addr)),
+            Err(err) => Err(Error::Parse(format!("couldn't parse SocketAddr `{}`: {}", s, err)))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SocketAddr {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<SocketAddr, D::Error> {
+        let s: String = Deserialize::deserialize(de)?;
+        s.parse().map_err(|err| SerdeError::custom(format!("invalid SocketAddr: {}", err)))
+    }
+}
+
+impl Deref for SocketAddr {
+    type Target = NetSocketAddr;
+
+    fn deref(&self) -> &NetSocketAddr {
+        &self.0
+    }
+}
+
+impl Display for SocketAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+
 /// Encapsulate a url with additional methods and traits.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Url(pub url::Url);
@@ -105,10 +144,120 @@ impl Display for Url {
 }
 
 
-/// Enumerate the supported HTTP methods.
+/// A list of equivalent servers to try in order, remembering the last one that
+/// responded so later requests start there. Clones share the selected server.
 #[derive(Clone, Debug)]
+pub struct Servers {
+    pub urls: Vec<Url>,
+    current:  Arc<AtomicUsize>,
+}
+
+impl Servers {
+    /// Create a new list of servers, failing if it is empty.
+    pub fn new(urls: Vec<Url>) -> Result<Self, Error> {
+        if urls.is_empty() {
+            Err(Error::Parse("at least one server url required".into()))
+        } else {
+            Ok(Servers { urls: urls, current: Arc::new(AtomicUsize::new(0)) })
+        }
+    }
+
+    /// Returns the last server that responded.
+    pub fn current(&self) -> &Url {
+        &self.urls[self.current.load(Ordering::SeqCst) % self.urls.len()]
+    }
+
+    /// Call the function with each server in turn, starting at the current one,
+    /// until it returns without a connection or server error.
+    pub fn try_each<T, F>(&self, mut f: F) -> Result<T, Error>
+        where F: FnMut(&Url) -> Result<T, Error>
+    {
+        let start = self.current.load(Ordering::SeqCst);
+        let mut last_err = None;
+        for n in 0..self.urls.len() {
+            let index = (start + n) % self.urls.len();
+            let result = f(&self.urls[index]);
+            let unavailable = match result {
+                Err(ref err) if is_unavailable(err) => {
+                    warn!("server {} unavailable: {}", self.urls[index], err);
+                    true
+                }
+                _ => false
+            };
+            if ! unavailable {
+                if index != start {
+                    info!("Selected server {}", self.urls[index]);
+                    self.current.store(index, Ordering::SeqCst);
+                }
+                return result;
+            }
+            last_err = result.err();
+        }
+        Err(last_err.unwrap_or_else(|| Error::Client("no servers available".into())))
+    }
+}
+
+/// Whether the error indicates the server could not handle any request, as
+/// it couldn't be connected to or returned a 5xx response.
+fn is_unavailable(err: &Error) -> bool {
+    match *err {
+        Error::Connect(_)    => true,
+        Error::Http(ref res) => res.code.is_server_error(),
+        _ => false
+    }
+}
+
+impl PartialEq for Servers {
+    fn eq(&self, other: &Servers) -> bool {
+        self.urls == other.urls
+    }
+}
+
+impl Eq for Servers {}
+
+impl FromStr for Servers {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Servers::new(s.split(',').map(|url| url.trim().parse()).collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+impl<'de> Deserialize<'de> for Servers {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Servers, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(Url),
+            Many(Vec<Url>),
+        }
+
+        let urls = match OneOrMany::deserialize(de)? {
+            OneOrMany::One(url)   => vec![url],
+            OneOrMany::Many(urls) => urls,
+        };
+        Servers::new(urls).map_err(|err| SerdeError::custom(format!("invalid servers: {}", err)))
+    }
+}
+
+impl Serialize for Servers {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.urls.serialize(ser)
+    }
+}
+
+impl Display for Servers {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.current())
+    }
+}
+
+
+/// Enumerate the supported HTTP methods.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Method {
     Get,
+    Patch,
     Post,
     Put,
 }
@@ -116,9 +265,10 @@ pub enum Method {
 impl Into<HyperMethod> for Method {
     fn into(self) -> HyperMethod {
         match self {
-            Method::Get  => HyperMethod::Get,
-            Method::Post => HyperMethod::Post,
-            Method::Put  => HyperMethod::Put,
+            Method::Get   => HyperMethod::Get,
+            Method::Patch => HyperMethod::Patch,
+            Method::Post  => HyperMethod::Post,
+            Method::Put   => HyperMethod::Put,
         }
     }
 }
@@ -126,9 +276,10 @@ impl Into<HyperMethod> for Method {
 impl Display for Method {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let method = match *self {
-            Method::Get  => "GET".to_string(),
-            Method::Post => "POST".to_string(),
-            Method::Put  => "PUT".to_string(),
+            Method::Get   => "GET".to_string(),
+            Method::Patch => "PATCH".to_string(),
+            Method::Post  => "POST".to_string(),
+            Method::Put   => "PUT".to_string(),
         };
         write!(f, "{}", method)
     }
@@ -152,4 +303,45 @@ mod tests {
         assert_eq!(no_slash.join("/two"), "http://localhost:1234/foo/two".parse().unwrap());
         assert_eq!(no_slash.join("/query%25?x=1"), "http://localhost:1234/foo/query%25?x=1".parse().unwrap());
     }
+
+    #[test]
+    fn test_servers_failover() {
+        let servers: Servers = "http://one.local, http://two.local".parse().unwrap();
+        assert_eq!(servers.current(), &"http://one.local".parse().unwrap());
+
+        let mut tried = Vec::new();
+        let result = servers.try_each(|url| {
+            tried.push(url.clone());
+            if url.0.host_str() == Some("one.local") { Err(Error::Connect("down".into())) } else { Ok(1) }
+        });
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(tried.len(), 2);
+        assert_eq!(servers.clone().current(), &"http://two.local".parse().unwrap());
+
+        let result: Result<(), Error> = servers.try_each(|_| Err(Error::Parse("bad reply".into())));
+        assert!(result.is_err());
+        assert_eq!(servers.current(), &"http://two.local".parse().unwrap());
+
+        let mut tried = 0;
+        let result: Result<(), Error> = servers.try_each(|_| { tried += 1; Err(Error::Client("couldn't read body".into())) });
+        assert!(result.is_err());
+        assert_eq!(tried, 1);
+        assert_eq!(servers.current(), &"http://two.local".parse().unwrap());
+        assert!("".parse::<Servers>().is_err());
+    }
+
+    #[test]
+    fn test_parse_socket_addr() {
+        let any_v4: SocketAddr = "0.0.0.0:8888".parse().unwrap();
+        assert!(any_v4.is_ipv4());
+        let any_v6: SocketAddr = "[::]:8888".parse().unwrap();
+        assert!(any_v6.is_ipv6());
+        assert_eq!(format!("{}", any_v6), "[::]:8888");
+        let iface: SocketAddr = "[fe80::1]:3012".parse().unwrap();
+        assert_eq!(iface.port(), 3012);
+
+        assert!("localhost:8888".parse::<SocketAddr>().is_err());
+        assert!("::1:8888".parse::<SocketAddr>().is_err());
+        assert!("127.0.0.1".parse::<SocketAddr>().is_err());
+    }
 }
@@ -4,16 +4,17 @@ use json;
 use std::fmt::Debug;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, ErrorKind};
 use std::path::Path;
 use std::process::{Command, Output};
 use std::str;
+use std::time::Duration;
 use tar::Archive;
 
 use datatype::{EcuCustom, EcuVersion, Error, InstallCode, InstallOutcome,
                TufMeta, TufImage, Url, Util};
-use http::{Client, Response};
-use pacman::Credentials;
+use http::Response;
+use pacman::{Credentials, TimedOutput, run_with_timeout};
 
 
 const REMOTE_NAME: &'static str = "sota-remote";
@@ -25,14 +26,22 @@ const BOOT_BRANCH: &'static str = "/usr/share/sota/branchname";
 pub struct Ostree;
 
 impl Ostree {
-    fn run<S: AsRef<OsStr> + Debug>(args: &[S]) -> Result<Output, Error> {
+    fn command<S: AsRef<OsStr> + Debug>(args: &[S]) -> Command {
         debug!("running `ostree` command with args: {:?}", args);
-        Command::new("ostree")
-            .args(args)
+        let mut cmd = Command::new("ostree");
+        cmd.args(args)
             .env("OSTREE_REPO", "/sysroot/ostree/repo")
-            .env("OSTREE_BOOT_PARTITION", "/boot")
+            .env("OSTREE_BOOT_PARTITION", "/boot");
+        cmd
+    }
+
+    fn run<S: AsRef<OsStr> + Debug>(args: &[S]) -> Result<Output, Error> {
+        Ostree::command(args)
             .output()
-            .map_err(|err| Error::OSTree(err.to_string()))
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound => Error::OSTree("ostree not found on PATH".into()),
+                _ => Error::OSTree(err.to_string())
+            })
             .and_then(|output| if output.status.success() {
                 Ok(output)
             } else {
@@ -42,10 +51,133 @@ impl Ostree {
             })
     }
 
+    /// Run a long-running command such as a pull, killing it along with any
+    /// processes it started after the timeout.
+    fn run_timeout<S: AsRef<OsStr> + Debug>(args: &[S], timeout: Duration) -> Result<TimedOutput, Error> {
+        let output = run_with_timeout(&mut Ostree::command(args), timeout)?;
+        if output.code == Some(0) {
+            Ok(output)
+        } else {
+            Err(Error::OSTree(format!("stdout: {}\nstderr: {}", output.stdout, output.stderr)))
+        }
+    }
+
     fn hash(commit: &str) -> Result<String, Error> {
         let data = Vec::from_hex(commit)?;
         Ok(base64::encode(&data).replace('/', "_").trim_right_matches('=').into())
     }
+
+    /// List the configured remotes with `ostree remote list`.
+    pub fn list_remotes() -> Result<Vec<OstreeRemote>, Error> {
+        let output = Ostree::run(&["remote", "list", "--show-urls"])?;
+        OstreeRemote::parse_list(str::from_utf8(&output.stdout)?)
+    }
+
+    /// Add a remote with `ostree remote add --force`, atomically replacing
+    /// any existing remote with the same name.
+    pub fn add_remote(remote: &OstreeRemote) -> Result<(), Error> {
+        debug!("adding ostree remote: {}", remote.name);
+        Ostree::run(&remote.add_args()).map(|_| ())
+    }
+
+    /// Make the existing deployment of this commit the default again with
+    /// `ostree admin deploy`, failing when no deployment has the commit.
+    pub fn redeploy(commit: &str) -> Result<(), Error> {
+        let output = Ostree::run(&["admin", "status"])?;
+        let branches = OstreeBranch::parse("", "", str::from_utf8(&output.stdout)?)?;
+        if ! branches.iter().any(|branch| branch.package.commit == commit) {
+            return Err(Error::OSTree(format!("no deployment of commit {}", commit)));
+        }
+        debug!("redeploying ostree commit {}", commit);
+        Ostree::run(&["admin", "deploy", "--karg-proc-cmdline", commit]).map(|_| ())
+    }
+
+    /// Delete a remote with `ostree remote delete`.
+    pub fn delete_remote(name: &str) -> Result<(), Error> {
+        debug!("deleting ostree remote: {}", name);
+        Ostree::run(&["remote", "delete", "--", name]).map(|_| ())
+    }
+}
+
+
+/// A remote `OSTree` repository to pull commits from.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct OstreeRemote {
+    pub name:                 String,
+    pub url:                  String,
+    pub gpg_verify:           bool,
+    pub tls_ca_path:          Option<String>,
+    pub tls_client_cert_path: Option<String>,
+    pub tls_client_key_path:  Option<String>,
+}
+
+impl OstreeRemote {
+    /// Create a new remote with GPG verification and TLS settings disabled.
+    pub fn new(name: String, url: String) -> Self {
+        OstreeRemote { name: name, url: url, ..OstreeRemote::default() }
+    }
+
+    /// Create a new remote using the TLS certificates from the credentials.
+    pub fn from_credentials(name: String, url: String, creds: &Credentials) -> Self {
+        OstreeRemote {
+            tls_ca_path:          creds.ca_file.clone(),
+            tls_client_cert_path: creds.cert_file.clone(),
+            tls_client_key_path:  creds.pkey_file.clone(),
+            ..OstreeRemote::new(name, url)
+        }
+    }
+
+    /// Apply a `key=value` setting of `gpg-verify`, `tls-ca-path`,
+    /// `tls-client-cert-path` or `tls-client-key-path`.
+    pub fn set(&mut self, setting: &str) -> Result<(), Error> {
+        let mut split = setting.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some("gpg-verify"), Some(val)) => {
+                self.gpg_verify = val.parse().map_err(|_| Error::Parse(format!("invalid gpg-verify: {}", val)))?;
+            }
+            (Some("tls-ca-path"), Some(val))          => self.tls_ca_path = Some(val.into()),
+            (Some("tls-client-cert-path"), Some(val)) => self.tls_client_cert_path = Some(val.into()),
+            (Some("tls-client-key-path"), Some(val))  => self.tls_client_key_path = Some(val.into()),
+            _ => return Err(Error::Parse(format!("unknown ostree remote setting: {}", setting)))
+        }
+        Ok(())
+    }
+
+    /// The arguments to add or replace this remote with the `ostree` command.
+    pub fn add_args(&self) -> Vec<String> {
+        let mut args = vec!["remote".into(), "add".into(), "--force".into()];
+        if ! self.gpg_verify {
+            args.push("--no-gpg-verify".into());
+        }
+        if let Some(ref ca) = self.tls_ca_path {
+            args.push(format!("--set=tls-ca-path={}", ca));
+        }
+        if let Some(ref cert) = self.tls_client_cert_path {
+            args.push(format!("--set=tls-client-cert-path={}", cert));
+        }
+        if let Some(ref key) = self.tls_client_key_path {
+            args.push(format!("--set=tls-client-key-path={}", key));
+        }
+        args.push("--".into());
+        args.push(self.name.clone());
+        args.push(self.url.clone());
+        args
+    }
+
+    /// Parse the output from `ostree remote list --show-urls`.
+    fn parse_list(stdout: &str) -> Result<Vec<OstreeRemote>, Error> {
+        stdout.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let tokens = line.split_whitespace().collect::<Vec<_>>();
+                match tokens.len() {
+                    2 => Ok(OstreeRemote::new(tokens[0].into(), tokens[1].into())),
+                    _ => Err(Error::OSTree(format!("couldn't parse remote: {:?}", line)))
+                }
+            })
+            .collect()
+    }
 }
 
 
@@ -70,45 +202,71 @@ impl OstreePackage {
         }
     }
 
-    /// Convert from `TufMeta` into an `OstreePackage`.
+    /// Convert from `TufMeta` into an `OstreePackage`, pulling from the custom
+    /// `uri` of the target when set, otherwise from treehub.
     pub fn from_meta(mut meta: TufMeta, refname: String, hash_type: &str, treehub: &Url) -> Result<Self, Error> {
         match (meta.hashes.remove(hash_type), meta.custom) {
-            (Some(commit), Some(custom)) => match custom.ecuIdentifier {
-                Some(ecu) => Ok(OstreePackage::new(ecu, refname, commit, treehub)),
-                None => Err(Error::UptaneTargets(format!("{} missing ecuIdentifier", refname))),
+            (Some(commit), Some(custom)) => match (custom.ecuIdentifier, custom.uri) {
+                (Some(ecu), Some(uri)) => {
+                    let url = Self::pull_url(&refname, &uri)?;
+                    Ok(OstreePackage::new(ecu, refname, commit, &url))
+                }
+                (Some(ecu), None) => Ok(OstreePackage::new(ecu, refname, commit, treehub)),
+                (None, _) => Err(Error::UptaneTargets(format!("{} missing ecuIdentifier", refname))),
             },
             (None, _) => Err(Error::UptaneTargets(format!("{} missing {} hash", refname, hash_type))),
             (_, None) => Err(Error::UptaneTargets(format!("{} missing custom field", refname))),
         }
     }
 
+    /// Parse a custom target uri, accepting only http(s) urls.
+    fn pull_url(refname: &str, uri: &str) -> Result<Url, Error> {
+        let url = uri.parse::<Url>()
+            .map_err(|err| Error::UptaneTargets(format!("{} has an invalid uri `{}`: {}", refname, uri, err)))?;
+        match url.0.scheme() {
+            "http" | "https" => Ok(url),
+            scheme => Err(Error::UptaneTargets(format!("{} uri has unsupported scheme: {}", refname, scheme)))
+        }
+    }
+
     /// Convert the current `OstreePackage` into an `EcuVersion`.
     pub fn into_version(self, custom: Option<EcuCustom>) -> EcuVersion {
         let meta = TufMeta::from("sha256".into(), self.commit);
         EcuVersion::from(self.ecu_serial, TufImage { filepath: self.refName, fileinfo: meta }, custom)
     }
 
-    /// Install this package using the `ostree` command.
-    pub fn install(&self, creds: &Credentials) -> Result<InstallOutcome, Error> {
+    /// Install this package using the `ostree` command, killing each step
+    /// that runs longer than the timeout. The commit is checked to be
+    /// fetchable before anything is downloaded.
+    pub fn install(&self, creds: &Credentials, timeout: Duration) -> Result<InstallOutcome, Error> {
         debug!("installing ostree commit {}", self.commit);
         let from = Self::get_latest(&self.ecu_serial)?;
         if from.commit == self.commit {
             return Ok(InstallOutcome::empty(InstallCode::ALREADY_PROCESSED));
         }
-        self.get_delta(&*creds.client, &self.pullUri, &from.commit)
-            .and_then(|dir| Ostree::run(&["static-delta", "apply-offline", &dir]))
-            .or_else(|_| self.pull_commit(REMOTE_NAME, creds))
-            .map(|_| ())?;
-
-        let output = Ostree::run(&["admin", "deploy", "--karg-proc-cmdline", &self.commit])?;
-        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
-        if output.status.success() {
+        creds.meter.allow_download()?;
+        self.add_remote(REMOTE_NAME, creds)?;
+        self.check_commit(creds, timeout)?;
+        let delta = if self.is_treehub(creds) {
+            self.get_delta(creds, &self.pullUri, &from.commit)
+        } else {
+            Err(Error::OSTree("static deltas are only fetched from treehub".into()))
+        };
+        delta
+            .and_then(|dir| Ostree::run_timeout(&["static-delta", "apply-offline", &dir], timeout).map(|_| ()))
+            .or_else(|_| self.pull_commit(REMOTE_NAME, creds, timeout).map(|output| {
+                if let Some(bytes) = output.stdout.lines().filter_map(transferred_bytes).last() {
+                    creds.meter.add(bytes);
+                }
+            }))?;
+
+        let output = run_with_timeout(&mut Ostree::command(&["admin", "deploy", "--karg-proc-cmdline", &self.commit]), timeout)?;
+        if output.code == Some(0) {
             Util::write_file(NEW_PACKAGE, &json::to_vec(self)?)
                 .unwrap_or_else(|err| error!("couldn't save package info: {}", err));
-            Ok(InstallOutcome::new(InstallCode::OK, stdout, stderr))
+            Ok(InstallOutcome::new(InstallCode::OK, output.stdout, output.stderr))
         } else {
-            Ok(InstallOutcome::new(InstallCode::INSTALL_FAILED, stdout, stderr))
+            Ok(InstallOutcome::new(InstallCode::INSTALL_FAILED, output.stdout, output.stderr))
         }
     }
 
@@ -140,16 +298,17 @@ impl OstreePackage {
     }
 
     /// Extract a static delta between two commits (if it exists) and return the path.
-    pub fn get_delta(&self, client: &Client, server: &str, current_commit: &str) -> Result<String, Error> {
+    pub fn get_delta(&self, creds: &Credentials, server: &str, current_commit: &str) -> Result<String, Error> {
         debug!("getting a static delta from {}", current_commit);
         let (current, next)  = (Ostree::hash(current_commit)?, Ostree::hash(&self.commit)?);
         let (prefix, suffix) = current.split_at(2);
         let url = format!("{}/deltas/{}/{}-{}/apply-offline.tar", server, prefix, suffix, next);
-        let data = match client.get(url.parse()?, None).recv().expect("get_delta") {
+        let data = match creds.client.get(url.parse()?, None).recv().expect("get_delta") {
             Response::Success(data) => Ok(data),
             Response::Failed(data)  => Err(data.into()),
             Response::Error(err)    => Err(*err)
         }?;
+        creds.meter.add(data.body.len() as u64);
 
         let tar = format!("/tmp/sota-delta-{}-{}.tar", current_commit, self.commit);
         let mut file = File::create(&tar)?;
@@ -158,35 +317,74 @@ impl OstreePackage {
         Ok(format!("/tmp/sota-delta/{}/{}-{}", prefix, suffix, next))
     }
 
-    /// Pull a commit from a remote repository with `ostree pull`.
-    pub fn pull_commit(&self, remote: &str, creds: &Credentials) -> Result<Output, Error> {
-        let _ = self.add_remote(remote, creds)?;
+    /// Pull a commit with `ostree pull` from a remote added with `add_remote`.
+    pub fn pull_commit(&self, remote: &str, creds: &Credentials, timeout: Duration) -> Result<TimedOutput, Error> {
         debug!("pulling from ostree remote: {}", remote);
+        Ostree::run_timeout(&self.pull_args(remote, creds, false), timeout)
+    }
+
+    /// Check the commit can be fetched from the remote added with `add_remote`
+    /// by pulling only its metadata, so a missing commit fails before
+    /// anything is deployed.
+    pub fn check_commit(&self, creds: &Credentials, timeout: Duration) -> Result<(), Error> {
+        self.check_commit_with(creds, |args| Ostree::run_timeout(args, timeout))
+    }
 
-        let mut args = vec!["pull".into(), remote.into()];
+    /// Check the commit is fetchable, running the metadata-only pull with `run`.
+    fn check_commit_with<F>(&self, creds: &Credentials, run: F) -> Result<(), Error>
+        where F: FnOnce(&[String]) -> Result<TimedOutput, Error>
+    {
+        debug!("checking ostree commit {} is fetchable", self.commit);
+        run(&self.pull_args(REMOTE_NAME, creds, true))
+            .map(|_| ())
+            .map_err(|err| {
+                let reason = match err {
+                    Error::OSTree(reason) => reason,
+                    err => err.to_string()
+                };
+                Error::OSTree(format!("commit {} not fetchable from {}: {}", self.commit, self.pullUri, reason))
+            })
+    }
+
+    /// The arguments to pull this commit with the `ostree` command, fetching
+    /// only the commit metadata when `metadata_only` is set.
+    pub fn pull_args(&self, remote: &str, creds: &Credentials, metadata_only: bool) -> Vec<String> {
+        let mut args = vec!["pull".into()];
+        if metadata_only {
+            args.push("--commit-metadata-only".into());
+        }
+        args.push(remote.into());
         if let Some(ref token) = creds.token {
-            args.push(format!("--http-header='Authorization=Bearer {}'", token));
+            if self.is_treehub(creds) {
+                args.push(format!("--http-header='Authorization=Bearer {}'", token));
+            }
         }
         args.push(self.commit.clone());
-        Ostree::run(&args)
+        args
     }
 
-    /// Add a remote repository with `ostree remote add`.
-    pub fn add_remote(&self, remote: &str, creds: &Credentials) -> Result<Output, Error> {
-        let _ = Ostree::run(&["remote", "delete", remote]);
-        debug!("adding ostree remote: {}", remote);
+    /// Add a remote repository with `ostree remote add`, using the TLS
+    /// certificates only when pulling from treehub.
+    pub fn add_remote(&self, remote: &str, creds: &Credentials) -> Result<(), Error> {
+        Ostree::add_remote(&self.remote(remote, creds))
+    }
 
-        let mut args = vec!["remote".into(), "add".into(), "--no-gpg-verify".into()];
-        if let Some(ref ca) = creds.ca_file {
-            args.push(format!("--set=tls-ca-path={}", ca));
+    /// The remote to pull this package from.
+    fn remote(&self, remote: &str, creds: &Credentials) -> OstreeRemote {
+        if self.is_treehub(creds) {
+            OstreeRemote::from_credentials(remote.into(), self.pullUri.clone(), creds)
+        } else {
+            OstreeRemote::new(remote.into(), self.pullUri.clone())
         }
-        if let Some(ref pkey) = creds.pkey_file {
-            args.push(format!("--set=tls-client-cert-path={}", pkey));
-            args.push(format!("--set=tls-client-key-path={}", pkey));
+    }
+
+    /// Returns true when the package is pulled from the treehub server that
+    /// the credentials are for. Any other host is pulled from anonymously.
+    fn is_treehub(&self, creds: &Credentials) -> bool {
+        match (creds.treehub.as_ref(), self.pullUri.parse::<Url>()) {
+            (Some(treehub), Ok(url)) => treehub.0.origin() == url.0.origin(),
+            _ => false
         }
-        args.push(remote.into());
-        args.push(self.pullUri.clone());
-        Ostree::run(&args)
     }
 }
 
@@ -230,9 +428,38 @@ impl OstreeBranch {
 }
 
 
+/// Parse the bytes fetched from the `... transferred in N seconds` summary
+/// line printed by `ostree pull`.
+fn transferred_bytes(line: &str) -> Option<u64> {
+    let summary = line.rsplit(';').next().unwrap_or("");
+    let words = summary.split_whitespace().collect::<Vec<_>>();
+    if words.len() < 3 || words[2] != "transferred" {
+        return None;
+    }
+    let amount = match words[0].parse::<f64>() {
+        Ok(amount) => amount,
+        Err(_) => return None
+    };
+    let scale = match words[1] {
+        "B" | "bytes" => 1u64,
+        "kB" | "KB"   => 1000,
+        "KiB"         => 1024,
+        "MB"          => 1000 * 1000,
+        "MiB"         => 1024 * 1024,
+        "GB"          => 1000 * 1000 * 1000,
+        "GiB"         => 1024 * 1024 * 1024,
+        _ => return None
+    };
+    Some((amount * scale as f64) as u64)
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bandwidth::DownloadMeter;
+    use datatype::TufCustom;
+    use http::TestClient;
 
 
     const OSTREE_ADMIN_STATUS: &'static str = r#"
@@ -254,4 +481,119 @@ mod tests {
         assert_eq!(branches[1].package.commit, "ce19c41036cc45e49b0cecf6b157523c2105c4de1c");
         assert_eq!(branches[1].package.refName, "<branch>-ce19c41036cc45e49b0cecf6b157523c2105c4de1c");
     }
+
+    #[test]
+    fn package_from_meta_uri() {
+        let treehub = "https://treehub.local/api/v2".parse::<Url>().unwrap();
+        let meta = |uri: Option<&str>| {
+            let mut meta = TufMeta::from("sha256".into(), "abc".into());
+            meta.custom = Some(TufCustom { ecuIdentifier: Some("serial".into()), uri: uri.map(String::from) });
+            meta
+        };
+
+        let pkg = OstreePackage::from_meta(meta(None), "ref".into(), "sha256", &treehub).expect("treehub package");
+        assert_eq!(pkg.pullUri, "https://treehub.local/api/v2");
+        let pkg = OstreePackage::from_meta(meta(Some("https://cdn.local/ostree")), "ref".into(), "sha256", &treehub).expect("cdn package");
+        assert_eq!(pkg.pullUri, "https://cdn.local/ostree");
+        assert_eq!(pkg.commit, "abc");
+        for uri in &["file:///var/ostree", "not a uri"] {
+            match OstreePackage::from_meta(meta(Some(uri)), "ref".into(), "sha256", &treehub) {
+                Err(Error::UptaneTargets(_)) => (),
+                other => panic!("expected UptaneTargets error for {}, got {:?}", uri, other)
+            }
+        }
+    }
+
+    fn credentials(token: Option<&str>) -> Credentials {
+        Credentials {
+            client:    Box::new(TestClient::default()),
+            token:     token.map(String::from),
+            ca_file:   None,
+            cert_file: None,
+            pkey_file: None,
+            treehub:   Some("https://treehub.local/api/v2".parse().unwrap()),
+            meter:     DownloadMeter::default(),
+        }
+    }
+
+    #[test]
+    fn pull_transferred_bytes() {
+        let line = "3 metadata, 12 content objects fetched; 1.5 MB transferred in 2 seconds";
+        assert_eq!(transferred_bytes(line), Some(1_500_000));
+        assert_eq!(transferred_bytes("2 metadata, 0 content objects fetched; 569 bytes transferred in 0 seconds"), Some(569));
+        assert_eq!(transferred_bytes("1 metadata, 4 content objects fetched; 12 KiB transferred in 1 seconds"), Some(12 * 1024));
+        assert_eq!(transferred_bytes("Receiving objects: 50% (6/12) 1.2 MB"), None);
+    }
+
+    #[test]
+    fn commit_pull_args() {
+        let pkg = OstreePackage::new("serial".into(), "ref".into(), "abc".into(), &"https://treehub.local".parse().unwrap());
+        assert_eq!(pkg.pull_args("sota-remote", &credentials(None), false), vec!["pull", "sota-remote", "abc"]);
+        assert_eq!(pkg.pull_args("sota-remote", &credentials(Some("t0k3n")), true), vec![
+            "pull", "--commit-metadata-only", "sota-remote", "--http-header='Authorization=Bearer t0k3n'", "abc",
+        ]);
+
+        let cdn = OstreePackage::new("serial".into(), "ref".into(), "abc".into(), &"https://cdn.local/ostree".parse().unwrap());
+        assert_eq!(cdn.pull_args("sota-remote", &credentials(Some("t0k3n")), false), vec!["pull", "sota-remote", "abc"]);
+    }
+
+    #[test]
+    fn remote_credentials() {
+        let mut creds = credentials(Some("t0k3n"));
+        creds.ca_file = Some("/etc/ca.pem".into());
+        creds.cert_file = Some("/etc/device.crt".into());
+        creds.pkey_file = Some("/etc/device.pem".into());
+
+        let pkg = OstreePackage::new("serial".into(), "ref".into(), "abc".into(), &"https://treehub.local/api/v2".parse().unwrap());
+        let remote = pkg.remote("sota-remote", &creds);
+        assert_eq!(remote.tls_client_cert_path, Some("/etc/device.crt".into()));
+        assert_eq!(remote.tls_client_key_path, Some("/etc/device.pem".into()));
+
+        let cdn = OstreePackage::new("serial".into(), "ref".into(), "abc".into(), &"https://cdn.local/ostree".parse().unwrap());
+        assert_eq!(cdn.remote("sota-remote", &creds), OstreeRemote::new("sota-remote".into(), "https://cdn.local/ostree".into()));
+    }
+
+    #[test]
+    fn check_commit_not_found() {
+        let pkg = OstreePackage::new("serial".into(), "ref".into(), "abc".into(), &"https://cdn.local/ostree".parse().unwrap());
+        let result = pkg.check_commit_with(&credentials(None), |args| {
+            assert_eq!(args.to_vec(), vec!["pull", "--commit-metadata-only", "sota-remote", "abc"]);
+            Err(Error::OSTree("error: Commit abc not found".into()))
+        });
+        match result {
+            Err(Error::OSTree(err)) => assert_eq!(err, "commit abc not fetchable from https://cdn.local/ostree: error: Commit abc not found"),
+            other => panic!("expected OSTree error, got {:?}", other)
+        }
+
+        let found = TimedOutput { code: Some(0), stdout: "".into(), stderr: "".into(), timed_out: false };
+        assert!(pkg.check_commit_with(&credentials(None), |_| Ok(found)).is_ok());
+    }
+
+    #[test]
+    fn parse_remotes() {
+        let stdout = "sota-remote  https://treehub.local/api/v2\nbackup       https://backup.local/treehub\n";
+        let remotes = OstreeRemote::parse_list(stdout).expect("couldn't parse remotes");
+        assert_eq!(remotes, vec![
+            OstreeRemote::new("sota-remote".into(), "https://treehub.local/api/v2".into()),
+            OstreeRemote::new("backup".into(), "https://backup.local/treehub".into()),
+        ]);
+        assert!(OstreeRemote::parse_list("no-url").is_err());
+    }
+
+    #[test]
+    fn remote_add_args() {
+        let mut remote = OstreeRemote::new("treehub".into(), "https://treehub.local".into());
+        assert_eq!(remote.add_args(), vec!["remote", "add", "--force", "--no-gpg-verify", "--", "treehub", "https://treehub.local"]);
+
+        remote.set("gpg-verify=true").unwrap();
+        remote.set("tls-ca-path=/etc/ca.pem").unwrap();
+        remote.set("tls-client-key-path=/etc/device.pem").unwrap();
+        assert!(remote.set("tls-unknown=/etc/other.pem").is_err());
+        assert_eq!(remote.add_args(), vec![
+            "remote", "add", "--force",
+            "--set=tls-ca-path=/etc/ca.pem",
+            "--set=tls-client-key-path=/etc/device.pem",
+            "--", "treehub", "https://treehub.local",
+        ]);
+    }
 }
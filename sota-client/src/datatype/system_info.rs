@@ -0,0 +1,179 @@
+use json;
+use serde::de::{Deserialize, Deserializer, Error as SerdeError};
+use serde::ser::{Serialize, Serializer};
+use std::path::Path;
+use std::process::Command as ShellCommand;
+use std::str::FromStr;
+
+use datatype::{Error, Util};
+
+
+/// The sources used to generate a system information report.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SystemInfo {
+    /// The output of a single command is sent unchanged.
+    Command(String),
+    /// The JSON output of each command is merged into a single document,
+    /// keyed by the name of each command.
+    Commands(Vec<String>),
+    /// The contents of a static JSON file.
+    File(String),
+}
+
+impl SystemInfo {
+    /// Create from a list of sources, where a single source ending in `.json`
+    /// is read as a static file.
+    pub fn from_sources(mut sources: Vec<String>) -> Result<SystemInfo, Error> {
+        match sources.len() {
+            0 => Err(Error::Config("system_info needs at least one source".into())),
+            1 => sources.pop().expect("single source").parse(),
+            _ => Ok(SystemInfo::Commands(sources))
+        }
+    }
+
+    /// Generate a new system information report.
+    pub fn report(&self) -> Result<String, Error> {
+        match *self {
+            SystemInfo::Command(ref cmd) => run_command(cmd),
+
+            SystemInfo::Commands(ref cmds) => {
+                let mut merged = json::Map::new();
+                for cmd in cmds {
+                    let output = run_command(cmd)?;
+                    let value = json::from_str::<json::Value>(&output)
+                        .map_err(|err| Error::SystemInfo(format!("{} didn't output valid JSON: {}", cmd, err)))?;
+                    let name = command_name(cmd);
+                    let key = if merged.contains_key(&name) { cmd.clone() } else { name };
+                    let _ = merged.insert(key, value);
+                }
+                Ok(json::to_string(&merged)?)
+            }
+
+            SystemInfo::File(ref path) => {
+                let text = Util::read_text(path)
+                    .map_err(|err| Error::SystemInfo(format!("{}: {}", path, err)))?;
+                let _ = json::from_str::<json::Value>(&text)
+                    .map_err(|err| Error::SystemInfo(format!("{} isn't valid JSON: {}", path, err)))?;
+                Ok(text)
+            }
+        }
+    }
+}
+
+impl FromStr for SystemInfo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SystemInfo, Error> {
+        if s.is_empty() {
+            Err(Error::Config("empty system_info source".into()))
+        } else if s.ends_with(".json") {
+            Ok(SystemInfo::File(s.into()))
+        } else {
+            Ok(SystemInfo::Command(s.into()))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SystemInfo {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<SystemInfo, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Sources {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        let sources = match Sources::deserialize(de)? {
+            Sources::One(source)   => vec![source],
+            Sources::Many(sources) => sources,
+        };
+        SystemInfo::from_sources(sources).map_err(|err| SerdeError::custom(format!("invalid system_info: {}", err)))
+    }
+}
+
+impl Serialize for SystemInfo {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            SystemInfo::Command(ref cmd)   => ser.serialize_str(cmd),
+            SystemInfo::Commands(ref cmds) => cmds.serialize(ser),
+            SystemInfo::File(ref path)     => ser.serialize_str(path),
+        }
+    }
+}
+
+
+/// Run a command, returning its UTF-8 output.
+fn run_command(cmd: &str) -> Result<String, Error> {
+    let output = ShellCommand::new(cmd)
+        .output()
+        .map_err(|err| Error::SystemInfo(format!("{}: {}", cmd, err)))?;
+    if ! output.status.success() {
+        warn!("system info command {} exited with {}", cmd, output.status);
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|err| Error::SystemInfo(format!("{} didn't output valid UTF-8: {}", cmd, err)))
+}
+
+/// Returns the file name of a command's path.
+fn command_name(cmd: &str) -> String {
+    Path::new(cmd)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(cmd)
+        .into()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use pacman::test::TestDir;
+
+
+    fn script(dir: &str, name: &str, body: &str) -> String {
+        let path = format!("{}/{}", dir, name);
+        let mut file = OpenOptions::new().create(true).write(true).mode(0o755).open(&path).unwrap();
+        writeln!(&mut file, "#!/bin/sh\n{}", body).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_parse_sources() {
+        assert_eq!("/usr/bin/lshw".parse::<SystemInfo>().unwrap(), SystemInfo::Command("/usr/bin/lshw".into()));
+        assert_eq!("/etc/info.json".parse::<SystemInfo>().unwrap(), SystemInfo::File("/etc/info.json".into()));
+        assert!(SystemInfo::from_sources(Vec::new()).is_err());
+        assert_eq!(SystemInfo::from_sources(vec!["a".into(), "b".into()]).unwrap(),
+                   SystemInfo::Commands(vec!["a".into(), "b".into()]));
+    }
+
+    #[test]
+    fn test_merge_commands() {
+        let dir = TestDir::new("sota-system-info");
+        let hw = script(&dir.0, "hw", r#"echo '{"cpus": 4}'"#);
+        let custom = script(&dir.0, "custom", r#"echo '["a", "b"]'"#);
+        let merged = SystemInfo::Commands(vec![hw, custom.clone()]).report().unwrap();
+        let expected = json::from_str::<json::Value>(r#"{"hw": {"cpus": 4}, "custom": ["a", "b"]}"#).unwrap();
+        assert_eq!(json::from_str::<json::Value>(&merged).unwrap(), expected);
+
+        let broken = script(&dir.0, "broken", "echo 'not json'");
+        match SystemInfo::Commands(vec![custom, broken.clone()]).report() {
+            Err(Error::SystemInfo(err)) => assert!(err.contains(&broken)),
+            other => panic!("expected SystemInfo error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_static_file() {
+        let dir = TestDir::new("sota-system-info-file");
+        let path = format!("{}/info.json", dir.0);
+        Util::write_file(&path, br#"{"model": "test"}"#).unwrap();
+        assert_eq!(SystemInfo::File(path.clone()).report().unwrap(), r#"{"model": "test"}"#);
+        fs::remove_file(&path).unwrap();
+        Util::write_file(&path, b"model: test").unwrap();
+        assert!(SystemInfo::File(path).report().is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use base64;
 use chrono::{DateTime, Utc};
 use crypto::digest::Digest;
-use crypto::sha2::Sha256;
+use crypto::sha2::{Sha256, Sha512};
 use json;
 use pem;
 use serde::de::{Deserialize, Deserializer, Error as SerdeError};
@@ -26,15 +26,19 @@ pub struct TufImage {
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct TufMeta {
+    #[serde(default)]
     pub length: u64,
+    #[serde(default)]
     pub hashes: HashMap<String, String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub custom: Option<TufCustom>,
 }
 
 impl TufMeta {
     pub fn from(hash_type: String, commit: String) -> Self {
-        TufMeta { length: 0, hashes: hashmap!{ hash_type => commit }, custom: None }
+        TufMeta { length: 0, hashes: hashmap!{ hash_type => commit }, version: None, custom: None }
     }
 }
 
@@ -102,7 +106,12 @@ pub struct RoleData {
 
 impl RoleData {
     pub fn expired(&self) -> bool {
-        self.expires < Utc::now()
+        self.expired_at(Utc::now())
+    }
+
+    /// Whether the metadata had expired at the given time.
+    pub fn expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires < now
     }
 }
 
@@ -122,6 +131,16 @@ pub struct Key {
 }
 
 impl Key {
+    /// Create a key from the text of a public key file, which is either a PEM
+    /// encoded RSA key or a hex encoded Ed25519 key.
+    pub fn from_public(text: String) -> Self {
+        if text.trim_left().starts_with("-----BEGIN") {
+            Key { keytype: KeyType::Rsa, keyval: KeyValue { public: text } }
+        } else {
+            Key { keytype: KeyType::Ed25519, keyval: KeyValue { public: text.trim().to_string() } }
+        }
+    }
+
     pub fn key_id(&self) -> Result<String, Error> {
         let mut hasher = Sha256::new();
         match self.keytype {
@@ -184,6 +203,62 @@ impl FromStr for KeyType {
 }
 
 
+/// How the key id of the primary ECU's signing key is derived.
+#[derive(Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum KeyIdDigest {
+    /// SHA256 of the public key file.
+    Sha256,
+    /// SHA512 of the public key file.
+    Sha512,
+    /// TUF-style key id of the PEM-encoded RSA public key.
+    Tuf,
+}
+
+impl KeyIdDigest {
+    /// Derive the key id from the contents of the public key file.
+    pub fn key_id(&self, pub_key: &[u8]) -> Result<String, Error> {
+        match *self {
+            KeyIdDigest::Sha256 => {
+                let mut hasher = Sha256::new();
+                hasher.input(pub_key);
+                Ok(hasher.result_str())
+            }
+
+            KeyIdDigest::Sha512 => {
+                let mut hasher = Sha512::new();
+                hasher.input(pub_key);
+                Ok(hasher.result_str())
+            }
+
+            KeyIdDigest::Tuf => {
+                let public = String::from_utf8(pub_key.to_vec())?;
+                Key { keytype: KeyType::Rsa, keyval: KeyValue { public: public } }.key_id()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyIdDigest {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let s: String = Deserialize::deserialize(de)?;
+        s.parse().map_err(|err| SerdeError::custom(format!("unknown KeyIdDigest: {}", err)))
+    }
+}
+
+impl FromStr for KeyIdDigest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "sha256" => Ok(KeyIdDigest::Sha256),
+            "sha512" => Ok(KeyIdDigest::Sha512),
+            "tuf"    => Ok(KeyIdDigest::Tuf),
+            _        => Err(Error::Parse(format!("unknown key id digest: {}", s)))
+        }
+    }
+}
+
+
 pub type Manifests = HashMap<String, TufSigned>;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -214,6 +289,25 @@ impl EcuVersion {
             timeserver_time: "1970-01-01T00:00:00Z".into(),
         }
     }
+
+    /// Set the current and previous times attested by a timeserver, keeping
+    /// the defaults when no signed time has been received.
+    pub fn set_timeserver_times(&mut self, time: Option<DateTime<Utc>>, previous: Option<DateTime<Utc>>) {
+        let format = |time: DateTime<Utc>| time.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        if let Some(time) = time {
+            self.timeserver_time = format(time);
+        }
+        if let Some(previous) = previous {
+            self.previous_timeserver_time = format(previous);
+        }
+    }
+}
+
+/// The current time signed by a timeserver, echoing the nonce sent in the request.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SignedTime {
+    pub time:  DateTime<Utc>,
+    pub nonce: String,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -226,3 +320,34 @@ impl EcuCustom {
         EcuCustom { operation_result }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datatype::Util;
+
+
+    #[test]
+    fn test_key_id_digests() {
+        let pub_key = Util::read_file("tests/keys/rsa.pub").expect("read rsa.pub");
+        assert_eq!(KeyIdDigest::Sha256.key_id(&pub_key).unwrap(),
+                   "596e3ac59bd39884b6e686ce497944eae22a528d396e54de48b783b6e24ab219");
+        assert_eq!(KeyIdDigest::Sha512.key_id(&pub_key).unwrap(),
+                   "a35834d7c1f99306a8506794e839cf755dcbd87b05f719c743421d7a092ff0da\
+                    43b664e95ccce62a6fb0f1be574e7122f6650c4b55812eab9cf0317ccd156c93");
+        assert_eq!(KeyIdDigest::Tuf.key_id(&pub_key).unwrap(),
+                   "e627606365ae399187d3d68d24fa70d6f4f11d4c0c81ada110b831527901c4f2");
+    }
+
+    #[test]
+    fn test_key_from_public() {
+        let rsa = Key::from_public(Util::read_text("tests/keys/rsa.pub").expect("read rsa.pub"));
+        assert_eq!(rsa.keytype, KeyType::Rsa);
+        assert_eq!(rsa.key_id().unwrap(), "e627606365ae399187d3d68d24fa70d6f4f11d4c0c81ada110b831527901c4f2");
+
+        let ed25519 = Key::from_public("  abcdef0123\n".into());
+        assert_eq!(ed25519.keytype, KeyType::Ed25519);
+        assert_eq!(ed25519.keyval.public, "abcdef0123");
+    }
+}
@@ -1,5 +1,9 @@
+use libc;
+use pem;
+use std::ffi::CString;
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, Read, Write};
+use std::io::{self, BufReader, Read, Write};
+use std::mem;
 use std::path::Path;
 
 use datatype::Error;
@@ -18,6 +22,11 @@ impl Util {
         Ok(buf)
     }
 
+    /// Read the DER-encoded contents of a PEM file.
+    pub fn read_pem(path: &str) -> Result<Vec<u8>, Error> {
+        Ok(pem::parse(Util::read_file(path)?)?.contents)
+    }
+
     pub fn read_text(path: &str) -> Result<String, Error> {
         trace!("reading text from file: {}", path);
         let mut file = BufReader::new(File::open(path)
@@ -45,4 +54,53 @@ impl Util {
         file.flush()?;
         Ok(())
     }
+
+    /// Returns the number of bytes available on the filesystem containing `path`.
+    pub fn free_space(path: &str) -> Result<u64, Error> {
+        let cpath = CString::new(path).map_err(|err| Error::Client(format!("invalid path {}: {}", path, err)))?;
+        let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+        if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+
+    /// Fails unless `dir` is an existing directory that files can be created in.
+    pub fn check_writable(dir: &str) -> Result<(), Error> {
+        if ! Path::new(dir).is_dir() {
+            return Err(Error::Client(format!("{} is not a directory", dir)));
+        }
+        let probe = format!("{}/.sota-write-check", dir);
+        File::create(&probe).map_err(|err| Error::Client(format!("couldn't write to {}: {}", dir, err)))?;
+        fs::remove_file(&probe)?;
+        Ok(())
+    }
+
+    /// Fails with `Error::DiskSpace` unless `size` bytes plus the required
+    /// headroom fit on the filesystem containing `path`.
+    pub fn check_space(path: &str, size: u64, headroom: u64) -> Result<(), Error> {
+        let free = Util::free_space(path)?;
+        if free < size.saturating_add(headroom) {
+            Err(Error::DiskSpace)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_check_space() {
+        assert!(Util::free_space("/tmp").unwrap() > 0);
+        assert!(Util::check_space("/tmp", 1, 0).is_ok());
+        match Util::check_space("/tmp", u64::max_value(), 0) {
+            Err(Error::DiskSpace) => (),
+            other => panic!("expected DiskSpace, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,69 @@
+use chan::{self, Sender};
+use std::collections::HashSet;
+use std::thread;
+
+use datatype::Event;
+use interpreter::CommandExec;
+
+
+/// A `CommandFilter` only forwards the commands on a gateway's allow-list for
+/// processing, rejecting the rest with an `Event::CommandRejected`.
+pub struct CommandFilter {
+    pub gateway: String,
+    pub allowed: HashSet<String>,
+}
+
+impl CommandFilter {
+    pub fn new(gateway: &str, allowed: &[String]) -> Self {
+        CommandFilter { gateway: gateway.into(), allowed: allowed.iter().cloned().collect() }
+    }
+
+    /// Returns a sender for the gateway to use in place of `ctx` that only
+    /// forwards the allowed commands.
+    pub fn wrap(self, ctx: Sender<CommandExec>, etx: Sender<Event>) -> Sender<CommandExec> {
+        let (ftx, frx) = chan::sync::<CommandExec>(0);
+        thread::spawn(move || for exec in frx.iter() {
+            self.forward(exec, &ctx, &etx)
+        });
+        ftx
+    }
+
+    fn forward(&self, exec: CommandExec, ctx: &Sender<CommandExec>, etx: &Sender<Event>) {
+        let name = exec.cmd.name();
+        if self.allowed.contains(name) {
+            ctx.send(exec);
+        } else {
+            warn!("{} gateway not allowed to send command: {}", self.gateway, name);
+            let event = Event::CommandRejected(name.into());
+            if let Some(reply) = exec.etx {
+                reply.send(event.clone());
+            }
+            etx.send(event);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    use datatype::{Command, GatewayConfig};
+
+
+    #[test]
+    fn reject_disallowed_commands() {
+        let (ctx, crx) = chan::async::<CommandExec>();
+        let (etx, erx) = chan::async::<Event>();
+        let filtered = CommandFilter::new("http", &GatewayConfig::network_commands()).wrap(ctx, etx);
+
+        let (reply_tx, reply_rx) = chan::async::<Event>();
+        filtered.send(CommandExec { cmd: Command::Shutdown, etx: Some(reply_tx) });
+        assert_eq!(reply_rx.recv(), Some(Event::CommandRejected("Shutdown".into())));
+        assert_eq!(erx.recv(), Some(Event::CommandRejected("Shutdown".into())));
+
+        filtered.send(CommandExec { cmd: Command::StartInstall(Uuid::default()), etx: None });
+        assert_eq!(crx.recv().map(|exec| exec.cmd), Some(Command::StartInstall(Uuid::default())));
+    }
+}
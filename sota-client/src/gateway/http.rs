@@ -1,26 +1,31 @@
 use chan::{self, Sender, Receiver};
 use hyper::header::ContentType;
-use hyper::mime::{Mime, SubLevel, TopLevel};
+use hyper::method::Method;
+use hyper::mime::{Attr, Mime, SubLevel, TopLevel, Value};
 use hyper::server::{Handler, Server, Request as HyperRequest, Response as HyperResponse};
 use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
 use json;
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 use std::thread;
 
 use datatype::Event;
 use gateway::Gateway;
 use interpreter::CommandExec;
+use metrics::Metrics;
 
 
-/// The `Http` gateway parses `Command`s from the body of incoming requests.
+/// The `Http` gateway parses `Command`s from the body of incoming requests,
+/// and serves the device metrics to `GET /metrics` requests.
 pub struct Http {
-    pub server: SocketAddrV4
+    pub server: SocketAddr
 }
 
 impl Gateway for Http {
     fn start(&mut self, ctx: Sender<CommandExec>, _: Receiver<Event>) {
         info!("Starting HTTP gateway at http://{}", self.server);
         let server = Server::http(&self.server).expect("http gateway");
+        Metrics::enable();
         thread::spawn(move || server.handle(HttpHandler { ctx: ctx.clone() }).expect("serve http"));
     }
 }
@@ -32,6 +37,12 @@ struct HttpHandler {
 
 impl Handler for HttpHandler {
     fn handle(&self, req: HyperRequest, mut resp: HyperResponse) {
+        if req.method == Method::Get && req.uri == RequestUri::AbsolutePath("/metrics".into()) {
+            let version = (Attr::Ext("version".into()), Value::Ext("0.0.4".into()));
+            resp.headers_mut().set(ContentType(Mime(TopLevel::Text, SubLevel::Plain, vec![version])));
+            return resp.send(Metrics::export().as_bytes()).expect("couldn't send HTTP response");
+        }
+
         let mut body = Vec::new();
         json::from_reader(req)
             .map(|cmd| {
@@ -53,14 +64,39 @@ impl Handler for HttpHandler {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::Utc;
     use crossbeam;
     use std::time::Duration;
     use uuid::Uuid;
 
-    use datatype::Command;
+    use datatype::{Command, InstallCode, InstallResult};
     use http::{AuthClient, Client, Response, TlsClient, TlsData};
 
 
+    #[test]
+    fn http_metrics() {
+        TlsClient::init(TlsData::default());
+        let (ctx, _crx) = chan::sync::<CommandExec>(0);
+        let (_etx, erx) = chan::sync::<Event>(0);
+        Http { server: "127.0.0.1:8889".parse().unwrap() }.start(ctx, erx);
+        thread::sleep(Duration::from_millis(100)); // wait before connecting
+
+        let result = InstallResult::new("id".into(), InstallCode::OK, "".into());
+        Metrics::update(|metrics| metrics.observe(&Event::InstallComplete(result), Utc::now()));
+        let rx = AuthClient::default().get("http://127.0.0.1:8889/metrics".parse().unwrap(), None);
+        let body = match rx.recv().expect("http resp") {
+            Response::Success(data) => String::from_utf8(data.body).expect("utf8 body"),
+            Response::Failed(data)  => panic!("failed response: {}", data),
+            Response::Error(err)    => panic!("error response: {}", err)
+        };
+        let installed = body.lines()
+            .find(|line| line.starts_with("sota_installs_total{result=\"OK\"}"))
+            .and_then(|line| line.split_whitespace().last())
+            .and_then(|count| count.parse::<u64>().ok())
+            .expect("install counter");
+        assert!(installed >= 1);
+    }
+
     #[test]
     fn http_connections() {
         TlsClient::init(TlsData::default());
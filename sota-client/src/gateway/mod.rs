@@ -1,6 +1,7 @@
 pub mod console;
 #[cfg(feature = "rvi")]
 pub mod dbus;
+pub mod filter;
 pub mod http;
 #[cfg(feature = "socket")]
 pub mod socket;
@@ -10,6 +11,7 @@ pub mod websocket;
 pub use self::console::Console;
 #[cfg(feature = "rvi")]
 pub use self::dbus::DBus;
+pub use self::filter::CommandFilter;
 pub use self::http::Http;
 #[cfg(feature = "socket")]
 pub use self::socket::Socket;
@@ -1,66 +1,193 @@
-use chan::Sender;
+use chan::{self, Sender, Receiver};
 use hyper::client::{Body, Client as HyperClient, ProxyConfig, RedirectPolicy,
                     Response as HyperResponse};
-use hyper::header::{Authorization, Basic, Bearer, Connection, ContentLength,
-                    ContentType, Headers, Location, UserAgent};
+use hyper::client::pool::{Config as PoolConfig, Pool};
+use hyper::header::{Authorization, Basic, Bearer, Connection, ContentLength, ContentType,
+                    Encoding, Headers, Location, ProxyAuthorization, TransferEncoding};
 use hyper::mime::{Attr, Mime, TopLevel, SubLevel, Value};
 use hyper::net::{HttpConnector, HttpsConnector};
 use hyper::status::StatusCode;
-use std::{env, str};
+use std::collections::HashMap;
+use std::str;
 use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 use time;
 
-use datatype::{Auth, Error};
-use http::{Client, Request, Response, ResponseData, TlsClient};
-use url::Url;
+use datatype::{Auth, Error, Url};
+use http::{BasicAuth, Client, Proxy, Redirects, Request, RequestSigner, Response, ResponseData,
+           ThrottledReader, TlsClient, write_sink};
+use http::proxy::TunnelConnector;
 
 
-/// The `AuthClient` will attach an `Authentication` header to each outgoing request.
+lazy_static! {
+    static ref POOL: Mutex<Option<ConnectionPool>> = Mutex::new(None);
+    static ref PROXIED: Mutex<HashMap<String, (TlsClient, Arc<HyperClient>)>> = Mutex::new(HashMap::new());
+}
+
+
+/// The `AuthClient` will attach an `Authentication` header to each outgoing
+/// request, signing it and sending it through a proxy server when configured.
+/// Requests to hosts behind a basic-auth reverse proxy also carry its credentials.
+/// HTTPS requests are tunneled through the proxy, with any proxy credentials
+/// only sent in the CONNECT request.
 pub struct AuthClient {
     auth: Auth,
-    client: HyperClient,
-    version: Option<String>,
+    basic_auth: Option<BasicAuth>,
+    client: Arc<HyperClient>,
+    keep_alive: bool,
+    proxy: Proxy,
+    redirects: Redirects,
+    signer: Option<RequestSigner>,
+    headers: HashMap<String, String>,
 }
 
 impl Default for AuthClient {
     fn default() -> Self {
-        Self::from(Auth::None, None)
+        Self::from(Auth::None, HashMap::new())
     }
 }
 
 impl Client for AuthClient {
     fn chan_request(&self, req: Request, resp_tx: Sender<Response>) {
-        resp_tx.send(self.send(AuthRequest::new(&self.auth, req)));
+        resp_tx.send(self.send(AuthRequest::new(&self.auth, self.signer.as_ref(), req), None, 0));
+    }
+
+    fn headers(&self) -> HashMap<String, String> {
+        self.headers.clone()
+    }
+
+    fn send_stream(&self, mut req: Request, body: &mut Read) -> Receiver<Response> {
+        if self.signer.is_some() {
+            // the whole body is needed to calculate the signature
+            let mut buf = Vec::new();
+            return match body.read_to_end(&mut buf) {
+                Ok(_) => self.send_request(Request { body: Some(buf), ..req }),
+                Err(err) => {
+                    let (resp_tx, resp_rx) = chan::async::<Response>();
+                    resp_tx.send(Response::Error(Box::new(Error::Client(format!("couldn't read body: {}", err)))));
+                    resp_rx
+                }
+            };
+        }
+
+        info!("{} {} (streamed)", req.method, req.url);
+        for (name, value) in self.headers() {
+            req.headers.entry(name).or_insert(value);
+        }
+        let (resp_tx, resp_rx) = chan::async::<Response>();
+        resp_tx.send(self.send(AuthRequest::new(&self.auth, None, req), Some(body), 0));
+        resp_rx
     }
 }
 
 impl AuthClient {
-    /// Create a new HTTP client for the given `Auth` type.
-    pub fn from(auth: Auth, version: Option<String>) -> Self {
-        let mut client = env::var("HTTP_PROXY").map(|ref proxy| {
-            let tls = TlsClient::default();
-            let url = Url::parse(proxy).expect("couldn't parse HTTP_PROXY");
-            let host = url.host_str().expect("couldn't parse HTTP_PROXY host").to_string();
-            let port = url.port_or_known_default().expect("couldn't parse HTTP_PROXY port");
-            let proxy = ProxyConfig::new(url.scheme(), host, port, HttpConnector::default(), tls);
-            HyperClient::with_proxy_config(proxy)
-        }).unwrap_or_else(|_| HyperClient::with_connector(HttpsConnector::new(TlsClient::default())));
+    /// Create a new HTTP client for the given `Auth` type that will attach
+    /// these static headers to each request.
+    pub fn from(auth: Auth, headers: HashMap<String, String>) -> Self {
+        let pooled = POOL.lock().unwrap().as_mut().map(|pool| pool.client(TlsClient::default()));
+        let keep_alive = pooled.is_some();
+        let client = pooled.unwrap_or_else(|| {
+            let mut client = HyperClient::with_connector(HttpsConnector::new(TlsClient::default()));
+            client.set_redirect_policy(RedirectPolicy::FollowNone);
+            Arc::new(client)
+        });
+        AuthClient {
+            auth,
+            basic_auth: BasicAuth::current(),
+            client,
+            keep_alive,
+            proxy: Proxy::current(),
+            redirects: Redirects::current(),
+            signer: RequestSigner::current(),
+            headers
+        }
+    }
+
+    /// Share a pool of keep-alive connections between every `AuthClient`
+    /// created after this call, keeping up to `max_idle` connections open for
+    /// `idle_timeout` each. `TlsClient::init` must be called first.
+    pub fn init_pool(max_idle: usize, idle_timeout: Duration) {
+        *POOL.lock().unwrap() = Some(ConnectionPool::new(max_idle, idle_timeout));
+    }
 
+    /// Return the HTTP client that connects to the URL via the proxy server,
+    /// creating it on first use or whenever the TLS settings change.
+    fn proxy_client(url: &Url, proxy: &Url) -> Result<Arc<HyperClient>, Error> {
+        let tls = TlsClient::default();
+        let key = format!("{} via {}", url.0.scheme(), proxy);
+        let mut clients = PROXIED.lock().unwrap();
+        if let Some(&(ref cached, ref client)) = clients.get(&key) {
+            if tls.same_connector(cached) {
+                return Ok(client.clone());
+            }
+        }
+
+        let mut client = if url.0.scheme() == "https" {
+            HyperClient::with_connector(HttpsConnector::with_connector(tls.clone(), TunnelConnector::new(proxy)?))
+        } else {
+            let host = proxy.0.host_str().ok_or_else(|| Error::Parse(format!("no proxy host: {}", proxy)))?;
+            let port = proxy.0.port_or_known_default().ok_or_else(|| Error::Parse(format!("no proxy port: {}", proxy)))?;
+            let config = ProxyConfig::new(proxy.0.scheme(), host.to_string(), port, HttpConnector::default(), tls.clone());
+            HyperClient::with_proxy_config(config)
+        };
         client.set_redirect_policy(RedirectPolicy::FollowNone);
-        AuthClient { auth, client, version }
+        let client = Arc::new(client);
+        clients.insert(key, (tls, client.clone()));
+        Ok(client)
     }
 
-    fn send(&self, req: AuthRequest) -> Response {
+    /// Send the request, reading the body from `stream` with chunked
+    /// transfer-encoding when set. `followed` counts the redirects so far.
+    fn send(&self, req: AuthRequest, stream: Option<&mut Read>, followed: u32) -> Response {
         let started = time::precise_time_ns();
+        let streamed = stream.is_some();
         let mut headers = req.headers.clone();
-        if let Some(ref version) = self.version {
-            headers.set(UserAgent(format!("sota-client/{}", version)));
+        if streamed {
+            headers.remove::<ContentLength>();
+            headers.set(TransferEncoding(vec![Encoding::Chunked]));
+        }
+        for (name, value) in &req.request.headers {
+            headers.set_raw(name.clone(), vec![value.clone().into_bytes()]);
+        }
+        if let Some(ref basic) = self.basic_auth {
+            basic.apply(&req.request.url, &mut headers);
         }
 
-        let mut request = self.client
+        let proxied;
+        let client = match self.proxy.for_url(&req.request.url) {
+            Some(proxy) => {
+                debug!("sending request via proxy {}", proxy.0.host_str().unwrap_or(""));
+                let plain = req.request.url.0.scheme() == "http";
+                if plain && ! proxy.0.username().is_empty() {
+                    // the proxy reads plain HTTP requests directly, rather than tunneling them
+                    headers.set(ProxyAuthorization(Basic {
+                        username: proxy.0.username().to_string(),
+                        password: proxy.0.password().map(|pass| pass.to_string()),
+                    }));
+                }
+                proxied = match Self::proxy_client(&req.request.url, proxy) {
+                    Ok(client) => client,
+                    Err(err) => return Response::Error(Box::new(err))
+                };
+                &*proxied
+            }
+            None => {
+                if self.keep_alive {
+                    headers.set(Connection::keep_alive());
+                }
+                &*self.client
+            }
+        };
+
+        let mut request = client
             .request(req.request.method.clone().into(), (*req.request.url).clone())
             .headers(headers);
-        if let Some(ref body) = req.request.body {
+        if let Some(stream) = stream {
+            request = request.body(Body::ChunkedBody(stream));
+            debug!("streaming request body");
+        } else if let Some(ref body) = req.request.body {
             request = request.body(Body::BufBody(body, body.len()));
             debug!("request length: {} bytes", body.len());
             if let Ok(text) = str::from_utf8(body) {
@@ -75,9 +202,36 @@ impl AuthClient {
                 let latency = time::precise_time_ns() as f64 - started as f64;
                 debug!("response latency: {}ms", (latency / 1e6) as u32);
 
+                let max_bytes = req.request.max_bytes;
+                let too_large = |len: u64| max_bytes.map_or(false, |max| len > max);
+                if resp.headers.get::<ContentLength>().map_or(false, |len| too_large(len.0)) {
+                    return Response::Error(Box::new(Error::TooLarge(req.request.url.to_string())));
+                }
+
                 let mut body = Vec::new();
-                let data = match resp.read_to_end(&mut body) {
-                    Ok(_) => ResponseData { code: resp.status, body: body },
+                let headers = resp.headers.iter()
+                    .map(|header| (header.name().to_lowercase(), header.value_string()))
+                    .collect();
+                let status = resp.status;
+                let read = {
+                    let reader = ThrottledReader::new(&mut resp, req.request.rate_limit);
+                    let mut reader = reader.take(max_bytes.map_or(u64::max_value(), |max| max + 1));
+                    let canceled = req.request.canceled.as_ref().map(|flag| &**flag);
+                    match req.request.sink {
+                        Some(ref path) if status.is_success() => {
+                            write_sink(path, status == StatusCode::PartialContent, &mut reader, canceled)
+                        }
+                        _ => reader.read_to_end(&mut body).map(|len| len as u64)
+                    }
+                };
+                let data = match read {
+                    Ok(len) if too_large(len) => {
+                        return Response::Error(Box::new(Error::TooLarge(req.request.url.to_string())));
+                    }
+                    Ok(_) => ResponseData { code: resp.status, url: resp.url.to_string(), body: body, headers: headers },
+                    Err(_) if req.request.canceled.as_ref().map_or(false, |flag| flag.load(Ordering::SeqCst)) => {
+                        return Response::Error(Box::new(Error::Aborted(req.request.url.to_string())));
+                    }
                     Err(err) => {
                         let msg = format!("couldn't read response body: {}", err);
                         return Response::Error(Box::new(Error::Client(msg)));
@@ -85,8 +239,10 @@ impl AuthClient {
                 };
                 debug!("response body size: {}", data.body.len());
 
-                if resp.status.is_redirection() {
-                    self.redirect_request(&req, resp)
+                if resp.status.is_redirection() && streamed {
+                    Response::Error(Box::new(Error::Client("can't redirect a streamed request".into())))
+                } else if resp.status.is_redirection() {
+                    self.redirect_request(&req, resp, followed)
                 } else if resp.status.is_success() {
                     Response::Success(data)
                 } else if resp.status == StatusCode::Unauthorized || resp.status == StatusCode::Forbidden {
@@ -96,27 +252,47 @@ impl AuthClient {
                 }
             }
 
-            Err(err) => Response::Error(Box::new(Error::Client(format!("couldn't send request: {}", err))))
+            Err(err) => Response::Error(Box::new(Error::Connect(format!("couldn't send request: {}", err))))
         }
     }
 
-    /// Redirect drops the Authorization header.
-    fn redirect_request(&self, req: &AuthRequest, resp: HyperResponse) -> Response {
+    /// Follow a redirect within the configured limits. The Authorization and
+    /// signature headers are dropped on cross-origin redirects unless allowed.
+    fn redirect_request(&self, req: &AuthRequest, resp: HyperResponse, followed: u32) -> Response {
         resp.headers
             .get::<Location>()
             .map(|loc| {
-                self.send(AuthRequest::new(&Auth::None, Request {
-                    url: match loc.parse() {
-                        Ok(absolute) => absolute,
-                        Err(_) if loc[0..1] == *"/" => req.request.url.join(loc), // relative
-                        Err(err) => {
-                            let msg = format!("`{}` not a url: {}", loc, err);
-                            return Response::Error(Box::new(Error::Parse(msg)))
-                        }
-                    },
-                    method: req.request.method.clone(),
-                    body:   req.request.body.clone(),
-                }))
+                let url: Url = match loc.parse() {
+                    Ok(absolute) => absolute,
+                    Err(_) if loc[0..1] == *"/" => req.request.url.join(loc), // relative
+                    Err(err) => {
+                        let msg = format!("`{}` not a url: {}", loc, err);
+                        return Response::Error(Box::new(Error::Parse(msg)))
+                    }
+                };
+                let keep_auth = match self.redirects.follow(&req.request.url, &url, followed) {
+                    Ok(keep_auth) => keep_auth,
+                    Err(err) => return Response::Error(Box::new(err))
+                };
+                if ! keep_auth {
+                    debug!("dropping authentication headers on redirect to {}", url);
+                }
+                let request = Request {
+                    url:        url,
+                    method:     req.request.method.clone(),
+                    body:       req.request.body.clone(),
+                    headers:    req.request.headers.clone(),
+                    max_bytes:  req.request.max_bytes,
+                    rate_limit: req.request.rate_limit,
+                    sink:       req.request.sink.clone(),
+                    canceled:   req.request.canceled.clone(),
+                };
+                let redirect = if keep_auth {
+                    AuthRequest::new(&self.auth, self.signer.as_ref(), request)
+                } else {
+                    AuthRequest::new(&Auth::None, None, request)
+                };
+                self.send(redirect, None, followed + 1)
             })
             .unwrap_or_else(|| {
                 Response::Error(Box::new(Error::Client("redirect missing Location header".into())))
@@ -125,13 +301,49 @@ impl AuthClient {
 }
 
 
+/// A hyper client reusing keep-alive connections, rebuilt whenever the TLS
+/// settings are changed with `TlsClient::init`.
+struct ConnectionPool {
+    max_idle:     usize,
+    idle_timeout: Duration,
+    tls:          TlsClient,
+    client:       Arc<HyperClient>,
+}
+
+impl ConnectionPool {
+    fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        let tls = TlsClient::default();
+        let client = Self::build(max_idle, idle_timeout, tls.clone());
+        ConnectionPool { max_idle, idle_timeout, tls, client }
+    }
+
+    /// Return the pooled client, rebuilding it if the TLS settings changed.
+    fn client(&mut self, tls: TlsClient) -> Arc<HyperClient> {
+        if ! tls.same_connector(&self.tls) {
+            debug!("rebuilding connection pool for new TLS settings");
+            self.client = Self::build(self.max_idle, self.idle_timeout, tls.clone());
+            self.tls = tls;
+        }
+        self.client.clone()
+    }
+
+    fn build(max_idle: usize, idle_timeout: Duration, tls: TlsClient) -> Arc<HyperClient> {
+        let mut pool = Pool::with_connector(PoolConfig { max_idle: max_idle }, HttpsConnector::new(tls));
+        pool.set_idle_timeout(Some(idle_timeout));
+        let mut client = HyperClient::with_connector(pool);
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+        Arc::new(client)
+    }
+}
+
+
 struct AuthRequest {
     request: Request,
     headers: Headers,
 }
 
 impl AuthRequest {
-    fn new(auth: &Auth, req: Request) -> Self {
+    fn new(auth: &Auth, signer: Option<&RequestSigner>, req: Request) -> Self {
         let mut headers = Headers::new();
 
         headers.set(Connection::close());
@@ -161,6 +373,10 @@ impl AuthRequest {
             }
         };
 
+        if let Some(signer) = signer {
+            headers.set_raw(signer.header.clone(), vec![signer.sign(&req).into_bytes()]);
+        }
+
         AuthRequest {
             request: req,
             headers: headers,
@@ -172,9 +388,14 @@ impl AuthRequest {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hyper::header::Host;
+    use hyper::server::{Request as ServerRequest, Response as ServerResponse, Server};
+    use hyper::uri::RequestUri;
     use json;
+    use std::thread;
 
-    use http::{Client, Response, TlsClient, TlsData};
+    use datatype::{AccessToken, Method};
+    use http::{Client, Response, SigningAlgorithm, TlsClient, TlsData};
 
 
     fn get_client() -> AuthClient {
@@ -182,6 +403,82 @@ mod tests {
         AuthClient::default()
     }
 
+    fn token_client() -> AuthClient {
+        let token = AccessToken { access_token: "token".into(), token_type: "bearer".into(), expires_in: 0, scope: "".into() };
+        AuthClient { auth: Auth::Token(token), proxy: Proxy::default(), ..get_client() }
+    }
+
+    /// Redirect `/same` and `/cross` to `/echo` on the same or another origin,
+    /// and `/loop` to itself. `/echo` replies with the Authorization header.
+    fn redirect_handler(req: ServerRequest, mut resp: ServerResponse) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new()
+        };
+        let port = req.headers.get::<Host>().and_then(|host| host.port).unwrap_or(80);
+        let location = match path.as_str() {
+            "/same"  => Some("/echo".to_string()),
+            "/cross" => Some(format!("http://localhost:{}/echo", port)),
+            "/loop"  => Some("/loop".to_string()),
+            _ => None
+        };
+        if let Some(location) = location {
+            *resp.status_mut() = StatusCode::Found;
+            resp.headers_mut().set(Location(location));
+        }
+        let auth = req.headers.get_raw("Authorization").map_or(Vec::new(), |values| values[0].clone());
+        resp.send(&auth).expect("send response");
+    }
+
+    fn start_redirect_server(addr: &str) {
+        let server = Server::http(addr).expect("redirect server");
+        let _ = server.handle(redirect_handler).expect("serve redirects");
+        thread::sleep(Duration::from_millis(100)); // wait before connecting
+    }
+
+    #[test]
+    fn test_redirect_keeps_same_origin_auth() {
+        start_redirect_server("127.0.0.1:8891");
+        let client = token_client();
+        for (path, auth) in vec![("same", "Bearer token"), ("cross", "")] {
+            let url = format!("http://127.0.0.1:8891/{}", path).parse().unwrap();
+            match client.get(url, None).recv().unwrap() {
+                Response::Success(data) => assert_eq!(String::from_utf8(data.body).unwrap(), auth),
+                Response::Failed(data)  => panic!("failed response: {}", data),
+                Response::Error(err)    => panic!("error response: {}", err)
+            }
+        }
+    }
+
+    #[test]
+    fn test_basic_auth_header() {
+        start_redirect_server("127.0.0.1:8893");
+        let basic = BasicAuth {
+            username:     "user".into(),
+            password:     "pass".into(),
+            hosts:        vec!["127.0.0.1".into()],
+            token_header: "X-Authorization".into(),
+        };
+        let client = AuthClient { basic_auth: Some(basic), ..token_client() };
+        match client.get("http://127.0.0.1:8893/echo".parse().unwrap(), None).recv().unwrap() {
+            Response::Success(data) => assert_eq!(String::from_utf8(data.body).unwrap(), "Basic dXNlcjpwYXNz"),
+            other => panic!("expected echoed auth header, got {}", other)
+        }
+    }
+
+    #[test]
+    fn test_redirect_loop() {
+        start_redirect_server("127.0.0.1:8892");
+        let url = "http://127.0.0.1:8892/loop".parse().unwrap();
+        match token_client().get(url, None).recv().unwrap() {
+            Response::Error(err) => match *err {
+                Error::Client(ref msg) => assert_eq!(msg, "too many redirects"),
+                ref other => panic!("expected too many redirects, got {}", other)
+            },
+            other => panic!("expected redirect error, got {}", other)
+        }
+    }
+
     #[test]
     fn test_send_get_request() {
         let url = "http://eu.httpbin.org/bytes/16?seed=123".parse().unwrap();
@@ -207,4 +504,19 @@ mod tests {
             Response::Error(err)   => panic!("error response: {}", err)
         };
     }
+
+    #[test]
+    fn test_signature_header() {
+        let signer = RequestSigner {
+            key:       "secret".into(),
+            algorithm: SigningAlgorithm::HmacSha256,
+            header:    "X-Signature".into(),
+            canonical: "{method}\n{path}\n{body_sha256}".into(),
+        };
+        let req = || Request::new(Method::Get, "http://localhost:8080/api/v1/updates".parse().unwrap(), None);
+        let signed = AuthRequest::new(&Auth::None, Some(&signer), req());
+        let expect = b"d6246a4442952248678eb4c21e3f291d3dbbee8e8767eee35c3289364fc780cf".to_vec();
+        assert_eq!(signed.headers.get_raw("X-Signature"), Some(&[expect][..]));
+        assert!(AuthRequest::new(&Auth::None, None, req()).headers.get_raw("X-Signature").is_none());
+    }
 }
@@ -0,0 +1,100 @@
+use hyper::header::{Authorization, Basic, Headers};
+use std::sync::Mutex;
+
+use datatype::{NetworkConfig, Url};
+
+
+lazy_static! {
+    static ref BASIC_AUTH: Mutex<Option<BasicAuth>> = Mutex::new(None);
+}
+
+
+/// Credentials for an HTTP basic-auth reverse proxy in front of some hosts.
+/// The proxy reads the `Authorization` header, so any bearer token or client
+/// credentials already set there are moved to `token_header` instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicAuth {
+    pub username:     String,
+    pub password:     String,
+    pub hosts:        Vec<String>,
+    pub token_header: String,
+}
+
+impl BasicAuth {
+    /// Set the basic-auth credentials used by all subsequently created clients.
+    pub fn init(basic: Option<BasicAuth>) {
+        *BASIC_AUTH.lock().unwrap() = basic;
+    }
+
+    /// Return the basic-auth credentials set with `BasicAuth::init`.
+    pub fn current() -> Option<BasicAuth> {
+        BASIC_AUTH.lock().unwrap().clone()
+    }
+
+    /// Create the basic-auth credentials when both the credentials and the
+    /// hosts to send them to are configured.
+    pub fn from_config(network: &NetworkConfig) -> Option<BasicAuth> {
+        let (username, password) = match network.basic_auth {
+            Some((ref username, ref password)) => (username.clone(), password.clone()),
+            None => return None
+        };
+        let hosts = network.basic_auth_hosts.as_ref().map_or(Vec::new(), |hosts| {
+            hosts.split(',').map(|host| host.trim().to_lowercase()).filter(|host| !host.is_empty()).collect()
+        });
+        if hosts.is_empty() {
+            warn!("not sending network.basic_auth as network.basic_auth_hosts is empty");
+            return None;
+        }
+        Some(BasicAuth { username, password, hosts, token_header: network.token_header.clone() })
+    }
+
+    /// Check whether requests to this URL should carry the basic-auth header.
+    pub fn applies_to(&self, url: &Url) -> bool {
+        let host = url.0.host_str().unwrap_or("").to_lowercase();
+        self.hosts.iter().any(|entry| *entry == host)
+    }
+
+    /// Set the basic-auth header for requests to one of the configured hosts,
+    /// moving any existing `Authorization` header to `token_header`.
+    pub fn apply(&self, url: &Url, headers: &mut Headers) {
+        if ! self.applies_to(url) {
+            return;
+        }
+        if let Some(existing) = headers.get_raw("Authorization").map(|raw| raw.to_vec()) {
+            headers.set_raw(self.token_header.clone(), existing);
+        }
+        headers.set(Authorization(Basic {
+            username: self.username.clone(),
+            password: Some(self.password.clone()),
+        }));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::Bearer;
+
+
+    #[test]
+    fn test_moves_token_header() {
+        let basic = BasicAuth {
+            username:     "user".into(),
+            password:     "pass".into(),
+            hosts:        vec!["staging.example.com".into()],
+            token_header: "X-Authorization".into(),
+        };
+        let mut headers = Headers::new();
+        headers.set(Authorization(Bearer { token: "token".into() }));
+        basic.apply(&"https://Staging.example.com/api/v1".parse().unwrap(), &mut headers);
+        assert_eq!(headers.get_raw("Authorization"), Some(&[b"Basic dXNlcjpwYXNz".to_vec()][..]));
+        assert_eq!(headers.get_raw("X-Authorization"), Some(&[b"Bearer token".to_vec()][..]));
+
+        let mut other = Headers::new();
+        other.set(Authorization(Bearer { token: "token".into() }));
+        basic.apply(&"https://ota.example.com/api/v1".parse().unwrap(), &mut other);
+        assert_eq!(other.get_raw("Authorization"), Some(&[b"Bearer token".to_vec()][..]));
+        assert!(other.get_raw("X-Authorization").is_none());
+    }
+}
@@ -1,7 +1,16 @@
 use chan::{self, Sender, Receiver};
 use hyper::status::StatusCode;
+use std::cmp;
+use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
 use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use datatype::{Error, Method, Url};
 
@@ -11,26 +20,63 @@ use datatype::{Error, Method, Url};
 pub trait Client: Send {
     fn chan_request(&self, req: Request, resp_tx: Sender<Response>);
 
-    fn send_request(&self, req: Request) -> Receiver<Response> {
+    /// Static headers attached to every request sent from this client.
+    fn headers(&self) -> HashMap<String, String> { HashMap::new() }
+
+    fn send_request(&self, mut req: Request) -> Receiver<Response> {
         info!("{} {}", req.method, req.url);
+        for (name, value) in self.headers() {
+            req.headers.entry(name).or_insert(value);
+        }
         let (resp_tx, resp_rx) = chan::async::<Response>();
         self.chan_request(req, resp_tx);
         resp_rx
     }
 
     fn get(&self, url: Url, body: Option<Vec<u8>>) -> Receiver<Response> {
-        let method = Method::Get;
-        self.send_request(Request { method, url, body })
+        self.send_request(Request::new(Method::Get, url, body))
+    }
+
+    /// Request the remainder of a resource starting from the byte offset.
+    fn get_range(&self, url: Url, offset: u64) -> Receiver<Response> {
+        self.send_request(Request::new(Method::Get, url, None).range(offset))
+    }
+
+    /// Request a resource from the byte offset, reading the response body at
+    /// no more than `bytes_per_sec`.
+    fn get_throttled(&self, url: Url, offset: u64, bytes_per_sec: u64) -> Receiver<Response> {
+        self.send_request(Request::new(Method::Get, url, None).range(offset).rate_limit(Some(bytes_per_sec)))
+    }
+
+    fn patch(&self, url: Url, body: Option<Vec<u8>>) -> Receiver<Response> {
+        self.send_request(Request::new(Method::Patch, url, body))
     }
 
     fn post(&self, url: Url, body: Option<Vec<u8>>) -> Receiver<Response> {
-        let method = Method::Post;
-        self.send_request(Request { method, url, body })
+        self.send_request(Request::new(Method::Post, url, body))
     }
 
     fn put(&self, url: Url, body: Option<Vec<u8>>) -> Receiver<Response> {
-        let method = Method::Put;
-        self.send_request(Request { method, url, body })
+        self.send_request(Request::new(Method::Put, url, body))
+    }
+
+    /// Send a PUT request with the body read from a stream.
+    fn put_stream(&self, url: Url, body: &mut Read) -> Receiver<Response> {
+        self.send_stream(Request::new(Method::Put, url, None), body)
+    }
+
+    /// Send the request with the body read from a stream. By default the
+    /// whole body is read into memory before sending.
+    fn send_stream(&self, req: Request, body: &mut Read) -> Receiver<Response> {
+        let mut buf = Vec::new();
+        match body.read_to_end(&mut buf) {
+            Ok(_) => self.send_request(Request { body: Some(buf), ..req }),
+            Err(err) => {
+                let (resp_tx, resp_rx) = chan::async::<Response>();
+                resp_tx.send(Response::Error(Box::new(Error::Client(format!("couldn't read body: {}", err)))));
+                resp_rx
+            }
+        }
     }
 
     fn is_testing(&self) -> bool { false }
@@ -38,11 +84,116 @@ pub trait Client: Send {
 
 
 /// A new HTTP request to be sent from a specific Client.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request {
-    pub method: Method,
-    pub url:    Url,
-    pub body:   Option<Vec<u8>>,
+    pub method:     Method,
+    pub url:        Url,
+    pub body:       Option<Vec<u8>>,
+    pub headers:    HashMap<String, String>,
+    pub max_bytes:  Option<u64>,
+    pub rate_limit: Option<u64>,
+    pub sink:       Option<String>,
+    pub canceled:   Option<Arc<AtomicBool>>,
+}
+
+impl Request {
+    /// Create a new request without any extra headers or limits.
+    pub fn new(method: Method, url: Url, body: Option<Vec<u8>>) -> Self {
+        Request { method, url, body, headers: HashMap::new(), max_bytes: None, rate_limit: None, sink: None, canceled: None }
+    }
+
+    /// Fail with `Error::TooLarge` when the response body exceeds `max_bytes`.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Request the remainder of the resource from a non-zero byte offset.
+    pub fn range(mut self, offset: u64) -> Self {
+        if offset > 0 {
+            self.headers.insert("Range".into(), format!("bytes={}-", offset));
+        }
+        self
+    }
+
+    /// Read the response body at no more than this many bytes per second.
+    pub fn rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.rate_limit = bytes_per_sec;
+        self
+    }
+
+    /// Write a successful response body to the file at `path` rather than
+    /// keeping it in memory.
+    pub fn sink(mut self, path: String) -> Self {
+        self.sink = Some(path);
+        self
+    }
+
+    /// Stop writing the response body to the `sink` once the flag is set.
+    pub fn canceled(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.canceled = Some(flag);
+        self
+    }
+}
+
+/// Write a response body to the file at `path`, returning the number of bytes
+/// written. A partial response is appended to the file while any other
+/// response replaces it. The `canceled` flag is checked before each chunk is
+/// written, leaving the bytes written so far in the file.
+pub fn write_sink(path: &str, partial: bool, body: &mut Read, canceled: Option<&AtomicBool>) -> io::Result<u64> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).write(true).append(partial).truncate(!partial).open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut written = 0;
+    loop {
+        if canceled.map_or(false, |flag| flag.load(Ordering::SeqCst)) {
+            return Err(io::Error::new(ErrorKind::Other, "canceled"));
+        }
+        let len = match body.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(len) => len,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err)
+        };
+        file.write_all(&buf[..len])?;
+        written += len as u64;
+    }
+}
+
+
+/// Wraps a reader so that no more than `bytes_per_sec` are read on average,
+/// sleeping between reads as needed. Without a limit reads are passed through.
+pub struct ThrottledReader<R: Read> {
+    inner:         R,
+    bytes_per_sec: Option<u64>,
+    started:       Instant,
+    total:         u64,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(inner: R, bytes_per_sec: Option<u64>) -> Self {
+        ThrottledReader { inner: inner, bytes_per_sec: bytes_per_sec, started: Instant::now(), total: 0 }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let rate = match self.bytes_per_sec {
+            Some(rate) => rate,
+            None => return self.inner.read(buf)
+        };
+        let max = cmp::min(buf.len() as u64, cmp::max(rate / 10, 1)) as usize;
+        let read = self.inner.read(&mut buf[..max])?;
+        self.total += read as u64;
+        let due = Duration::from_millis(self.total * 1000 / rate);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+        Ok(read)
+    }
 }
 
 
@@ -66,11 +217,49 @@ impl Display for Response {
 }
 
 
-/// Wraps the HTTP Status Code as well as any returned body.
+/// Wraps the HTTP Status Code as well as any returned headers and body.
+/// Header names are stored in lowercase.
 #[derive(Debug)]
 pub struct ResponseData {
-    pub code: StatusCode,
-    pub body: Vec<u8>
+    pub code:    StatusCode,
+    pub url:     String,
+    pub body:    Vec<u8>,
+    pub headers: HashMap<String, String>,
+}
+
+impl ResponseData {
+    /// Return up to `max` bytes of the body as lossy UTF-8 text.
+    pub fn body_excerpt(&self, max: usize) -> String {
+        let len = if self.body.len() > max { max } else { self.body.len() };
+        String::from_utf8_lossy(&self.body[..len]).into_owned()
+    }
+
+    /// Returns the lowercase media type of a `Content-Type` header, without
+    /// any parameters such as the charset.
+    pub fn content_type(&self) -> Option<String> {
+        self.headers.get("content-type").map(|ct| ct.split(';').next().unwrap_or("").trim().to_lowercase())
+    }
+
+    /// Parse a `Content-Length` header.
+    pub fn content_length(&self) -> Option<u64> {
+        self.headers.get("content-length").and_then(|len| len.trim().parse().ok())
+    }
+
+    /// Parse a `Content-Range: bytes <start>-<end>/<total>` header.
+    pub fn content_range(&self) -> Option<(u64, u64, u64)> {
+        let range = match self.headers.get("content-range") {
+            Some(range) if range.trim().starts_with("bytes ") => &range.trim()[6..],
+            _ => return None
+        };
+        let parts = range.split(|c| c == '-' || c == '/').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return None;
+        }
+        match (parts[0].parse(), parts[1].parse(), parts[2].parse()) {
+            (Ok(start), Ok(end), Ok(total)) => Some((start, end, total)),
+            _ => None
+        }
+    }
 }
 
 impl Display for ResponseData {
@@ -84,3 +273,45 @@ impl Display for ResponseData {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn with_range(range: &str) -> ResponseData {
+        let headers = hashmap!{ "content-range".to_string() => range.to_string() };
+        ResponseData { code: StatusCode::PartialContent, url: String::new(), body: Vec::new(), headers: headers }
+    }
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(with_range("bytes 6-10/11").content_range(), Some((6, 10, 11)));
+        assert_eq!(with_range("bytes */11").content_range(), None);
+        assert_eq!(with_range("6-10/11").content_range(), None);
+    }
+
+    #[test]
+    fn test_body_excerpt() {
+        let mut data = with_range("bytes 6-10/11");
+        data.body = "héllo world".as_bytes().to_vec();
+        assert_eq!(data.body_excerpt(100), "héllo world");
+        assert_eq!(data.body_excerpt(5), "héll");
+        assert_eq!(data.body_excerpt(2), "h\u{FFFD}");
+    }
+
+    #[test]
+    fn test_throttled_reader() {
+        let body = vec![0u8; 300];
+        let started = Instant::now();
+        let mut read = Vec::new();
+        ThrottledReader::new(&body[..], Some(1000)).read_to_end(&mut read).expect("read body");
+        assert_eq!(read, body);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        let started = Instant::now();
+        ThrottledReader::new(&body[..], None).read_to_end(&mut Vec::new()).expect("read body");
+        assert!(started.elapsed() < Duration::from_millis(300));
+    }
+}
@@ -1,9 +1,17 @@
 pub mod auth_client;
+pub mod basic_auth;
 pub mod http_client;
+pub mod proxy;
+pub mod redirect;
+pub mod signer;
 pub mod test_client;
 pub mod tls;
 
 pub use self::auth_client::AuthClient;
-pub use self::http_client::{Client, Request, Response, ResponseData};
+pub use self::basic_auth::BasicAuth;
+pub use self::http_client::{Client, Request, Response, ResponseData, ThrottledReader, write_sink};
+pub use self::proxy::Proxy;
+pub use self::redirect::Redirects;
+pub use self::signer::{RequestSigner, SigningAlgorithm};
 pub use self::test_client::TestClient;
-pub use self::tls::{Pkcs12, TlsClient, TlsData};
+pub use self::tls::{Pkcs12, TlsClient, TlsData, cert_not_after};
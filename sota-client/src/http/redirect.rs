@@ -0,0 +1,85 @@
+use std::sync::Mutex;
+
+use datatype::{Error, NetworkConfig, Url};
+
+
+lazy_static! {
+    static ref REDIRECTS: Mutex<Option<Redirects>> = Mutex::new(None);
+}
+
+
+/// Limits on how HTTP redirects are followed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Redirects {
+    /// The maximum number of redirects followed for a single request.
+    pub max_redirects: u32,
+    /// Only follow redirects to the same scheme, host and port.
+    pub same_host:     bool,
+    /// Keep the Authorization and signature headers on cross-origin redirects.
+    pub keep_auth:     bool,
+}
+
+impl Default for Redirects {
+    fn default() -> Self {
+        Redirects { max_redirects: 5, same_host: false, keep_auth: false }
+    }
+}
+
+impl Redirects {
+    /// Set the redirect limits used by all subsequently created clients.
+    pub fn init(redirects: Redirects) {
+        *REDIRECTS.lock().unwrap() = Some(redirects);
+    }
+
+    /// Return the redirect limits set with `Redirects::init`, or the defaults.
+    pub fn current() -> Redirects {
+        REDIRECTS.lock().unwrap().unwrap_or_default()
+    }
+
+    /// Read the redirect limits from the config.
+    pub fn from_config(network: &NetworkConfig) -> Redirects {
+        Redirects {
+            max_redirects: network.max_redirects,
+            same_host:     network.redirect_same_host,
+            keep_auth:     network.redirect_keep_auth,
+        }
+    }
+
+    /// Check whether a redirect may be followed after `followed` redirects so
+    /// far, returning whether the authentication headers should be kept.
+    pub fn follow(&self, from: &Url, to: &Url, followed: u32) -> Result<bool, Error> {
+        let same_origin = from.0.origin() == to.0.origin();
+        if followed >= self.max_redirects {
+            Err(Error::Client("too many redirects".into()))
+        } else if self.same_host && ! same_origin {
+            Err(Error::Client(format!("cross-origin redirect to {} not allowed", to)))
+        } else {
+            Ok(same_origin || self.keep_auth)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_follow_redirects() {
+        let from = "https://example.com/api/v1/updates".parse::<Url>().unwrap();
+        let same = "https://example.com/cdn/image".parse::<Url>().unwrap();
+        let cross = "https://cdn.example.com/image".parse::<Url>().unwrap();
+
+        let redirects = Redirects::default();
+        assert!(redirects.follow(&from, &same, 0).unwrap());
+        assert!(!redirects.follow(&from, &cross, 4).unwrap());
+        assert!(redirects.follow(&from, &same, 5).is_err());
+
+        let keep = Redirects { keep_auth: true, ..Redirects::default() };
+        assert!(keep.follow(&from, &cross, 0).unwrap());
+        let strict = Redirects { same_host: true, ..Redirects::default() };
+        assert!(strict.follow(&from, &same, 0).is_ok());
+        assert!(strict.follow(&from, &cross, 0).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::{Sha256, Sha512};
+use hex::ToHex;
+use serde::de::{Deserialize, Deserializer, Error as SerdeError};
+use serde::ser::{Serialize, Serializer};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use datatype::{Error, NetworkConfig};
+use http::Request;
+
+
+lazy_static! {
+    static ref SIGNER: Mutex<Option<RequestSigner>> = Mutex::new(None);
+}
+
+
+/// The HMAC algorithm used to sign outgoing requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SigningAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl FromStr for SigningAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SigningAlgorithm, Error> {
+        match s.to_lowercase().as_str() {
+            "hmac-sha256" | "hmac_sha256" => Ok(SigningAlgorithm::HmacSha256),
+            "hmac-sha512" | "hmac_sha512" => Ok(SigningAlgorithm::HmacSha512),
+            _ => Err(Error::Parse(format!("unknown signing algorithm: {}", s)))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SigningAlgorithm {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<SigningAlgorithm, D::Error> {
+        let s: String = Deserialize::deserialize(de)?;
+        s.parse().map_err(|err| SerdeError::custom(format!("invalid signing algorithm: {}", err)))
+    }
+}
+
+impl Serialize for SigningAlgorithm {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            SigningAlgorithm::HmacSha256 => ser.serialize_str("hmac-sha256"),
+            SigningAlgorithm::HmacSha512 => ser.serialize_str("hmac-sha512"),
+        }
+    }
+}
+
+
+/// Adds an HMAC signature header to outgoing requests. The signed text is built
+/// from the `canonical` template by replacing `{method}`, `{host}`, `{path}`,
+/// `{query}` and `{body_sha256}` with the values from each request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestSigner {
+    pub key:       String,
+    pub algorithm: SigningAlgorithm,
+    pub header:    String,
+    pub canonical: String,
+}
+
+impl RequestSigner {
+    /// Set the request signer used by all subsequently created clients.
+    pub fn init(signer: Option<RequestSigner>) {
+        *SIGNER.lock().unwrap() = signer;
+    }
+
+    /// Return the request signer set with `RequestSigner::init`.
+    pub fn current() -> Option<RequestSigner> {
+        SIGNER.lock().unwrap().clone()
+    }
+
+    /// Create a request signer when a signing key is configured.
+    pub fn from_config(network: &NetworkConfig) -> Option<RequestSigner> {
+        network.signing_key.as_ref().map(|key| RequestSigner {
+            key:       key.clone(),
+            algorithm: network.signing_algorithm,
+            header:    network.signing_header.clone(),
+            canonical: network.signing_canonical.clone(),
+        })
+    }
+
+    /// Build the text to be signed for this request.
+    pub fn canonical_string(&self, req: &Request) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(req.body.as_ref().map_or(&[][..], |body| &body[..]));
+        self.canonical
+            .replace("{method}", &format!("{}", req.method))
+            .replace("{host}", req.url.0.host_str().unwrap_or(""))
+            .replace("{path}", req.url.0.path())
+            .replace("{query}", req.url.0.query().unwrap_or(""))
+            .replace("{body_sha256}", &hasher.result_str())
+    }
+
+    /// Returns the hex-encoded HMAC of the canonical request string.
+    pub fn sign(&self, req: &Request) -> String {
+        let text = self.canonical_string(req);
+        match self.algorithm {
+            SigningAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::new(Sha256::new(), self.key.as_bytes());
+                mac.input(text.as_bytes());
+                mac.result().code().to_hex()
+            }
+
+            SigningAlgorithm::HmacSha512 => {
+                let mut mac = Hmac::new(Sha512::new(), self.key.as_bytes());
+                mac.input(text.as_bytes());
+                mac.result().code().to_hex()
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use datatype::Method;
+
+
+    fn request(method: Method, url: &str, body: Option<&[u8]>) -> Request {
+        Request::new(method, url.parse().unwrap(), body.map(|body| body.to_vec()))
+    }
+
+    #[test]
+    fn test_sign_request() {
+        let network = NetworkConfig { signing_key: Some("secret".into()), ..NetworkConfig::default() };
+        let signer = RequestSigner::from_config(&network).expect("signer");
+        let req = request(Method::Get, "http://localhost:8080/api/v1/updates", None);
+        assert_eq!(signer.sign(&req), "d6246a4442952248678eb4c21e3f291d3dbbee8e8767eee35c3289364fc780cf");
+        assert!(RequestSigner::from_config(&NetworkConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_sign_request_template() {
+        let signer = RequestSigner {
+            key:       "secret".into(),
+            algorithm: SigningAlgorithm::HmacSha512,
+            header:    "X-Signature".into(),
+            canonical: "{method} {path}?{query} {body_sha256}".into(),
+        };
+        let req = request(Method::Post, "https://example.com/api/v1/updates?limit=1", Some(b"hello"));
+        assert_eq!(signer.canonical_string(&req),
+                   "POST /api/v1/updates?limit=1 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(signer.sign(&req), "691c32a9fa58891f309965f41e230a4dcb523e4f0b07277c201aa2b4840031c4\
+                                       92d544cf88711ab9f4b408761260ff0e9a6fb995d468489125e2734aafaec545");
+    }
+}
@@ -1,22 +1,41 @@
 use chan::Sender;
 use hyper::status::StatusCode;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
 
-use datatype::{Error, Util};
-use http::{Client, Request, Response, ResponseData};
+use datatype::{Error, Method, Url, Util};
+use http::{Client, Request, Response, ResponseData, write_sink};
 
 
-/// The `TestClient` will return an ordered list of successful HTTP responses.
+/// The `TestClient` will return an ordered list of HTTP responses, recording
+/// each request it receives. Responses without a success status code are
+/// returned as `Response::Failed`.
 #[derive(Default)]
 pub struct TestClient {
-    responses: RefCell<VecDeque<Vec<u8>>>
+    responses: RefCell<VecDeque<ResponseData>>,
+    requests:  RefCell<Vec<Request>>,
+    expected:  RefCell<VecDeque<(Method, Url)>>,
+    headers:   HashMap<String, String>,
 }
 
 impl TestClient {
     /// Create a new `TestClient` that will return these responses.
     pub fn from(responses: Vec<Vec<u8>>) -> TestClient {
-        TestClient { responses: RefCell::new(VecDeque::from(responses)) }
+        TestClient::from_responses(responses.into_iter()
+            .map(|body| ResponseData { code: StatusCode::Ok, url: String::new(), body: body, headers: HashMap::new() })
+            .collect())
+    }
+
+    /// Create a new `TestClient` that will return these full responses,
+    /// including their status codes and headers.
+    pub fn from_responses(responses: Vec<ResponseData>) -> TestClient {
+        TestClient {
+            responses: RefCell::new(VecDeque::from(responses)),
+            requests:  RefCell::new(Vec::new()),
+            expected:  RefCell::new(VecDeque::new()),
+            headers:   HashMap::new(),
+        }
     }
 
     /// Create a new `TestClient` that will return each file's data as a response.
@@ -26,19 +45,133 @@ impl TestClient {
             .collect();
         TestClient::from(responses)
     }
+
+    /// Expect each incoming request to match the method and URL in this order,
+    /// returning an error response on a mismatch.
+    pub fn expect_requests(self, expected: Vec<(Method, Url)>) -> TestClient {
+        *self.expected.borrow_mut() = VecDeque::from(expected);
+        self
+    }
+
+    /// Attach these static headers to each outgoing request.
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> TestClient {
+        self.headers = headers;
+        self
+    }
+
+    /// Return a copy of every request received so far.
+    pub fn recorded_requests(&self) -> Vec<Request> {
+        self.requests.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl ResponseData {
+    /// Create a response with this status code and body, without any headers.
+    pub fn new(code: StatusCode, body: Vec<u8>) -> ResponseData {
+        ResponseData { code: code, url: String::new(), body: body, headers: HashMap::new() }
+    }
 }
 
 impl Client for TestClient {
     fn chan_request(&self, req: Request, resp_tx: Sender<Response>) {
+        self.requests.borrow_mut().push(req.clone());
+
+        if let Some((method, url)) = self.expected.borrow_mut().pop_front() {
+            if method != req.method || url != req.url {
+                let msg = format!("expected {} {}, got {} {}", method, url, req.method, req.url);
+                return resp_tx.send(Response::Error(Box::new(Error::Client(msg))));
+            }
+        }
+
         self.responses
             .borrow_mut()
             .pop_front()
-            .map(|body| ResponseData { code: StatusCode::Ok, body: body })
-            .map(|data| resp_tx.send(Response::Success(data)))
+            .map(|mut data| {
+                data.url = req.url.to_string();
+                if req.max_bytes.map_or(false, |max| data.body.len() as u64 > max) {
+                    resp_tx.send(Response::Error(Box::new(Error::TooLarge(req.url.to_string()))))
+                } else if data.code.is_success() {
+                    if let Some(ref path) = req.sink {
+                        let partial = data.code == StatusCode::PartialContent;
+                        let canceled = req.canceled.as_ref().map(|flag| &**flag);
+                        let written = write_sink(path, partial, &mut &*data.body, canceled);
+                        match written {
+                            Ok(_) => data.body = Vec::new(),
+                            Err(_) if canceled.map_or(false, |flag| flag.load(Ordering::SeqCst)) => {
+                                return resp_tx.send(Response::Error(Box::new(Error::Aborted(req.url.to_string()))));
+                            }
+                            Err(err) => return resp_tx.send(Response::Error(Box::new(Error::Io(err))))
+                        }
+                    }
+                    resp_tx.send(Response::Success(data))
+                } else {
+                    resp_tx.send(Response::Failed(data))
+                }
+            })
             .unwrap_or_else(|| {
                 resp_tx.send(Response::Error(Box::new(Error::Client(req.url.to_string()))))
             })
     }
 
+    fn headers(&self) -> HashMap<String, String> {
+        self.headers.clone()
+    }
+
     fn is_testing(&self) -> bool { true }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datatype::Config;
+
+
+    #[test]
+    fn records_requests() {
+        let client = TestClient::from(vec![b"one".to_vec(), b"two".to_vec()]);
+        let _ = client.get("http://localhost/one".parse().unwrap(), None).recv();
+        let _ = client.put("http://localhost/two".parse().unwrap(), Some(b"body".to_vec())).recv();
+
+        let requests = client.recorded_requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method, Method::Get);
+        assert_eq!(requests[0].url, "http://localhost/one".parse().unwrap());
+        assert_eq!(requests[1].method, Method::Put);
+        assert_eq!(requests[1].body, Some(b"body".to_vec()));
+    }
+
+    #[test]
+    fn static_headers() {
+        let mut config = Config::default();
+        config.device.uuid = "00000000-0000-0000-0000-000000000001".parse().unwrap();
+        let client = TestClient::from(vec![b"one".to_vec(), b"two".to_vec()])
+            .with_headers(config.http_headers(Some("1.0")));
+        let _ = client.get("http://localhost/one".parse().unwrap(), None).recv();
+        let _ = client.get_range("http://localhost/two".parse().unwrap(), 10).recv();
+
+        for req in client.recorded_requests() {
+            assert_eq!(req.headers["User-Agent"], "sota-client/1.0");
+            assert_eq!(req.headers["x-device-uuid"], "00000000-0000-0000-0000-000000000001");
+        }
+        assert_eq!(client.recorded_requests()[1].headers["Range"], "bytes=10-");
+    }
+
+    #[test]
+    fn expected_requests() {
+        let client = TestClient::from(vec![b"one".to_vec(), b"two".to_vec()])
+            .expect_requests(vec![
+                (Method::Get, "http://localhost/one".parse().unwrap()),
+                (Method::Get, "http://localhost/two".parse().unwrap()),
+            ]);
+        match client.get("http://localhost/one".parse().unwrap(), None).recv().unwrap() {
+            Response::Success(data) => assert_eq!(data.body, b"one".to_vec()),
+            _ => panic!("expected success response")
+        }
+        match client.post("http://localhost/two".parse().unwrap(), None).recv().unwrap() {
+            Response::Error(_) => (),
+            _ => panic!("expected error response")
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
 use hyper::error::{Error as HyperError, Result as HyperResult};
 use hyper::net::{HttpStream, NetworkStream, SslClient};
 use openssl::pkcs12::{ParsedPkcs12, Pkcs12 as OpensslPkcs12};
@@ -12,7 +13,7 @@ use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use datatype::Util;
+use datatype::{Error, Util};
 
 
 lazy_static! {
@@ -73,7 +74,18 @@ impl Pkcs12 {
 }
 
 
+/// Read the expiry (notAfter) time of a PEM-encoded certificate.
+pub fn cert_not_after(cert_file: &str) -> Result<DateTime<Utc>, Error> {
+    let cert = X509::from_pem(&Util::read_file(cert_file)?)?;
+    // printed as e.g. "Jan  5 12:00:00 2020 GMT"
+    let text = format!("{}", cert.not_after()).split_whitespace().collect::<Vec<_>>().join(" ");
+    let naive = NaiveDateTime::parse_from_str(&text, "%b %d %H:%M:%S %Y GMT")?;
+    Ok(DateTime::from_utc(naive, Utc))
+}
+
+
 /// TLS client for HTTPS communication.
+#[derive(Clone)]
 pub struct TlsClient(Arc<TlsConnector>);
 
 impl TlsClient {
@@ -81,6 +93,11 @@ impl TlsClient {
     pub fn init(tls: TlsData) {
         *CONNECTOR.lock().unwrap() = Some(Arc::new(TlsConnector::new(tls)));
     }
+
+    /// Whether both clients were created from the same `TlsClient::init` call.
+    pub fn same_connector(&self, other: &TlsClient) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
 impl Default for TlsClient {
@@ -1,18 +1,20 @@
 use chrono::{DateTime, Utc};
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use flate2::read::GzDecoder;
 use std::collections::{HashMap, HashSet};
 use std::fs::{self, File, OpenOptions};
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::Path;
 use std::os::unix::fs::FileExt;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
 
 use datatype::{Error, Util};
 
 
-const CHUNK_DIR: &'static str = "/tmp/sota-image-chunks";
+pub const CHUNK_DIR: &'static str = "/tmp/sota-image-chunks";
 const CHUNK_SIZE: usize = 64*1024;
 
 
@@ -25,6 +27,39 @@ impl Default for Chunk {
 }
 
 
+/// The compression applied to each chunk of an image while in transit.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkCompression {
+    Gzip,
+}
+
+impl FromStr for ChunkCompression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ChunkCompression, Error> {
+        match s.to_lowercase().as_str() {
+            "gzip" => Ok(ChunkCompression::Gzip),
+            _ => Err(Error::Image(format!("unsupported chunk compression: {}", s)))
+        }
+    }
+}
+
+impl ChunkCompression {
+    /// Decompress a single chunk of data, failing when it expands to more
+    /// than `CHUNK_SIZE` bytes.
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        match *self {
+            ChunkCompression::Gzip => { let _ = GzDecoder::new(data)?.take(CHUNK_SIZE as u64 + 1).read_to_end(&mut out)?; }
+        }
+        if out.len() > CHUNK_SIZE {
+            return Err(Error::Image(format!("decompressed chunk is larger than {} bytes", CHUNK_SIZE)));
+        }
+        Ok(out)
+    }
+}
+
+
 /// Metadata regarding an image to be transferred between ECUs in chunks.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ImageMeta {
@@ -107,6 +142,8 @@ impl ImageReader {
 pub struct ImageWriter {
     pub meta: ImageMeta,
     pub image_dir: String,
+    pub chunks_dir: String,
+    pub compression: Option<ChunkCompression>,
     pub last_written: DateTime<Utc>,
     pub chunks_written: HashSet<u64>,
     pub chunks_available: HashSet<u64>,
@@ -119,6 +156,8 @@ impl ImageWriter {
         ImageWriter {
             meta: meta,
             image_dir: image_dir,
+            chunks_dir: CHUNK_DIR.into(),
+            compression: None,
             last_written: Utc::now(),
             chunks_written: HashSet::new(),
             chunks_available: chunks,
@@ -127,7 +166,7 @@ impl ImageWriter {
 
     /// Write a specific chunk of an image to disk for re-assembly.
     pub fn write_chunk(&mut self, data: &[u8], index: u64) -> Result<(), Error> {
-        let chunk_path = format!("{}/{}/{}", CHUNK_DIR, self.meta.image_name, index);
+        let chunk_path = format!("{}/{}/{}", self.chunks_dir, self.meta.image_name, index);
         let path = Path::new(&chunk_path);
         if let Some(dir) = path.parent() { fs::create_dir_all(dir)?; }
         trace!("saving chunk {} to {}", index, chunk_path);
@@ -143,7 +182,7 @@ impl ImageWriter {
         if ! self.chunks_available.is_empty() {
             return Err(Error::Image(format!("{} chunks remaining", self.chunks_available.len())))
         }
-        let chunks_dir = format!("{}/{}", CHUNK_DIR, self.meta.image_name);
+        let chunks_dir = format!("{}/{}", self.chunks_dir, self.meta.image_name);
         let mut indices = fs::read_dir(&chunks_dir)?
             .map(|entry| {
                 entry.map_err(|err| Error::Image(format!("bad entry: {}", err)))
@@ -227,7 +266,7 @@ impl ImageWriter {
 
 impl Drop for ImageWriter {
     fn drop(&mut self) {
-        let _ = fs::remove_dir_all(format!("{}/{}", CHUNK_DIR, self.meta.image_name));
+        let _ = fs::remove_dir_all(format!("{}/{}", self.chunks_dir, self.meta.image_name));
     }
 }
 
@@ -238,19 +277,30 @@ pub struct Transfers {
     pub active: HashMap<String, ImageWriter>,
     pub image_sizes: HashMap<String, u64>,
     pub images_dir: String,
+    pub chunks_dir: String,
     pub timeout: Duration,
+    pub headroom: u64,
+    pub max_active: usize,
 }
 
 impl Transfers {
-    pub fn new(images_dir: String, timeout: Duration) -> Self {
+    pub fn new(images_dir: String, timeout: Duration, headroom: u64, max_active: usize) -> Self {
         Transfers {
             active: HashMap::new(),
             image_sizes: HashMap::new(),
             images_dir: images_dir,
+            chunks_dir: CHUNK_DIR.into(),
             timeout: timeout,
+            headroom: headroom,
+            max_active: max_active,
         }
     }
 
+    /// Returns true when no more transfers may be started.
+    pub fn is_full(&self) -> bool {
+        self.active.len() >= self.max_active
+    }
+
     pub fn prune(&mut self) {
         let inactive = self.active.iter()
             .filter_map(|(name, image)| {
@@ -263,15 +313,105 @@ impl Transfers {
             self.active.remove(&image_name);
         }
     }
+
+    /// Abort all active transfers, removing any partially written images.
+    /// Returns the number of transfers aborted.
+    pub fn abort_all(&mut self) -> usize {
+        let count = self.active.len();
+        for (name, writer) in self.active.drain() {
+            let path = format!("{}/{}", writer.image_dir, name);
+            if Path::new(&path).exists() {
+                fs::remove_file(&path).unwrap_or_else(|err| error!("couldn't remove {}: {}", path, err));
+            }
+            info!("Aborted image transfer: {}", name);
+        }
+        count
+    }
+}
+
+
+/// Returns the path of the verified sha256 checksum saved alongside an artifact.
+pub fn checksum_path(artifact: &str) -> String {
+    format!("{}.sha256", artifact)
+}
+
+/// Remove any verified checksum saved alongside an artifact.
+pub fn remove_checksum(artifact: &str) {
+    let path = checksum_path(artifact);
+    if Path::new(&path).exists() {
+        fs::remove_file(&path).unwrap_or_else(|err| error!("couldn't remove {}: {}", path, err));
+    }
+}
+
+/// Remove the partial downloads (files named by their update id with a `.part`
+/// extension) left in a directory, returning the number of files removed.
+pub fn remove_partial_downloads(dir: &str) -> Result<usize, Error> {
+    if ! Path::new(dir).is_dir() {
+        return Ok(0);
+    }
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_partial = name.ends_with(".part") && Uuid::parse_str(&name[..name.len() - 5]).is_ok();
+        if is_partial && entry.metadata()?.is_file() {
+            fs::remove_file(entry.path())?;
+            debug!("removed partial download {}/{}", dir, name);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Remove the downloaded update artifacts (files named by their update id)
+/// from each directory, skipping the names in `keep` and any file modified
+/// within `older_than`. Returns the number of bytes freed and files removed.
+pub fn remove_artifacts(dirs: &[&str], keep: &HashSet<String>, older_than: Option<Duration>) -> Result<(u64, usize), Error> {
+    let mut freed = 0;
+    let mut count = 0;
+    for dir in dirs {
+        if ! Path::new(dir).is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if keep.contains(&name) || Uuid::parse_str(&name).is_err() {
+                continue;
+            }
+            let meta = entry.metadata()?;
+            if ! meta.is_file() {
+                continue;
+            }
+            if let Some(min_age) = older_than {
+                let age = meta.modified().ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .unwrap_or(Duration::from_secs(0));
+                if age < min_age {
+                    continue;
+                }
+            }
+            fs::remove_file(entry.path())?;
+            remove_checksum(&format!("{}/{}", dir, name));
+            debug!("removed artifact {}/{}", dir, name);
+            freed += meta.len();
+            count += 1;
+        }
+    }
+    Ok((freed, count))
 }
 
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
     use ring::rand::{SecureRandom, SystemRandom};
+    use std::io::Write;
 
     use datatype::Util;
+    use pacman::test::TestDir;
 
 
     fn new_reader(image_name: String, image_dir: String, data: &[u8]) -> ImageReader {
@@ -312,4 +452,64 @@ mod test {
         let written = Util::read_file(&format!("{}/{}", dir, outfile)).expect("written");
         assert_eq!(&written[..], &buf[..]);
     }
+
+    #[test]
+    fn abort_all_transfers() {
+        let dir = TestDir::new("sota-test-abort");
+        let meta = ImageMeta::new("partial.dat".into(), 2 * CHUNK_SIZE as u64, 2, "".into());
+        let mut writer = ImageWriter::new(meta, dir.0.clone());
+        writer.write_direct(&[1; CHUNK_SIZE], 0).expect("write direct");
+        let partial = format!("{}/partial.dat", dir.0);
+        assert!(Path::new(&partial).exists());
+
+        let mut transfers = Transfers::new(dir.0.clone(), Duration::from_secs(60), 0, 10);
+        transfers.active.insert("partial.dat".into(), writer);
+        assert_eq!(transfers.abort_all(), 1);
+        assert!(transfers.active.is_empty());
+        assert!(! Path::new(&partial).exists());
+        assert_eq!(transfers.abort_all(), 0);
+    }
+
+    #[test]
+    fn remove_orphaned_partial_downloads() {
+        let dir = TestDir::new("sota-test-partials");
+        let partial = format!("{}/{}.part", dir.0, Uuid::new_v4());
+        for path in &[partial.clone(), format!("{}/notes.part", dir.0), format!("{}/{}", dir.0, Uuid::new_v4())] {
+            Util::write_file(path, b"partial").expect("write file");
+        }
+        assert_eq!(remove_partial_downloads(&dir.0).unwrap(), 1);
+        assert!(! Path::new(&partial).exists());
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 2);
+        assert_eq!(remove_partial_downloads(&format!("{}/missing", dir.0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn remove_update_artifacts() {
+        let dir = TestDir::new("sota-test-artifacts");
+        let dir = &dir.0;
+        let installed = format!("{}", Uuid::new_v4());
+        let active = format!("{}", Uuid::new_v4());
+        for name in &[installed.as_str(), active.as_str(), "unrelated.txt"] {
+            Util::write_file(&format!("{}/{}", dir, name), &[0; 100]).expect("write artifact");
+        }
+
+        let keep = hashset!{ active.clone() };
+        assert_eq!(remove_artifacts(&[dir.as_str()], &keep, Some(Duration::from_secs(3600))).unwrap(), (0, 0));
+        assert_eq!(remove_artifacts(&[dir.as_str()], &keep, None).unwrap(), (100, 1));
+        assert!(! Path::new(&format!("{}/{}", dir, installed)).exists());
+        assert!(Path::new(&format!("{}/{}", dir, active)).exists());
+        assert!(Path::new(&format!("{}/unrelated.txt", dir)).exists());
+    }
+
+    #[test]
+    fn decompress_bounded() {
+        let compress = |data: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let chunk = vec![7; CHUNK_SIZE];
+        assert_eq!(ChunkCompression::Gzip.decompress(&compress(&chunk)).expect("decompress"), chunk);
+        assert!(ChunkCompression::Gzip.decompress(&compress(&vec![7; CHUNK_SIZE + 1])).is_err());
+    }
 }
@@ -0,0 +1,200 @@
+use json;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use uuid::Uuid;
+
+use datatype::{DeviceConfig, Error, InstallCode, InstallResult, Ostree, OstreePackage, Util};
+use pacman::PacMan;
+use sota;
+
+
+/// Records the update being installed so that an install interrupted by a
+/// reboot is still reported on restart. Ostree and Uptane deployments keep the
+/// marker after installing so the next startup can check the booted commit,
+/// rolling back to the `previous` commit when the health check fails.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InstallMarker {
+    pub update_id:       Uuid,
+    pub package_manager: PacMan,
+    pub commit:          Option<String>,
+    #[serde(default)]
+    pub previous:        Option<String>,
+}
+
+impl InstallMarker {
+    /// Create a marker for installing the package at `package_path`, reading
+    /// the expected commit from it for ostree packages.
+    pub fn new(update_id: Uuid, package_manager: PacMan, package_path: &str) -> Self {
+        let commit = if package_manager == PacMan::Ostree {
+            Util::read_file(package_path).ok()
+                .and_then(|data| json::from_slice::<OstreePackage>(&data).ok())
+                .map(|pkg| pkg.commit)
+        } else {
+            None
+        };
+        let previous = if package_manager == PacMan::Ostree { booted_commit().ok() } else { None };
+        InstallMarker { update_id: update_id, package_manager: package_manager, commit: commit, previous: previous }
+    }
+
+    /// Write the marker before the package manager is started.
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        Util::write_file(path, &json::to_vec(self)?)
+    }
+
+    /// Read the marker left at `path`, if any.
+    pub fn load(path: &str) -> Result<Option<Self>, Error> {
+        if Path::new(path).exists() {
+            Ok(Some(json::from_slice(&Util::read_file(path)?)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Create a marker for an Uptane install, expecting the primary to boot
+    /// this commit when it has a target.
+    pub fn uptane(commit: Option<String>) -> Self {
+        let previous = commit.as_ref().and_then(|_| booted_commit().ok());
+        InstallMarker { update_id: Uuid::nil(), package_manager: PacMan::Uptane, commit: commit, previous: previous }
+    }
+
+    /// Whether the install is only complete once its commit has booted.
+    fn checks_boot(&self) -> bool {
+        match self.package_manager {
+            PacMan::Ostree | PacMan::Uptane => true,
+            _ => false
+        }
+    }
+
+    /// Remove the marker once the outcome of the install is known.
+    pub fn remove(path: &str) {
+        match fs::remove_file(path) {
+            Err(ref err) if err.kind() != ErrorKind::NotFound => error!("couldn't remove install marker {}: {}", path, err),
+            _ => ()
+        }
+    }
+
+    /// Returns the result of an install that was interrupted, comparing the
+    /// booted ostree commit with the expected one.
+    pub fn resolve(&self, booted: Result<String, Error>) -> InstallResult {
+        let id = format!("{}", self.update_id);
+        match (self.checks_boot(), self.commit.as_ref(), booted) {
+            (true, Some(expected), Ok(ref booted)) if booted == expected => {
+                InstallResult::new(id, InstallCode::OK, format!("booted the installed commit {}", booted))
+            }
+            (true, Some(expected), Ok(booted)) => {
+                InstallResult::new(id, InstallCode::INSTALL_FAILED, format!("expected to boot commit {}, booted {}", expected, booted))
+            }
+            (true, Some(_), Err(err)) => {
+                InstallResult::new(id, InstallCode::GENERAL_ERROR, format!("couldn't read the booted commit: {}", err))
+            }
+            _ => InstallResult::new(id, InstallCode::GENERAL_ERROR, "install interrupted by a restart".into())
+        }
+    }
+
+    /// Check for a marker left by an install interrupted by a reboot,
+    /// returning the result to report then removing the marker. Once the
+    /// expected commit has booted the post-install health check is run.
+    pub fn recover(device: &DeviceConfig) -> Option<InstallResult> {
+        let path = match device.install_marker_file {
+            Some(ref path) => path,
+            None => return None
+        };
+        Self::recover_with(path, booted_commit, |marker| {
+            let previous = marker.previous.as_ref().map(String::as_str);
+            sota::check_health(device, &marker.update_id, previous).map(|(result, _)| result)
+        })
+    }
+
+    fn recover_with<B, H>(path: &str, booted: B, health: H) -> Option<InstallResult>
+        where B: FnOnce() -> Result<String, Error>,
+              H: FnOnce(&InstallMarker) -> Option<InstallResult>
+    {
+        let marker = match Self::load(path) {
+            Ok(Some(marker)) => marker,
+            Ok(None) => return None,
+            Err(err) => {
+                error!("couldn't read install marker {}: {}", path, err);
+                Self::remove(path);
+                return None;
+            }
+        };
+        info!("Install of {} was interrupted, checking its outcome", marker.update_id);
+        let mut result = marker.resolve(if marker.checks_boot() { booted() } else { Ok(String::new()) });
+        if marker.checks_boot() && result.result_code == InstallCode::OK {
+            if let Some(failed) = health(&marker) {
+                result = failed;
+            }
+        }
+        Self::remove(path);
+        Some(result)
+    }
+}
+
+
+/// Returns the ostree commit that is currently booted.
+fn booted_commit() -> Result<String, Error> {
+    Ostree::get_current("", "").map(|pkg| pkg.commit)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use datatype::Url;
+    use pacman::test::TestDir;
+
+
+    #[test]
+    fn test_recover_booted_commit() {
+        let dir = TestDir::new("sota-install-marker");
+        let package = format!("{}/package", dir.0);
+        let marker_path = format!("{}/install.marker", dir.0);
+        let treehub: Url = "http://localhost/treehub".parse().unwrap();
+        let pkg = OstreePackage::new("".into(), "sota".into(), "abc123".into(), &treehub);
+        Util::write_file(&package, &json::to_vec(&pkg).unwrap()).unwrap();
+
+        let marker = InstallMarker::new(Uuid::default(), PacMan::Ostree, &package);
+        assert_eq!(marker.commit, Some("abc123".into()));
+        marker.save(&marker_path).expect("save marker");
+
+        let result = InstallMarker::recover_with(&marker_path, || Ok("abc123".into()), |_| None).expect("result");
+        assert_eq!(result.id, format!("{}", Uuid::default()));
+        assert_eq!(result.result_code, InstallCode::OK);
+        assert!(!Path::new(&marker_path).exists());
+        assert!(InstallMarker::recover_with(&marker_path, || Ok("abc123".into()), |_| None).is_none());
+
+        marker.save(&marker_path).expect("save marker");
+        let result = InstallMarker::recover_with(&marker_path, || Ok("def456".into()), |_| panic!("health checked")).expect("result");
+        assert_eq!(result.result_code, InstallCode::INSTALL_FAILED);
+    }
+
+    #[test]
+    fn test_recover_uptane_commit() {
+        let dir = TestDir::new("sota-install-marker-uptane");
+        let marker_path = format!("{}/install.marker", dir.0);
+        InstallMarker::uptane(Some("abc123".into())).save(&marker_path).expect("save marker");
+        let result = InstallMarker::recover_with(&marker_path, || Ok("abc123".into()), |_| None).expect("result");
+        assert_eq!(result.result_code, InstallCode::OK);
+
+        InstallMarker::uptane(Some("abc123".into())).save(&marker_path).expect("save marker");
+        let result = InstallMarker::recover_with(&marker_path, || Ok("def456".into()), |_| panic!("health checked")).expect("result");
+        assert_eq!(result.result_code, InstallCode::INSTALL_FAILED);
+    }
+
+    #[test]
+    fn test_recover_unhealthy_commit() {
+        let dir = TestDir::new("sota-install-marker-health");
+        let marker_path = format!("{}/install.marker", dir.0);
+        let mut marker = InstallMarker::uptane(Some("abc123".into()));
+        marker.previous = Some("def456".into());
+        marker.save(&marker_path).expect("save marker");
+        let result = InstallMarker::recover_with(&marker_path, || Ok("abc123".into()), |marker| {
+            assert_eq!(marker.previous, Some("def456".into()));
+            Some(InstallResult::new("id".into(), InstallCode::INSTALL_FAILED, "health check failed".into()))
+        }).expect("result");
+        assert_eq!(result.result_code, InstallCode::INSTALL_FAILED);
+        assert_eq!(result.result_text, "health check failed");
+    }
+}
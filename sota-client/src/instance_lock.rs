@@ -0,0 +1,103 @@
+use libc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use datatype::Error;
+
+
+/// An exclusive lock on a file holding the process id, preventing more than
+/// one client instance from installing updates at the same time. The file is
+/// removed while still locked then the lock released when dropped.
+pub struct InstanceLock {
+    path: String,
+    file: File,
+}
+
+impl InstanceLock {
+    /// Take the lock at `path` then write the current process id to it,
+    /// failing if another instance already holds it.
+    pub fn acquire(path: &str) -> Result<Self, Error> {
+        loop {
+            let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)
+                .map_err(|err| Error::Client(format!("couldn't open lock file {}: {}", path, err)))?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                    return Err(Error::Io(err));
+                }
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(Error::Client(format!("{} is held by another instance (pid {})", path, pid.trim())));
+            }
+
+            // the previous holder removes the file before unlocking it, so
+            // the lock only counts if the file is still the one at the path
+            if ! InstanceLock::is_current(&file, path) {
+                debug!("lock file {} was replaced after opening, retrying", path);
+                continue;
+            }
+
+            file.set_len(0)?;
+            let _ = file.seek(SeekFrom::Start(0))?;
+            file.write_all(format!("{}\n", unsafe { libc::getpid() }).as_bytes())?;
+            file.flush()?;
+            return Ok(InstanceLock { path: path.into(), file: file });
+        }
+    }
+
+    /// Returns true if the opened file is the one currently at `path`.
+    fn is_current(file: &File, path: &str) -> bool {
+        match (file.metadata(), fs::metadata(path)) {
+            (Ok(opened), Ok(current)) => opened.dev() == current.dev() && opened.ino() == current.ino(),
+            _ => false
+        }
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN); }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use pacman::test::TestDir;
+
+
+    #[test]
+    fn test_second_instance_fails() {
+        let dir = TestDir::new("sota-instance-lock");
+        let path = format!("{}/sota.pid", dir.0);
+        let lock = InstanceLock::acquire(&path).expect("first lock");
+        match InstanceLock::acquire(&path) {
+            Err(Error::Client(err)) => assert_eq!(err, format!("{} is held by another instance (pid {})", path, unsafe { libc::getpid() })),
+            Err(err) => panic!("expected a held lock error, got {}", err),
+            Ok(_) => panic!("expected the second lock to fail")
+        }
+
+        drop(lock);
+        assert!(!Path::new(&path).exists());
+        let _ = InstanceLock::acquire(&path).expect("lock after release");
+    }
+
+    #[test]
+    fn test_replaced_file_not_current() {
+        let dir = TestDir::new("sota-instance-lock-replaced");
+        let path = format!("{}/sota.pid", dir.0);
+        let file = File::create(&path).expect("create lock file");
+        assert!(InstanceLock::is_current(&file, &path));
+
+        fs::remove_file(&path).expect("remove lock file");
+        assert!(! InstanceLock::is_current(&file, &path));
+        let _ = File::create(&path).expect("replace lock file");
+        assert!(! InstanceLock::is_current(&file, &path));
+    }
+}
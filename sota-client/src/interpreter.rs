@@ -1,18 +1,44 @@
 use chan::{Sender, Receiver};
+use chrono::{self, DateTime, Utc};
+use json;
 use std::cell::RefCell;
-use std::process::{self, Command as ShellCommand};
+use std::collections::HashSet;
+use std::{fs, mem};
+use std::path::Path;
+use std::process;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
 
 use authenticate::oauth2;
-use datatype::{Auth, Command, Config, EcuCustom, Error, Event, InstallCode,
-               InstallOutcome, InstallResult, RoleName, RequestStatus, Url};
-use http::{AuthClient, Client};
+use bandwidth::{Bandwidth, DownloadMeter};
+use bounded::Critical;
+use broadcast::History;
+use datatype::{AbortedUpdates, Auth, AuthBackoff, Clock, Command, Config, EcuCustom, Error, Event, InstallCode,
+               InstallOutcome, InstallQueue, InstallResult, Ostree, RoleName, RequestStatus,
+               SystemClock, SystemInfo, UpdateRequest, Url, Util};
+#[cfg(feature = "rvi")]
+use datatype::{InstalledPackage, InstalledSoftware};
+use diagnostics::{Diagnostics, MetadataVersions};
+use http::{AuthClient, Client, ResponseData};
+use images;
+use logging::ReloadableLogger;
+use metrics::Metrics;
 use pacman::{Credentials, PacMan};
+use report_cache::ReportCache;
 #[cfg(feature = "rvi")]
 use rvi::Services;
+use schedule::InstallSchedule;
 use sota::Sota;
-use uptane::Uptane;
+use spool::ReportSpool;
+use token_cache::TokenCache;
+use uptane::{Uptane, Verified};
+
 
+/// The maximum number of response body bytes included in an `HttpError`.
+const HTTP_EXCERPT_LEN: usize = 256;
 
 /// An `Interpreter` loops over any incoming values, on receipt of which it
 /// delegates to the `interpret` function which will respond with output values.
@@ -27,33 +53,109 @@ pub trait Interpreter<I, O> {
 }
 
 
+/// A callback run for each `Event` received by the `EventInterpreter`.
+///
+/// Hooks run synchronously on the interpreter's thread in the order they were
+/// registered, so they must be `Send` and should return quickly. Every event is
+/// broadcast to several threads, so any state shared with other threads needs
+/// its own synchronization.
+pub type EventHook = Box<Fn(&Event) + Send>;
+
+/// A callback run for each `Command` before the `CommandInterpreter` processes
+/// it, returning `false` to veto the command. The same threading expectations
+/// as an `EventHook` apply.
+pub type CommandHook = Box<Fn(&Command) -> bool + Send>;
+
+
 /// The `EventInterpreter` listens for `Event`s and queues `Command`s for processing.
 pub struct EventInterpreter {
     pub initial: bool,
     pub loop_tx: Sender<Event>,
     pub auth:    Auth,
     pub pacman:  PacMan,
-    pub auto_dl: bool,
-    pub sysinfo: Option<String>,
+    /// Whether pending updates are downloaded automatically, shared with the
+    /// `CommandInterpreter` so that changes apply to the next poll.
+    pub auto_dl: Arc<Mutex<bool>>,
+    /// Updates waiting for the user to confirm them, shared with the
+    /// `CommandInterpreter` so that their artifacts aren't cleaned up.
+    pub pending: Arc<Mutex<HashSet<Uuid>>>,
+    pub sysinfo: Option<SystemInfo>,
+    pub hooks:   Vec<EventHook>,
+    pub backoff: AuthBackoff,
+    /// Whether an `Authenticate` command will be sent once the backoff delay
+    /// after a failure has passed.
+    pub retrying: Arc<Mutex<bool>>,
+    pub aborted: AbortedUpdates,
+    /// Events broadcast by `CheckUpdates`, shared with the `CommandInterpreter`
+    /// so that previewed updates aren't downloaded or installed.
+    pub previews: Arc<Mutex<Vec<Event>>>,
 }
 
 impl Interpreter<Event, CommandExec> for EventInterpreter {
     fn interpret(&mut self, event: Event, ctx: &Sender<CommandExec>) {
         info!("EventInterpreter received: {}", event);
+        Metrics::update(|metrics| metrics.observe(&event, Utc::now()));
+        for hook in &self.hooks {
+            hook(&event);
+        }
+        self.handle(event, ctx);
+    }
+}
+
+impl EventInterpreter {
+    /// Queue the commands for an event.
+    fn handle(&mut self, event: Event, ctx: &Sender<CommandExec>) {
         let queue = |cmd| ctx.send(CommandExec { cmd: cmd, etx: None });
+        if self.take_preview(&event) {
+            debug!("not acting on previewed updates");
+            return;
+        }
 
         match event {
-            Event::Authenticated if self.initial => {
-                self.loop_tx.send(Event::InstalledPackagesNeeded);
-                self.loop_tx.send(Event::SystemInfoNeeded);
-                self.loop_tx.send(Event::UptaneManifestNeeded);
-                self.initial = false;
+            Event::Authenticated => {
+                self.backoff.reset();
+                if self.initial {
+                    self.loop_tx.send(Event::InstalledPackagesNeeded);
+                    self.loop_tx.send(Event::SystemInfoNeeded);
+                    self.loop_tx.send(Event::UptaneManifestNeeded);
+                    queue(Command::SendPendingReports);
+                    self.initial = false;
+                }
             }
 
             Event::DownloadComplete(ref dl) if self.pacman != PacMan::Off => {
                 queue(Command::StartInstall(dl.update_id));
             }
 
+            #[cfg(any(test, feature = "debug"))]
+            Event::PackageManagerChanged(ref pacman) => {
+                self.pacman = pacman.clone();
+            }
+
+            Event::UpdateAvailable(ref avail) if avail.request_confirmation => {
+                match avail.update_id.parse::<Uuid>() {
+                    Ok(id) => { let _ = self.pending.lock().unwrap().insert(id); }
+                    Err(err) => error!("couldn't parse update id {}: {}", avail.update_id, err)
+                }
+            }
+
+            Event::UpdateApproved(id) => {
+                if self.pending.lock().unwrap().remove(&id) {
+                    queue(Command::StartDownload(id));
+                } else {
+                    warn!("no update {} is waiting for confirmation", id);
+                }
+            }
+
+            Event::UpdateDenied(id) => {
+                if self.pending.lock().unwrap().remove(&id) {
+                    let result = InstallResult::new(format!("{}", id), InstallCode::USER_DECLINED, "declined by user".into());
+                    queue(Command::SendInstallReport(result.into_report()));
+                } else {
+                    warn!("no update {} is waiting for confirmation", id);
+                }
+            }
+
             Event::DownloadFailed(id, reason) => {
                 let result = InstallResult::new(format!("{}", id), InstallCode::GENERAL_ERROR, reason);
                 queue(Command::SendInstallReport(result.into_report()));
@@ -75,7 +177,27 @@ impl Interpreter<Event, CommandExec> for EventInterpreter {
             }
 
             Event::NotAuthenticated => {
-                queue(Command::Authenticate(self.auth.clone()));
+                if *self.retrying.lock().unwrap() {
+                    debug!("not authenticating until the pending retry");
+                } else {
+                    queue(Command::Authenticate(self.auth.clone()));
+                }
+            }
+
+            Event::AuthenticationFailed(ref reason) => {
+                let delay = self.backoff.next_attempt();
+                warn!("authentication failed {} times ({}), retrying in {} seconds", self.backoff.failures, reason, delay.as_secs());
+                self.loop_tx.send(Event::AuthenticationBackoff { next_attempt_secs: delay.as_secs() });
+                let mut retrying = self.retrying.lock().unwrap();
+                if ! *retrying {
+                    *retrying = true;
+                    let (ctx, auth, retry) = (ctx.clone(), self.auth.clone(), self.retrying.clone());
+                    thread::spawn(move || {
+                        thread::sleep(delay);
+                        *retry.lock().unwrap() = false;
+                        ctx.send(CommandExec { cmd: Command::Authenticate(auth), etx: None });
+                    });
+                }
             }
 
             Event::SystemInfoNeeded => {
@@ -86,13 +208,18 @@ impl Interpreter<Event, CommandExec> for EventInterpreter {
                 for request in requests {
                     let id = request.requestId;
                     match request.status {
-                        RequestStatus::Pending if self.auto_dl => queue(Command::StartDownload(id)),
+                        RequestStatus::Pending if *self.auto_dl.lock().unwrap() => queue(Command::StartDownload(id)),
+                        RequestStatus::Pending => { let _ = self.pending.lock().unwrap().insert(id); }
                         RequestStatus::InFlight if self.pacman == PacMan::Off => (),
                         RequestStatus::InFlight if self.pacman.is_installed(&request.packageId) => {
                             let result = InstallResult::new(format!("{}", id), InstallCode::OK, "<generated>".to_string());
                             queue(Command::SendInstallReport(result.into_report()));
                         }
                         RequestStatus::InFlight => queue(Command::StartDownload(id)),
+                        RequestStatus::Canceled => {
+                            self.aborted.cancel(id);
+                            queue(Command::ServerAbort(id));
+                        }
                         _ => ()
                     }
                 }
@@ -102,6 +229,10 @@ impl Interpreter<Event, CommandExec> for EventInterpreter {
                 queue(Command::UptaneSendManifest(Some(manifests)));
             }
 
+            Event::UptaneKeyRotated(_) => {
+                queue(Command::UptaneSendManifest(None));
+            }
+
             Event::UptaneManifestNeeded if self.pacman == PacMan::Uptane => {
                 queue(Command::UptaneSendManifest(None));
             }
@@ -113,16 +244,31 @@ impl Interpreter<Event, CommandExec> for EventInterpreter {
             _ => ()
         }
     }
+
+    /// Returns true and forgets the event if it was broadcast by `CheckUpdates`.
+    fn take_preview(&self, event: &Event) -> bool {
+        let mut previews = self.previews.lock().unwrap();
+        match previews.iter().position(|preview| preview == event) {
+            Some(index) => { let _ = previews.remove(index); true }
+            None => false
+        }
+    }
 }
 
 
 /// Wraps a `Command` for execution and (optionally) waits for the outcome `Event`.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CommandExec {
     pub cmd: Command,
     pub etx: Option<Sender<Event>>,
 }
 
+impl Critical for CommandExec {
+    fn is_critical(&self) -> bool {
+        self.cmd.is_critical()
+    }
+}
+
 /// Toggles the `CommandInterpreter`'s handling procedure.
 #[derive(Clone)]
 pub enum CommandMode {
@@ -140,76 +286,262 @@ pub struct CommandInterpreter {
     pub auth: Auth,
     pub http: Box<Client>,
     pub version: Option<String>,
+    pub queue: InstallQueue,
+    pub hooks: Vec<CommandHook>,
+    pub clock: Arc<Clock>,
+    pub token_expiry: Option<DateTime<Utc>>,
+    /// Commands received after the access token expired, run once the client
+    /// has authenticated again.
+    pub awaiting_auth: Vec<CommandExec>,
+    pub schedule: InstallSchedule,
+    pub history: History<Event>,
+    pub reports: ReportCache,
+    /// Rate-limited downloads that finished on a background thread, with
+    /// whether each one succeeded.
+    pub throttled: Arc<Mutex<Vec<(Uuid, bool)>>>,
+    /// Uptane targets whose download was deferred by the bandwidth rules,
+    /// installed again by the first poll after this time.
+    pub deferred_targets: Option<(Box<Verified>, DateTime<Utc>)>,
+    /// The commands that would start a download or install while paused, run
+    /// when resumed. Only the latest Uptane targets are kept.
+    pub paused: Option<Vec<Command>>,
+    /// Updates canceled by the server, shared with the `EventInterpreter` so
+    /// that in-flight work stops before the `ServerAbort` command is handled.
+    pub aborted: AbortedUpdates,
+    /// Updates waiting for the user to confirm them, shared with the
+    /// `EventInterpreter`.
+    pub pending: Arc<Mutex<HashSet<Uuid>>>,
+    /// Events broadcast by `CheckUpdates` that the `EventInterpreter` ignores.
+    pub previews: Arc<Mutex<Vec<Event>>>,
+    /// Whether pending updates are downloaded automatically, shared with the
+    /// `EventInterpreter`.
+    pub auto_dl: Arc<Mutex<bool>>,
 }
 
 impl Interpreter<CommandExec, Event> for  CommandInterpreter {
     fn interpret(&mut self, exec: CommandExec, etx: &Sender<Event>) {
         info!("CommandInterpreter received: {}", &exec.cmd);
-        let event = match self.process_command(exec.cmd, etx) {
-            Ok(ev) => ev,
-            Err(Error::HttpAuth(resp)) => { error!("{}", resp); Event::NotAuthenticated }
-            Err(err) => Event::Error(err.to_string())
+        self.finish_throttled(etx);
+        if self.token_expired(&exec.cmd) && (! self.awaiting_auth.is_empty() || ! self.refresh_token(etx)) {
+            warn!("access token expired, running after authenticating: {}", exec.cmd);
+            self.awaiting_auth.retain(|waiting| waiting.cmd != exec.cmd);
+            self.awaiting_auth.push(exec);
+            etx.send(Event::NotAuthenticated);
+            return;
+        }
+
+        let preview = exec.cmd == Command::CheckUpdates;
+        let event = if self.hooks.iter().any(|hook| ! hook(&exec.cmd)) {
+            warn!("command vetoed by hook: {}", exec.cmd);
+            Event::Error(format!("command vetoed by hook: {}", exec.cmd))
+        } else {
+            match self.process_command(exec.cmd, etx) {
+                Ok(ev) => ev,
+                Err(Error::HttpAuth(resp)) => {
+                    error!("{}", resp);
+                    etx.send(http_error(&resp));
+                    Event::NotAuthenticated
+                }
+                Err(Error::Http(resp)) => { error!("{}", resp); http_error(&resp) }
+                Err(err) => Event::Error(err.to_string())
+            }
         };
+
+        if let CommandMode::Uptane(ref uptane) = self.mode {
+            if let Some(skew) = uptane.borrow_mut().take_clock_skew() {
+                etx.send(Event::ClockSkewDetected { skew_sec: skew });
+            }
+            for mismatch in uptane.borrow_mut().take_hash_mismatches() {
+                etx.send(mismatch);
+            }
+        }
         exec.etx.map(|etx| etx.send(event.clone()));
-        etx.send(event);
+        let authenticated = event == Event::Authenticated;
+
+        // replayed events are only returned to the caller
+        match event {
+            Event::UpdatesReceived(_) | Event::UptaneTargetsUpdated(_) if preview => {
+                self.previews.lock().unwrap().push(event.clone());
+                etx.send(event)
+            }
+            Event::RecentEvents(_) => (),
+            _ => etx.send(event)
+        }
+
+        if authenticated {
+            for exec in mem::replace(&mut self.awaiting_auth, Vec::new()) {
+                self.interpret(exec, etx);
+            }
+        }
     }
 }
 
 impl CommandInterpreter {
     fn process_command(&mut self, cmd: Command, etx: &Sender<Event>) -> Result<Event, Error> {
+        if self.paused.is_some() && defers_while_paused(&cmd) {
+            let name = cmd.name();
+            info!("Paused, deferring: {}", cmd);
+            let deferred = self.paused.as_mut().expect("paused");
+            deferred.retain(|queued| *queued != cmd && ! (queued.name() == "UptaneStartInstall" && name == "UptaneStartInstall"));
+            deferred.push(cmd);
+            return Ok(Event::CommandDeferred(name.into()));
+        }
+
         let event = match (cmd, self.mode.clone()) {
             (Command::Authenticate(creds @ Auth::Credentials(_)), _) => {
-                let server = self.config.auth.as_ref().expect("auth config").server.join("/token");
-                if self.http.is_testing() {
-                    self.auth = Auth::Token(oauth2(server, &*self.http)?);
+                let config = self.config.auth.as_ref().expect("auth config");
+                let max_bytes = self.config.network.max_response_bytes;
+                let token = match config.server.try_each(|server| {
+                    if self.http.is_testing() {
+                        oauth2(server.join("/token"), &*self.http, max_bytes)
+                    } else {
+                        oauth2(server.join("/token"), &self.auth_client(creds.clone()), max_bytes)
+                    }
+                }) {
+                    Ok(token) => token,
+                    Err(Error::Http(resp)) |
+                    Err(Error::HttpAuth(resp)) => {
+                        error!("{}", resp);
+                        return Ok(Event::AuthenticationFailed(format!("{} returned {}", resp.url, resp.code)));
+                    }
+                    Err(err) => return Ok(Event::AuthenticationFailed(err.to_string()))
+                };
+
+                if let Some(ref required) = config.required_scope {
+                    let scopes = token.scopes();
+                    let missing = required.split_whitespace().filter(|scope| ! scopes.contains(scope)).collect::<Vec<_>>();
+                    if ! missing.is_empty() {
+                        let reason = format!("access token is missing required scopes: {}", missing.join(" "));
+                        error!("{}", reason);
+                        return Ok(Event::AuthenticationFailed(reason));
+                    }
+                }
+
+                self.token_expiry = if token.expires_in > 0 {
+                    Some(self.clock.now() + chrono::Duration::seconds(i64::from(token.expires_in)))
                 } else {
-                    self.auth = Auth::Token(oauth2(server, &AuthClient::from(creds, self.version.clone()))?);
-                    self.http = Box::new(AuthClient::from(self.auth.clone(), self.version.clone()));
+                    None
+                };
+                let expiry = self.token_expiry;
+                if let Err(err) = TokenCache::from_config(config).and_then(|cache| cache.map_or(Ok(()), |cache| cache.save(&token, expiry))) {
+                    error!("couldn't save the access token: {}", err);
+                }
+                self.auth = Auth::Token(token);
+                if ! self.http.is_testing() {
+                    self.http = Box::new(self.auth_client(self.auth.clone()));
                 }
                 Event::Authenticated
             }
 
             (Command::Authenticate(auth), _) => {
+                self.token_expiry = None;
                 self.auth = auth;
                 if ! self.http.is_testing() {
-                    self.http = Box::new(AuthClient::from(self.auth.clone(), self.version.clone()));
+                    self.http = Box::new(self.auth_client(self.auth.clone()));
                 }
                 Event::Authenticated
             }
 
-            (Command::GetUpdateRequests, CommandMode::Uptane(uptane)) => {
-                let mut uptane = uptane.borrow_mut();
-                let _ = uptane.get_director(&*self.http, RoleName::Root)?;
-                let targets = uptane.get_director(&*self.http, RoleName::Targets)?;
-                if targets.is_new() {
-                    Event::UptaneTargetsUpdated(Box::new(targets))
-                } else {
-                    Event::UptaneNoUpdates
+            (Command::ReAuthenticate, _) => {
+                let auth = match self.config.initial_auth() {
+                    Ok(auth) => auth,
+                    Err(reason) => return Ok(Event::AuthenticationFailed(reason.into()))
+                };
+                match self.process_command(Command::Authenticate(auth), etx) {
+                    Ok(Event::Authenticated) => Event::Authenticated,
+                    Ok(Event::AuthenticationFailed(reason)) => Event::AuthenticationFailed(reason),
+                    Ok(event) => Event::AuthenticationFailed(event.to_string()),
+                    Err(Error::Http(resp)) |
+                    Err(Error::HttpAuth(resp)) => Event::AuthenticationFailed(format!("{} returned {}", resp.url, resp.code)),
+                    Err(err) => Event::AuthenticationFailed(err.to_string())
                 }
             }
 
+            (Command::GetUpdateRequests, CommandMode::Uptane(uptane)) => {
+                let started = self.clock.now();
+                let received = uptane.borrow().received_bytes;
+                let polled = self.poll_director(&mut uptane.borrow_mut());
+                let updates_found = match polled {
+                    Ok(Event::UptaneTargetsUpdated(ref targets)) => targets.data.targets.as_ref().map_or(0, |targets| targets.len()),
+                    _ => 0
+                };
+                let uptane = uptane.borrow();
+                self.send_poll_telemetry(etx, uptane.director_server.to_string(), started, uptane.received_bytes - received, updates_found);
+                polled?
+            }
+
             (Command::GetUpdateRequests, _) => {
-                let mut sota = Sota::new(&self.config, &*self.http);
-                let mut updates = sota.get_update_requests()?;
+                let started = self.clock.now();
+                let (polled, server) = {
+                    let mut sota = Sota::new(&self.config, &*self.http);
+                    let polled = sota.poll_update_requests();
+                    (polled, sota.server().to_string())
+                };
+                let (bytes, updates_found) = match polled {
+                    Ok((ref updates, bytes)) => (bytes, updates.len()),
+                    Err(_) => (0, 0)
+                };
+                self.send_poll_telemetry(etx, server, started, bytes, updates_found);
+                let (mut updates, _) = polled?;
+                self.record_checksums(&updates);
                 if updates.is_empty() {
                     Event::NoUpdateRequests
                 } else {
                     updates.sort_by_key(|u| u.installPos);
+                    let max = self.config.core.max_updates_per_poll;
+                    if updates.len() > max {
+                        warn!("Received {} update requests, only queueing the first {}.", updates.len(), max);
+                        updates.truncate(max);
+                    }
                     Event::UpdatesReceived(updates)
                 }
             }
 
+            (Command::CheckUpdates, CommandMode::Uptane(uptane)) => {
+                let mut uptane = uptane.borrow_mut();
+                uptane.sync_time(&*self.http);
+                Event::UptaneTargetsUpdated(Box::new(uptane.check_director_targets(&*self.http)?))
+            }
+
+            (Command::CheckUpdates, _) => {
+                let mut updates = Sota::new(&self.config, &*self.http).get_update_requests()?;
+                self.record_checksums(&updates);
+                updates.sort_by_key(|u| u.installPos);
+                Event::UpdatesReceived(updates)
+            }
+
             (Command::ListInstalledPackages, _) => {
                 Event::FoundInstalledPackages(self.config.device.package_manager.installed_packages()?)
             }
 
             (Command::ListSystemInfo, _) => {
-                Event::FoundSystemInfo(self.system_info()?)
+                Event::FoundSystemInfo(self.system_info()?.report()?)
+            }
+
+            (Command::GetStorageInfo, mode) => {
+                let images_dir = match mode {
+                    CommandMode::Uptane(_) => &self.config.uptane.images_dir,
+                    _ => &self.config.rvi.storage_dir
+                };
+                Event::StorageInfo {
+                    packages_dir_free:  dir_free_space(Sota::new(&self.config, &*self.http).packages_dir()),
+                    images_dir_free:    dir_free_space(images_dir),
+                    metadata_dir_free:  dir_free_space(&self.config.uptane.metadata_path),
+                    download_dirs_free: self.config.device.download_dirs().into_iter()
+                        .map(|dir| (dir.to_string(), dir_free_space(dir)))
+                        .collect(),
+                }
+            }
+
+            (Command::VerifySystemIntegrity, _) => {
+                let device = &self.config.device;
+                let timeout = Duration::from_secs(device.install_timeout);
+                Event::IntegrityReport { issues: device.package_manager.verify_integrity(&device.ostree_repo, timeout)? }
             }
 
             (Command::SendInstalledPackages(packages), _) => {
                 let mut sota = Sota::new(&self.config, &*self.http);
-                sota.send_installed_packages(&packages)?;
+                sota.report_installed_packages(&packages)?;
                 Event::InstalledPackagesSent
             }
 
@@ -220,9 +552,31 @@ impl CommandInterpreter {
                 Event::InstalledSoftwareSent
             }
 
+            #[cfg(feature = "rvi")]
+            (Command::SendInstalledSoftwareNow, CommandMode::Rvi(services)) => {
+                if self.config.device.package_manager != PacMan::Off {
+                    let packages = self.config.device.package_manager.installed_packages()?
+                        .into_iter()
+                        .map(InstalledPackage::from_package)
+                        .collect();
+                    let services = services.borrow_mut();
+                    let installed = InstalledSoftware::new(packages, Vec::new());
+                    services.remote.lock().unwrap().send_installed_software(installed).map_err(Error::Rvi)?;
+                    Event::InstalledSoftwareSent
+                } else if self.config.gateway.dbus {
+                    Event::InstalledSoftwareNeeded
+                } else {
+                    Event::Error("no package manager or dbus gateway to list installed software".into())
+                }
+            }
+
+            (Command::SendInstalledSoftwareNow, _) => {
+                Event::Error("installed software reports require rvi mode".into())
+            }
+
             (Command::SendSystemInfo, _) => {
                 let mut sota = Sota::new(&self.config, &*self.http);
-                sota.send_system_info(self.system_info()?.into_bytes())?;
+                sota.send_system_info(self.system_info()?)?;
                 Event::SystemInfoSent
             }
 
@@ -234,11 +588,54 @@ impl CommandInterpreter {
             }
 
             (Command::SendInstallReport(report), _) => {
+                if self.reports.contains(&report) {
+                    info!("Skipping a duplicate install report for {}", report.update_id);
+                    return Ok(Event::InstallReportSkipped(report.update_id));
+                }
                 let mut sota = Sota::new(&self.config, &*self.http);
-                sota.send_install_report(&report)?;
+                let spool = self.config.device.report_spool_dir.clone().map(ReportSpool::new);
+                if let Err(err) = sota.send_install_report(&report) {
+                    if let Some(spool) = spool {
+                        spool.save(&report).unwrap_or_else(|err| error!("couldn't spool report: {}", err));
+                    }
+                    return Err(err);
+                }
+                if let Some(spool) = spool {
+                    spool.remove(&report.update_id)
+                        .and_then(|_| send_spooled_reports(&mut sota, &spool))
+                        .unwrap_or_else(|err| error!("couldn't send spooled reports: {}", err));
+                }
+                self.reports.insert(report.clone());
                 Event::InstallReportSent(report)
             }
 
+            (Command::GetPendingReports, _) => match self.config.device.report_spool_dir {
+                Some(ref dir) => Event::PendingReports(ReportSpool::new(dir.clone()).pending()?),
+                None => Event::PendingReports(Vec::new())
+            },
+
+            (Command::SendPendingReports, _) => match self.config.device.report_spool_dir {
+                Some(ref dir) => {
+                    let spool = ReportSpool::new(dir.clone());
+                    send_spooled_reports(&mut Sota::new(&self.config, &*self.http), &spool)?;
+                    Event::PendingReports(spool.pending()?)
+                }
+                None => Event::PendingReports(Vec::new())
+            },
+
+            (Command::ClearPendingReports, _) => match self.config.device.report_spool_dir {
+                Some(ref dir) => Event::PendingReportsCleared(ReportSpool::new(dir.clone()).clear()?),
+                None => Event::PendingReportsCleared(Vec::new())
+            },
+
+            (Command::ReplayEvents(count), _) => Event::RecentEvents(self.history.last(count)),
+
+            (Command::ExportDiagnostics { path }, _) => {
+                self.diagnostics()?.write(&path)?;
+                info!("diagnostics written to {}", path);
+                Event::DiagnosticsExported { path: path }
+            }
+
             #[cfg(feature = "rvi")]
             (Command::StartDownload(id), CommandMode::Rvi(services)) => {
                 let services = services.borrow_mut();
@@ -246,38 +643,339 @@ impl CommandInterpreter {
                 Event::DownloadingUpdate(id)
             }
 
+            (Command::StartDownload(id), _) if self.aborted.is_canceled(&id) => Event::UpdateAborted(id),
+
             (Command::StartDownload(id), _) => {
-                let mut sota = Sota::new(&self.config, &*self.http);
+                let verified = self.queue.checksum(&id)
+                    .and_then(|checksum| Sota::new(&self.config, &*self.http).verified_download(&id, checksum));
+                if let Some(dl) = verified {
+                    info!("Update {} was already downloaded and verified", id);
+                    self.queue.push(id);
+                    etx.send(Event::DownloadSkipped(id));
+                    return Ok(Event::DownloadComplete(dl));
+                }
+                let bandwidth = Bandwidth::from_config(&self.config.device)?;
+                let rate_limit = match bandwidth.check(self.clock.now())? {
+                    Ok(rate_limit) => rate_limit,
+                    Err(reason) => {
+                        let retry_at = bandwidth.next_start(self.clock.now())?;
+                        info!("Deferring the download of {} until {}: {}", id, retry_at, reason);
+                        self.schedule.defer_download(id, retry_at);
+                        return Ok(Event::DownloadDeferred(id, reason));
+                    }
+                };
+                if rate_limit.is_some() && ! self.http.is_testing() {
+                    return Ok(self.download_throttled(id, rate_limit, etx));
+                }
                 etx.send(Event::DownloadingUpdate(id));
-                sota.download_update(id)
-                    .map(Event::DownloadComplete)
-                    .unwrap_or_else(|err| Event::DownloadFailed(id, err.to_string()))
+                let download = Sota::new(&self.config, &*self.http).clock(self.clock.clone()).aborted(self.aborted.clone())
+                    .rate_limit(rate_limit).download_update(id);
+                match download {
+                    Ok(dl) => {
+                        self.queue.push(id);
+                        Event::DownloadComplete(dl)
+                    }
+                    Err(Error::Aborted(_)) => Event::UpdateAborted(id),
+                    Err(err) => {
+                        self.queue.remove(&id);
+                        if ! self.config.device.continue_on_error {
+                            self.queue.halted = true;
+                        }
+                        for event in self.process_queue(etx) {
+                            etx.send(event);
+                        }
+                        Event::DownloadFailed(id, err.to_string())
+                    }
+                }
+            }
+
+            #[cfg(feature = "rvi")]
+            (Command::AbortAllTransfers, CommandMode::Rvi(services)) => {
+                let services = services.borrow_mut();
+                let count = services.transfers.lock().unwrap().abort_all();
+                self.abort_all_transfers(count)?
+            }
+
+            (Command::AbortAllTransfers, _) => self.abort_all_transfers(0)?,
+
+            (Command::ListBandwidthRules, _) => {
+                let bandwidth = Bandwidth::from_config(&self.config.device)?;
+                let used = bandwidth.used(self.clock.now())?;
+                Event::BandwidthRules { rules: bandwidth.rules, monthly_cap: bandwidth.cap, used_bytes: used }
+            }
+
+            (Command::SetBandwidthRules(rules), _) => {
+                let joined = rules.iter().map(|rule| rule.to_string()).collect::<Vec<_>>().join(",");
+                self.config.device.download_rules = if rules.is_empty() { None } else { Some(joined) };
+                Event::BandwidthRulesChanged(rules)
+            }
+
+            (Command::ServerAbort(id), _) if self.aborted.is_aborted(&id) => Event::UpdateAborted(id),
+
+            #[cfg(feature = "rvi")]
+            (Command::ServerAbort(id), CommandMode::Rvi(services)) => {
+                let services = services.borrow_mut();
+                let _ = services.transfers.lock().unwrap().active.remove(&format!("{}", id));
+                self.abort_update(id)?
+            }
+
+            (Command::ServerAbort(id), _) => self.abort_update(id)?,
+
+            #[cfg(feature = "rvi")]
+            (Command::CleanupArtifacts { older_than_secs }, CommandMode::Rvi(services)) => {
+                let services = services.borrow_mut();
+                let active = services.transfers.lock().unwrap().active.keys().cloned().collect();
+                self.cleanup_artifacts(active, older_than_secs)?
             }
 
+            (Command::CleanupArtifacts { older_than_secs }, _) => self.cleanup_artifacts(HashSet::new(), older_than_secs)?,
+
+            (Command::StartInstall(id), CommandMode::Sota) if self.aborted.is_canceled(&id) => Event::UpdateAborted(id),
+
             (Command::StartInstall(id), CommandMode::Sota) => {
-                let mut sota = Sota::new(&self.config, &*self.http);
-                etx.send(Event::InstallingUpdate(id));
-                let result = sota.install_update(&id, &self.credentials())?;
-                if result.result_code.is_success() {
-                    Event::InstallComplete(result)
+                if let Some(at) = self.ostree_pull_deferred()? {
+                    info!("Deferring the install of {} until {}", id, at);
+                    self.schedule.add(id, at)?;
+                    return Ok(Event::InstallScheduled { update_id: id, at: at });
+                }
+                if self.schedule.remove(&id)? {
+                    etx.send(Event::InstallStarting(id));
+                    // wait behind any updates that must be installed first
+                    if ! self.queue.is_empty() {
+                        self.queue.push(id);
+                    }
+                }
+                if self.queue.contains(&id) {
+                    self.queue.set_ready(id);
+                    let mut events = self.process_queue(etx);
+                    let last = events.pop().unwrap_or(Event::InstallQueued(id));
+                    for event in events {
+                        etx.send(event);
+                    }
+                    last
+                } else {
+                    Self::install_event(self.install_update(id, etx)?)
+                }
+            }
+
+            (Command::ScheduleInstall { update_id, at }, CommandMode::Sota) => {
+                let sota = Sota::new(&self.config, &*self.http).clock(self.clock.clone());
+                if ! sota.is_downloaded(&update_id) {
+                    let bandwidth = Bandwidth::from_config(&self.config.device)?;
+                    let rate_limit = bandwidth.check(self.clock.now())?.map_err(Error::DownloadDeferred)?;
+                    etx.send(Event::DownloadingUpdate(update_id));
+                    let _ = sota.rate_limit(rate_limit).download_update(update_id)?;
+                }
+                self.schedule.add(update_id, at)?;
+                Event::InstallScheduled { update_id: update_id, at: at }
+            }
+
+            (Command::ScheduleInstall { .. }, _) => {
+                Event::Error("scheduled installs require sota mode".into())
+            }
+
+            (Command::OstreeListRemotes, _) => {
+                self.require_ostree()?;
+                Event::OstreeRemotes(Ostree::list_remotes()?)
+            }
+
+            (Command::OstreeAddRemote(remote), _) => {
+                self.require_ostree()?;
+                Ostree::add_remote(&remote)?;
+                Event::OstreeRemoteAdded(remote.name)
+            }
+
+            (Command::OstreeDeleteRemote(name), _) => {
+                self.require_ostree()?;
+                Ostree::delete_remote(&name)?;
+                Event::OstreeRemoteDeleted(name)
+            }
+
+            (Command::ConfirmUpdate { update_id, approved }, _) => {
+                if approved {
+                    Event::UpdateApproved(update_id)
                 } else {
-                    Event::InstallFailed(result)
+                    Event::UpdateDenied(update_id)
+                }
+            }
+
+            (Command::Pause, _) => {
+                if self.paused.is_none() {
+                    self.paused = Some(Vec::new());
+                }
+                Event::Paused
+            }
+
+            (Command::Resume, _) => {
+                for cmd in self.paused.take().unwrap_or_default() {
+                    self.interpret(CommandExec { cmd: cmd, etx: None }, etx);
                 }
+                Event::Resumed
+            }
+
+            (Command::SetAutoDownload(enabled), _) => {
+                *self.auto_dl.lock().unwrap() = enabled;
+                Event::AutoDownloadChanged(enabled)
+            }
+
+            #[cfg(any(test, feature = "debug"))]
+            (Command::SetPackageManager(pacman), _) => {
+                warn!("replacing the package manager with {:?}", pacman);
+                self.config.device.package_manager = pacman.clone();
+                Event::PackageManagerChanged(pacman)
+            }
+
+            (Command::GetLogLevel, _) => Event::LogLevel(ReloadableLogger::active_level()?),
+
+            (Command::SetLogLevel(level), _) => {
+                ReloadableLogger::set_active_level(&level)?;
+                info!("log level changed to {}", level);
+                Event::LogLevelChanged(level)
             }
 
             (Command::Shutdown, _) => process::exit(0),
 
+            (Command::SyncInstalledPackages, _) => {
+                if self.config.device.package_manager == PacMan::Off {
+                    warn!("not syncing installed packages as the package manager is off");
+                    Event::Error("no package manager".into())
+                } else {
+                    let packages = self.config.device.package_manager.installed_packages()?;
+                    Sota::new(&self.config, &*self.http).send_installed_packages(&packages)?;
+                    Event::InstalledPackagesSent
+                }
+            }
+
+            (Command::InstallLocalBundle { path }, CommandMode::Uptane(uptane)) => {
+                let mut uptane = uptane.borrow_mut();
+                match uptane.install_bundle(&path, self.credentials()?) {
+                    Ok((signed, true))  => Event::UptaneInstallComplete(signed),
+                    Ok((signed, false)) => Event::UptaneInstallFailed(signed),
+                    Err(err) => {
+                        error!("couldn't install local bundle {}: {}", path, err);
+                        return Err(err);
+                    }
+                }
+            }
+
+            (Command::InstallLocalBundle { .. }, _) => {
+                warn!("not installing a local bundle outside of uptane mode");
+                Event::Error("local bundles require uptane".into())
+            }
+
+            (Command::UptaneExportManifest, CommandMode::Uptane(uptane)) => {
+                let (manifest, cjson) = uptane.borrow_mut().export_manifest()?;
+                Event::UptaneManifest { manifest: json::to_string(&manifest)?, canonical_json: String::from_utf8(cjson)? }
+            }
+
+            (Command::UptaneExportManifest, _) => {
+                Event::Error("uptane manifests require uptane mode".into())
+            }
+
+            (Command::UptaneMetadataVersions, CommandMode::Uptane(uptane)) => {
+                let (director, repo) = uptane.borrow().metadata_versions();
+                Event::UptaneMetadataVersions { director: director, repo: repo }
+            }
+
+            (Command::UptaneMetadataVersions, _) => {
+                Event::Error("uptane metadata requires uptane mode".into())
+            }
+
+            (Command::UptaneListEcus, CommandMode::Uptane(uptane)) => {
+                Event::UptaneEcus(uptane.borrow_mut().ecu_versions()?)
+            }
+
+            (Command::UptaneListEcus, _) => {
+                Event::Error("listing ECUs requires uptane mode".into())
+            }
+
+            (Command::UptaneGetRawMetadata { service, role }, CommandMode::Uptane(uptane)) => {
+                let (json, verified) = uptane.borrow_mut().raw_metadata(&*self.http, service, role)?;
+                Event::UptaneRawMetadata { service: service, role: role, json: json, verified: verified }
+            }
+
+            (Command::UptaneGetRawMetadata { .. }, _) => {
+                Event::Error("uptane metadata requires uptane mode".into())
+            }
+
+            (Command::UptaneVerifyTarget(refname), CommandMode::Uptane(uptane)) => {
+                let mut uptane = uptane.borrow_mut();
+                let _ = uptane.get_director(&*self.http, RoleName::Root)?;
+                let targets = uptane.get_director_targets(&*self.http)?;
+                self.keep_new_targets(&targets);
+                let (authorized, reason) = uptane.authorize_target(&targets, &refname);
+                Event::UptaneTargetAuthorized { authorized: authorized, reason: reason }
+            }
+
+            (Command::UptaneVerifyTarget(_), _) => {
+                Event::Error("verifying targets requires uptane mode".into())
+            }
+
+            (Command::UptaneForceInstall(refname), CommandMode::Uptane(uptane)) => {
+                let mut uptane = uptane.borrow_mut();
+                let _ = uptane.get_director(&*self.http, RoleName::Root)?;
+                let targets = uptane.get_director_targets(&*self.http)?;
+                self.keep_new_targets(&targets);
+                let (authorized, reason) = uptane.authorize_target(&targets, &refname);
+                if ! authorized {
+                    warn!("Not forcing the install of {}: {}", refname, reason);
+                    return Ok(Event::Error(reason));
+                }
+                info!("Forcing the install of {}", refname);
+                match uptane.force_install(targets, &refname, self.treehub()?, self.credentials()?) {
+                    Ok((signed, true))  => Event::UptaneInstallComplete(signed),
+                    Ok((signed, false)) => Event::UptaneInstallFailed(signed),
+                    Err(err) => {
+                        error!("Uptane forced installation error: {}", err);
+                        let mut result = InstallOutcome::error(err.to_string()).into_result(uptane.primary_ecu.clone());
+                        result.forced = Some(true);
+                        let report = uptane.signed_report(Some(EcuCustom::from_result(result)))?;
+                        Event::UptaneInstallFailed(hashmap!{ uptane.primary_ecu.clone() => report })
+                    }
+                }
+            }
+
+            (Command::UptaneForceInstall(_), _) => {
+                Event::Error("forcing an install requires uptane mode".into())
+            }
+
+            (Command::UptaneRotateKey { new_key_path, new_pub_key_path }, CommandMode::Uptane(uptane)) => {
+                let keyid = uptane.borrow_mut().rotate_key(&new_key_path, &new_pub_key_path)?;
+                Event::UptaneKeyRotated(keyid)
+            }
+
+            (Command::UptaneRotateKey { .. }, _) => {
+                warn!("not rotating the Uptane key outside of uptane mode");
+                Event::Error("no uptane private key".into())
+            }
+
             (Command::UptaneSendManifest(manifests), CommandMode::Uptane(uptane)) => {
                 let mut uptane = uptane.borrow_mut();
                 uptane.put_manifest(&*self.http, manifests)?;
                 Event::UptaneManifestSent
             }
 
+            (Command::UptaneSendManifestNow, CommandMode::Uptane(uptane)) => {
+                uptane.borrow_mut().put_manifest(&*self.http, None)?;
+                Event::UptaneManifestSent
+            }
+
+            (Command::UptaneSendManifestNow, _) => {
+                Event::Error("sending a manifest requires uptane mode".into())
+            }
+
             (Command::UptaneStartInstall(targets), CommandMode::Uptane(uptane)) => {
                 let mut uptane = uptane.borrow_mut();
-                match uptane.install(*targets, self.treehub()?, self.credentials()) {
+                let retry = targets.clone();
+                match uptane.install(*targets, self.treehub()?, self.credentials()?) {
                     Ok((signed, true))  => Event::UptaneInstallComplete(signed),
                     Ok((signed, false)) => Event::UptaneInstallFailed(signed),
+                    Err(Error::DownloadDeferred(reason)) => {
+                        let at = Bandwidth::from_config(&self.config.device)?.next_start(self.clock.now())?;
+                        info!("Deferring the Uptane install until {}: {}", at, reason);
+                        self.deferred_targets = Some((retry, at));
+                        Event::DownloadDeferred(Uuid::nil(), reason)
+                    }
                     Err(err) => {
                         error!("Uptane installation error: {}", err);
                         let result = InstallOutcome::error(err.to_string()).into_result(uptane.primary_ecu.clone());
@@ -296,53 +994,433 @@ impl CommandInterpreter {
         Ok(event)
     }
 
-    /// Generate a new system information report.
-    fn system_info(&self) -> Result<String, Error> {
-        let cmd = self.config.device.system_info.as_ref()
-            .ok_or_else(|| Error::Config("device.system_info not set".into()))?;
-        ShellCommand::new(cmd)
-            .output()
-            .map_err(|err| Error::SystemInfo(err.to_string()))
-            .and_then(|info| Ok(String::from_utf8(info.stdout)?))
+    /// Keep new targets metadata that was only checked so the next poll still
+    /// offers it, as the verified version can't be rolled back to re-fetch it.
+    fn keep_new_targets(&mut self, targets: &Verified) {
+        if targets.is_new() {
+            self.deferred_targets = Some((Box::new(targets.clone()), self.clock.now()));
+        }
     }
 
-    /// Retrieve the current access token and device certificates for TLS.
-    fn credentials(&self) -> Credentials {
-        let client = Box::new(AuthClient::from(self.auth.clone(), self.version.clone()));
-        let token = if let Auth::Token(ref t) = self.auth {
-            Some(t.access_token.clone())
-        } else {
-            None
-        };
-        let (ca_file, cert_file, pkey_file) = if let Some(ref tls) = self.config.tls {
-            (Some(tls.ca_file.clone()), Some(tls.cert_file.clone()), Some(tls.pkey_file.clone()))
-        } else {
-            (None, None, None)
-        };
-        Credentials { client, token, ca_file, cert_file, pkey_file }
+    /// Authenticate again with the configured credentials once the access
+    /// token has expired, returning true if a new token was received.
+    fn refresh_token(&mut self, etx: &Sender<Event>) -> bool {
+        info!("access token expired, authenticating again");
+        let event = self.process_command(Command::ReAuthenticate, etx)
+            .unwrap_or_else(|err| Event::AuthenticationFailed(err.to_string()));
+        let refreshed = event == Event::Authenticated;
+        etx.send(event);
+        refreshed
     }
 
-    /// Return the treehub URL.
-    fn treehub(&self) -> Result<Url, Error> {
-        self.config.tls.as_ref()
-            .map(|tls| tls.server.join("/treehub"))
-            .ok_or_else(|| Error::Config("tls.server required".into()))
+    /// Returns true when the access token has expired so the command should
+    /// wait until the client has authenticated again.
+    fn token_expired(&self, cmd: &Command) -> bool {
+        match *cmd {
+            Command::Authenticate(_) | Command::ReAuthenticate => false,
+            _ => self.token_expiry.map_or(false, |expiry| self.clock.now() >= expiry)
+        }
     }
-}
 
+    /// Returns when to retry an install that pulls from ostree if the
+    /// bandwidth rules don't allow a download now.
+    fn ostree_pull_deferred(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        if self.config.device.package_manager != PacMan::Ostree {
+            return Ok(None);
+        }
+        let bandwidth = Bandwidth::from_config(&self.config.device)?;
+        match bandwidth.check(self.clock.now())? {
+            Ok(_) => Ok(None),
+            Err(reason) => {
+                info!("Not pulling from ostree now: {}", reason);
+                bandwidth.next_start(self.clock.now()).map(Some)
+            }
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
+    /// Download an update at a limited rate on a separate thread so that
+    /// other commands are still handled meanwhile, sending the outcome once
+    /// the download finishes.
+    fn download_throttled(&self, id: Uuid, rate_limit: Option<u64>, etx: &Sender<Event>) -> Event {
+        let config = self.config.clone();
+        let client = self.auth_client(self.auth.clone());
+        let clock = self.clock.clone();
+        let aborted = self.aborted.clone();
+        let throttled = self.throttled.clone();
+        let etx = etx.clone();
+        thread::spawn(move || {
+            let download = Sota::new(&config, &client).clock(clock).aborted(aborted).rate_limit(rate_limit).download_update(id);
+            if let Err(Error::Aborted(_)) = download {
+                return etx.send(Event::UpdateAborted(id));
+            }
+            throttled.lock().unwrap().push((id, download.is_ok()));
+            match download {
+                Ok(dl) => etx.send(Event::DownloadComplete(dl)),
+                Err(err) => etx.send(Event::DownloadFailed(id, err.to_string()))
+            }
+        });
+        Event::DownloadingUpdate(id)
+    }
+
+    /// Queue the rate-limited downloads that finished on a background thread,
+    /// halting the queue after a failed download as in the foreground.
+    fn finish_throttled(&mut self, etx: &Sender<Event>) {
+        let finished = mem::replace(&mut *self.throttled.lock().unwrap(), Vec::new());
+        let mut failed = false;
+        for (id, downloaded) in finished {
+            if downloaded {
+                self.queue.push(id);
+            } else {
+                failed = true;
+                self.queue.remove(&id);
+                if ! self.config.device.continue_on_error {
+                    self.queue.halted = true;
+                }
+            }
+        }
+        if failed {
+            for event in self.process_queue(etx) {
+                etx.send(event);
+            }
+        }
+    }
+
+    /// Install a single update using the current package manager, recording
+    /// how long the installation took and which attempt this was.
+    fn install_update(&mut self, id: Uuid, etx: &Sender<Event>) -> Result<InstallResult, Error> {
+        if self.config.device.verify_signature {
+            let signature = self.queue.signature(&id).map(String::from);
+            let sota = Sota::new(&self.config, &*self.http);
+            if let Err(err) = sota.verify_signature(&id, signature.as_ref().map(String::as_str)) {
+                error!("refusing to install {}: {}", id, err);
+                self.queue.remove(&id);
+                let _ = sota.remove_update(&id)?;
+                return Ok(InstallResult::new(format!("{}", id), InstallCode::VALIDATION_FAILED, format!("{}", err)));
+            }
+        }
+        let attempt = self.queue.next_attempt(id);
+        etx.send(Event::InstallingUpdate(id));
+        let started = self.clock.now();
+        let checksum = self.queue.checksum(&id).map(String::from);
+        let mut result = Sota::new(&self.config, &*self.http)
+            .install_update(&id, &self.credentials()?, checksum.as_ref().map(String::as_str))?;
+        // nothing changed when already installed, and ostree commits are checked once booted
+        if result.result_code == InstallCode::OK && self.config.device.package_manager != PacMan::Ostree {
+            if let Some((failed, rolled_back)) = Sota::new(&self.config, &*self.http).check_health(&id) {
+                if rolled_back {
+                    etx.send(Event::InstallRolledBack(id));
+                }
+                result = failed;
+            }
+        }
+        let elapsed = self.clock.now().signed_duration_since(started);
+        result.duration_ms = Some(elapsed.num_milliseconds() as u64);
+        result.attempt = Some(attempt);
+        if result.result_code.is_success() {
+            self.queue.clear_attempts(&id);
+            let device = &self.config.device;
+            if device.package_manager.reboot_required(device.reboot_marker_file.as_ref().map(String::as_str)) {
+                info!("Update {} requires a reboot", id);
+                result.reboot_required = Some(true);
+                etx.send(Event::RebootRequired(id));
+                if device.reboot_after_install {
+                    Sota::new(&self.config, &*self.http).schedule_reboot()
+                        .unwrap_or_else(|err| error!("couldn't schedule a reboot: {}", err));
+                }
+            }
+            if self.config.device.cleanup_after_install {
+                let path = format!("{}/{}", Sota::new(&self.config, &*self.http).packages_dir(), id);
+                if Path::new(&path).exists() {
+                    fs::remove_file(&path).unwrap_or_else(|err| error!("couldn't remove {}: {}", path, err));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Remove the downloaded artifacts except those of the active transfers,
+    /// the queued or scheduled installs and the updates awaiting confirmation.
+    fn cleanup_artifacts(&self, mut keep: HashSet<String>, older_than_secs: Option<u64>) -> Result<Event, Error> {
+        keep.extend(self.queue.queued().iter().map(|id| format!("{}", id)));
+        keep.extend(self.schedule.pending().iter().map(|install| format!("{}", install.update_id)));
+        keep.extend(self.pending.lock().unwrap().iter().map(|id| format!("{}", id)));
+        let sota = Sota::new(&self.config, &*self.http);
+        let mut dirs = vec![sota.packages_dir(), sota.staging_dir(), self.config.rvi.storage_dir.as_str()];
+        dirs.sort();
+        dirs.dedup();
+        let (freed_bytes, count) = images::remove_artifacts(&dirs, &keep, older_than_secs.map(Duration::from_secs))?;
+        info!("Removed {} artifacts, freeing {} bytes", count, freed_bytes);
+        Ok(Event::ArtifactsCleaned { freed_bytes: freed_bytes, count: count })
+    }
+
+    /// Remove the partial downloads left when the client stopped mid-transfer,
+    /// counting them along with the active transfers already aborted.
+    fn abort_all_transfers(&self, aborted: usize) -> Result<Event, Error> {
+        let sota = Sota::new(&self.config, &*self.http);
+        let orphaned = images::remove_partial_downloads(sota.partial_dir())?;
+        info!("Aborted {} transfers and removed {} partial downloads", aborted, orphaned);
+        Ok(Event::TransfersAborted(aborted + orphaned))
+    }
+
+    /// Stop an update canceled by the server from being downloaded or
+    /// installed, removing its artifacts and reporting the abort unless the
+    /// update was already installed.
+    fn abort_update(&mut self, id: Uuid) -> Result<Event, Error> {
+        info!("Aborting update {} canceled by the server", id);
+        self.queue.abort(id);
+        self.aborted.add(id)?;
+        let _ = self.schedule.remove(&id)?;
+        let mut sota = Sota::new(&self.config, &*self.http);
+        let removed = sota.remove_update(&id)?;
+        debug!("removed {} artifacts of aborted update {}", removed, id);
+        if self.was_installed(&id) {
+            info!("Update {} was already installed, not reporting the abort", id);
+        } else {
+            let result = InstallResult::new(format!("{}", id), InstallCode::GENERAL_ERROR, "aborted by the server".into());
+            sota.send_install_report(&result.into_report())
+                .unwrap_or_else(|err| error!("couldn't report the abort of {}: {}", id, err));
+        }
+        Ok(Event::UpdateAborted(id))
+    }
+
+    /// Whether a recent event shows the update was installed.
+    fn was_installed(&self, id: &Uuid) -> bool {
+        let update_id = format!("{}", id);
+        self.history.last(self.config.gateway.event_history).iter().any(|event| match *event {
+            Event::InstallComplete(ref result) => result.id == update_id,
+            _ => false
+        })
+    }
+
+    /// Fetch the latest Director metadata, returning the targets to install
+    /// unless they are already installed or deferred until later.
+    fn poll_director(&mut self, uptane: &mut Uptane) -> Result<Event, Error> {
+        uptane.sync_time(&*self.http);
+        let _ = uptane.get_director(&*self.http, RoleName::Root)?;
+        let targets = uptane.get_director_targets(&*self.http)?;
+        if ! targets.is_new() {
+            match self.deferred_targets.take() {
+                Some((deferred, at)) => if at > self.clock.now() {
+                    self.deferred_targets = Some((deferred, at));
+                    Ok(Event::UptaneNoUpdates)
+                } else if deferred.targets_installed(&uptane.installed_images()) {
+                    Ok(Event::UptaneNoUpdates)
+                } else {
+                    Ok(Event::UptaneTargetsUpdated(deferred))
+                },
+                None => Ok(Event::UptaneNoUpdates)
+            }
+        } else if targets.targets_installed(&uptane.installed_images()) {
+            info!("New targets metadata matches the installed images.");
+            Ok(Event::UptaneNoUpdates)
+        } else {
+            self.deferred_targets = None;
+            Ok(Event::UptaneTargetsUpdated(Box::new(targets)))
+        }
+    }
+
+    /// Send the server, latency, bytes received and updates found by a poll,
+    /// including a failed one, when `core.poll_telemetry` is set.
+    fn send_poll_telemetry(&self, etx: &Sender<Event>, server: String, started: DateTime<Utc>, bytes: u64, updates_found: usize) {
+        if self.config.core.poll_telemetry {
+            let latency = self.clock.now().signed_duration_since(started);
+            etx.send(Event::PollTelemetry {
+                server:        server,
+                latency_ms:    latency.num_milliseconds() as u64,
+                bytes:         bytes,
+                updates_found: updates_found,
+            });
+        }
+    }
+
+    /// Remember the expected package checksums and signatures to verify
+    /// before installing.
+    fn record_checksums(&mut self, updates: &[UpdateRequest]) {
+        for update in updates {
+            if let Some(ref checksum) = update.checksum {
+                self.queue.set_checksum(update.requestId, checksum.clone());
+            }
+            if let Some(ref signature) = update.signature {
+                self.queue.set_signature(update.requestId, signature.clone());
+            }
+        }
+    }
+
+    /// Convert an installation result into the corresponding outcome `Event`.
+    fn install_event(result: InstallResult) -> Event {
+        if result.result_code.is_success() {
+            Event::InstallComplete(result)
+        } else {
+            Event::InstallFailed(result)
+        }
+    }
+
+    /// Install each ready update in the queue strictly in order, halting the
+    /// remaining updates after a failure unless `continue_on_error` is set.
+    fn process_queue(&mut self, etx: &Sender<Event>) -> Vec<Event> {
+        let mut events = Vec::new();
+        while let Some(id) = self.queue.next_ready() {
+            if self.aborted.is_canceled(&id) {
+                info!("Skipping the install of {} canceled by the server", id);
+                events.push(Event::UpdateAborted(id));
+                continue;
+            }
+            let result = if self.queue.halted {
+                let text = "skipped after an earlier queued update failed".to_string();
+                InstallResult::new(format!("{}", id), InstallCode::DEPENDENCY_FAILURE, text)
+            } else {
+                self.install_update(id, etx).unwrap_or_else(|err| {
+                    InstallResult::new(format!("{}", id), InstallCode::GENERAL_ERROR, err.to_string())
+                })
+            };
+            if ! result.result_code.is_success() && ! self.config.device.continue_on_error {
+                self.queue.halted = true;
+            }
+            self.queue.add_result(result.clone());
+            events.push(Self::install_event(result));
+        }
+
+        if self.queue.is_empty() {
+            let results = self.queue.finish();
+            if ! results.is_empty() {
+                events.push(Event::InstallQueueComplete(results));
+            }
+        }
+        events
+    }
+
+    /// Gather the current state into a support bundle. Sections that can't be
+    /// read are logged and left empty rather than failing the export.
+    fn diagnostics(&self) -> Result<Diagnostics, Error> {
+        let mut diagnostics = Diagnostics::new(&self.config, self.version.clone())?;
+        match self.mode {
+            CommandMode::Uptane(ref uptane) => {
+                let (director, repo) = uptane.borrow().metadata_versions();
+                diagnostics.uptane = Some(MetadataVersions { director: director, repo: repo });
+            }
+            #[cfg(feature = "rvi")]
+            CommandMode::Rvi(ref services) => {
+                let services = services.borrow();
+                diagnostics.active_transfers = services.transfers.lock().unwrap().active.keys().cloned().collect();
+            }
+            CommandMode::Sota => ()
+        }
+        if let Some(ref dir) = self.config.device.report_spool_dir {
+            diagnostics.pending_reports = ReportSpool::new(dir.clone()).pending()
+                .unwrap_or_else(|err| { warn!("couldn't read pending reports: {}", err); Vec::new() });
+        }
+        diagnostics.queued_updates = self.queue.queued();
+        diagnostics.recent_events = self.history.last(self.config.gateway.event_history);
+        diagnostics.installed_packages = self.config.device.package_manager.installed_packages()
+            .unwrap_or_else(|err| { warn!("couldn't list installed packages: {}", err); Vec::new() });
+        Ok(diagnostics)
+    }
+
+    /// Return the sources that generate a system information report.
+    fn system_info(&self) -> Result<&SystemInfo, Error> {
+        self.config.device.system_info.as_ref()
+            .ok_or_else(|| Error::Config("device.system_info not set".into()))
+    }
+
+    /// Create a new HTTP client sending the configured static headers.
+    fn auth_client(&self, auth: Auth) -> AuthClient {
+        AuthClient::from(auth, self.config.http_headers(self.version.as_ref().map(String::as_str)))
+    }
+
+    /// Retrieve the current access token and device certificates for TLS,
+    /// with the bandwidth rules for downloads made while installing.
+    fn credentials(&self) -> Result<Credentials, Error> {
+        let client = Box::new(self.auth_client(self.auth.clone()));
+        let token = if let Auth::Token(ref t) = self.auth {
+            Some(t.access_token.clone())
+        } else {
+            None
+        };
+        let (ca_file, cert_file, pkey_file) = if let Some(ref tls) = self.config.tls {
+            (Some(tls.ca_file.clone()), Some(tls.cert_file.clone()), Some(tls.pkey_file.clone()))
+        } else {
+            (None, None, None)
+        };
+        let treehub = self.treehub().ok().or_else(|| Some(self.config.core.server.current().clone()));
+        let meter = DownloadMeter { bandwidth: Bandwidth::from_config(&self.config.device)?, clock: self.clock.clone() };
+        Ok(Credentials { client, token, ca_file, cert_file, pkey_file, treehub, meter })
+    }
+
+    /// Return an error unless updates are installed with ostree.
+    fn require_ostree(&self) -> Result<(), Error> {
+        match self.config.device.package_manager {
+            PacMan::Ostree | PacMan::Uptane => Ok(()),
+            ref pacman => Err(Error::OSTree(format!("remotes unavailable with package manager {:?}", pacman)))
+        }
+    }
+
+    /// Return the treehub URL.
+    fn treehub(&self) -> Result<Url, Error> {
+        self.config.tls.as_ref()
+            .map(|tls| tls.server.join("/treehub"))
+            .ok_or_else(|| Error::Config("tls.server required".into()))
+    }
+}
+
+/// Send each spooled report, stopping at the first failure.
+fn send_spooled_reports(sota: &mut Sota, spool: &ReportSpool) -> Result<(), Error> {
+    for report in spool.reports()? {
+        info!("Sending spooled install report for {}", report.update_id);
+        sota.send_install_report(&report)?;
+        spool.remove(&report.update_id)?;
+    }
+    Ok(())
+}
+
+/// Whether a command starts a download or install, and so waits until resumed.
+fn defers_while_paused(cmd: &Command) -> bool {
+    match *cmd {
+        Command::StartDownload(_)
+        | Command::StartInstall(_)
+        | Command::InstallLocalBundle { .. }
+        | Command::UptaneStartInstall(_)
+        | Command::UptaneForceInstall(_) => true,
+        _ => false
+    }
+}
+
+/// Describe a failed HTTP response with a truncated copy of its body.
+fn http_error(resp: &ResponseData) -> Event {
+    Event::HttpError { status: resp.code.to_u16(), url: resp.url.clone(), body_excerpt: resp.body_excerpt(HTTP_EXCERPT_LEN) }
+}
+
+/// Return the free bytes in a directory, or zero when it can't be read.
+fn dir_free_space(dir: &str) -> u64 {
+    Util::free_space(dir).unwrap_or_else(|err| {
+        warn!("couldn't read free space in {}: {}", dir, err);
+        0
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use base64;
     use chan::{self, Sender, Receiver};
-    use std::thread;
+    use chrono::TimeZone;
+    use crypto::digest::Digest;
+    use crypto::sha2::Sha256;
+    use hyper::status::StatusCode;
     use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
     use uuid::Uuid;
 
-    use datatype::{Auth, Command, Config, DownloadComplete, Event, InstallCode};
+    use datatype::{Auth, AuthConfig, ClientCredentials, Command, Config, DownloadComplete, Event,
+                   FakeClock, InstallCode, Package, RequestStatus, SignatureType, UpdateRequest};
     use http::TestClient;
+    use json;
     use pacman::PacMan;
+    use pacman::test::{MemoryPacMan, TestDir};
+    use schedule::ScheduledInstall;
+    use uptane::tests::new_uptane;
 
 
     fn new_interpreter(replies: Vec<Vec<u8>>, succeeds: bool) -> (Sender<Command>, Receiver<Event>) {
@@ -352,13 +1430,8 @@ mod tests {
         thread::spawn(move || {
             let mut config = Config::default();
             config.device.package_manager = PacMan::new_tpm(succeeds);
-            let mut ci = CommandInterpreter {
-                mode: CommandMode::Sota,
-                config: config,
-                auth: Auth::None,
-                http: Box::new(TestClient::from(replies)),
-                version: None,
-            };
+            let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(replies));
+            ci.clock = Arc::new(FakeClock::default());
             while let Some(cmd) = crx.recv() {
                 ci.interpret(CommandExec { cmd: cmd, etx: None }, &etx);
             }
@@ -367,8 +1440,72 @@ mod tests {
         (ctx, erx)
     }
 
+    fn test_interpreter(mode: CommandMode, config: Config, http: TestClient) -> CommandInterpreter {
+        let auto_dl = config.device.auto_download;
+        CommandInterpreter {
+            mode: mode,
+            config: config,
+            auth: Auth::None,
+            http: Box::new(http),
+            version: None,
+            queue: InstallQueue::default(),
+            hooks: Vec::new(),
+            clock: Arc::new(SystemClock),
+            token_expiry: None,
+            awaiting_auth: Vec::new(),
+            schedule: InstallSchedule::default(),
+            history: History::default(),
+            reports: ReportCache::default(),
+            throttled: Arc::default(),
+            deferred_targets: None,
+            paused: None,
+            aborted: AbortedUpdates::default(),
+            pending: Arc::default(),
+            previews: Arc::default(),
+            auto_dl: Arc::new(Mutex::new(auto_dl)),
+        }
+    }
+
+    fn test_event_interpreter(loop_tx: Sender<Event>, pacman: PacMan, auto_dl: bool) -> EventInterpreter {
+        EventInterpreter {
+            initial: false,
+            loop_tx: loop_tx,
+            auth:    Auth::None,
+            pacman:  pacman,
+            auto_dl: Arc::new(Mutex::new(auto_dl)),
+            pending: Arc::default(),
+            sysinfo: None,
+            hooks:   Vec::new(),
+            backoff: AuthBackoff::new(Duration::from_secs(1), Duration::from_secs(300)),
+            retrying: Arc::default(),
+            aborted: AbortedUpdates::default(),
+            previews: Arc::default(),
+        }
+    }
+
+    fn update_request(id: Uuid, status: RequestStatus) -> UpdateRequest {
+        UpdateRequest {
+            requestId:  id,
+            status:     status,
+            packageId:  Package { name: "fake-pkg".into(), version: "0.1.1".into() },
+            installPos: 0,
+            createdAt:  "2010-01-01".into(),
+            checksum:   None,
+            signature:  None,
+        }
+    }
+
     fn new_result(code: InstallCode) -> InstallResult {
-        InstallResult::new(format!("{}", Uuid::default()), code, "stdout: \nstderr: \n".into())
+        new_id_result(Uuid::default(), code)
+    }
+
+    /// The result of a first install attempt, taking no time on the stopped
+    /// clock of `new_interpreter`.
+    fn new_id_result(id: Uuid, code: InstallCode) -> InstallResult {
+        let mut result = InstallResult::new(format!("{}", id), code, "stdout: \nstderr: \n".into());
+        result.duration_ms = Some(0);
+        result.attempt = Some(1);
+        result
     }
 
     fn assert_rx<X: PartialEq + Debug>(rx: &Receiver<X>, vals: &[X]) {
@@ -391,6 +1528,621 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn installed_in_flight_update_not_downloaded() {
+        let dir = TestDir::new("sota-memory-pacman");
+        let pacman = MemoryPacMan::default();
+        let request = update_request(Uuid::new_v4(), RequestStatus::InFlight);
+
+        let (ctx, crx) = chan::async::<CommandExec>();
+        let (ltx, _) = chan::async::<Event>();
+        let mut ei = test_event_interpreter(ltx, PacMan::Memory(pacman.clone()), true);
+        ei.interpret(Event::UpdatesReceived(vec![request.clone()]), &ctx);
+        assert_eq!(crx.recv().map(|exec| exec.cmd), Some(Command::StartDownload(request.requestId)));
+
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.package_manager = PacMan::Memory(pacman.clone());
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![b"fake-pkg 0.1.1".to_vec()]));
+        let (etx, _) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartDownload(request.requestId), etx: None }, &etx);
+        ci.interpret(CommandExec { cmd: Command::StartInstall(request.requestId), etx: None }, &etx);
+        assert_eq!(pacman.installed_packages(), vec![request.packageId.clone()]);
+
+        ei.interpret(Event::UpdatesReceived(vec![request.clone()]), &ctx);
+        match crx.recv().map(|exec| exec.cmd) {
+            Some(Command::SendInstallReport(report)) => assert_eq!(report.update_id, format!("{}", request.requestId)),
+            other => panic!("expected SendInstallReport, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn confirm_update() {
+        let (ctx, erx) = new_interpreter(Vec::new(), true);
+        ctx.send(Command::ConfirmUpdate { update_id: Uuid::default(), approved: true });
+        ctx.send(Command::ConfirmUpdate { update_id: Uuid::default(), approved: false });
+        assert_rx(&erx, &[Event::UpdateApproved(Uuid::default()), Event::UpdateDenied(Uuid::default())]);
+
+        let (approve, deny) = (Uuid::new_v4(), Uuid::new_v4());
+        let (ltx, _) = chan::async::<Event>();
+        let mut ei = test_event_interpreter(ltx, PacMan::Off, false);
+        let mut queued = |event: Event| {
+            let (ctx, crx) = chan::async::<CommandExec>();
+            ei.interpret(event, &ctx);
+            drop(ctx);
+            crx.iter().map(|exec| exec.cmd).collect::<Vec<_>>()
+        };
+
+        let requests = vec![update_request(approve, RequestStatus::Pending), update_request(deny, RequestStatus::Pending)];
+        assert!(queued(Event::UpdatesReceived(requests)).is_empty());
+        assert_eq!(queued(Event::UpdateApproved(approve)), vec![Command::StartDownload(approve)]);
+        assert!(queued(Event::UpdateApproved(approve)).is_empty());
+        match queued(Event::UpdateDenied(deny)).pop() {
+            Some(Command::SendInstallReport(report)) => {
+                assert_eq!(report.update_id, format!("{}", deny));
+                assert_eq!(report.operation_results[0].result_code, InstallCode::USER_DECLINED);
+            }
+            other => panic!("expected SendInstallReport, got {:?}", other)
+        }
+        assert!(queued(Event::UpdateDenied(deny)).is_empty());
+    }
+
+    #[test]
+    fn interpreter_hooks() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let record: EventHook = Box::new(move |event: &Event| recorded.lock().unwrap().push(event.clone()));
+        let (ltx, _) = chan::async::<Event>();
+        let mut ei = test_event_interpreter(ltx, PacMan::Off, true);
+        ei.hooks = vec![record];
+        let (ctx, _) = chan::async::<CommandExec>();
+        ei.interpret(Event::NoUpdateRequests, &ctx);
+        assert_eq!(*seen.lock().unwrap(), vec![Event::NoUpdateRequests]);
+
+        let veto: CommandHook = Box::new(|cmd: &Command| *cmd != Command::GetPendingReports);
+        let mut ci = test_interpreter(CommandMode::Sota, Config::default(), TestClient::default());
+        ci.hooks = vec![veto];
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::GetPendingReports, etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::Error(err)) => assert!(err.contains("vetoed"), err),
+            other => panic!("expected vetoed command, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn set_auto_download() {
+        let request = update_request(Uuid::default(), RequestStatus::Pending);
+        let (ltx, _) = chan::async::<Event>();
+        let mut ei = test_event_interpreter(ltx, PacMan::Off, true);
+        let mut ci = test_interpreter(CommandMode::Sota, Config::default(), TestClient::default());
+        ci.auto_dl = ei.auto_dl.clone();
+        let mut set_auto_download = |enabled: bool| {
+            let (etx, erx) = chan::async::<Event>();
+            ci.interpret(CommandExec { cmd: Command::SetAutoDownload(enabled), etx: None }, &etx);
+            assert_eq!(erx.recv(), Some(Event::AutoDownloadChanged(enabled)));
+        };
+        let mut queued = |event: Event| {
+            let (ctx, crx) = chan::async::<CommandExec>();
+            ei.interpret(event, &ctx);
+            drop(ctx);
+            crx.iter().map(|exec| exec.cmd).collect::<Vec<_>>()
+        };
+
+        let download = vec![Command::StartDownload(request.requestId)];
+        assert_eq!(queued(Event::UpdatesReceived(vec![request.clone()])), download);
+        set_auto_download(false);
+        assert!(queued(Event::UpdatesReceived(vec![request.clone()])).is_empty());
+        set_auto_download(true);
+        assert_eq!(queued(Event::UpdatesReceived(vec![request.clone()])), download);
+    }
+
+    #[test]
+    fn max_updates_per_poll() {
+        let request = |pos: i32| UpdateRequest {
+            packageId:  Package { name: format!("pkg-{}", pos), version: "0.1.1".into() },
+            installPos: pos,
+            ..update_request(Uuid::new_v4(), RequestStatus::Pending)
+        };
+        let requests = vec![request(2), request(0), request(1)];
+        let mut config = Config::default();
+        config.core.max_updates_per_poll = 2;
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![json::to_vec(&requests).unwrap()]));
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::GetUpdateRequests, etx: None }, &etx);
+        let received = erx.recv().expect("updates received");
+        assert_eq!(received, Event::UpdatesReceived(vec![requests[1].clone(), requests[2].clone()]));
+
+        let (ltx, _) = chan::async::<Event>();
+        let mut ei = test_event_interpreter(ltx, PacMan::Off, true);
+        let (ctx, crx) = chan::async::<CommandExec>();
+        ei.interpret(received, &ctx);
+        drop(ctx);
+        let queued = crx.iter().map(|exec| exec.cmd).collect::<Vec<_>>();
+        assert_eq!(queued, vec![Command::StartDownload(requests[1].requestId), Command::StartDownload(requests[2].requestId)]);
+    }
+
+    #[test]
+    fn poll_telemetry() {
+        let mut config = Config::default();
+        config.core.server = "http://primary.example.com,http://fallback.example.com".parse().unwrap();
+        config.core.poll_telemetry = true;
+        let request = update_request(Uuid::default(), RequestStatus::Pending);
+        let reply = json::to_vec(&vec![request.clone()]).unwrap();
+        let bytes = reply.len() as u64;
+        let http = TestClient::from_responses(vec![
+            ResponseData::new(StatusCode::ServiceUnavailable, Vec::new()),
+            ResponseData::new(StatusCode::Ok, reply),
+        ]);
+        let mut ci = test_interpreter(CommandMode::Sota, config, http);
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::GetUpdateRequests, etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::PollTelemetry { server, latency_ms, bytes: received, updates_found }) => {
+                assert_eq!(server, "http://fallback.example.com/");
+                assert!(latency_ms < 10_000);
+                assert_eq!(received, bytes);
+                assert_eq!(updates_found, 1);
+            }
+            other => panic!("expected poll telemetry, got {:?}", other)
+        }
+        assert_eq!(erx.recv(), Some(Event::UpdatesReceived(vec![request])));
+    }
+
+    #[test]
+    fn poll_telemetry_failed() {
+        let mut config = Config::default();
+        config.core.server = "http://primary.example.com".parse().unwrap();
+        config.core.poll_telemetry = true;
+        let http = TestClient::from_responses(vec![
+            ResponseData::new(StatusCode::ServiceUnavailable, Vec::new()),
+        ]);
+        let mut ci = test_interpreter(CommandMode::Sota, config, http);
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::GetUpdateRequests, etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::PollTelemetry { server, bytes, updates_found, .. }) => {
+                assert_eq!(server, "http://primary.example.com/");
+                assert_eq!(bytes, 0);
+                assert_eq!(updates_found, 0);
+            }
+            other => panic!("expected poll telemetry, got {:?}", other)
+        }
+        match erx.recv() {
+            Some(Event::UpdatesReceived(_)) | Some(Event::NoUpdateRequests) => panic!("expected the poll to fail"),
+            _ => ()
+        }
+    }
+
+    #[test]
+    fn poll_telemetry_uptane() {
+        let mut config = Config::default();
+        config.core.poll_telemetry = true;
+        let paths = [
+            "tests/uptane_basic/director/root.json",
+            "tests/uptane_basic/director/timestamp.json",
+            "tests/uptane_basic/director/snapshot.json",
+            "tests/uptane_basic/director/targets.json",
+        ];
+        let bytes = paths.iter().map(|path| Util::read_file(path).unwrap().len() as u64).sum::<u64>();
+        let mode = CommandMode::Uptane(Rc::new(RefCell::new(new_uptane())));
+        let mut ci = test_interpreter(mode, config, TestClient::from_paths(&paths));
+        ci.clock = Arc::new(FakeClock::default());
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::GetUpdateRequests, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::PollTelemetry {
+            server:        "http://localhost:8001/".into(),
+            latency_ms:    0,
+            bytes:         bytes,
+            updates_found: 1,
+        }));
+        match erx.recv() {
+            Some(Event::UptaneTargetsUpdated(_)) => (),
+            other => panic!("expected new targets, got {:?}", other)
+        }
+
+        // a failed poll still reports its telemetry
+        ci.interpret(CommandExec { cmd: Command::GetUpdateRequests, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::PollTelemetry {
+            server:        "http://localhost:8001/".into(),
+            latency_ms:    0,
+            bytes:         0,
+            updates_found: 0,
+        }));
+    }
+
+    #[test]
+    fn download_skipped_when_verified() {
+        let dir = TestDir::new("sota-download-skipped");
+        let id = Uuid::new_v4();
+        let artifact = format!("{}/{}", dir.0, id);
+        Util::write_file(&artifact, b"package").expect("write artifact");
+        let checksum = "bc4a71180870f7945155fbb02f4b0a2e3faa2a62d6d31b7039013055ed19869a";
+        Util::write_file(&format!("{}.sha256", artifact), checksum.as_bytes()).expect("write checksum");
+
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        assert_eq!(Sota::new(&config, &TestClient::default()).verified_download(&id, "0000"), None);
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::default());
+        ci.queue.set_checksum(id, checksum.to_uppercase());
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartDownload(id), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::DownloadSkipped(id)));
+        let dl = DownloadComplete { update_id: id, update_image: artifact, signature: "".into() };
+        assert_eq!(erx.recv(), Some(Event::DownloadComplete(dl)));
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let dir = TestDir::new("sota-pause-resume");
+        let pacman = MemoryPacMan::default();
+        let id = Uuid::new_v4();
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.package_manager = PacMan::Memory(pacman.clone());
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![b"fake-pkg 0.1.1".to_vec()]));
+
+        let (etx, erx) = chan::async::<Event>();
+        for cmd in vec![Command::Pause, Command::StartDownload(id), Command::StartDownload(id), Command::StartInstall(id)] {
+            ci.interpret(CommandExec { cmd: cmd, etx: None }, &etx);
+        }
+        assert_eq!(erx.recv(), Some(Event::Paused));
+        assert_eq!(erx.recv(), Some(Event::CommandDeferred("StartDownload".into())));
+        assert_eq!(erx.recv(), Some(Event::CommandDeferred("StartDownload".into())));
+        assert_eq!(erx.recv(), Some(Event::CommandDeferred("StartInstall".into())));
+        assert!(pacman.installed_packages().is_empty());
+        assert_eq!(ci.paused.as_ref().map(|deferred| deferred.len()), Some(2));
+
+        ci.interpret(CommandExec { cmd: Command::Resume, etx: None }, &etx);
+        assert!(ci.paused.is_none());
+        assert_eq!(pacman.installed_packages().len(), 1);
+        drop(etx);
+        let events = erx.iter().collect::<Vec<_>>();
+        assert_eq!(events.last(), Some(&Event::Resumed));
+        assert_eq!(events.iter().filter(|ev| match **ev { Event::DownloadComplete(_) => true, _ => false }).count(), 1);
+    }
+
+    #[test]
+    fn check_updates_not_downloaded() {
+        let request = update_request(Uuid::default(), RequestStatus::Pending);
+        let reply = format!("[{}]", json::to_string(&request).unwrap()).into_bytes();
+        let mut ci = test_interpreter(CommandMode::Sota, Config::default(), TestClient::from(vec![reply]));
+
+        let (etx, erx) = chan::async::<Event>();
+        let (btx, brx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::CheckUpdates, etx: Some(etx) }, &btx);
+        let received = Event::UpdatesReceived(vec![request]);
+        assert_eq!(erx.recv(), Some(received.clone()));
+        assert_eq!(brx.recv(), Some(received.clone()));
+
+        // the broadcast preview doesn't start a download, but a later poll does
+        let (ltx, _) = chan::async::<Event>();
+        let (ctx, crx) = chan::async::<CommandExec>();
+        let mut ei = test_event_interpreter(ltx, PacMan::Off, true);
+        ei.previews = ci.previews.clone();
+        ei.interpret(received.clone(), &ctx);
+        assert!(ci.previews.lock().unwrap().is_empty());
+        ei.interpret(received, &ctx);
+        drop(ctx);
+        assert_eq!(crx.iter().map(|exec| exec.cmd).collect::<Vec<_>>(), vec![Command::StartDownload(Uuid::default())]);
+    }
+
+    #[test]
+    fn authenticate_missing_scope() {
+        let token = br#"{"access_token": "token", "token_type": "bearer", "expires_in": 3600, "scope": "scope1"}"#;
+        let mut config = Config::default();
+        config.auth = Some(AuthConfig { required_scope: Some("scope1 scope2".into()), ..AuthConfig::default() });
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![token.to_vec()]));
+
+        let (etx, erx) = chan::async::<Event>();
+        let creds = ClientCredentials { client_id: "id".into(), client_secret: "secret".into() };
+        ci.interpret(CommandExec { cmd: Command::Authenticate(Auth::Credentials(creds)), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::AuthenticationFailed("access token is missing required scopes: scope2".into())));
+        assert_eq!(ci.auth, Auth::None);
+    }
+
+    #[test]
+    fn reauthenticate_failed() {
+        let unauthorized = ResponseData::new(StatusCode::Unauthorized, br#"{"error": "invalid_client"}"#.to_vec());
+        let mut config = Config::default();
+        config.auth = Some(AuthConfig::default());
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from_responses(vec![unauthorized]));
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::ReAuthenticate, etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::AuthenticationFailed(reason)) => assert!(reason.ends_with("returned 401 Unauthorized"), "{}", reason),
+            event => panic!("unexpected event: {:?}", event)
+        }
+        assert_eq!(ci.auth, Auth::None);
+    }
+
+    #[test]
+    fn expired_token_reauthenticates() {
+        let token = br#"{"access_token": "token", "token_type": "bearer", "expires_in": 3600, "scope": ""}"#;
+        let response = |code: StatusCode, body: &[u8]| ResponseData::new(code, body.to_vec());
+        let clock = FakeClock::default();
+        let mut config = Config::default();
+        config.auth = Some(AuthConfig::default());
+        let http = TestClient::from_responses(vec![
+            response(StatusCode::Ok, token),
+            response(StatusCode::Ok, b"[]"),
+            response(StatusCode::Ok, token),
+            response(StatusCode::Ok, b"[]"),
+            response(StatusCode::Unauthorized, br#"{"error": "invalid_client"}"#),
+            response(StatusCode::Ok, token),
+            response(StatusCode::Ok, b"[]"),
+        ]);
+        let mut ci = test_interpreter(CommandMode::Sota, config, http);
+        ci.clock = Arc::new(clock.clone());
+
+        let (etx, erx) = chan::async::<Event>();
+        let creds = Auth::Credentials(ClientCredentials { client_id: "id".into(), client_secret: "secret".into() });
+        let mut ei = test_event_interpreter(etx.clone(), PacMan::Off, false);
+        ei.auth = creds.clone();
+        ci.interpret(CommandExec { cmd: Command::Authenticate(creds), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::Authenticated));
+        ci.interpret(CommandExec { cmd: Command::GetUpdateRequests, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::NoUpdateRequests));
+
+        // the token is refreshed before running the command
+        clock.advance(chrono::Duration::seconds(3600));
+        ci.interpret(CommandExec { cmd: Command::GetUpdateRequests, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::Authenticated));
+        assert_eq!(erx.recv(), Some(Event::NoUpdateRequests));
+        assert!(ci.token_expiry.expect("token expiry") > clock.now());
+
+        // the command waits for the next authentication when the refresh fails
+        clock.advance(chrono::Duration::seconds(3600));
+        ci.interpret(CommandExec { cmd: Command::GetUpdateRequests, etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::AuthenticationFailed(_)) => (),
+            event => panic!("unexpected event: {:?}", event)
+        }
+        assert_eq!(erx.recv(), Some(Event::NotAuthenticated));
+        assert_eq!(ci.awaiting_auth.len(), 1);
+
+        let (ctx, crx) = chan::async::<CommandExec>();
+        ei.interpret(Event::NotAuthenticated, &ctx);
+        let reauth = crx.recv().expect("authenticate command");
+        ci.interpret(reauth, &etx);
+        assert_eq!(erx.recv(), Some(Event::Authenticated));
+        assert_eq!(erx.recv(), Some(Event::NoUpdateRequests));
+        assert!(ci.awaiting_auth.is_empty());
+    }
+
+    #[test]
+    fn auth_failure_backoff() {
+        let (ltx, lrx) = chan::async::<Event>();
+        let mut ei = test_event_interpreter(ltx, PacMan::Off, false);
+        ei.backoff = AuthBackoff::new(Duration::from_millis(10), Duration::from_secs(60));
+
+        let (ctx, crx) = chan::async::<CommandExec>();
+        ei.interpret(Event::NotAuthenticated, &ctx);
+        assert_eq!(crx.recv().map(|exec| exec.cmd), Some(Command::Authenticate(Auth::None)));
+        assert_eq!(ei.backoff.failures, 0);
+
+        // a single retry is pending however many failures arrive meanwhile
+        ei.interpret(Event::AuthenticationFailed("first".into()), &ctx);
+        ei.interpret(Event::NotAuthenticated, &ctx);
+        ei.interpret(Event::AuthenticationFailed("second".into()), &ctx);
+        ei.interpret(Event::NotAuthenticated, &ctx);
+        assert_eq!(lrx.recv(), Some(Event::AuthenticationBackoff { next_attempt_secs: 0 }));
+        assert_eq!(lrx.recv(), Some(Event::AuthenticationBackoff { next_attempt_secs: 0 }));
+        assert_eq!(ei.backoff.failures, 2);
+        drop(ctx);
+        assert_eq!(crx.recv().map(|exec| exec.cmd), Some(Command::Authenticate(Auth::None)));
+        assert_eq!(crx.recv().map(|exec| exec.cmd), None);
+        assert!(! *ei.retrying.lock().unwrap());
+
+        let (ctx, _crx) = chan::async::<CommandExec>();
+        ei.interpret(Event::Authenticated, &ctx);
+        assert_eq!(ei.backoff.failures, 0);
+    }
+
+    #[test]
+    fn http_error_status() {
+        let body = vec![b'x'; 1024];
+        let http = TestClient::from_responses(vec![
+            ResponseData::new(StatusCode::NotFound, body)
+        ]);
+        let mut ci = test_interpreter(CommandMode::Sota, Config::default(), http);
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::CheckUpdates, etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::HttpError { status, url, body_excerpt }) => {
+                assert_eq!(status, 404);
+                assert!(url.ends_with("/updates"), url);
+                assert_eq!(body_excerpt.len(), HTTP_EXCERPT_LEN);
+            }
+            other => panic!("expected HttpError, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn get_storage_info() {
+        let mut config = Config::default();
+        config.device.packages_dir = "/tmp/sota-storage-info/missing".into();
+        config.device.package_manager = PacMan::Deb;
+        config.device.deb_packages_dir = Some("/tmp".into());
+        config.device.staging_dir = Some("/tmp/sota-storage-info/staging".into());
+        config.rvi.storage_dir = "/tmp/sota-storage-info/missing".into();
+        config.uptane.metadata_path = "/tmp/sota-storage-info/missing".into();
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::default());
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::GetStorageInfo, etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::StorageInfo { packages_dir_free, images_dir_free, metadata_dir_free, download_dirs_free }) => {
+                assert!(packages_dir_free > 0);
+                assert_eq!(images_dir_free, 0);
+                assert_eq!(metadata_dir_free, 0);
+                assert!(download_dirs_free["/tmp"] > 0);
+                assert_eq!(download_dirs_free["/tmp/sota-storage-info/staging"], 0);
+            }
+            other => panic!("expected StorageInfo, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn abort_all_transfers() {
+        let dir = TestDir::new("sota-abort-all-transfers");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::default());
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::AbortAllTransfers, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::TransfersAborted(0)));
+
+        let partial = format!("{}/{}.part", dir.0, Uuid::new_v4());
+        Util::write_file(&partial, b"orphaned").expect("write partial");
+        ci.interpret(CommandExec { cmd: Command::AbortAllTransfers, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::TransfersAborted(1)));
+        assert!(! Path::new(&partial).exists());
+    }
+
+    #[test]
+    fn export_diagnostics() {
+        let dir = TestDir::new("sota-export-diagnostics");
+        let path = format!("{}/diagnostics.json", dir.0);
+        let (ctx, erx) = new_interpreter(vec![], true);
+        ctx.send(Command::ExportDiagnostics { path: path.clone() });
+        assert_rx(&erx, &[Event::DiagnosticsExported { path: path.clone() }]);
+        let exported: json::Value = json::from_slice(&Util::read_file(&path).unwrap()).unwrap();
+        assert!(exported["installed_packages"].is_array());
+    }
+
+    #[test]
+    fn ostree_remotes_need_ostree() {
+        let (ctx, erx) = new_interpreter(vec![], true);
+        ctx.send(Command::OstreeDeleteRemote("treehub".into()));
+        match erx.recv() {
+            Some(Event::Error(err)) => assert!(err.starts_with("OSTree error: remotes unavailable")),
+            other => panic!("expected Error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn spool_pending_reports() {
+        let dir = TestDir::new("sota-pending-reports");
+        let mut config = Config::default();
+        config.device.report_spool_dir = Some(dir.0.clone());
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![Vec::new(), Vec::new()]));
+        let (etx, erx) = chan::async::<Event>();
+        let mut send = |cmd| {
+            ci.interpret(CommandExec { cmd: cmd, etx: None }, &etx);
+            erx.recv().expect("no event")
+        };
+
+        // the first two reports are sent then the third fails and is spooled
+        let report = |id: &str| InstallResult::new(id.into(), InstallCode::OK, "".into()).into_report();
+        assert_eq!(send(Command::SendInstallReport(report("one"))), Event::InstallReportSent(report("one")));
+        assert_eq!(send(Command::SendInstallReport(report("two"))), Event::InstallReportSent(report("two")));
+        match send(Command::SendInstallReport(report("three"))) {
+            Event::Error(_) => (),
+            other => panic!("expected Error, got {:?}", other)
+        }
+        match send(Command::GetPendingReports) {
+            Event::PendingReports(ref pending) if pending.len() == 1 => assert_eq!(pending[0].update_id, "three"),
+            other => panic!("expected PendingReports, got {:?}", other)
+        }
+        match send(Command::ClearPendingReports) {
+            Event::PendingReportsCleared(ref cleared) if cleared.len() == 1 => assert_eq!(cleared[0].update_id, "three"),
+            other => panic!("expected PendingReportsCleared, got {:?}", other)
+        }
+        assert_eq!(send(Command::GetPendingReports), Event::PendingReports(Vec::new()));
+    }
+
+    #[test]
+    fn send_pending_reports() {
+        let dir = TestDir::new("sota-send-pending-reports");
+        let spool = ReportSpool::new(dir.0.clone());
+        spool.save(&InstallResult::new("spooled".into(), InstallCode::OK, "".into()).into_report()).unwrap();
+        let mut config = Config::default();
+        config.device.report_spool_dir = Some(dir.0.clone());
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![Vec::new()]));
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::SendPendingReports, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::PendingReports(Vec::new())));
+        assert!(spool.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replay_events() {
+        let history = History::new(3);
+        for event in vec![Event::NotAuthenticated, Event::Authenticated, Event::NoUpdateRequests, Event::UptaneManifestSent] {
+            history.push(event);
+        }
+        let mut ci = test_interpreter(CommandMode::Sota, Config::default(), TestClient::default());
+        ci.history = history;
+        let (etx, erx) = chan::async::<Event>();
+        let (reply_tx, reply_rx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::ReplayEvents(2), etx: Some(reply_tx) }, &etx);
+        assert_eq!(reply_rx.recv(), Some(Event::RecentEvents(vec![Event::NoUpdateRequests, Event::UptaneManifestSent])));
+
+        // replayed events aren't broadcast
+        ci.interpret(CommandExec { cmd: Command::ReplayEvents(5), etx: None }, &etx);
+        ci.interpret(CommandExec { cmd: Command::Authenticate(Auth::None), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::Authenticated));
+    }
+
+    #[test]
+    fn uptane_send_manifest_now() {
+        let mode = CommandMode::Uptane(Rc::new(RefCell::new(new_uptane())));
+        let mut ci = test_interpreter(mode, Config::default(), TestClient::from(vec![Vec::new()]));
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::UptaneSendManifestNow, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::UptaneManifestSent));
+
+        ci.mode = CommandMode::Sota;
+        ci.interpret(CommandExec { cmd: Command::UptaneSendManifestNow, etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::Error(err)) => assert!(err.contains("requires uptane mode")),
+            other => panic!("expected Error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn uptane_force_install_rejected() {
+        let metadata = |count: usize| {
+            let paths = ["tests/uptane_basic/director/root.json", "tests/uptane_basic/director/timestamp.json",
+                         "tests/uptane_basic/director/snapshot.json", "tests/uptane_basic/director/targets.json"];
+            TestClient::from_paths(&paths.iter().cycle().take(4 * count).cloned().collect::<Vec<_>>())
+        };
+        let mode = CommandMode::Uptane(Rc::new(RefCell::new(new_uptane())));
+        let mut ci = test_interpreter(mode, Config::default(), metadata(2));
+        let (etx, erx) = chan::async::<Event>();
+
+        ci.interpret(CommandExec { cmd: Command::UptaneForceInstall("/missing.img".into()), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::Error("/missing.img not found in targets.json".into())));
+        ci.interpret(CommandExec { cmd: Command::UptaneForceInstall("/file.img".into()), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::Error("/file.img is for unknown ECU some-ecu-id".into())));
+
+        ci.mode = CommandMode::Sota;
+        ci.interpret(CommandExec { cmd: Command::UptaneForceInstall("/file.img".into()), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::Error("forcing an install requires uptane mode".into())));
+    }
+
+    #[test]
+    fn sync_installed_packages() {
+        let (ctx, erx) = new_interpreter(vec!["[]".into(); 10], true);
+        ctx.send(Command::SyncInstalledPackages);
+        assert_rx(&erx, &[Event::InstalledPackagesSent]);
+    }
+
+    #[test]
+    fn send_installed_software_needs_rvi() {
+        let (ctx, erx) = new_interpreter(vec![], true);
+        ctx.send(Command::SendInstalledSoftwareNow);
+        assert_rx(&erx, &[Event::Error("installed software reports require rvi mode".into())]);
+    }
+
     #[test]
     fn install_update_success() {
         let (ctx, erx) = new_interpreter(vec!["[]".into(); 10], true);
@@ -401,6 +2153,77 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn schedule_install() {
+        let (ctx, erx) = new_interpreter(vec!["[]".into(); 10], true);
+        let id = Uuid::new_v4();
+        let at = Utc::now() + chrono::Duration::hours(1);
+        ctx.send(Command::ScheduleInstall { update_id: id, at: at });
+        assert_rx(&erx, &[
+            Event::DownloadingUpdate(id),
+            Event::InstallScheduled { update_id: id, at: at },
+        ]);
+        ctx.send(Command::ScheduleInstall { update_id: id, at: at });
+        assert_rx(&erx, &[Event::InstallScheduled { update_id: id, at: at }]);
+    }
+
+    #[test]
+    fn scheduled_install_started() {
+        let mut config = Config::default();
+        config.device.package_manager = PacMan::new_tpm(true);
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec!["[]".into(); 10]));
+        let id = Uuid::new_v4();
+        let at = Utc::now();
+        ci.schedule.add(id, at).expect("schedule install");
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::Pause, etx: None }, &etx);
+        ci.interpret(CommandExec { cmd: Command::StartInstall(id), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::Paused));
+        assert_eq!(erx.recv(), Some(Event::CommandDeferred("StartInstall".into())));
+        assert_eq!(ci.schedule.pending(), vec![ScheduledInstall { update_id: id, at: at }]);
+
+        ci.interpret(CommandExec { cmd: Command::Resume, etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::InstallStarting(id)));
+        assert_eq!(erx.recv(), Some(Event::InstallingUpdate(id)));
+        assert!(ci.schedule.pending().is_empty());
+    }
+
+    #[test]
+    fn set_package_manager() {
+        let (ctx, erx) = new_interpreter(vec!["[]".into(); 10], true);
+        let failing = PacMan::new_tpm(false);
+        ctx.send(Command::SetPackageManager(failing.clone()));
+        assert_rx(&erx, &[Event::PackageManagerChanged(failing)]);
+
+        ctx.send(Command::StartInstall(Uuid::default()));
+        assert_rx(&erx, &[
+            Event::InstallingUpdate(Uuid::default()),
+            Event::InstallFailed(new_result(InstallCode::INSTALL_FAILED)),
+        ]);
+    }
+
+    #[test]
+    fn install_update_timing() {
+        let mut config = Config::default();
+        config.device.package_manager = PacMan::new_tpm(true);
+        config.device.post_install_health_cmd = Some("sleep 0.2; exit 1".into());
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec!["[]".into(); 10]));
+
+        let (etx, erx) = chan::async::<Event>();
+        for attempt in 1..3 {
+            ci.interpret(CommandExec { cmd: Command::StartInstall(Uuid::default()), etx: None }, &etx);
+            assert_eq!(erx.recv(), Some(Event::InstallingUpdate(Uuid::default())));
+            match erx.recv() {
+                Some(Event::InstallFailed(result)) => {
+                    assert!(result.duration_ms.expect("duration_ms") >= 200);
+                    assert_eq!(result.attempt, Some(attempt));
+                }
+                event => panic!("unexpected event: {:?}", event)
+            }
+        }
+    }
+
     #[test]
     fn install_update_failed() {
         let (ctx, erx) = new_interpreter(vec!["[]".into(); 10], false);
@@ -410,4 +2233,333 @@ mod tests {
             Event::InstallFailed(new_result(InstallCode::INSTALL_FAILED)),
         ]);
     }
+
+    #[test]
+    fn server_abort_in_flight() {
+        let dir = TestDir::new("sota-server-abort");
+        let (installed, canceled) = (Uuid::new_v4(), Uuid::new_v4());
+        for id in &[installed, canceled] {
+            Util::write_file(&format!("{}/{}", dir.0, id), b"package").expect("write artifact");
+        }
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.package_manager = PacMan::new_tpm(true);
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![Vec::new()]));
+        ci.queue.push(installed);
+        ci.queue.push(canceled);
+        ci.queue.set_ready(canceled);
+
+        let (ltx, _) = chan::async::<Event>();
+        let mut ei = test_event_interpreter(ltx, PacMan::new_tpm(true), true);
+        ei.aborted = ci.aborted.clone();
+        let request = update_request(canceled, RequestStatus::Canceled);
+        let (ctx, crx) = chan::async::<CommandExec>();
+        ei.interpret(Event::UpdatesReceived(vec![request]), &ctx);
+        assert!(ci.aborted.is_canceled(&canceled));
+
+        // the queued batch is installing before the `ServerAbort` is handled
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartInstall(installed), etx: None }, &etx);
+        ci.interpret(crx.recv().expect("ServerAbort"), &etx);
+        ci.interpret(CommandExec { cmd: Command::StartDownload(canceled), etx: None }, &etx);
+        drop(etx);
+        let events = erx.iter().collect::<Vec<_>>();
+        assert!(events.contains(&Event::InstallingUpdate(installed)));
+        assert!(! events.contains(&Event::InstallingUpdate(canceled)));
+        assert_eq!(events.iter().filter(|ev| **ev == Event::UpdateAborted(canceled)).count(), 3);
+        assert!(ci.aborted.is_aborted(&canceled));
+        assert!(! Path::new(&format!("{}/{}", dir.0, canceled)).exists());
+    }
+
+    #[test]
+    fn cleanup_artifacts_after_install() {
+        let dir = TestDir::new("sota-cleanup-artifacts");
+        let artifact = format!("{}/{}", dir.0, Uuid::default());
+        Util::write_file(&artifact, b"package").expect("write artifact");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.package_manager = PacMan::new_tpm(true);
+        config.device.cleanup_after_install = true;
+        config.rvi.storage_dir = dir.0.clone();
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec!["[]".into(); 10]));
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartInstall(Uuid::default()), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::InstallingUpdate(Uuid::default())));
+        assert!(! Path::new(&artifact).exists());
+
+        let (queued, confirming) = (Uuid::new_v4(), Uuid::new_v4());
+        ci.queue.push(queued);
+        ci.pending.lock().unwrap().insert(confirming);
+        for id in &[Uuid::default(), queued, confirming] {
+            Util::write_file(&format!("{}/{}", dir.0, id), b"package").expect("write artifact");
+        }
+        ci.interpret(CommandExec { cmd: Command::CleanupArtifacts { older_than_secs: None }, etx: None }, &etx);
+        drop(etx);
+        let cleaned = erx.iter().find(|event| match *event { Event::ArtifactsCleaned { .. } => true, _ => false });
+        assert_eq!(cleaned, Some(Event::ArtifactsCleaned { freed_bytes: 7, count: 1 }));
+        assert!(! Path::new(&artifact).exists());
+        assert!(Path::new(&format!("{}/{}", dir.0, queued)).exists());
+        assert!(Path::new(&format!("{}/{}", dir.0, confirming)).exists());
+    }
+
+    #[test]
+    fn install_update_rolled_back() {
+        let dir = TestDir::new("sota-health-check");
+        let mut config = Config::default();
+        config.device.package_manager = PacMan::new_tpm(true);
+        config.device.post_install_health_cmd = Some("echo unhealthy >&2; exit 1".into());
+        config.device.rollback_cmd = Some(format!("touch {}/rolled_back", dir.0));
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec!["[]".into(); 10]));
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartInstall(Uuid::default()), etx: None }, &etx);
+        drop(etx);
+        let events = erx.iter().collect::<Vec<_>>();
+        assert_eq!(events[0], Event::InstallingUpdate(Uuid::default()));
+        assert_eq!(events[1], Event::InstallRolledBack(Uuid::default()));
+        match events[2] {
+            Event::InstallFailed(ref result) => {
+                assert_eq!(result.result_code, InstallCode::INSTALL_FAILED);
+                assert_eq!(result.result_text, "health check failed: unhealthy; rolled back");
+            }
+            ref event => panic!("unexpected event: {:?}", event)
+        }
+        assert!(Path::new(&format!("{}/rolled_back", dir.0)).exists());
+    }
+
+    #[test]
+    fn install_update_already_installed() {
+        let dir = TestDir::new("sota-health-already-installed");
+        let pacman = MemoryPacMan::default();
+        pacman.push_result(InstallCode::ALREADY_PROCESSED);
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.package_manager = PacMan::Memory(pacman);
+        config.device.post_install_health_cmd = Some("exit 1".into());
+        config.device.rollback_cmd = Some(format!("touch {}/rolled_back", dir.0));
+        Util::write_file(&format!("{}/{}", dir.0, Uuid::default()), b"fake-pkg 0.1.1").expect("write package");
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::default());
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartInstall(Uuid::default()), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::InstallingUpdate(Uuid::default())));
+        match erx.recv() {
+            Some(Event::InstallComplete(ref result)) => assert_eq!(result.result_code, InstallCode::ALREADY_PROCESSED),
+            event => panic!("unexpected event: {:?}", event)
+        }
+        assert!(! Path::new(&format!("{}/rolled_back", dir.0)).exists());
+    }
+
+    #[test]
+    fn install_update_reboot_required() {
+        let dir = TestDir::new("sota-reboot-required");
+        let marker = format!("{}/reboot-required", dir.0);
+        Util::write_file(&marker, b"").expect("write marker");
+        let mut config = Config::default();
+        config.device.package_manager = PacMan::new_tpm(true);
+        config.device.reboot_marker_file = Some(marker);
+        config.device.reboot_after_install = true;
+        config.device.reboot_cmd = format!("touch {}/rebooted", dir.0);
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec!["[]".into(); 10]));
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartInstall(Uuid::default()), etx: None }, &etx);
+        drop(etx);
+        let events = erx.iter().collect::<Vec<_>>();
+        assert_eq!(events[0], Event::InstallingUpdate(Uuid::default()));
+        assert_eq!(events[1], Event::RebootRequired(Uuid::default()));
+        match events[2] {
+            Event::InstallComplete(ref result) => assert_eq!(result.reboot_required, Some(true)),
+            ref event => panic!("unexpected event: {:?}", event)
+        }
+        assert!(Path::new(&format!("{}/rebooted", dir.0)).exists());
+    }
+
+    #[test]
+    fn install_queue_in_order() {
+        let first  = "00000000-0000-0000-0000-000000000001".parse::<Uuid>().unwrap();
+        let second = "00000000-0000-0000-0000-000000000002".parse::<Uuid>().unwrap();
+        let (ctx, erx) = new_interpreter(vec!["[]".into(); 10], true);
+        ctx.send(Command::StartDownload(first));
+        ctx.send(Command::StartDownload(second));
+        assert_rx(&erx, &[
+            Event::DownloadingUpdate(first),
+            Event::DownloadComplete(DownloadComplete {
+                update_id:    first,
+                update_image: format!("/tmp/{}", first),
+                signature:    "".to_string()
+            }),
+            Event::DownloadingUpdate(second),
+            Event::DownloadComplete(DownloadComplete {
+                update_id:    second,
+                update_image: format!("/tmp/{}", second),
+                signature:    "".to_string()
+            }),
+        ]);
+
+        ctx.send(Command::StartInstall(second));
+        assert_rx(&erx, &[Event::InstallQueued(second)]);
+        ctx.send(Command::StartInstall(first));
+        assert_rx(&erx, &[
+            Event::InstallingUpdate(first),
+            Event::InstallComplete(new_id_result(first, InstallCode::OK)),
+            Event::InstallingUpdate(second),
+            Event::InstallComplete(new_id_result(second, InstallCode::OK)),
+            Event::InstallQueueComplete(vec![
+                new_id_result(first, InstallCode::OK),
+                new_id_result(second, InstallCode::OK),
+            ]),
+        ]);
+    }
+
+    #[test]
+    fn install_queue_halts_on_failure() {
+        let first  = "00000000-0000-0000-0000-000000000003".parse::<Uuid>().unwrap();
+        let second = "00000000-0000-0000-0000-000000000004".parse::<Uuid>().unwrap();
+        let (ctx, erx) = new_interpreter(vec!["[]".into(); 10], false);
+        ctx.send(Command::StartDownload(first));
+        ctx.send(Command::StartDownload(second));
+        for _ in 0..4 { let _ = erx.recv(); }
+
+        ctx.send(Command::StartInstall(second));
+        assert_rx(&erx, &[Event::InstallQueued(second)]);
+        ctx.send(Command::StartInstall(first));
+        let skipped = InstallResult::new(format!("{}", second), InstallCode::DEPENDENCY_FAILURE,
+                                         "skipped after an earlier queued update failed".into());
+        assert_rx(&erx, &[
+            Event::InstallingUpdate(first),
+            Event::InstallFailed(new_id_result(first, InstallCode::INSTALL_FAILED)),
+            Event::InstallFailed(skipped.clone()),
+            Event::InstallQueueComplete(vec![new_id_result(first, InstallCode::INSTALL_FAILED), skipped]),
+        ]);
+    }
+
+    #[test]
+    fn install_queue_after_download() {
+        let dir = TestDir::new("sota-queue-download");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.continue_on_error = true;
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![b"hello".to_vec()]));
+        let (downloaded, failed) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let (etx, _erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartDownload(downloaded), etx: None }, &etx);
+        assert_eq!(ci.queue.queued(), vec![downloaded]);
+        ci.interpret(CommandExec { cmd: Command::StartDownload(failed), etx: None }, &etx);
+        assert_eq!(ci.queue.queued(), vec![downloaded]);
+        ci.interpret(CommandExec { cmd: Command::ServerAbort(downloaded), etx: None }, &etx);
+        assert!(ci.queue.is_empty());
+    }
+
+    #[test]
+    fn install_verifies_signature() {
+        let dir = TestDir::new("sota-install-signature");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.package_manager = PacMan::new_tpm(true);
+        config.device.continue_on_error = true;
+        config.device.verify_signature = true;
+        config.device.signature_key = Some("tests/keys/rsa.pub".into());
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::default());
+
+        let (unsigned, signed) = (Uuid::new_v4(), Uuid::new_v4());
+        for id in &[unsigned, signed] {
+            Util::write_file(&format!("{}/{}", dir.0, id), b"package").expect("write package");
+            ci.queue.push(*id);
+        }
+        let mut hasher = Sha256::new();
+        hasher.input(b"package");
+        let pri_key = Util::read_file("tests/keys/rsa.der").expect("rsa.der");
+        let sig = SignatureType::RsaSsaPss.sign_msg(hasher.result_str().as_bytes(), &pri_key).expect("sign");
+        ci.queue.set_signature(unsigned, "".into());
+        ci.queue.set_signature(signed, base64::encode(&sig));
+
+        let (etx, erx) = chan::async::<Event>();
+        ci.interpret(CommandExec { cmd: Command::StartInstall(unsigned), etx: None }, &etx);
+        match erx.recv() {
+            Some(Event::InstallFailed(result)) => assert_eq!(result.result_code, InstallCode::VALIDATION_FAILED),
+            other => panic!("expected a validation failure, got {:?}", other)
+        }
+        assert_eq!(ci.queue.queued(), vec![signed]);
+        assert!(! Path::new(&format!("{}/{}", dir.0, unsigned)).exists());
+
+        ci.interpret(CommandExec { cmd: Command::StartInstall(signed), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::InstallingUpdate(signed)));
+        match erx.recv() {
+            Some(Event::InstallComplete(result)) => assert_eq!(result.result_code, InstallCode::OK),
+            other => panic!("expected the signed update to install, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn duplicate_install_report() {
+        // the client only has replies for the first report and the changed outcome
+        let (ctx, erx) = new_interpreter(vec![Vec::new(); 2], true);
+        let report = InstallResult::new(format!("{}", Uuid::default()), InstallCode::OK, "".into()).into_report();
+        ctx.send(Command::SendInstallReport(report.clone()));
+        ctx.send(Command::SendInstallReport(report.clone()));
+        assert_rx(&erx, &[Event::InstallReportSent(report.clone()), Event::InstallReportSkipped(report.update_id)]);
+
+        let failed = InstallResult::new(format!("{}", Uuid::default()), InstallCode::INSTALL_FAILED, "".into()).into_report();
+        ctx.send(Command::SendInstallReport(failed.clone()));
+        assert_rx(&erx, &[Event::InstallReportSent(failed)]);
+    }
+
+    #[test]
+    fn bandwidth_deferred_download() {
+        let dir = TestDir::new("sota-bandwidth-deferred");
+        let clock = FakeClock::new(Utc.ymd(2017, 1, 1).and_hms(12, 0, 0));
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.download_rules = Some("00:00-06:00=full".into());
+        config.device.download_monthly_cap = Some(5);
+        config.device.download_usage_file = Some(format!("{}/usage.json", dir.0));
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::from(vec![b"hello".to_vec()]));
+        ci.clock = Arc::new(clock.clone());
+
+        let (etx, erx) = chan::async::<Event>();
+        let id = Uuid::default();
+        ci.interpret(CommandExec { cmd: Command::StartDownload(id), etx: None }, &etx);
+        let reason = "outside the download windows 00:00-06:00=full".to_string();
+        assert_eq!(erx.recv(), Some(Event::DownloadDeferred(id, reason)));
+        assert!(! ci.queue.contains(&id));
+        assert!(ci.schedule.take_due_downloads(clock.now()).is_empty());
+
+        clock.advance(chrono::Duration::hours(14));
+        assert_eq!(ci.schedule.take_due_downloads(clock.now()), vec![id]);
+        ci.interpret(CommandExec { cmd: Command::StartDownload(id), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::DownloadingUpdate(id)));
+        match erx.recv() {
+            Some(Event::DownloadComplete(_)) => (),
+            event => panic!("unexpected event: {:?}", event)
+        }
+
+        ci.interpret(CommandExec { cmd: Command::ListBandwidthRules, etx: None }, &etx);
+        let rules = vec!["00:00-06:00=full".parse().unwrap()];
+        assert_eq!(erx.recv(), Some(Event::BandwidthRules { rules: rules, monthly_cap: Some(5), used_bytes: 5 }));
+        ci.interpret(CommandExec { cmd: Command::SetBandwidthRules(Vec::new()), etx: None }, &etx);
+        assert_eq!(erx.recv(), Some(Event::BandwidthRulesChanged(Vec::new())));
+        ci.interpret(CommandExec { cmd: Command::StartDownload(id), etx: None }, &etx);
+        let reason = "monthly download cap reached (5 of 5 bytes)".to_string();
+        assert_eq!(erx.recv(), Some(Event::DownloadDeferred(id, reason)));
+    }
+
+    #[test]
+    fn bandwidth_deferred_ostree_install() {
+        let clock = FakeClock::new(Utc.ymd(2017, 1, 1).and_hms(12, 0, 0));
+        let mut config = Config::default();
+        config.device.package_manager = PacMan::Ostree;
+        config.device.download_rules = Some("00:00-06:00=full".into());
+        let mut ci = test_interpreter(CommandMode::Sota, config, TestClient::default());
+        ci.clock = Arc::new(clock.clone());
+
+        let (etx, erx) = chan::async::<Event>();
+        let id = Uuid::default();
+        ci.interpret(CommandExec { cmd: Command::StartInstall(id), etx: None }, &etx);
+        let at = Utc.ymd(2017, 1, 2).and_hms(0, 0, 0);
+        assert_eq!(erx.recv(), Some(Event::InstallScheduled { update_id: id, at: at }));
+        assert_eq!(ci.schedule.pending(), vec![ScheduledInstall { update_id: id, at: at }]);
+    }
 }
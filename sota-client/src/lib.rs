@@ -2,12 +2,15 @@ extern crate base64;
 extern crate bincode;
 extern crate bytes;
 extern crate byteorder;
+#[macro_use]
 extern crate chan;
 extern crate chrono;
 extern crate crossbeam;
 extern crate crypto;
 #[cfg(feature = "rvi")]
 extern crate dbus;
+extern crate env_logger;
+extern crate flate2;
 extern crate hex;
 extern crate hmac;
 extern crate hyper;
@@ -40,15 +43,28 @@ extern crate url;
 extern crate uuid;
 
 pub mod atomic;
+pub mod audit;
 pub mod authenticate;
+pub mod bandwidth;
+pub mod bounded;
 pub mod broadcast;
 pub mod datatype;
+pub mod diagnostics;
 pub mod gateway;
 pub mod http;
 pub mod images;
+pub mod install_marker;
+pub mod instance_lock;
 pub mod interpreter;
+pub mod logging;
+pub mod metrics;
 pub mod pacman;
+pub mod poller;
+pub mod report_cache;
 #[cfg(feature = "rvi")]
 pub mod rvi;
+pub mod schedule;
 pub mod sota;
+pub mod spool;
+pub mod token_cache;
 pub mod uptane;
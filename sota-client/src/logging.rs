@@ -0,0 +1,164 @@
+use env_logger::{LogBuilder, Logger};
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+use std::sync::{Arc, RwLock};
+
+use datatype::Error;
+
+
+type Format = Fn(&LogRecord) -> String + Sync + Send;
+
+lazy_static! {
+    static ref ACTIVE: RwLock<Option<Arc<ReloadableLogger>>> = RwLock::new(None);
+}
+
+
+/// An `env_logger` wrapper whose `RUST_LOG`-style filter directives can be
+/// replaced while the client is running.
+pub struct ReloadableLogger {
+    format: Arc<Format>,
+    inner:  RwLock<(String, Logger)>,
+    max:    RwLock<Option<MaxLogLevelFilter>>,
+}
+
+impl ReloadableLogger {
+    /// Create a logger that writes each record with `format` when allowed by
+    /// the filter directives in `level`.
+    pub fn new<F>(format: F, level: &str) -> Self
+        where F: Fn(&LogRecord) -> String + Sync + Send + 'static
+    {
+        let format: Arc<Format> = Arc::new(format);
+        let logger = build_logger(format.clone(), level);
+        ReloadableLogger {
+            format: format,
+            inner:  RwLock::new((level.into(), logger)),
+            max:    RwLock::new(None),
+        }
+    }
+
+    /// Install as the global logger so the level can be changed at runtime.
+    pub fn init(self) -> Result<(), Error> {
+        let active = Arc::new(self);
+        let global = active.clone();
+        log::set_logger(move |max| {
+            max.set(global.filter());
+            *global.max.write().unwrap() = Some(max);
+            Box::new(GlobalLogger(global))
+        }).map_err(|err| Error::Config(format!("couldn't set the logger: {}", err)))?;
+        *ACTIVE.write().unwrap() = Some(active);
+        Ok(())
+    }
+
+    /// Returns the current filter directives.
+    pub fn level(&self) -> String {
+        self.inner.read().unwrap().0.clone()
+    }
+
+    /// Returns the most verbose level allowed by the current filter.
+    pub fn filter(&self) -> LogLevelFilter {
+        self.inner.read().unwrap().1.filter()
+    }
+
+    /// Replace the filter with new directives such as `debug` or
+    /// `info,sota::uptane=trace`.
+    pub fn set_level(&self, level: &str) -> Result<(), Error> {
+        check_directives(level)?;
+        let logger = build_logger(self.format.clone(), level);
+        if let Some(ref max) = *self.max.read().unwrap() {
+            max.set(logger.filter());
+        }
+        *self.inner.write().unwrap() = (level.into(), logger);
+        Ok(())
+    }
+
+    /// Returns the filter directives of the global logger.
+    pub fn active_level() -> Result<String, Error> {
+        Ok(active()?.level())
+    }
+
+    /// Replace the filter directives of the global logger.
+    pub fn set_active_level(level: &str) -> Result<(), Error> {
+        active()?.set_level(level)
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        self.inner.read().unwrap().1.enabled(metadata)
+    }
+
+    fn log(&self, record: &LogRecord) {
+        self.inner.read().unwrap().1.log(record)
+    }
+}
+
+
+struct GlobalLogger(Arc<ReloadableLogger>);
+
+impl Log for GlobalLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &LogRecord) {
+        self.0.log(record)
+    }
+}
+
+
+fn active() -> Result<Arc<ReloadableLogger>, Error> {
+    ACTIVE.read().unwrap().clone().ok_or_else(|| Error::Config("the logger can't be reconfigured".into()))
+}
+
+fn build_logger(format: Arc<Format>, level: &str) -> Logger {
+    let mut builder = LogBuilder::new();
+    builder.format(move |record| (*format)(record));
+    builder.filter(Some("hyper"), LogLevelFilter::Info);
+    builder.parse(level);
+    builder.build()
+}
+
+/// Fails unless each comma-separated directive is a level or `module=level`,
+/// so that a mistyped level isn't silently read as a module name.
+fn check_directives(level: &str) -> Result<(), Error> {
+    let spec = level.split('/').next().unwrap_or("");
+    if spec.trim().is_empty() {
+        return Err(Error::Parse("empty log level".into()));
+    }
+    for directive in spec.split(',').map(str::trim) {
+        let mut parts = directive.split('=');
+        let valid = match (parts.next(), parts.next(), parts.next()) {
+            (Some(level), None, None)        => level.parse::<LogLevelFilter>().is_ok(),
+            (Some(module), Some(level), None) => !module.is_empty() && level.parse::<LogLevelFilter>().is_ok(),
+            _ => false
+        };
+        if !valid {
+            return Err(Error::Parse(format!("invalid log level directive: {}", directive)));
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_toggle_level() {
+        let logger = ReloadableLogger::new(|record| format!("{}", record.args()), "info");
+        assert_eq!(logger.level(), "info");
+        assert_eq!(logger.filter(), LogLevelFilter::Info);
+
+        logger.set_level("debug").expect("set debug");
+        assert_eq!(logger.level(), "debug");
+        assert_eq!(logger.filter(), LogLevelFilter::Debug);
+
+        logger.set_level("info").expect("set info");
+        assert_eq!(logger.filter(), LogLevelFilter::Info);
+
+        assert!(logger.set_level("debgu").is_err());
+        assert!(logger.set_level("").is_err());
+        assert_eq!(logger.level(), "info");
+    }
+}
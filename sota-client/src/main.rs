@@ -1,7 +1,6 @@
 extern crate chan;
 extern crate chan_signal;
 extern crate crossbeam;
-extern crate env_logger;
 extern crate getopts;
 extern crate hyper;
 #[macro_use]
@@ -9,31 +8,42 @@ extern crate log;
 extern crate sota;
 extern crate time;
 
-use chan::{Sender, Receiver};
+use chan::Receiver;
 use chan_signal::Signal;
-use env_logger::LogBuilder;
 use getopts::Options;
-use log::LogLevelFilter;
-use std::{env, process, thread};
+use log::LogRecord;
+use std::{env, process};
 use std::cell::RefCell;
+use std::collections::HashSet;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use sota::datatype::{Command, Config, EcuConfig, Event};
-use sota::gateway::{Console, Gateway, Http};
+use sota::datatype::{AbortedUpdates, Auth, AuthBackoff, Clock, Config, EcuConfig, Event, InstallQueue, SystemClock, SystemInfo, Util};
+use sota::gateway::{CommandFilter, Console, Gateway, Http};
 #[cfg(feature = "rvi")]
 use sota::gateway::DBus;
 #[cfg(feature = "socket")]
 use sota::gateway::Socket;
 #[cfg(feature = "websocket")]
 use sota::gateway::Websocket;
+use sota::audit::AuditLog;
+use sota::bandwidth::Bandwidth;
+use sota::bounded::BoundedQueue;
 use sota::broadcast::Broadcast;
-use sota::http::{AuthClient, TlsClient};
+use sota::http::{AuthClient, BasicAuth, Proxy, Redirects, RequestSigner, TlsClient};
+use sota::install_marker::InstallMarker;
+use sota::instance_lock::InstanceLock;
 use sota::interpreter::{CommandExec, CommandMode, CommandInterpreter,
                         EventInterpreter, Interpreter};
+use sota::logging::ReloadableLogger;
 use sota::pacman::PacMan;
+use sota::report_cache::ReportCache;
+use sota::poller;
 #[cfg(feature = "rvi")]
 use sota::rvi::{Edge, Services};
+use sota::schedule::{self, InstallSchedule};
+use sota::token_cache::TokenCache;
 use sota::uptane::Uptane;
 
 
@@ -50,21 +60,71 @@ fn main() {
     let version = start_logging();
     let config = build_config(&version);
     TlsClient::init(config.tls_data());
+    if config.network.pool_max_idle > 0 {
+        AuthClient::init_pool(config.network.pool_max_idle, Duration::from_secs(config.network.pool_idle_sec));
+    }
+    Proxy::init(Proxy::from_config(&config.network));
+    RequestSigner::init(RequestSigner::from_config(&config.network));
+    Redirects::init(Redirects::from_config(&config.network));
+    BasicAuth::init(BasicAuth::from_config(&config.network));
+    let lock = config.device.lock_file.as_ref()
+        .map(|path| InstanceLock::acquire(path).unwrap_or_else(|err| exit!(2, "couldn't start the client: {}", err)));
     let auth = config.initial_auth().unwrap_or_else(|err| exit!(2, err));
-
-    let (ctx, crx) = chan::async::<CommandExec>();
+    config.device.check_download_dirs().unwrap_or_else(|err| exit!(2, "{}", err));
+    Bandwidth::from_config(&config.device).unwrap_or_else(|err| exit!(2, "{}", err));
+    if config.device.verify_signature {
+        let path = config.device.signature_key.as_ref()
+            .unwrap_or_else(|| exit!(2, "device.signature_key is required when device.verify_signature is set"));
+        let _ = Util::read_pem(path).unwrap_or_else(|err| exit!(2, "couldn't read device.signature_key: {}", err));
+    }
+    let install_schedule = InstallSchedule::load(Some(config.device.install_schedule_path.clone()))
+        .unwrap_or_else(|err| exit!(2, "couldn't load the install schedule: {}", err));
+    let aborted = AbortedUpdates::load(Some(format!("{}/aborted.json", config.device.packages_dir)))
+        .unwrap_or_else(|err| exit!(2, "couldn't load the aborted updates: {}", err));
+    let cached_token = config.auth.as_ref()
+        .map_or(Ok(None), TokenCache::from_config)
+        .unwrap_or_else(|err| exit!(2, "couldn't read the token cache key: {}", err))
+        .and_then(|cache| cache.load(SystemClock.now()).unwrap_or_else(|err| { warn!("couldn't load the saved access token: {}", err); None }));
+
+    let (ctx, queue_rx) = chan::sync::<CommandExec>(0);
+    let (queue_tx, crx) = chan::sync::<CommandExec>(0);
     let (etx, erx) = chan::async::<Event>();
-    let mut broadcast = Broadcast::new(erx);
-    etx.send(Event::NotAuthenticated);
+    let mut broadcast = Broadcast::with_history(erx, config.gateway.event_history);
+    let event_history = broadcast.history();
+    // an unexpired saved token is used until the server rejects it
+    etx.send(if cached_token.is_some() { Event::Authenticated } else { Event::NotAuthenticated });
+    // report an install that was interrupted by a reboot, signing it once uptane has started
+    let mut interrupted = InstallMarker::recover(&config.device);
+    if config.device.package_manager != PacMan::Uptane {
+        if let Some(result) = interrupted.take() {
+            etx.send(if result.result_code.is_success() { Event::InstallComplete(result) } else { Event::InstallFailed(result) });
+        }
+    }
 
     crossbeam::scope(|scope| {
+        let mut queue = BoundedQueue::new(config.device.command_queue_size, config.device.command_queue_policy);
+        scope.spawn(move || queue.start(queue_rx, queue_tx));
+
         let signals = chan_signal::notify(&[Signal::INT, Signal::TERM]);
-        scope.spawn(move || start_signal_handler(&signals));
+        scope.spawn(move || start_signal_handler(&signals, lock));
 
         if config.core.polling {
-            let poll_tick = config.core.polling_sec;
-            let poll_ctx  = ctx.clone();
-            scope.spawn(move || start_update_poller(poll_tick, &poll_ctx));
+            let poll_tick  = Duration::from_secs(config.core.polling_sec);
+            let poll_delay = poller::startup_jitter(Duration::from_secs(config.core.startup_jitter_sec));
+            let poll_ctx   = ctx.clone();
+            scope.spawn(move || poller::start_update_poller(poll_tick, poll_delay, &poll_ctx));
+        }
+
+        let sched_ctx = ctx.clone();
+        let schedule  = install_schedule.clone();
+        scope.spawn(move || schedule::start_install_scheduler(schedule, Arc::new(SystemClock), Duration::from_secs(1), &sched_ctx));
+
+        if let Some(ref tls) = config.tls {
+            let cert_file  = tls.cert_file.clone();
+            let cert_warn  = tls.expiry_warn_days;
+            let cert_check = Duration::from_secs(tls.expiry_check_sec);
+            let cert_etx   = etx.clone();
+            scope.spawn(move || poller::start_cert_monitor(&cert_file, cert_warn, cert_check, &cert_etx));
         }
 
         if config.gateway.console {
@@ -77,7 +137,7 @@ fn main() {
             #[cfg(not(feature = "rvi"))]
             exit!(2, "dbus gateway requires 'rvi' binary feature");
             #[cfg(feature = "rvi")] {
-                let dbus_ctx = ctx.clone();
+                let dbus_ctx = CommandFilter::new("dbus", &config.gateway.dbus_commands).wrap(ctx.clone(), etx.clone());
                 let dbus_erx = broadcast.subscribe();
                 let mut dbus = DBus { cfg: config.dbus.clone() };
                 scope.spawn(move || dbus.start(dbus_ctx, dbus_erx));
@@ -85,7 +145,7 @@ fn main() {
         }
 
         if config.gateway.http {
-            let http_ctx = ctx.clone();
+            let http_ctx = CommandFilter::new("http", &config.gateway.http_commands).wrap(ctx.clone(), etx.clone());
             let http_erx = broadcast.subscribe();
             let mut http = Http { server: *config.network.http_server };
             scope.spawn(move || http.start(http_ctx, http_erx));
@@ -95,7 +155,7 @@ fn main() {
             #[cfg(not(feature = "rvi"))]
             exit!(2, "rvi gateway requires 'rvi' binary feature");
             #[cfg(feature = "rvi")] {
-                let services = Services::new(config.rvi.clone(), format!("{}", config.device.uuid), etx.clone());
+                let services = Services::new(config.rvi.clone(), &config.device, etx.clone());
                 let mut edge = Edge::new(services, config.network.rvi_edge_server.clone(), config.rvi.client.clone());
                 scope.spawn(move || edge.start());
             }
@@ -105,7 +165,7 @@ fn main() {
             #[cfg(not(feature = "socket"))]
             exit!(2, "socket gateway requires 'socket' binary feature");
             #[cfg(feature = "socket")] {
-                let socket_ctx = ctx.clone();
+                let socket_ctx = CommandFilter::new("socket", &config.gateway.socket_commands).wrap(ctx.clone(), etx.clone());
                 let socket_erx = broadcast.subscribe();
                 let mut socket = Socket {
                     cmd_sock: config.network.socket_commands_path.clone(),
@@ -119,20 +179,36 @@ fn main() {
             #[cfg(not(feature = "websocket"))]
             exit!(2, "websocket gateway requires 'websocket' binary feature");
             #[cfg(feature = "websocket")] {
-                let ws_ctx = ctx.clone();
+                let ws_ctx = CommandFilter::new("websocket", &config.gateway.websocket_commands).wrap(ctx.clone(), etx.clone());
                 let ws_erx = broadcast.subscribe();
                 let mut ws = Websocket { server: config.network.websocket_server.clone() };
                 scope.spawn(move || ws.start(ws_ctx, ws_erx));
             }
         }
 
+        if let Some(ref path) = config.device.audit_log {
+            let audit_erx = broadcast.subscribe();
+            let audit = AuditLog { path: path.clone(), max_size: config.device.audit_log_size };
+            scope.spawn(move || audit.run(audit_erx));
+        }
+
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let previews = Arc::new(Mutex::new(Vec::new()));
+        let auto_dl = Arc::new(Mutex::new(config.device.auto_download));
         let mut event_int = EventInterpreter {
             initial: true,
             loop_tx: etx.clone(),
             auth:    auth.clone(),
             pacman:  config.device.package_manager.clone(),
-            auto_dl: config.device.auto_download,
+            auto_dl: auto_dl.clone(),
+            pending: pending.clone(),
             sysinfo: config.device.system_info.clone(),
+            hooks:   Vec::new(),
+            backoff: AuthBackoff::new(Duration::from_secs(config.core.auth_backoff_sec),
+                                      Duration::from_secs(config.core.auth_backoff_max_sec)),
+            retrying: Arc::default(),
+            aborted: aborted.clone(),
+            previews: previews.clone(),
         };
         let ei_erx = broadcast.subscribe();
         let ei_ctx = ctx.clone();
@@ -141,23 +217,47 @@ fn main() {
         scope.spawn(move || {
             let mut mode = CommandMode::Sota;
             if let PacMan::Uptane = config.device.package_manager {
-                let uptane = Uptane::new(&config).unwrap_or_else(|err| exit!(2, "couldn't start uptane: {}", err));
+                let mut uptane = Uptane::new(&config).unwrap_or_else(|err| exit!(2, "couldn't start uptane: {}", err));
+                if let Some(result) = interrupted {
+                    match uptane.interrupted_install(result) {
+                        Ok(event) => etx.send(event),
+                        Err(err)  => error!("couldn't sign the interrupted install report: {}", err)
+                    }
+                }
                 mode = CommandMode::Uptane(Rc::new(RefCell::new(uptane)));
             }
             #[cfg(feature = "rvi")] {
                 if config.gateway.rvi {
-                    let services = Services::new(config.rvi.clone(), format!("{}", config.device.uuid), etx.clone());
+                    let services = Services::new(config.rvi.clone(), &config.device, etx.clone());
                     mode = CommandMode::Rvi(Rc::new(RefCell::new(services)));
                 }
             }
 
-            let http = Box::new(AuthClient::from(auth.clone(), version.clone()));
+            let token_expiry = cached_token.as_ref().and_then(|cached| cached.expires_at);
+            let auth = cached_token.map_or(auth, |cached| Auth::Token(cached.token));
+            let http = Box::new(AuthClient::from(auth.clone(), config.http_headers(version.as_ref().map(String::as_str))));
+            let reports = ReportCache::from_config(&config.device);
             let mut cmd_int = CommandInterpreter {
                 mode: mode,
                 config: config,
                 auth: auth,
                 http: http,
-                version: version
+                version: version,
+                queue: InstallQueue::default(),
+                hooks: Vec::new(),
+                clock: Arc::new(SystemClock),
+                token_expiry: token_expiry,
+                awaiting_auth: Vec::new(),
+                schedule: install_schedule,
+                history: event_history,
+                reports: reports,
+                throttled: Arc::default(),
+                deferred_targets: None,
+                paused: None,
+                aborted: aborted,
+                pending: pending,
+                previews: previews,
+                auto_dl: auto_dl,
             };
             cmd_int.run(crx, etx)
         });
@@ -169,34 +269,24 @@ fn main() {
 fn start_logging() -> Option<String> {
     let version = option_env!("SOTA_VERSION");
 
-    let mut builder = LogBuilder::new();
-    builder.format(move |log| {
+    let format = move |log: &LogRecord| {
         format!("{} ({}): {} - {}", time::now_utc().rfc3339(), version.unwrap_or("unknown"), log.level(), log.args())
-    });
-    builder.filter(Some("hyper"), LogLevelFilter::Info);
-    builder.parse(&env::var("RUST_LOG").unwrap_or_else(|_| "INFO".to_string()));
-    builder.init().expect("builder already initialized");
+    };
+    let level = env::var("RUST_LOG").unwrap_or_else(|_| "INFO".to_string());
+    ReloadableLogger::new(format, &level).init().expect("logger already initialized");
 
     version.map(|v| v.into())
 }
 
-fn start_signal_handler(signals: &Receiver<Signal>) {
+fn start_signal_handler(signals: &Receiver<Signal>, lock: Option<InstanceLock>) {
     loop {
         match signals.recv() {
-            Some(Signal::INT) | Some(Signal::TERM) => process::exit(0),
+            Some(Signal::INT) | Some(Signal::TERM) => break,
             _ => ()
         }
     }
-}
-
-fn start_update_poller(interval: u64, ctx: &Sender<CommandExec>) {
-    info!("Polling for new updates every {} seconds.", interval);
-    let (etx, erx) = chan::async::<Event>();
-    loop {
-        ctx.send(CommandExec { cmd: Command::GetUpdateRequests, etx: Some(etx.clone()) });
-        let _ = erx.recv(); // wait for the response before starting the timer
-        thread::sleep(Duration::from_secs(interval));
-    }
+    drop(lock);
+    process::exit(0);
 }
 
 fn build_config(version: &Option<String>) -> Config {
@@ -209,14 +299,22 @@ fn build_config(version: &Option<String>) -> Config {
     opts.optflag("v", "version", "print the version then quit");
     opts.optopt("c", "config", "change config path", "PATH");
 
-    opts.optopt("", "auth-server", "change the auth server", "URL");
+    opts.optopt("", "auth-server", "change the auth server URLs (comma-separated)", "URLS");
     opts.optopt("", "auth-client-id", "change the auth client id", "ID");
     opts.optopt("", "auth-client-secret", "change the auth client secret", "SECRET");
+    opts.optopt("", "auth-required-scope", "change the space-separated scopes the access token must carry", "SCOPE");
+    opts.optopt("", "auth-token-cache", "change where the access token is saved between restarts", "PATH");
+    opts.optopt("", "auth-token-cache-key", "change the device secret used to encrypt the saved access token", "PATH");
 
-    opts.optopt("", "core-server", "change the core server", "URL");
+    opts.optopt("", "core-server", "change the core server URLs (comma-separated)", "URLS");
     opts.optopt("", "core-polling", "toggle polling the core server for updates", "BOOL");
     opts.optopt("", "core-polling-sec", "change the core polling interval", "SECONDS");
     opts.optopt("", "core-ca-file", "pin the core CA certificates path", "PATH");
+    opts.optopt("", "core-startup-jitter-sec", "change the maximum random delay before the first poll", "SECONDS");
+    opts.optopt("", "core-auth-backoff-sec", "change the delay after the first repeated authentication failure", "SECONDS");
+    opts.optopt("", "core-auth-backoff-max-sec", "change the maximum delay between failed authentication attempts", "SECONDS");
+    opts.optopt("", "core-max-updates-per-poll", "change the maximum number of update requests queued per poll", "COUNT");
+    opts.optopt("", "core-poll-telemetry", "toggle emitting telemetry events after each poll", "BOOL");
 
     opts.optopt("", "dbus-name", "change the dbus registration name", "NAME");
     opts.optopt("", "dbus-path", "change the dbus path", "PATH");
@@ -230,11 +328,48 @@ fn build_config(version: &Option<String>) -> Config {
     opts.optopt("", "device-package-manager", "change the package manager", "MANAGER");
     opts.optopt("", "device-p12-path", "change the PKCS12 file path", "PATH");
     opts.optopt("", "device-p12-password", "change the PKCS12 file password", "PASSWORD");
-    opts.optopt("", "device-system-info", "change the system information command", "PATH");
+    opts.optmulti("", "device-system-info", "add a system information command or static JSON file", "PATH");
+    opts.optopt("", "device-continue-on-error", "toggle installing queued updates after a failure", "BOOL");
+    opts.optopt("", "device-install-timeout", "change the package installation timeout", "SECONDS");
+    opts.optopt("", "device-disk-headroom", "change the free disk space required after a download", "BYTES");
+    opts.optopt("", "device-audit-log", "change the path to write an audit log of events", "PATH");
+    opts.optopt("", "device-audit-log-size", "change the audit log size before rotation", "BYTES");
+    opts.optopt("", "device-verify-signature", "toggle verifying download signatures before install", "BOOL");
+    opts.optopt("", "device-signature-key", "change the public key path for verifying downloads", "PATH");
+    opts.optopt("", "device-report-dedup-size", "change the number of recent install reports remembered to skip duplicates", "INT");
+    opts.optopt("", "device-report-dedup-ttl", "change how long an install report is remembered to skip duplicates", "SECS");
+    opts.optopt("", "device-command-queue-size", "change the maximum number of waiting commands", "SIZE");
+    opts.optopt("", "device-command-queue-policy", "change the policy when the command queue is full", "POLICY");
+    opts.optopt("", "device-report-spool-dir", "change the directory for install reports waiting to be sent", "PATH");
+    opts.optopt("", "device-deb-packages-dir", "change the download directory for deb packages", "PATH");
+    opts.optopt("", "device-rpm-packages-dir", "change the download directory for rpm packages", "PATH");
+    opts.optopt("", "device-ostree-packages-dir", "change the download directory for ostree images", "PATH");
+    opts.optopt("", "device-ostree-repo", "change the ostree repository checked for integrity", "PATH");
+    opts.optopt("", "device-chunks-dir", "change the directory for partial downloads and image chunks", "PATH");
+    opts.optopt("", "device-staging-dir", "change the directory for downloads waiting to be verified", "PATH");
+    opts.optopt("", "device-package-snapshot", "report installed package changes since the snapshot at this path", "PATH");
+    opts.optopt("", "device-package-full-every", "change how many package change reports are sent between full reports", "COUNT");
+    opts.optopt("", "device-post-install-health-cmd", "change the command checking the system is healthy after an install", "CMD");
+    opts.optopt("", "device-rollback-cmd", "change the command rolling back an install without built-in rollback", "CMD");
+    opts.optopt("", "device-cleanup-after-install", "change whether downloaded packages are removed once installed", "BOOL");
+    opts.optopt("", "device-max-report-output-bytes", "change the maximum install output sent in a report", "BYTES");
+    opts.optopt("", "device-install-schedule-path", "change the file that scheduled installs are saved to", "PATH");
+    opts.optopt("", "device-reboot-marker-file", "change the file signaling a deb or rpm install needs a reboot", "PATH");
+    opts.optopt("", "device-reboot-after-install", "change whether to reboot once an install needs it", "BOOL");
+    opts.optopt("", "device-reboot-cmd", "change the command scheduling a reboot", "CMD");
+    opts.optopt("", "device-lock-file", "change the lock file preventing a second client instance", "PATH");
+    opts.optopt("", "device-install-marker-file", "change the file recording an install in progress across reboots", "PATH");
+    opts.optopt("", "device-check-content-type", "toggle rejecting downloads with an unexpected Content-Type", "BOOL");
+    opts.optopt("", "device-expected-content-type", "change the comma-separated Content-Types allowed for downloads", "TYPES");
+    opts.optopt("", "device-download-rules", "change the comma-separated download windows, such as 00:00-06:00=full", "RULES");
+    opts.optopt("", "device-download-monthly-cap", "change the bytes that may be downloaded each month", "BYTES");
+    opts.optopt("", "device-download-usage-file", "change the file tracking bytes downloaded this month", "PATH");
 
     opts.optmulti("", "ecu-serial", "add a secondary ECU serial", "SERIAL");
     opts.optmulti("", "ecu-public-key-path", "add a secondary ECU public key path", "PATH");
     opts.optmulti("", "ecu-manifest-path", "add a secondary ECU manifest path", "PATH");
+    opts.optmulti("", "ecu-version-cmd", "set the command reporting a secondary ECU's signed version", "SERIAL=CMD");
+    opts.optmulti("", "ecu-install-cmd", "set the command flashing a secondary ECU", "SERIAL=CMD");
 
     opts.optopt("", "gateway-console", "toggle the console gateway", "BOOL");
     opts.optopt("", "gateway-dbus", "toggle the dbus gateway", "BOOL");
@@ -242,30 +377,65 @@ fn build_config(version: &Option<String>) -> Config {
     opts.optopt("", "gateway-rvi", "toggle the rvi gateway", "BOOL");
     opts.optopt("", "gateway-socket", "toggle the unix domain socket gateway", "BOOL");
     opts.optopt("", "gateway-websocket", "toggle the websocket gateway", "BOOL");
+    opts.optopt("", "gateway-dbus-commands", "change the commands allowed from the dbus gateway", "CMDS");
+    opts.optopt("", "gateway-http-commands", "change the commands allowed from the http gateway", "CMDS");
+    opts.optopt("", "gateway-socket-commands", "change the commands allowed from the socket gateway", "CMDS");
+    opts.optopt("", "gateway-websocket-commands", "change the commands allowed from the websocket gateway", "CMDS");
+    opts.optopt("", "gateway-event-history", "change how many recent events are kept for replaying", "COUNT");
 
-    opts.optopt("", "network-http-server", "change the http server gateway address", "ADDR");
+    opts.optopt("", "network-http-server", "change the http server gateway address (IPv4 or IPv6)", "ADDR");
     opts.optopt("", "network-rvi-edge-server", "change the rvi edge server gateway address", "ADDR");
     opts.optopt("", "network-socket-commands-path", "change the socket path for reading commands", "PATH");
     opts.optopt("", "network-socket-events-path", "change the socket path for sending events", "PATH");
     opts.optopt("", "network-websocket-server", "change the websocket gateway address", "ADDR");
+    opts.optopt("", "network-http-proxy", "change the proxy server for HTTP requests", "URL");
+    opts.optopt("", "network-https-proxy", "change the proxy server for HTTPS requests", "URL");
+    opts.optopt("", "network-no-proxy", "change the comma-separated hosts to connect to without a proxy", "HOSTS");
+    opts.optmulti("", "network-http-header", "add a static header to each HTTP request", "NAME:VALUE");
+    opts.optopt("", "network-max-response-bytes", "change the maximum size of an HTTP response body", "BYTES");
+    opts.optopt("", "network-max-target-bytes", "change the maximum size of a downloaded target image", "BYTES");
+    opts.optopt("", "network-pool-max-idle", "change the number of idle keep-alive connections kept open (0 disables)", "COUNT");
+    opts.optopt("", "network-pool-idle-sec", "change how long an idle keep-alive connection is kept open", "SEC");
+    opts.optopt("", "network-signing-key", "sign each HTTP request with an HMAC using this key", "KEY");
+    opts.optopt("", "network-signing-algorithm", "change the HMAC algorithm for signing requests", "hmac-sha256|hmac-sha512");
+    opts.optopt("", "network-signing-header", "change the header name for the request signature", "NAME");
+    opts.optopt("", "network-signing-canonical", "change the template of the signed request text", "TEMPLATE");
+    opts.optopt("", "network-max-redirects", "change the maximum number of redirects followed per request", "COUNT");
+    opts.optopt("", "network-redirect-same-host", "toggle only following redirects to the same host", "BOOL");
+    opts.optopt("", "network-redirect-keep-auth", "toggle sending authentication headers on cross-origin redirects", "BOOL");
+    opts.optopt("", "network-basic-auth", "send these basic-auth credentials to a reverse proxy", "USER:PASS");
+    opts.optopt("", "network-basic-auth-hosts", "change the comma-separated hosts sent basic-auth credentials", "HOSTS");
+    opts.optopt("", "network-token-header", "change the header for the token when basic-auth is sent", "NAME");
 
     opts.optopt("", "rvi-client", "change the rvi client URL", "URL");
     opts.optopt("", "rvi-storage-dir", "change the rvi storage directory", "PATH");
     opts.optopt("", "rvi-timeout", "change the rvi timeout", "TIMEOUT");
+    opts.optopt("", "rvi-hmac-secret", "require RVI messages to be signed with this secret", "SECRET");
+    opts.optopt("", "rvi-max-active-transfers", "change the maximum number of concurrent RVI transfers", "COUNT");
 
     opts.optopt("", "tls-server", "change the TLS server", "URL");
     opts.optopt("", "tls-ca-file", "pin the TLS root CA certificate chain", "PATH");
     opts.optopt("", "tls-cert-file", "change the TLS certificate", "PATH");
     opts.optopt("", "tls-pkey-file", "change the TLS private key", "PASSWORD");
+    opts.optopt("", "tls-expiry-warn-days", "change how many days before expiry the certificate is reported", "DAYS");
+    opts.optopt("", "tls-expiry-check-sec", "change the certificate expiry check interval", "SEC");
 
     opts.optopt("", "uptane-director-server", "change the Uptane Director server", "URL");
     opts.optopt("", "uptane-repo-server", "change the Uptane Repo server", "URL");
     opts.optopt("", "uptane-primary-ecu-serial", "change the primary ECU's serial", "TEXT");
     opts.optopt("", "uptane-metadata-path", "change the directory used to save Uptane metadata.", "PATH");
+    opts.optopt("", "uptane-images-dir", "change the directory used to save Uptane images.", "PATH");
     opts.optopt("", "uptane-private-key-path", "change the path to the private key for the primary ECU", "PATH");
     opts.optopt("", "uptane-public-key-path", "change the path to the public key for the primary ECU", "PATH");
+    opts.optopt("", "uptane-keyid-digest", "change how the primary ECU's key id is derived", "sha256|sha512|tuf");
     opts.optopt("", "uptane-atomic-primary", "change the atomic transaction Primary server", "IP:PORT");
     opts.optopt("", "uptane-atomic-timeout-sec", "change the atomic update timeout duration", "SEC");
+    opts.optopt("", "uptane-manifest-retries", "change how often a conflicting manifest is resent", "COUNT");
+    opts.optopt("", "uptane-max-clock-skew-sec", "change the clock difference from the server treated as skew", "SEC");
+    opts.optopt("", "uptane-timeserver-server", "change the Uptane timeserver used for signed time", "URL");
+    opts.optopt("", "uptane-timeserver-key-path", "change the path to the timeserver's public key", "PATH");
+    opts.optopt("", "uptane-trust-device-clock", "toggle checking metadata expiry against the device clock instead of the signed time", "BOOL");
+    opts.optopt("", "uptane-debug-canonical-json", "toggle logging where metadata differs from canonical json when verification fails", "BOOL");
 
     let cli = opts.parse(&args[1..]).expect("couldn't parse args");
     if cli.opt_present("help") {
@@ -280,15 +450,23 @@ fn build_config(version: &Option<String>) -> Config {
         .unwrap_or_else(|| exit!(1, "Config flag or SOTA_CONFIG environment variable required"));
 
     config.auth.as_mut().map(|auth_cfg| {
-        cli.opt_str("auth-server").map(|text| auth_cfg.server = text.parse().expect("Invalid auth-server URL"));
+        cli.opt_str("auth-server").map(|text| auth_cfg.server = text.parse().expect("Invalid auth-server URLs"));
         cli.opt_str("auth-client-id").map(|id| auth_cfg.client_id = id);
         cli.opt_str("auth-client-secret").map(|secret| auth_cfg.client_secret = secret);
+        cli.opt_str("auth-required-scope").map(|scope| auth_cfg.required_scope = Some(scope));
+        cli.opt_str("auth-token-cache").map(|path| auth_cfg.token_cache = Some(path));
+        cli.opt_str("auth-token-cache-key").map(|path| auth_cfg.token_cache_key = Some(path));
     });
 
-    cli.opt_str("core-server").map(|text| config.core.server = text.parse().expect("Invalid core-server URL"));
+    cli.opt_str("core-server").map(|text| config.core.server = text.parse().expect("Invalid core-server URLs"));
     cli.opt_str("core-polling").map(|polling| config.core.polling = polling.parse().expect("Invalid core-polling boolean"));
     cli.opt_str("core-polling-sec").map(|secs| config.core.polling_sec = secs.parse().expect("Invalid core-polling-sec"));
     cli.opt_str("core-ca-file").map(|path| config.core.ca_file = Some(path));
+    cli.opt_str("core-startup-jitter-sec").map(|secs| config.core.startup_jitter_sec = secs.parse().expect("Invalid core-startup-jitter-sec"));
+    cli.opt_str("core-auth-backoff-sec").map(|secs| config.core.auth_backoff_sec = secs.parse().expect("Invalid core-auth-backoff-sec"));
+    cli.opt_str("core-auth-backoff-max-sec").map(|secs| config.core.auth_backoff_max_sec = secs.parse().expect("Invalid core-auth-backoff-max-sec"));
+    cli.opt_str("core-max-updates-per-poll").map(|count| config.core.max_updates_per_poll = count.parse().expect("Invalid core-max-updates-per-poll"));
+    cli.opt_str("core-poll-telemetry").map(|telemetry| config.core.poll_telemetry = telemetry.parse().expect("Invalid core-poll-telemetry boolean"));
 
     cli.opt_str("dbus-name").map(|name| config.dbus.name = name);
     cli.opt_str("dbus-path").map(|path| config.dbus.path = path);
@@ -300,7 +478,45 @@ fn build_config(version: &Option<String>) -> Config {
     cli.opt_str("device-uuid").map(|uuid| config.device.uuid = uuid.parse().expect("Invalid device-uuid"));
     cli.opt_str("device-packages-dir").map(|path| config.device.packages_dir = path);
     cli.opt_str("device-package-manager").map(|text| config.device.package_manager = text.parse().expect("Invalid device-package-manager"));
-    cli.opt_str("device-system-info").map(|cmd| config.device.system_info = Some(cmd));
+    let system_info = cli.opt_strs("device-system-info");
+    if ! system_info.is_empty() {
+        config.device.system_info = Some(SystemInfo::from_sources(system_info).expect("Invalid device-system-info"));
+    }
+    cli.opt_str("device-continue-on-error").map(|cont| config.device.continue_on_error = cont.parse().expect("Invalid device-continue-on-error boolean"));
+    cli.opt_str("device-install-timeout").map(|secs| config.device.install_timeout = secs.parse().expect("Invalid device-install-timeout"));
+    cli.opt_str("device-disk-headroom").map(|bytes| config.device.disk_headroom = bytes.parse().expect("Invalid device-disk-headroom"));
+    cli.opt_str("device-audit-log").map(|path| config.device.audit_log = Some(path));
+    cli.opt_str("device-audit-log-size").map(|bytes| config.device.audit_log_size = bytes.parse().expect("Invalid device-audit-log-size"));
+    cli.opt_str("device-verify-signature").map(|verify| config.device.verify_signature = verify.parse().expect("Invalid device-verify-signature boolean"));
+    cli.opt_str("device-signature-key").map(|path| config.device.signature_key = Some(path));
+    cli.opt_str("device-report-dedup-size").map(|size| config.device.report_dedup_size = size.parse().expect("Invalid device-report-dedup-size"));
+    cli.opt_str("device-report-dedup-ttl").map(|secs| config.device.report_dedup_ttl = secs.parse().expect("Invalid device-report-dedup-ttl"));
+    cli.opt_str("device-command-queue-size").map(|size| config.device.command_queue_size = size.parse().expect("Invalid device-command-queue-size"));
+    cli.opt_str("device-command-queue-policy").map(|policy| config.device.command_queue_policy = policy.parse().expect("Invalid device-command-queue-policy"));
+    cli.opt_str("device-report-spool-dir").map(|path| config.device.report_spool_dir = Some(path));
+    cli.opt_str("device-deb-packages-dir").map(|path| config.device.deb_packages_dir = Some(path));
+    cli.opt_str("device-rpm-packages-dir").map(|path| config.device.rpm_packages_dir = Some(path));
+    cli.opt_str("device-ostree-packages-dir").map(|path| config.device.ostree_packages_dir = Some(path));
+    cli.opt_str("device-ostree-repo").map(|path| config.device.ostree_repo = path);
+    cli.opt_str("device-chunks-dir").map(|path| config.device.chunks_dir = Some(path));
+    cli.opt_str("device-staging-dir").map(|path| config.device.staging_dir = Some(path));
+    cli.opt_str("device-package-snapshot").map(|path| config.device.package_snapshot = Some(path));
+    cli.opt_str("device-package-full-every").map(|count| config.device.package_full_every = count.parse().expect("Invalid device-package-full-every"));
+    cli.opt_str("device-post-install-health-cmd").map(|cmd| config.device.post_install_health_cmd = Some(cmd));
+    cli.opt_str("device-rollback-cmd").map(|cmd| config.device.rollback_cmd = Some(cmd));
+    cli.opt_str("device-cleanup-after-install").map(|clean| config.device.cleanup_after_install = clean.parse().expect("Invalid device-cleanup-after-install boolean"));
+    cli.opt_str("device-max-report-output-bytes").map(|bytes| config.device.max_report_output_bytes = bytes.parse().expect("Invalid device-max-report-output-bytes"));
+    cli.opt_str("device-install-schedule-path").map(|path| config.device.install_schedule_path = path);
+    cli.opt_str("device-reboot-marker-file").map(|path| config.device.reboot_marker_file = Some(path));
+    cli.opt_str("device-reboot-after-install").map(|reboot| config.device.reboot_after_install = reboot.parse().expect("Invalid device-reboot-after-install boolean"));
+    cli.opt_str("device-reboot-cmd").map(|cmd| config.device.reboot_cmd = cmd);
+    cli.opt_str("device-lock-file").map(|path| config.device.lock_file = Some(path));
+    cli.opt_str("device-install-marker-file").map(|path| config.device.install_marker_file = Some(path));
+    cli.opt_str("device-check-content-type").map(|check| config.device.check_content_type = check.parse().expect("Invalid device-check-content-type boolean"));
+    cli.opt_str("device-expected-content-type").map(|types| config.device.expected_content_type = Some(types));
+    cli.opt_str("device-download-rules").map(|rules| config.device.download_rules = Some(rules));
+    cli.opt_str("device-download-monthly-cap").map(|cap| config.device.download_monthly_cap = Some(cap.parse().expect("Invalid device-download-monthly-cap")));
+    cli.opt_str("device-download-usage-file").map(|path| config.device.download_usage_file = Some(path));
 
     let ecu_serials = cli.opt_strs("ecu-serial");
     let ecu_keys = cli.opt_strs("ecu-public-key-path");
@@ -311,11 +527,19 @@ fn build_config(version: &Option<String>) -> Config {
             config.ecus = ecu_serials.into_iter()
                 .zip(ecu_keys)
                 .zip(ecu_manifests)
-                .map(|((s, p), m)| EcuConfig { ecu_serial: s, public_key_path: p, manifest_path: m })
+                .map(|((s, p), m)| EcuConfig { ecu_serial: s, public_key_path: p, manifest_path: m, version_cmd: None, install_cmd: None })
                 .collect::<Vec<EcuConfig>>();
         }
         _ => exit!(1, "equal number of 'ecu-' flags expected")
     }
+    for arg in cli.opt_strs("ecu-version-cmd") {
+        let (serial, cmd) = ecu_command(&arg);
+        ecu_config(&mut config, &serial).version_cmd = Some(cmd);
+    }
+    for arg in cli.opt_strs("ecu-install-cmd") {
+        let (serial, cmd) = ecu_command(&arg);
+        ecu_config(&mut config, &serial).install_cmd = Some(cmd);
+    }
 
     cli.opt_str("gateway-console").map(|console| config.gateway.console = console.parse().expect("Invalid gateway-console boolean"));
     cli.opt_str("gateway-dbus").map(|dbus| config.gateway.dbus = dbus.parse().expect("Invalid gateway-dbus boolean"));
@@ -323,32 +547,77 @@ fn build_config(version: &Option<String>) -> Config {
     cli.opt_str("gateway-rvi").map(|rvi| config.gateway.rvi = rvi.parse().expect("Invalid gateway-rvi boolean"));
     cli.opt_str("gateway-socket").map(|socket| config.gateway.socket = socket.parse().expect("Invalid gateway-socket boolean"));
     cli.opt_str("gateway-websocket").map(|websocket| config.gateway.websocket = websocket.parse().expect("Invalid gateway-websocket boolean"));
+    cli.opt_str("gateway-dbus-commands").map(|cmds| config.gateway.dbus_commands = cmds.split(',').map(String::from).collect());
+    cli.opt_str("gateway-http-commands").map(|cmds| config.gateway.http_commands = cmds.split(',').map(String::from).collect());
+    cli.opt_str("gateway-socket-commands").map(|cmds| config.gateway.socket_commands = cmds.split(',').map(String::from).collect());
+    cli.opt_str("gateway-websocket-commands").map(|cmds| config.gateway.websocket_commands = cmds.split(',').map(String::from).collect());
+    cli.opt_str("gateway-event-history").map(|count| config.gateway.event_history = count.parse().expect("Invalid gateway-event-history"));
 
     cli.opt_str("network-http-server").map(|addr| config.network.http_server = addr.parse().expect("Invalid network-http-server"));
     cli.opt_str("network-rvi-edge-server").map(|addr| config.network.rvi_edge_server = addr.parse().expect("Invalid network-rvi-edge-server"));
     cli.opt_str("network-socket-commands-path").map(|path| config.network.socket_commands_path = path);
     cli.opt_str("network-socket-events-path").map(|path| config.network.socket_events_path = path);
     cli.opt_str("network-websocket-server").map(|server| config.network.websocket_server = server);
+    cli.opt_str("network-http-proxy").map(|url| config.network.http_proxy = Some(url.parse().expect("Invalid network-http-proxy URL")));
+    cli.opt_str("network-https-proxy").map(|url| config.network.https_proxy = Some(url.parse().expect("Invalid network-https-proxy URL")));
+    cli.opt_str("network-no-proxy").map(|hosts| config.network.no_proxy = Some(hosts));
+    for header in cli.opt_strs("network-http-header") {
+        let mut split = header.splitn(2, ':');
+        let name  = split.next().expect("Invalid network-http-header").trim().to_string();
+        let value = split.next().expect("Invalid network-http-header").trim().to_string();
+        config.network.http_headers.insert(name, value);
+    }
+    cli.opt_str("network-max-response-bytes").map(|bytes| config.network.max_response_bytes = bytes.parse().expect("Invalid network-max-response-bytes"));
+    cli.opt_str("network-max-target-bytes").map(|bytes| config.network.max_target_bytes = bytes.parse().expect("Invalid network-max-target-bytes"));
+    cli.opt_str("network-pool-max-idle").map(|count| config.network.pool_max_idle = count.parse().expect("Invalid network-pool-max-idle"));
+    cli.opt_str("network-pool-idle-sec").map(|sec| config.network.pool_idle_sec = sec.parse().expect("Invalid network-pool-idle-sec"));
+    cli.opt_str("network-signing-key").map(|key| config.network.signing_key = Some(key));
+    cli.opt_str("network-signing-algorithm").map(|alg| config.network.signing_algorithm = alg.parse().expect("Invalid network-signing-algorithm"));
+    cli.opt_str("network-signing-header").map(|name| config.network.signing_header = name);
+    cli.opt_str("network-signing-canonical").map(|text| config.network.signing_canonical = text);
+    cli.opt_str("network-max-redirects").map(|count| config.network.max_redirects = count.parse().expect("Invalid network-max-redirects"));
+    cli.opt_str("network-redirect-same-host").map(|same| config.network.redirect_same_host = same.parse().expect("Invalid network-redirect-same-host boolean"));
+    cli.opt_str("network-redirect-keep-auth").map(|keep| config.network.redirect_keep_auth = keep.parse().expect("Invalid network-redirect-keep-auth boolean"));
+    cli.opt_str("network-basic-auth").map(|creds| {
+        let mut split = creds.splitn(2, ':');
+        let user = split.next().expect("Invalid network-basic-auth").to_string();
+        let pass = split.next().expect("Invalid network-basic-auth").to_string();
+        config.network.basic_auth = Some((user, pass));
+    });
+    cli.opt_str("network-basic-auth-hosts").map(|hosts| config.network.basic_auth_hosts = Some(hosts));
+    cli.opt_str("network-token-header").map(|name| config.network.token_header = name);
 
     cli.opt_str("rvi-client").map(|url| config.rvi.client = url.parse().expect("Invalid rvi-client URL"));
     cli.opt_str("rvi-storage-dir").map(|dir| config.rvi.storage_dir = dir);
     cli.opt_str("rvi-timeout").map(|timeout| config.rvi.timeout = Some(timeout.parse().expect("Invalid rvi-timeout")));
+    cli.opt_str("rvi-hmac-secret").map(|secret| config.rvi.hmac_secret = Some(secret));
+    cli.opt_str("rvi-max-active-transfers").map(|max| config.rvi.max_active_transfers = max.parse().expect("Invalid rvi-max-active-transfers"));
 
     config.tls.as_mut().map(|tls_cfg| {
         cli.opt_str("tls-server").map(|text| tls_cfg.server = text.parse().expect("Invalid tls-server URL"));
         cli.opt_str("tls-ca-file").map(|path| tls_cfg.ca_file = path);
         cli.opt_str("tls-cert-file").map(|path| tls_cfg.cert_file = path);
         cli.opt_str("tls-pkey-file").map(|path| tls_cfg.pkey_file = path);
+        cli.opt_str("tls-expiry-warn-days").map(|days| tls_cfg.expiry_warn_days = days.parse().expect("Invalid tls-expiry-warn-days"));
+        cli.opt_str("tls-expiry-check-sec").map(|sec| tls_cfg.expiry_check_sec = sec.parse().expect("Invalid tls-expiry-check-sec"));
     });
 
     cli.opt_str("uptane-director-server").map(|text| config.uptane.director_server = text.parse().expect("Invalid uptane-director-server URL"));
     cli.opt_str("uptane-repo-server").map(|text| config.uptane.repo_server = text.parse().expect("Invalid uptane-repo-server URL"));
     cli.opt_str("uptane-primary-ecu-serial").map(|text| config.uptane.primary_ecu_serial = text);
     cli.opt_str("uptane-metadata-path").map(|text| config.uptane.metadata_path = text);
+    cli.opt_str("uptane-images-dir").map(|text| config.uptane.images_dir = text);
     cli.opt_str("uptane-private-key-path").map(|text| config.uptane.private_key_path = text);
     cli.opt_str("uptane-public-key-path").map(|text| config.uptane.public_key_path = text);
+    cli.opt_str("uptane-keyid-digest").map(|text| config.uptane.keyid_digest = text.parse().expect("Invalid uptane-keyid-digest"));
     cli.opt_str("uptane-atomic-primary").map(|addr| config.uptane.atomic_primary = addr.parse().expect("Invalid uptane-atomic-primary"));
     cli.opt_str("uptane-atomic-timeout-sec").map(|sec| config.uptane.atomic_timeout_sec = sec.parse().expect("Invalid uptane-atomic-timeout-sec"));
+    cli.opt_str("uptane-manifest-retries").map(|count| config.uptane.manifest_retries = count.parse().expect("Invalid uptane-manifest-retries"));
+    cli.opt_str("uptane-max-clock-skew-sec").map(|sec| config.uptane.max_clock_skew_sec = sec.parse().expect("Invalid uptane-max-clock-skew-sec"));
+    cli.opt_str("uptane-timeserver-server").map(|text| config.uptane.timeserver_server = Some(text.parse().expect("Invalid uptane-timeserver-server URL")));
+    cli.opt_str("uptane-timeserver-key-path").map(|text| config.uptane.timeserver_key_path = Some(text));
+    cli.opt_str("uptane-trust-device-clock").map(|trust| config.uptane.trust_device_clock = trust.parse().expect("Invalid uptane-trust-device-clock boolean"));
+    cli.opt_str("uptane-debug-canonical-json").map(|debug| config.uptane.debug_canonical_json = debug.parse().expect("Invalid uptane-debug-canonical-json boolean"));
 
     if cli.opt_present("print") {
         exit!(0, "{:#?}", config);
@@ -356,3 +625,20 @@ fn build_config(version: &Option<String>) -> Config {
 
     config
 }
+
+/// Split a `SERIAL=CMD` flag into the ECU serial and its command.
+fn ecu_command(arg: &str) -> (String, String) {
+    let mut split = arg.splitn(2, '=');
+    match (split.next(), split.next()) {
+        (Some(serial), Some(cmd)) if ! serial.is_empty() => (serial.into(), cmd.into()),
+        _ => exit!(1, "expected SERIAL=CMD, got {}", arg)
+    }
+}
+
+/// Find the config of a secondary ECU by serial.
+fn ecu_config<'c>(config: &'c mut Config, serial: &str) -> &'c mut EcuConfig {
+    match config.ecus.iter().position(|ecu| ecu.ecu_serial == serial) {
+        Some(index) => &mut config.ecus[index],
+        None => exit!(1, "unknown ECU serial {}", serial)
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use datatype::Event;
+
+
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+
+/// Device counters and gauges that can be scraped in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub installs:        BTreeMap<String, u64>,
+    pub download_bytes:  u64,
+    pub pending_reports: u64,
+    pub last_poll:       Option<DateTime<Utc>>,
+}
+
+impl Metrics {
+    /// Start recording metrics. Updates are ignored until enabled so there is
+    /// no overhead unless they are exported.
+    pub fn enable() {
+        ENABLED.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once metrics are being recorded.
+    pub fn is_enabled() -> bool {
+        ENABLED.load(Ordering::Relaxed)
+    }
+
+    /// Apply an update to the recorded metrics when enabled.
+    pub fn update<F: FnOnce(&mut Metrics)>(update: F) {
+        if Self::is_enabled() {
+            update(&mut METRICS.lock().unwrap());
+        }
+    }
+
+    /// Return the recorded metrics in the Prometheus text format.
+    pub fn export() -> String {
+        METRICS.lock().unwrap().render(Utc::now())
+    }
+
+    /// Update the metrics affected by this event.
+    pub fn observe(&mut self, event: &Event, now: DateTime<Utc>) {
+        match *event {
+            Event::InstallComplete(ref result) |
+            Event::InstallFailed(ref result) => {
+                *self.installs.entry(format!("{:?}", result.result_code)).or_insert(0) += 1;
+            }
+
+            Event::UpdatesReceived(_)      |
+            Event::NoUpdateRequests        |
+            Event::UptaneTargetsUpdated(_) |
+            Event::UptaneNoUpdates         => self.last_poll = Some(now),
+
+            Event::PendingReports(ref pending) => self.pending_reports = pending.len() as u64,
+            Event::PendingReportsCleared(_)    => self.pending_reports = 0,
+
+            _ => ()
+        }
+    }
+
+    /// Format the metrics in the Prometheus text format.
+    pub fn render(&self, now: DateTime<Utc>) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP sota_installs_total Installations by result code.\n");
+        out.push_str("# TYPE sota_installs_total counter\n");
+        for (code, count) in &self.installs {
+            out.push_str(&format!("sota_installs_total{{result=\"{}\"}} {}\n", code, count));
+        }
+
+        out.push_str("# HELP sota_download_bytes_total Bytes of update packages downloaded.\n");
+        out.push_str("# TYPE sota_download_bytes_total counter\n");
+        out.push_str(&format!("sota_download_bytes_total {}\n", self.download_bytes));
+
+        out.push_str("# HELP sota_pending_reports Install reports waiting to be sent.\n");
+        out.push_str("# TYPE sota_pending_reports gauge\n");
+        out.push_str(&format!("sota_pending_reports {}\n", self.pending_reports));
+
+        if let Some(last_poll) = self.last_poll {
+            out.push_str("# HELP sota_seconds_since_last_poll Seconds since updates were last checked.\n");
+            out.push_str("# TYPE sota_seconds_since_last_poll gauge\n");
+            out.push_str(&format!("sota_seconds_since_last_poll {}\n", now.signed_duration_since(last_poll).num_seconds()));
+        }
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    use datatype::{InstallCode, InstallResult};
+
+
+    #[test]
+    fn test_render_metrics() {
+        let now = Utc::now();
+        let mut metrics = Metrics::default();
+        metrics.observe(&Event::InstallComplete(InstallResult::new("a".into(), InstallCode::OK, "".into())), now);
+        metrics.observe(&Event::InstallFailed(InstallResult::new("b".into(), InstallCode::INSTALL_FAILED, "".into())), now);
+        metrics.observe(&Event::InstallComplete(InstallResult::new("c".into(), InstallCode::OK, "".into())), now);
+        metrics.observe(&Event::NoUpdateRequests, now);
+        metrics.download_bytes = 1024;
+
+        let text = metrics.render(now + Duration::seconds(30));
+        assert!(text.contains("sota_installs_total{result=\"OK\"} 2\n"));
+        assert!(text.contains("sota_installs_total{result=\"INSTALL_FAILED\"} 1\n"));
+        assert!(text.contains("sota_download_bytes_total 1024\n"));
+        assert!(text.contains("sota_pending_reports 0\n"));
+        assert!(text.contains("sota_seconds_since_last_poll 30\n"));
+    }
+}
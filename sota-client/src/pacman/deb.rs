@@ -1,31 +1,69 @@
-use std::process::Command;
+use std::time::Duration;
 
-use datatype::{Error, Package, InstallCode};
-use pacman::{InstallOutcome, parse_packages};
+use datatype::{Error, Package};
+use pacman::{InstallOutcome, classify_install, find_command, is_file_issue, package_installed, parse_package,
+             query_versions, run_verify, run_with_timeout, stream_packages};
 
 
 /// Returns a list of installed DEB packages with
 /// `dpkg-query -f='${Package} ${Version}\n' -W`.
 pub fn installed_packages() -> Result<Vec<Package>, Error> {
-    Command::new("dpkg-query")
-        .arg("-f='${Package} ${Version}\n'")
-        .arg("-W")
-        .output()
-        .map_err(|err| Error::PacMan(format!("{}", err)))
-        .and_then(|output| Ok(String::from_utf8(output.stdout)?))
-        .and_then(|stdout| parse_packages(&stdout))
+    stream_packages(find_command("dpkg-query")?.arg("-f='${Package} ${Version}\n'").arg("-W"), parse_package)
 }
 
-/// Installs a new DEB package.
-pub fn install_package(path: &str) -> Result<InstallOutcome, Error> {
-    let output = Command::new("dpkg").arg("-E").arg("-i").arg(path).output()?;
-    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
-    let exists = (&stdout).contains("already installed");
-
-    match output.status.code() {
-        Some(0) if exists => Ok(InstallOutcome::new(InstallCode::ALREADY_PROCESSED, stdout, stderr)),
-        Some(0)           => Ok(InstallOutcome::new(InstallCode::OK, stdout, stderr)),
-        _                 => Ok(InstallOutcome::new(InstallCode::INSTALL_FAILED, stdout, stderr))
+/// Returns the versions of an installed DEB package with
+/// `dpkg-query -f='${Status} ${Version}\n' -W <name>`, one for each
+/// architecture it is installed for.
+pub fn installed_versions(name: &str) -> Result<Vec<String>, Error> {
+    query_versions(find_command("dpkg-query")?.arg("-f=${Status} ${Version}\n").arg("-W").arg(name), parse_installed_version)
+}
+
+/// Parse the version from a line of `dpkg-query` output, ignoring packages
+/// that were removed but still have configuration files.
+pub fn parse_installed_version(line: &str) -> Option<String> {
+    let parts = line.trim().splitn(4, ' ').collect::<Vec<_>>();
+    if parts.len() == 4 && parts[2] == "installed" {
+        Some(parts[3].into())
+    } else {
+        None
+    }
+}
+
+/// Installs a new DEB package, failing if it takes longer than the timeout.
+pub fn install_package(path: &str, timeout: Duration) -> Result<InstallOutcome, Error> {
+    let installed = is_installed(path).unwrap_or_else(|err| {
+        warn!("couldn't check whether {} is installed: {}", path, err);
+        false
+    });
+    let output = run_with_timeout(find_command("dpkg")?.env("LC_ALL", "C").arg("-E").arg("-i").arg(path), timeout)?;
+    let code = classify_install(&output, installed);
+    Ok(InstallOutcome::new(code, output.stdout, output.stderr))
+}
+
+/// Whether the version of the package in a DEB file is already installed,
+/// reading the package with `dpkg-deb -W <path>`.
+fn is_installed(path: &str) -> Result<bool, Error> {
+    let mut cmd = find_command("dpkg-deb")?;
+    cmd.arg("-W").arg("--showformat=${Package} ${Version}\n").arg(path);
+    package_installed(&mut cmd, parse_package, installed_versions)
+}
+
+/// Checks the installed files against the package database with `dpkg --verify`.
+pub fn verify_integrity(timeout: Duration) -> Result<Vec<String>, Error> {
+    run_verify(find_command("dpkg")?.env("LC_ALL", "C").arg("--verify"), is_file_issue, timeout)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_parse_installed_version() {
+        assert_eq!(parse_installed_version("install ok installed 1.2.3-4ubuntu1\n"), Some("1.2.3-4ubuntu1".into()));
+        assert_eq!(parse_installed_version("hold ok installed 2:8.0p1-1\n"), Some("2:8.0p1-1".into()));
+        assert_eq!(parse_installed_version("deinstall ok config-files 1.2.3-4\n"), None);
+        assert_eq!(parse_installed_version("unknown ok not-installed \n"), None);
     }
 }
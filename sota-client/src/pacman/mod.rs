@@ -5,20 +5,35 @@ pub mod test;
 pub mod uptane;
 
 
+use libc;
 use serde::de::{Deserialize, Deserializer, Error as SerdeError};
+use serde::ser::{Serialize, Serializer};
+use std::env;
+use std::ffi::OsStr;
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use datatype::{Error, Package, InstallOutcome};
+use bandwidth::DownloadMeter;
+use datatype::{Error, InstallCode, InstallOutcome, Package, Url};
 use http::Client;
 
 
-/// HTTP client and credentials for use by a package manager.
+/// HTTP client and credentials for use by a package manager, with the
+/// bandwidth rules that its downloads must follow.
 pub struct Credentials {
     pub client:    Box<Client>,
     pub token:     Option<String>,
     pub ca_file:   Option<String>,
     pub cert_file: Option<String>,
     pub pkey_file: Option<String>,
+    pub treehub:   Option<Url>,
+    pub meter:     DownloadMeter,
 }
 
 
@@ -30,7 +45,8 @@ pub enum PacMan {
     Rpm,
     Ostree,
     Uptane,
-    Test { filename: String, succeeds: bool }
+    Test { filename: String, succeeds: bool },
+    Memory(test::MemoryPacMan),
 }
 
 impl PacMan {
@@ -42,25 +58,108 @@ impl PacMan {
             PacMan::Rpm => rpm::installed_packages(),
             PacMan::Ostree => ostree::installed_packages(),
             PacMan::Uptane => uptane::installed_packages(),
-            PacMan::Test { ref filename, .. } => test::installed_packages(filename)
+            PacMan::Test { ref filename, .. } => test::installed_packages(filename),
+            PacMan::Memory(ref memory) => Ok(memory.installed_packages()),
         }
     }
 
-    /// Use a package manager to install a new package.
-    pub fn install_package(&self, path: &str, creds: &Credentials) -> Result<InstallOutcome, Error> {
+    /// Return every installed version of a single package, or none when it
+    /// isn't installed, without listing every installed package.
+    pub fn installed_versions(&self, name: &str) -> Result<Vec<String>, Error> {
         match *self {
             PacMan::Off => Err(Error::PacMan("no package manager".into())),
-            PacMan::Deb => deb::install_package(path),
-            PacMan::Rpm => rpm::install_package(path),
-            PacMan::Ostree => ostree::install_package(path, creds),
-            PacMan::Uptane => uptane::install_package(path, creds),
-            PacMan::Test { ref filename, succeeds } => test::install_package(filename, path, succeeds)
+            PacMan::Deb => deb::installed_versions(name),
+            PacMan::Rpm => rpm::installed_versions(name),
+            _ => Ok(self.installed_packages()?.into_iter().filter(|pkg| pkg.name == name).map(|pkg| pkg.version).collect())
         }
     }
 
-    /// Searches the result of `installed_packages` for a specific package.
+    /// Use a package manager to install a new package, killing the installation
+    /// if it runs longer than the timeout.
+    pub fn install_package(&self, path: &str, creds: &Credentials, timeout: Duration) -> Result<InstallOutcome, Error> {
+        match *self {
+            PacMan::Off => Err(Error::PacMan("no package manager".into())),
+            PacMan::Deb => deb::install_package(path, timeout),
+            PacMan::Rpm => rpm::install_package(path, timeout),
+            PacMan::Ostree => ostree::install_package(path, creds, timeout),
+            PacMan::Uptane => uptane::install_package(path, creds, timeout),
+            PacMan::Test { ref filename, succeeds } => test::install_package(filename, path, succeeds),
+            PacMan::Memory(ref memory) => memory.install_package(path),
+        }
+    }
+
+    /// Roll back the last installed update, running `rollback_cmd` for
+    /// package managers without built-in support. Ostree and Uptane
+    /// deployments return to the `previous` commit.
+    pub fn rollback(&self, rollback_cmd: Option<&str>, previous: Option<&str>, timeout: Duration) -> Result<(), Error> {
+        match *self {
+            PacMan::Off => Err(Error::PacMan("no package manager".into())),
+            PacMan::Ostree | PacMan::Uptane => ostree::rollback(previous),
+            _ => {
+                let cmd = rollback_cmd.ok_or_else(|| Error::PacMan("device.rollback_cmd not set".into()))?;
+                let output = run_with_timeout(Command::new("sh").arg("-c").arg(cmd), timeout)?;
+                if output.code == Some(0) {
+                    Ok(())
+                } else {
+                    Err(Error::PacMan(format!("rollback command failed: {}", output.stderr.trim())))
+                }
+            }
+        }
+    }
+
+    /// Run the package manager's integrity check, returning a description of
+    /// each modified or missing file that was found. Ostree checks the
+    /// repository at `ostree_repo`. The check is killed after the timeout.
+    pub fn verify_integrity(&self, ostree_repo: &str, timeout: Duration) -> Result<Vec<String>, Error> {
+        match *self {
+            PacMan::Off => Err(Error::PacMan("no package manager".into())),
+            PacMan::Deb => deb::verify_integrity(timeout),
+            PacMan::Rpm => rpm::verify_integrity(timeout),
+            PacMan::Ostree => ostree::verify_integrity(ostree_repo, timeout),
+            PacMan::Uptane => Err(Error::PacMan("integrity check not supported by uptane".into())),
+            PacMan::Test { .. } | PacMan::Memory(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Whether the last install only takes effect after a reboot. An ostree
+    /// deployment always needs one, otherwise it is signaled by the package
+    /// manager creating `marker_file`.
+    pub fn reboot_required(&self, marker_file: Option<&str>) -> bool {
+        match *self {
+            PacMan::Off => false,
+            PacMan::Ostree => true,
+            _ => marker_file.map_or(false, |path| Path::new(path).exists())
+        }
+    }
+
+    /// The package manager for an Uptane target, chosen by its file extension.
+    /// Targets without a package extension are OSTree commits.
+    pub fn for_target(refname: &str) -> PacMan {
+        match Path::new(refname).extension().and_then(|ext| ext.to_str()) {
+            Some("deb") => PacMan::Deb,
+            Some("rpm") => PacMan::Rpm,
+            _ => PacMan::Ostree
+        }
+    }
+
+    /// The `Content-Type`s a server may send for this package manager's
+    /// downloads, or none when any type is accepted.
+    pub fn content_types(&self) -> &'static [&'static str] {
+        match *self {
+            PacMan::Deb => &["application/vnd.debian.binary-package", "application/x-debian-package"],
+            PacMan::Rpm => &["application/x-rpm", "application/x-redhat-package-manager"],
+            PacMan::Ostree | PacMan::Uptane => &["application/json"],
+            _ => &[]
+        }
+    }
+
+    /// Whether this version of a package is installed, checked with
+    /// `installed_versions`.
     pub fn is_installed(&self, package: &Package) -> bool {
-        self.installed_packages().map(|packages| packages.contains(package)).unwrap_or(false)
+        match self.installed_versions(&package.name) {
+            Ok(versions) => versions.contains(&package.version),
+            Err(_) => false
+        }
     }
 }
 
@@ -74,6 +173,7 @@ impl FromStr for PacMan {
             "rpm" => Ok(PacMan::Rpm),
             "ostree" => Ok(PacMan::Ostree),
             "uptane" => Ok(PacMan::Uptane),
+            "test:memory" => Ok(PacMan::Memory(test::MemoryPacMan::default())),
             test if test.len() > 5 && test[..5] == *"test:" => {
                 Ok(PacMan::Test { filename: test[5..].into(), succeeds: true })
             },
@@ -89,28 +189,369 @@ impl<'de> Deserialize<'de> for PacMan {
     }
 }
 
+impl Serialize for PacMan {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            PacMan::Off    => ser.serialize_str("off"),
+            PacMan::Deb    => ser.serialize_str("deb"),
+            PacMan::Rpm    => ser.serialize_str("rpm"),
+            PacMan::Ostree => ser.serialize_str("ostree"),
+            PacMan::Uptane => ser.serialize_str("uptane"),
+            PacMan::Test { ref filename, .. } => ser.serialize_str(&format!("test:{}", filename)),
+            PacMan::Memory(_) => ser.serialize_str("test:memory"),
+        }
+    }
+}
 
-/// Split each line by the first space and return as list of package name and version.
-pub fn parse_packages(stdout: &str) -> Result<Vec<Package>, Error> {
+
+/// Returns a `Command` for a package manager binary, failing with a clear
+/// error rather than an opaque io error when it isn't on the `PATH`.
+pub fn find_command(program: &str) -> Result<Command, Error> {
+    let path = env::var_os("PATH").unwrap_or_default();
+    if on_path(program, &path) {
+        Ok(Command::new(program))
+    } else {
+        Err(Error::PacMan(format!("{} not found on PATH", program)))
+    }
+}
+
+fn on_path(program: &str, path: &OsStr) -> bool {
+    env::split_paths(path).any(|dir| dir.join(program).is_file())
+}
+
+
+/// The captured output of a command run with `run_with_timeout`.
+pub struct TimedOutput {
+    pub code:      Option<i32>,
+    pub stdout:    String,
+    pub stderr:    String,
+    pub timed_out: bool,
+}
+
+/// Run a command to completion, killing it if it exceeds the timeout. The
+/// command runs in its own process group so that any processes it started are
+/// killed too. Any output written before the command was killed is still
+/// captured.
+pub fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<TimedOutput, Error> {
+    unsafe {
+        cmd.before_exec(|| if libc::setpgid(0, 0) == 0 { Ok(()) } else { Err(io::Error::last_os_error()) });
+    }
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        .map_err(|err| Error::PacMan(format!("couldn't start command: {}", err)))?;
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        } else if started.elapsed() >= timeout {
+            error!("killing command after {}", describe_timeout(timeout));
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL); }
+            let _ = child.wait()?;
+            break None;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    let mut output = TimedOutput {
+        code:      status.and_then(|status| status.code()),
+        stdout:    stdout.join().unwrap_or_default(),
+        stderr:    stderr.join().unwrap_or_default(),
+        timed_out: status.is_none(),
+    };
+    if output.timed_out {
+        output.stderr.push_str(&format!("\ntimed out after {}", describe_timeout(timeout)));
+    }
+    Ok(output)
+}
+
+/// Describe a timeout in whole seconds, or milliseconds when shorter.
+fn describe_timeout(timeout: Duration) -> String {
+    if timeout.subsec_nanos() == 0 {
+        format!("{} seconds", timeout.as_secs())
+    } else {
+        format!("{}ms", timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos() / 1_000_000))
+    }
+}
+
+/// The longest that querying the installed versions of a package may take.
+pub const QUERY_TIMEOUT_SECS: u64 = 30;
+
+/// Classify a package manager installation by exit code, with a package
+/// whose version was installed before the installation started treated as
+/// already processed.
+pub fn classify_install(output: &TimedOutput, was_installed: bool) -> InstallCode {
+    match output.code {
+        _ if output.timed_out    => InstallCode::INSTALL_FAILED,
+        Some(_) if was_installed => InstallCode::ALREADY_PROCESSED,
+        Some(0)                  => InstallCode::OK,
+        _                        => InstallCode::INSTALL_FAILED
+    }
+}
+
+/// Read the name and version of the package in a file by running `cmd` and
+/// parsing its first line of output, returning whether that version is
+/// already installed according to the package database.
+pub fn package_installed<P, V>(cmd: &mut Command, parse: P, installed_versions: V) -> Result<bool, Error>
+    where P: Fn(&str) -> Option<Result<Package, Error>>,
+          V: Fn(&str) -> Result<Vec<String>, Error>
+{
+    let output = run_with_timeout(cmd, Duration::from_secs(QUERY_TIMEOUT_SECS))?;
+    if output.code != Some(0) {
+        return Err(Error::PacMan(format!("couldn't read package: {}", output.stderr.trim())));
+    }
+    match output.stdout.lines().filter_map(|line| parse(line)).next() {
+        Some(package) => {
+            let package = package?;
+            Ok(installed_versions(&package.name)?.contains(&package.version))
+        }
+        None => Err(Error::PacMan("couldn't read package: no output".into()))
+    }
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        String::from_utf8_lossy(&buf).into_owned()
+    })
+}
+
+
+/// Run an integrity check command, returning each line of stdout or stderr
+/// matching `is_issue`. A command that fails or times out without reporting
+/// any issues is an error.
+pub fn run_verify<F: Fn(&str) -> bool>(cmd: &mut Command, is_issue: F, timeout: Duration) -> Result<Vec<String>, Error> {
+    let output = run_with_timeout(cmd, timeout)?;
+    let issues = parse_issues(&format!("{}\n{}", output.stdout, output.stderr), is_issue);
+    if issues.is_empty() && output.code != Some(0) {
+        Err(Error::PacMan(format!("integrity check failed: {}", output.stderr.trim())))
+    } else {
+        Ok(issues)
+    }
+}
+
+/// Return each trimmed, non-empty line of output matching `is_issue`.
+pub fn parse_issues<F: Fn(&str) -> bool>(stdout: &str, is_issue: F) -> Vec<String> {
     stdout.lines()
-        .map(|line| line.trim_left_matches('\''))
-        .filter(|line| !line.is_empty())
-        .map(|line| {
-            let parts = line.splitn(2, ' ').collect::<Vec<_>>();
-            if parts.len() == 2 {
-                Ok(Package { name: parts[0].into(), version: parts[1].into() })
-            } else {
-                Err(Error::Parse(format!("couldn't parse package: {}", line)))
-            }
-        })
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && is_issue(line))
+        .map(String::from)
         .collect()
 }
 
+/// Whether a line of `dpkg --verify` or `rpm -Va` output describes a modified
+/// or missing file, e.g. `S.5....T.  c /etc/foo.conf` or `missing   /usr/bin/foo`.
+pub fn is_file_issue(line: &str) -> bool {
+    match line.split_whitespace().next() {
+        Some("missing") => true,
+        Some(attrs) => attrs.len() == 9 && attrs.chars().all(|c| "SM5DLUGTP.?".contains(c)),
+        None => false
+    }
+}
+
+/// Run a command querying a single package, parsing each line of stdout from
+/// a successful query as an installed version. A failed query means the
+/// package isn't installed, while a query running longer than
+/// `QUERY_TIMEOUT_SECS` is an error.
+pub fn query_versions<F>(cmd: &mut Command, parse: F) -> Result<Vec<String>, Error>
+    where F: Fn(&str) -> Option<String>
+{
+    let output = run_with_timeout(cmd, Duration::from_secs(QUERY_TIMEOUT_SECS))?;
+    match output.code {
+        _ if output.timed_out => Err(Error::PacMan(format!("package query timed out after {} seconds", QUERY_TIMEOUT_SECS))),
+        Some(0) => Ok(output.stdout.lines().filter_map(|line| parse(line)).collect()),
+        _       => Ok(Vec::new())
+    }
+}
+
+/// Run a command that lists the installed packages, parsing each line of
+/// stdout as it is read rather than collecting the whole output first.
+pub fn stream_packages<F>(cmd: &mut Command, parse: F) -> Result<Vec<Package>, Error>
+    where F: Fn(&str) -> Option<Result<Package, Error>>
+{
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::null()).spawn()
+        .map_err(|err| Error::PacMan(format!("{}", err)))?;
+    let stdout = child.stdout.take().expect("piped stdout");
+    let packages = read_packages(BufReader::new(stdout), parse);
+    if packages.is_err() {
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+    packages
+}
+
+/// Parse each line read into a package, skipping the lines `parse` ignores.
+pub fn read_packages<R, F>(mut reader: R, parse: F) -> Result<Vec<Package>, Error>
+    where R: BufRead,
+          F: Fn(&str) -> Option<Result<Package, Error>>
+{
+    let mut packages = Vec::new();
+    let mut buf = Vec::new();
+    while reader.read_until(b'\n', &mut buf)? > 0 {
+        let line = String::from_utf8(mem::replace(&mut buf, Vec::new()))?;
+        if let Some(package) = parse(line.trim_right_matches(|c| c == '\n' || c == '\r')) {
+            packages.push(package?);
+        }
+    }
+    Ok(packages)
+}
+
+/// Split each line by the first space and return as list of package name and version.
+pub fn parse_packages(stdout: &str) -> Result<Vec<Package>, Error> {
+    stdout.lines().filter_map(parse_package).collect()
+}
+
+/// Split a line by the first space into a package name and version, ignoring
+/// empty lines.
+pub fn parse_package(line: &str) -> Option<Result<Package, Error>> {
+    let line = line.trim_left_matches('\'');
+    if line.is_empty() {
+        return None;
+    }
+    let parts = line.splitn(2, ' ').collect::<Vec<_>>();
+    if parts.len() == 2 {
+        Some(Ok(Package { name: parts[0].into(), version: parts[1].into() }))
+    } else {
+        Some(Err(Error::Parse(format!("couldn't parse package: {}", line))))
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::File;
 
+    use pacman::test::TestDir;
+
+
+    fn output(code: Option<i32>, stdout: &str, stderr: &str) -> TimedOutput {
+        TimedOutput { code: code, stdout: stdout.into(), stderr: stderr.into(), timed_out: code.is_none() }
+    }
+
+    #[test]
+    fn test_find_command() {
+        assert!(on_path("sh", OsStr::new("/usr/local/bin:/usr/bin:/bin")));
+        assert!(!on_path("dpkg", OsStr::new("/sota/missing/bin")));
+        match find_command("sota-missing-package-manager") {
+            Err(Error::PacMan(err)) => assert_eq!(err, "sota-missing-package-manager not found on PATH"),
+            other => panic!("expected missing binary, got {:?}", other.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn test_off_package_manager() {
+        assert!(PacMan::Off.installed_packages().is_err());
+        assert!(PacMan::Off.verify_integrity("/sysroot/ostree/repo", Duration::from_secs(5)).is_err());
+        assert!(!PacMan::Off.is_installed(&Package { name: "apa".into(), version: "0.0.0".into() }));
+    }
+
+    #[test]
+    fn test_installed_versions() {
+        let pacman = PacMan::Memory(test::MemoryPacMan::new(vec![
+            Package { name: "apa".into(), version: "1.0".into() },
+            Package { name: "apa".into(), version: "1.1".into() },
+        ]));
+        assert_eq!(pacman.installed_versions("apa").unwrap(), vec!["1.0".to_string(), "1.1".to_string()]);
+        assert!(pacman.installed_versions("bepa").unwrap().is_empty());
+        assert!(pacman.is_installed(&Package { name: "apa".into(), version: "1.1".into() }));
+        assert!(!pacman.is_installed(&Package { name: "apa".into(), version: "2.0".into() }));
+        assert!(PacMan::Off.installed_versions("apa").is_err());
+    }
+
+    #[test]
+    fn test_query_versions() {
+        let query = |script: &str, parse: fn(&str) -> Option<String>| {
+            query_versions(Command::new("sh").arg("-c").arg(script), parse).expect("query versions")
+        };
+        assert_eq!(query("echo 'install ok installed 1.2-3'", deb::parse_installed_version), vec!["1.2-3".to_string()]);
+        assert!(query("echo 'dpkg-query: no packages found matching apa' >&2; exit 1", deb::parse_installed_version).is_empty());
+        assert_eq!(query("printf '4.2-1.el7\\n1:4.3-1.el7\\n'", rpm::parse_installed_version),
+                   vec!["4.2-1.el7".to_string(), "1:4.3-1.el7".to_string()]);
+        assert!(query("echo 'package apa is not installed'; exit 1", rpm::parse_installed_version).is_empty());
+    }
+
+    #[test]
+    fn test_reboot_required() {
+        let dir = TestDir::new("sota-reboot-marker");
+        let marker = format!("{}/reboot-required", dir.0);
+        assert!(PacMan::Ostree.reboot_required(None));
+        assert!(PacMan::Ostree.reboot_required(Some(&marker)));
+        assert!(!PacMan::Deb.reboot_required(None));
+        assert!(!PacMan::Deb.reboot_required(Some(&marker)));
+        File::create(&marker).expect("create marker");
+        assert!(PacMan::Deb.reboot_required(Some(&marker)));
+        assert!(PacMan::Rpm.reboot_required(Some(&marker)));
+        assert!(!PacMan::Off.reboot_required(Some(&marker)));
+    }
+
+    #[test]
+    fn test_classify_already_installed() {
+        assert_eq!(classify_install(&output(Some(0), "", ""), true), InstallCode::ALREADY_PROCESSED);
+        assert_eq!(classify_install(&output(Some(1), "", "Paket foo-1.0 ist bereits installiert"), true), InstallCode::ALREADY_PROCESSED);
+        assert_eq!(classify_install(&output(Some(0), "package foo-1.0 is already installed", ""), false), InstallCode::OK);
+        assert_eq!(classify_install(&output(None, "", ""), true), InstallCode::INSTALL_FAILED);
+    }
+
+    #[test]
+    fn test_classify_warning_success() {
+        let stderr = "warning: foo.rpm: Header V3 RSA/SHA256 Signature, key ID fd431d51: NOKEY";
+        assert_eq!(classify_install(&output(Some(0), "", stderr), false), InstallCode::OK);
+        assert_eq!(classify_install(&output(Some(1), "", "error: dependency failed"), false), InstallCode::INSTALL_FAILED);
+        assert_eq!(classify_install(&output(None, "already installed", ""), false), InstallCode::INSTALL_FAILED);
+    }
+
+    #[test]
+    fn test_package_installed() {
+        let installed = |name: &str| -> Result<Vec<String>, Error> {
+            Ok(if name == "apa" { vec!["1.0".to_string()] } else { Vec::new() })
+        };
+        let read = |script: &str| package_installed(Command::new("sh").arg("-c").arg(script), parse_package, &installed);
+        assert_eq!(read("echo 'apa 1.0'").unwrap(), true);
+        assert_eq!(read("echo 'apa 1.1'").unwrap(), false);
+        assert_eq!(read("echo 'bepa 1.0'").unwrap(), false);
+        assert!(read("echo 'not a package' >&2; exit 2").is_err());
+    }
+
+    #[test]
+    fn test_parse_verify_issues() {
+        let dpkg = "??5??????   /usr/bin/sota_client\nmissing   c /etc/sota.toml\n\n\
+                    dpkg: warning: package foo has no md5sums\n";
+        assert_eq!(parse_issues(dpkg, is_file_issue), vec!["??5??????   /usr/bin/sota_client", "missing   c /etc/sota.toml"]);
+
+        let rpm = "S.5....T.  c /etc/ssh/sshd_config\nmissing     /usr/lib/libfoo.so.1\n.M.......    /var/log\n";
+        assert_eq!(parse_issues(rpm, is_file_issue), vec![
+            "S.5....T.  c /etc/ssh/sshd_config", "missing     /usr/lib/libfoo.so.1", ".M.......    /var/log"
+        ]);
+
+        let fsck = "Validating refs...\nEnumerating objects...\nVerifying content integrity of 2 commit objects...\n\
+                    error: Corrupted file object; checksum expected='abc' actual='def'\n";
+        assert_eq!(parse_issues(fsck, ostree::is_fsck_issue), vec!["error: Corrupted file object; checksum expected='abc' actual='def'"]);
+        assert!(parse_issues("Validating refs...\nVerifying content integrity of 2 commit objects...\n", ostree::is_fsck_issue).is_empty());
+    }
+
+    #[test]
+    fn test_verify_failure() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo 'missing     /usr/bin/foo' && exit 1");
+        assert_eq!(run_verify(&mut cmd, is_file_issue, Duration::from_secs(5)).unwrap(), vec!["missing     /usr/bin/foo"]);
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo 'no database' >&2 && exit 2");
+        match run_verify(&mut cmd, is_file_issue, Duration::from_secs(5)) {
+            Err(Error::PacMan(msg)) => assert!(msg.contains("no database")),
+            other => panic!("expected a PacMan error, got {:?}", other)
+        }
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("exec sleep 5");
+        match run_verify(&mut cmd, is_file_issue, Duration::from_millis(500)) {
+            Err(Error::PacMan(msg)) => assert!(msg.contains("timed out")),
+            other => panic!("expected a PacMan error, got {:?}", other)
+        }
+    }
 
     #[test]
     fn test_single_package() {
@@ -134,9 +575,57 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_read_packages() {
+        let reader = BufReader::new(&b"'one 1\r\n'two 2 beta\n'"[..]);
+        assert_eq!(read_packages(reader, parse_package).unwrap(), vec![
+            Package { name: "one".into(), version: "1".into() },
+            Package { name: "two".into(), version: "2 beta".into() },
+        ]);
+        assert!(read_packages(BufReader::new(&b"one 1\nfoobar\n"[..]), parse_package).is_err());
+    }
+
+    #[test]
+    fn test_stream_packages() {
+        let mut cmd = Command::new("printf");
+        cmd.arg("one 1\\ntwo 2\\n");
+        assert_eq!(stream_packages(&mut cmd, parse_package).unwrap().len(), 2);
+        assert!(stream_packages(&mut Command::new("sota-missing-package-manager"), parse_package).is_err());
+    }
+
     #[test]
     fn test_error_message() {
         let expect = "Parse error: couldn't parse package: foobar".to_string();
         assert_eq!(expect, format!("{}", parse_packages("foobar").unwrap_err()));
     }
+
+    #[test]
+    fn test_command_completes() {
+        let output = run_with_timeout(Command::new("echo").arg("done"), Duration::from_secs(5)).unwrap();
+        assert!(!output.timed_out);
+        assert_eq!(output.code, Some(0));
+        assert_eq!(output.stdout, "done\n");
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo partial && exec sleep 5");
+        let output = run_with_timeout(&mut cmd, Duration::from_millis(500)).unwrap();
+        assert!(output.timed_out);
+        assert_eq!(output.code, None);
+        assert_eq!(output.stdout, "partial\n");
+        assert!(output.stderr.contains("timed out after 500ms"));
+    }
+
+    #[test]
+    fn test_command_timeout_kills_group() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("sleep 5; echo finished");
+        let started = Instant::now();
+        let output = run_with_timeout(&mut cmd, Duration::from_millis(500)).unwrap();
+        assert!(output.timed_out);
+        assert_eq!(output.stdout, "");
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
 }
@@ -1,9 +1,10 @@
 use json;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::time::Duration;
 
-use datatype::{Error, OstreePackage, Package};
-use pacman::{Credentials, InstallOutcome, parse_packages};
+use datatype::{Error, Ostree, OstreePackage, Package};
+use pacman::{Credentials, InstallOutcome, find_command, parse_packages, run_verify};
 
 
 const PACKAGES_FILE: &'static str = "/usr/package.manifest";
@@ -15,7 +16,24 @@ pub fn installed_packages() -> Result<Vec<Package>, Error> {
     parse_packages(&packages)
 }
 
-pub fn install_package(path: &str, creds: &Credentials) -> Result<InstallOutcome, Error> {
+pub fn install_package(path: &str, creds: &Credentials, timeout: Duration) -> Result<InstallOutcome, Error> {
     let pkg: OstreePackage = json::from_reader(BufReader::new(File::open(path)?))?;
-    pkg.install(creds)
+    pkg.install(creds, timeout)
+}
+
+/// Rolls back to the deployment of the commit booted before the install.
+pub fn rollback(previous: Option<&str>) -> Result<(), Error> {
+    let commit = previous.ok_or_else(|| Error::OSTree("the previously booted commit is unknown".into()))?;
+    Ostree::redeploy(commit)
+}
+
+/// Checks the objects of the repository at `repo` for corruption with `ostree fsck`.
+pub fn verify_integrity(repo: &str, timeout: Duration) -> Result<Vec<String>, Error> {
+    run_verify(find_command("ostree")?.arg("fsck").arg(format!("--repo={}", repo)), is_fsck_issue, timeout)
+}
+
+/// Whether a line of `ostree fsck` output reports a problem rather than progress.
+pub fn is_fsck_issue(line: &str) -> bool {
+    let line = line.to_lowercase();
+    line.starts_with("error") || line.contains("corrupt") || line.contains("missing")
 }
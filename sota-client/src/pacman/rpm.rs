@@ -1,36 +1,119 @@
 use std::process::Command;
 use std::str;
+use std::time::Duration;
 
 use datatype::{Error, Package, InstallCode};
-use pacman::{InstallOutcome, parse_packages};
+use pacman::{InstallOutcome, classify_install, find_command, is_file_issue, package_installed, query_versions, run_verify,
+             run_with_timeout, stream_packages};
 
 
 /// Returns a list of installed RPM packages with
-/// `rpm -qa --queryformat ${NAME} ${VERSION}\n`.
+/// `rpm -qa --qf '%{NAME} %{VERSION}-%{RELEASE}\n'`, with the version prefixed
+/// by the package epoch when one is set.
 pub fn installed_packages() -> Result<Vec<Package>, Error> {
-    Command::new("rpm")
-        .arg("-qa")
-        .arg("--queryformat")
-        .arg("%{NAME} %{VERSION}\n")
-        .output()
-        .map_err(|err| Error::PacMan(format!("{}", err)))
-        .and_then(|output| Ok(String::from_utf8(output.stdout)?))
-        .and_then(|stdout| parse_packages(&stdout))
-}
-
-/// Installs a new RPM package with `rpm -Uvh --force <package-path>`.
-pub fn install_package(path: &str) -> Result<InstallOutcome, Error> {
-    let output = Command::new("rpm").arg("-Uvh").arg("--force").arg(path).output()?;
-    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
-    let exists = (&stdout).contains("already installed");
-
-    match output.status.code() {
-        Some(_) if exists => Ok(InstallOutcome::new(InstallCode::ALREADY_PROCESSED, stdout, stderr)),
-        Some(0) => {
-            let _ = Command::new("sync").status().map_err(|err| error!("couldn't run 'sync': {}", err));
-            Ok(InstallOutcome::new(InstallCode::OK, stdout, stderr))
-        }
-        _ => Ok(InstallOutcome::new(InstallCode::INSTALL_FAILED, stdout, stderr))
+    let mut cmd = find_command("rpm")?;
+    cmd.arg("-qa").arg("--qf").arg("%{NAME} %|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}\n");
+    stream_packages(&mut cmd, parse_rpm_package)
+}
+
+/// Returns every installed version of an RPM package with `rpm -q <name>`,
+/// formatted as in `installed_packages`.
+pub fn installed_versions(name: &str) -> Result<Vec<String>, Error> {
+    let mut cmd = find_command("rpm")?;
+    cmd.arg("-q").arg("--qf").arg("%|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}\n").arg(name);
+    query_versions(&mut cmd, parse_installed_version)
+}
+
+/// Parse a version from a line of `rpm -q` output.
+pub fn parse_installed_version(line: &str) -> Option<String> {
+    match line.trim() {
+        ""      => None,
+        version => Some(version.into())
+    }
+}
+
+/// Parse each line of `rpm -qa` output into a package name and version. The
+/// version is taken as the last field so any extra whitespace in the name is
+/// tolerated.
+pub fn parse_rpm_packages(stdout: &str) -> Result<Vec<Package>, Error> {
+    stdout.lines().filter_map(parse_rpm_package).collect()
+}
+
+/// Parse a single line of `rpm -qa` output, ignoring empty lines.
+pub fn parse_rpm_package(line: &str) -> Option<Result<Package, Error>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    let parts = line.rsplitn(2, char::is_whitespace).collect::<Vec<_>>();
+    if parts.len() == 2 && !parts[1].trim().is_empty() {
+        Some(Ok(Package { name: parts[1].trim().into(), version: parts[0].into() }))
+    } else {
+        Some(Err(Error::Parse(format!("couldn't parse rpm package: {}", line))))
+    }
+}
+
+/// Installs a new RPM package with `rpm -U --force <package-path>`, failing
+/// if it takes longer than the timeout.
+pub fn install_package(path: &str, timeout: Duration) -> Result<InstallOutcome, Error> {
+    let installed = is_installed(path).unwrap_or_else(|err| {
+        warn!("couldn't check whether {} is installed: {}", path, err);
+        false
+    });
+    let output = run_with_timeout(find_command("rpm")?.env("LC_ALL", "C").arg("-U").arg("--force").arg(path), timeout)?;
+    let code = classify_install(&output, installed);
+    if code == InstallCode::OK {
+        let _ = Command::new("sync").status().map_err(|err| error!("couldn't run 'sync': {}", err));
+    }
+    Ok(InstallOutcome::new(code, output.stdout, output.stderr))
+}
+
+/// Whether the version of the package in an RPM file is already installed,
+/// reading the package with `rpm -qp <path>`.
+fn is_installed(path: &str) -> Result<bool, Error> {
+    let mut cmd = find_command("rpm")?;
+    cmd.arg("-qp").arg("--qf").arg("%{NAME} %|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}\n").arg(path);
+    package_installed(&mut cmd, parse_rpm_package, installed_versions)
+}
+
+/// Checks the installed files against the package database with `rpm -Va`.
+pub fn verify_integrity(timeout: Duration) -> Result<Vec<String>, Error> {
+    run_verify(find_command("rpm")?.env("LC_ALL", "C").arg("-Va"), is_file_issue, timeout)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_rpm_packages("bash 4.2.46-34.el7\n").unwrap(), vec![
+            Package { name: "bash".into(), version: "4.2.46-34.el7".into() }
+        ]);
+    }
+
+    #[test]
+    fn test_parse_epoch_version() {
+        assert_eq!(parse_rpm_packages("openssl 1:1.0.2k-8.el7\ngzip 1.5-9.el7\n").unwrap(), vec![
+            Package { name: "openssl".into(), version: "1:1.0.2k-8.el7".into() },
+            Package { name: "gzip".into(), version: "1.5-9.el7".into() },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_extra_spaces() {
+        assert_eq!(parse_rpm_packages("  gpg pubkey  f4a80eb5-53a7ff4b \n\n").unwrap(), vec![
+            Package { name: "gpg pubkey".into(), version: "f4a80eb5-53a7ff4b".into() }
+        ]);
+        assert!(parse_rpm_packages("orphan").is_err());
+    }
+
+    #[test]
+    fn test_parse_installed_version() {
+        assert_eq!(parse_installed_version("1:1.0.2k-8.el7\n"), Some("1:1.0.2k-8.el7".into()));
+        assert_eq!(parse_installed_version("1.5-9.el7\n"), Some("1.5-9.el7".into()));
+        assert_eq!(parse_installed_version("\n"), None);
     }
 }
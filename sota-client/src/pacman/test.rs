@@ -1,10 +1,12 @@
+use std::collections::VecDeque;
 use std::fs::{self, File, OpenOptions};
 use std::io::BufReader;
 use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
 use time;
 
-use datatype::{Error, Package, InstallCode};
-use pacman::{InstallOutcome, PacMan};
+use datatype::{Error, Package, InstallCode, Util};
+use pacman::{self, InstallOutcome, PacMan};
 
 
 impl PacMan {
@@ -19,6 +21,62 @@ impl PacMan {
 }
 
 
+/// An in-memory package manager for tests. Clones share the same installed
+/// set, so a package installed through one clone is seen by all of them.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPacMan {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    installed: Vec<Package>,
+    results:   VecDeque<InstallCode>,
+}
+
+impl PartialEq for MemoryPacMan {
+    fn eq(&self, other: &MemoryPacMan) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl Eq for MemoryPacMan {}
+
+impl MemoryPacMan {
+    /// Create a new in-memory package manager with these packages installed.
+    pub fn new(installed: Vec<Package>) -> Self {
+        let pacman = MemoryPacMan::default();
+        pacman.state.lock().unwrap().installed = installed;
+        pacman
+    }
+
+    /// Return this code from the next installation instead of `InstallCode::OK`.
+    pub fn push_result(&self, code: InstallCode) {
+        self.state.lock().unwrap().results.push_back(code);
+    }
+
+    /// Returns a copy of the currently installed packages.
+    pub fn installed_packages(&self) -> Vec<Package> {
+        self.state.lock().unwrap().installed.clone()
+    }
+
+    /// Install each `<name> <version>` line in the file at `path`, replacing
+    /// any installed package with the same name. Nothing is installed unless
+    /// the next result code is `InstallCode::OK`.
+    pub fn install_package(&self, path: &str) -> Result<InstallOutcome, Error> {
+        let mut state = self.state.lock().unwrap();
+        let code = state.results.pop_front().unwrap_or(InstallCode::OK);
+        if code == InstallCode::OK {
+            for package in pacman::parse_packages(&Util::read_text(path)?)? {
+                state.installed.retain(|installed| installed.name != package.name);
+                state.installed.push(package);
+            }
+        }
+        Ok(InstallOutcome::empty(code))
+    }
+}
+
+
 /// Encapsulate a directory whose contents will be destroyed when it drops out of scope.
 pub struct TestDir(pub String);
 
@@ -111,6 +169,24 @@ mod tests {
         assert_eq!(installed_packages(&path).unwrap(), vec![apa(), bepa()]);
     }
 
+    #[test]
+    fn memory_install_packages() {
+        let dir  = TestDir::new("sota-tpm-test-5");
+        let path = format!("{}/update", dir.0);
+        let mut file = File::create(path.clone()).unwrap();
+        writeln!(&mut file, "bepa 2.0.0").unwrap();
+
+        let pacman = MemoryPacMan::new(vec![apa(), bepa()]);
+        let shared = pacman.clone();
+        pacman.push_result(InstallCode::INSTALL_FAILED);
+        assert_eq!(pacman.install_package(&path).unwrap().into_result("".into()).result_code, InstallCode::INSTALL_FAILED);
+        assert_eq!(shared.installed_packages(), vec![apa(), bepa()]);
+        assert_eq!(pacman.install_package(&path).unwrap().into_result("".into()).result_code, InstallCode::OK);
+        assert_eq!(shared.installed_packages(), vec![apa(), Package { name: "bepa".into(), version: "2.0.0".into() }]);
+        assert!(pacman == shared);
+        assert!(pacman != MemoryPacMan::default());
+    }
+
     #[test]
     fn failed_installation() {
         let dir  = TestDir::new("sota-tpm-test-4");
@@ -1,6 +1,7 @@
 use json;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::time::Duration;
 
 use datatype::{Error, OstreePackage, Package};
 use pacman::{Credentials, InstallOutcome, parse_packages};
@@ -15,7 +16,7 @@ pub fn installed_packages() -> Result<Vec<Package>, Error> {
     parse_packages(&packages)
 }
 
-pub fn install_package(path: &str, creds: &Credentials) -> Result<InstallOutcome, Error> {
+pub fn install_package(path: &str, creds: &Credentials, timeout: Duration) -> Result<InstallOutcome, Error> {
     let pkg: OstreePackage = json::from_reader(BufReader::new(File::open(path)?))?;
-    pkg.install(creds)
+    pkg.install(creds, timeout)
 }
@@ -0,0 +1,146 @@
+use chan::{self, Sender};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::thread;
+use std::time::Duration;
+
+use datatype::{Command, Event};
+use http::cert_not_after;
+use interpreter::CommandExec;
+
+
+/// Poll for new updates every `interval`, waiting for each response before
+/// starting the timer. The first poll is sent after `first_delay`.
+pub fn start_update_poller(interval: Duration, first_delay: Duration, ctx: &Sender<CommandExec>) {
+    if first_delay > Duration::from_secs(0) {
+        info!("Delaying the first poll for updates by {} ms.", as_millis(first_delay));
+        thread::sleep(first_delay);
+    }
+    info!("Polling for new updates every {} seconds.", interval.as_secs());
+    let (etx, erx) = chan::async::<Event>();
+    loop {
+        ctx.send(CommandExec { cmd: Command::GetUpdateRequests, etx: Some(etx.clone()) });
+        let _ = erx.recv(); // wait for the response before starting the timer
+        thread::sleep(interval);
+    }
+}
+
+/// Check the expiry of the TLS client certificate at startup then every
+/// `interval`, sending an event when it expires within `warn_days`.
+pub fn start_cert_monitor(cert_file: &str, warn_days: u64, interval: Duration, etx: &Sender<Event>) {
+    info!("Checking the expiry of {} every {} seconds.", cert_file, interval.as_secs());
+    loop {
+        match cert_not_after(cert_file) {
+            Ok(not_after) => if let Some(event) = cert_expiry_event(not_after, Utc::now(), warn_days) {
+                warn!("certificate {} expires at {}", cert_file, not_after);
+                etx.send(event);
+            },
+            Err(err) => error!("couldn't read the expiry of {}: {}", cert_file, err)
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Returns an event when the certificate has expired or will expire within
+/// `warn_days` of `now`.
+pub fn cert_expiry_event(not_after: DateTime<Utc>, now: DateTime<Utc>, warn_days: u64) -> Option<Event> {
+    if not_after <= now {
+        Some(Event::CertificateExpired)
+    } else if not_after - now < ChronoDuration::days(warn_days as i64) {
+        Some(Event::CertificateExpiringSoon { days_remaining: (not_after - now).num_days() })
+    } else {
+        None
+    }
+}
+
+/// Returns a random delay of up to `bound` so that devices started at the
+/// same time don't all poll at once.
+pub fn startup_jitter(bound: Duration) -> Duration {
+    let max = as_millis(bound);
+    if max == 0 {
+        return Duration::from_secs(0);
+    }
+    let mut bytes = [0; 8];
+    if let Err(err) = SystemRandom::new().fill(&mut bytes) {
+        warn!("couldn't generate startup jitter: {:?}", err);
+        return bound;
+    }
+    let random = bytes.iter().fold(0u64, |acc, byte| acc << 8 | u64::from(*byte));
+    Duration::from_millis(random % (max + 1))
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos()) / 1_000_000
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509, X509Name};
+    use std::fs::File;
+    use std::io::Write;
+    use std::time::Instant;
+
+    use pacman::test::TestDir;
+
+
+    fn write_cert(path: &str, days: u32) {
+        let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "sota-client").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&pkey).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(days).unwrap()).unwrap();
+        builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+        let pem = builder.build().to_pem().unwrap();
+        File::create(path).unwrap().write_all(&pem).unwrap();
+    }
+
+
+    #[test]
+    fn test_startup_jitter_bound() {
+        assert_eq!(startup_jitter(Duration::from_secs(0)), Duration::from_secs(0));
+        for _ in 0..100 {
+            assert!(startup_jitter(Duration::from_millis(50)) <= Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn test_cert_expiry() {
+        let dir = TestDir::new("sota-cert-expiry");
+        let path = format!("{}/device.crt", dir.0);
+        write_cert(&path, 3);
+
+        let now = Utc::now();
+        let not_after = cert_not_after(&path).expect("not_after");
+        assert!(not_after > now + ChronoDuration::days(2));
+        assert!(not_after < now + ChronoDuration::days(4));
+
+        assert_eq!(cert_expiry_event(not_after, now, 1), None);
+        assert_eq!(cert_expiry_event(not_after, now, 30), Some(Event::CertificateExpiringSoon { days_remaining: 2 }));
+        assert_eq!(cert_expiry_event(not_after, now + ChronoDuration::days(4), 30), Some(Event::CertificateExpired));
+
+        let (etx, erx) = chan::async::<Event>();
+        thread::spawn(move || start_cert_monitor(&path, 30, Duration::from_secs(60), &etx));
+        assert_eq!(erx.recv(), Some(Event::CertificateExpiringSoon { days_remaining: 2 }));
+    }
+
+    #[test]
+    fn test_first_poll_delayed() {
+        let (ctx, crx) = chan::sync::<CommandExec>(0);
+        let started = Instant::now();
+        thread::spawn(move || start_update_poller(Duration::from_secs(60), Duration::from_millis(200), &ctx));
+        let exec = crx.recv().expect("first poll");
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(exec.cmd, Command::GetUpdateRequests);
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use datatype::{DeviceConfig, InstallReport};
+
+
+/// Remember recently sent install reports so that an identical report isn't
+/// sent twice. A report with a different outcome for the same update is
+/// still sent.
+pub struct ReportCache {
+    size: usize,
+    ttl:  Duration,
+    sent: VecDeque<(InstallReport, Instant)>,
+}
+
+impl Default for ReportCache {
+    fn default() -> Self {
+        ReportCache::from_config(&DeviceConfig::default())
+    }
+}
+
+impl ReportCache {
+    /// Remember up to `size` reports for `ttl` each. A size of zero never
+    /// skips a report.
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        ReportCache { size: size, ttl: ttl, sent: VecDeque::new() }
+    }
+
+    /// Create a cache from the `report_dedup_*` device config.
+    pub fn from_config(device: &DeviceConfig) -> Self {
+        Self::new(device.report_dedup_size, Duration::from_secs(device.report_dedup_ttl))
+    }
+
+    /// Whether an identical report was sent within the last `ttl`.
+    pub fn contains(&mut self, report: &InstallReport) -> bool {
+        self.expire();
+        self.sent.iter().any(|&(ref sent, _)| sent == report)
+    }
+
+    /// Record that a report was sent, forgetting the oldest report once the
+    /// cache is full.
+    pub fn insert(&mut self, report: InstallReport) {
+        if self.size == 0 {
+            return;
+        }
+        self.sent.retain(|&(ref sent, _)| *sent != report);
+        while self.sent.len() >= self.size {
+            let _ = self.sent.pop_front();
+        }
+        self.sent.push_back((report, Instant::now()));
+    }
+
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.sent.retain(|&(_, sent)| sent.elapsed() < ttl);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datatype::{InstallCode, InstallResult};
+
+
+    fn report(id: &str, code: InstallCode) -> InstallReport {
+        InstallResult::new(id.into(), code, "".into()).into_report()
+    }
+
+    #[test]
+    fn test_remembers_recent_reports() {
+        let mut cache = ReportCache::new(2, Duration::from_secs(60));
+        cache.insert(report("one", InstallCode::OK));
+        cache.insert(report("two", InstallCode::OK));
+        assert!(cache.contains(&report("one", InstallCode::OK)));
+        assert!(!cache.contains(&report("one", InstallCode::INSTALL_FAILED)));
+        cache.insert(report("three", InstallCode::OK));
+        assert!(!cache.contains(&report("one", InstallCode::OK)));
+        assert!(cache.contains(&report("two", InstallCode::OK)));
+        assert!(cache.contains(&report("three", InstallCode::OK)));
+
+        let mut expired = ReportCache::new(2, Duration::from_secs(0));
+        expired.insert(report("one", InstallCode::OK));
+        assert!(!expired.contains(&report("one", InstallCode::OK)));
+
+        let mut disabled = ReportCache::new(0, Duration::from_secs(60));
+        disabled.insert(report("one", InstallCode::OK));
+        assert!(!disabled.contains(&report("one", InstallCode::OK)));
+    }
+}
@@ -31,7 +31,12 @@ impl<S: Serialize> RpcRequest<S> {
 
     /// Send a JSON-RPC POST request to the specified URL.
     pub fn send(&self, url: Url) -> Result<String, String> {
-        let rx = AuthClient::default().post(url, Some(json::to_vec(self).expect("serialize RpcRequest")));
+        self.send_with(&AuthClient::default(), url)
+    }
+
+    /// Send a JSON-RPC POST request to the specified URL with this client.
+    pub fn send_with(&self, client: &Client, url: Url) -> Result<String, String> {
+        let rx = client.post(url, Some(json::to_vec(self).expect("serialize RpcRequest")));
         match rx.recv().expect("no RpcRequest response received") {
             Response::Success(data) => String::from_utf8(data.body).or_else(|err| Err(format!("{}", err))),
             Response::Failed(data)  => Err(format!("{}", data)),
@@ -99,6 +104,11 @@ impl RpcErr {
         Self::new(id, ErrorCode { code: -32602, message: "Invalid params".to_string(), data: data })
     }
 
+    /// Create a new `RpcErr` with a reason of "Unauthorized".
+    pub fn unauthorized(id: u64, data: String) -> Self {
+        Self::new(id, ErrorCode { code: -32001, message: "Unauthorized".to_string(), data: data })
+    }
+
     /// Create a new `RpcErr` with a reason of "Couldn't handle request".
     pub fn unspecified(id: u64, data: String) -> Self {
         Self::new(id, ErrorCode { code: -32100, message: "Couldn't handle request".to_string(), data: data })
@@ -2,12 +2,15 @@ use base64;
 use std::sync::Mutex;
 use uuid::Uuid;
 
-use datatype::{Event, DownloadComplete, UpdateAvailable};
-use images::{ImageMeta, ImageWriter, Transfers};
+use datatype::{Error, Event, DownloadComplete, UpdateAvailable, Util};
+use images::{ChunkCompression, ImageMeta, ImageWriter, Transfers};
 use rvi::json_rpc::ChunkReceived;
 use rvi::services::{BackendServices, RemoteServices};
 
 
+/// The error reported when a new transfer would exceed `Transfers.max_active`.
+pub const TRANSFERS_FULL: &'static str = "too many active transfers";
+
 /// Each `Parameter` implementation handles a specific kind of RVI client request.
 pub trait Parameter {
     fn handle(&self, remote: &Mutex<RemoteServices>, transfers: &Mutex<Transfers>) -> Result<Option<Event>, String>;
@@ -34,7 +37,9 @@ impl Parameter for Notify {
 pub struct Start {
     update_id:   Uuid,
     chunkscount: u64,
-    checksum:    String
+    checksum:    String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<String>,
 }
 
 impl Parameter for Start {
@@ -43,13 +48,32 @@ impl Parameter for Start {
         let remote = remote.lock().unwrap();
         let mut transfers = transfers.lock().unwrap();
         let image_name = format!("{}", self.update_id);
-        let (dir, size) = {
+        if transfers.is_full() && ! transfers.active.contains_key(&image_name) {
+            warn!("rejecting transfer for update_id {}: {} transfers active", self.update_id, transfers.max_active);
+            return Err(format!("{} (max {})", TRANSFERS_FULL, transfers.max_active));
+        }
+        let (dir, chunks_dir, size) = {
             let dir = transfers.images_dir.clone();
             let size = transfers.image_sizes.get(&image_name).ok_or_else(|| format!("image size not found: {}", image_name))?;
-            (dir, *size)
+            (dir, transfers.chunks_dir.clone(), *size)
+        };
+        match Util::check_space(&dir, size, transfers.headroom) {
+            Err(Error::DiskSpace) => {
+                error!("not enough disk space for update_id {}", self.update_id);
+                return Ok(Some(Event::DownloadFailed(self.update_id, Error::DiskSpace.to_string())));
+            }
+            Err(err) => warn!("couldn't check free disk space in {}: {}", dir, err),
+            Ok(()) => ()
+        }
+        let compression = match self.compression {
+            Some(ref algorithm) => Some(algorithm.parse::<ChunkCompression>().map_err(|err| format!("{}", err))?),
+            None => None
         };
         let meta = ImageMeta::new(image_name.clone(), size, self.chunkscount, self.checksum.clone());
-        transfers.active.insert(image_name, ImageWriter::new(meta, dir));
+        let mut writer = ImageWriter::new(meta, dir);
+        writer.chunks_dir = chunks_dir;
+        writer.compression = compression;
+        transfers.active.insert(image_name, writer);
 
         let chunk = ChunkReceived {
             device:    remote.device_id.clone(),
@@ -77,8 +101,12 @@ impl Parameter for Chunk {
 
         let writer = transfers.active.get_mut(&format!("{}", self.update_id))
             .ok_or_else(|| format!("couldn't find transfer for update_id {}", self.update_id))?;
-        let chunk = base64::decode(&self.bytes)
+        let mut chunk = base64::decode(&self.bytes)
             .map_err(|err| format!("couldn't decode chunk for index {}: {}", self.index, err))?;
+        if let Some(compression) = writer.compression {
+            chunk = compression.decompress(&chunk)
+                .map_err(|err| format!("couldn't decompress chunk for index {}: {}", self.index, err))?;
+        }
         writer.write_chunk(&chunk, self.index)
             .map_err(|err| format!("couldn't write chunk: {}", err))
             .and_then(|_| {
@@ -146,3 +174,62 @@ impl Parameter for Abort {
         Ok(None)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    use std::time::Duration;
+
+    use pacman::test::TestDir;
+
+
+    fn start(update_id: Uuid) -> Start {
+        Start { update_id: update_id, chunkscount: 1, checksum: "".into(), compression: None }
+    }
+
+    #[test]
+    fn test_max_active_transfers() {
+        let first  = "00000000-0000-0000-0000-000000000001".parse::<Uuid>().unwrap();
+        let second = "00000000-0000-0000-0000-000000000002".parse::<Uuid>().unwrap();
+        let remote = Mutex::new(RemoteServices::new("device".into(), "http://localhost:8901".parse().unwrap()));
+        let mut transfers = Transfers::new("/tmp".into(), Duration::from_secs(60), 0, 1);
+        transfers.image_sizes.insert(format!("{}", first), 10);
+        transfers.image_sizes.insert(format!("{}", second), 10);
+        let transfers = Mutex::new(transfers);
+
+        // no backend is set so the ack fails after the transfer is added
+        let _ = start(first).handle(&remote, &transfers);
+        assert_eq!(transfers.lock().unwrap().active.len(), 1);
+        let err = start(second).handle(&remote, &transfers).unwrap_err();
+        assert!(err.contains(TRANSFERS_FULL));
+        assert_eq!(transfers.lock().unwrap().active.len(), 1);
+    }
+
+    #[test]
+    fn test_compressed_chunk() {
+        let dir = TestDir::new("sota-compressed-chunk");
+        let update_id = "00000000-0000-0000-0000-000000000003".parse::<Uuid>().unwrap();
+        let remote = Mutex::new(RemoteServices::new("device".into(), "http://localhost:8901".parse().unwrap()));
+        let mut transfers = Transfers::new(dir.0.clone(), Duration::from_secs(60), 0, 1);
+        transfers.chunks_dir = format!("{}/chunks", dir.0);
+        transfers.image_sizes.insert(format!("{}", update_id), 11);
+        let transfers = Mutex::new(transfers);
+
+        let mut start = start(update_id);
+        start.compression = Some("zstd".into());
+        assert!(start.handle(&remote, &transfers).unwrap_err().contains("unsupported"));
+        start.compression = Some("gzip".into());
+        let _ = start.handle(&remote, &transfers);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(b"hello world").unwrap();
+        let chunk = Chunk { update_id: update_id, bytes: base64::encode(&encoder.finish().unwrap()), index: 0 };
+        let _ = chunk.handle(&remote, &transfers);
+        let written = Util::read_file(&format!("{}/chunks/{}/0", dir.0, update_id)).unwrap();
+        assert_eq!(written, b"hello world".to_vec());
+    }
+}
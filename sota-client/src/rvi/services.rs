@@ -1,17 +1,24 @@
 use chan;
 use chan::Sender;
+use chrono::{DateTime, Utc};
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use hex::{FromHex, ToHex};
 use json;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use time;
 use uuid::Uuid;
 
-use datatype::{Event, InstallReport, InstalledSoftware, RviConfig, Url};
+use datatype::{CanonicalJson, Clock, DeviceConfig, Event, InstallReport, InstalledSoftware, RviConfig,
+               SystemClock, Url};
+use http::{AuthClient, Client};
 use images::Transfers;
 use rvi::json_rpc::{ChunkReceived, DownloadStarted, RpcErr, RpcOk, RpcRequest};
-use rvi::parameters::{Abort, Chunk, Finish, Notify, Parameter, Report, Start};
+use rvi::parameters::{Abort, Chunk, Finish, Notify, Parameter, Report, Start, TRANSFERS_FULL};
 
 
 /// Hold references to RVI service endpoints, currently active image transfers,
@@ -21,13 +28,21 @@ pub struct Services {
     pub remote: Arc<Mutex<RemoteServices>>,
     pub sender: Arc<Mutex<Sender<Event>>>,
     pub transfers: Arc<Mutex<Transfers>>,
+    pub hmac_secret: Option<String>,
+    pub nonces: Arc<Mutex<HashMap<String, i64>>>,
 }
 
 impl Services {
-    /// Set up a new RVI service handler.
-    pub fn new(rvi_cfg: RviConfig, device_id: String, sender: Sender<Event>) -> Self {
+    /// Set up a new RVI service handler, writing images to the download
+    /// directory for the device's package manager when one is configured.
+    pub fn new(rvi_cfg: RviConfig, device: &DeviceConfig, sender: Sender<Event>) -> Self {
         let timeout = Duration::from_secs(rvi_cfg.timeout.unwrap_or(300));
-        let transfers = Arc::new(Mutex::new(Transfers::new(rvi_cfg.storage_dir, timeout)));
+        let images_dir = device.download_dir(&device.package_manager).map(String::from).unwrap_or(rvi_cfg.storage_dir);
+        let mut transfers = Transfers::new(images_dir, timeout, device.disk_headroom, rvi_cfg.max_active_transfers);
+        if let Some(ref dir) = device.chunks_dir {
+            transfers.chunks_dir = dir.clone();
+        }
+        let transfers = Arc::new(Mutex::new(transfers));
         let prune = transfers.clone();
         thread::spawn(move || {
             let tick = chan::tick(Duration::from_secs(10));
@@ -39,9 +54,11 @@ impl Services {
         });
 
         Services {
-            remote: Arc::new(Mutex::new(RemoteServices::new(device_id, rvi_cfg.client))),
+            remote: Arc::new(Mutex::new(RemoteServices::new(format!("{}", device.uuid), rvi_cfg.client))),
             sender: Arc::new(Mutex::new(sender)),
             transfers: transfers,
+            hmac_secret: rvi_cfg.hmac_secret,
+            nonces: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -64,7 +81,13 @@ impl Services {
     pub fn handle_service(&self, service: &str, id: u64, msg: &str) -> Result<RpcOk<i32>, RpcErr> {
         match service {
             "/sota/notify"      => self.handle_message::<Notify>(id, msg),
-            "/sota/start"       => self.handle_message::<Start>(id, msg),
+            "/sota/start"       => self.handle_message::<Start>(id, msg).map_err(|err| {
+                if err.error.data.contains(TRANSFERS_FULL) {
+                    let max = self.transfers.lock().unwrap().max_active;
+                    self.sender.lock().unwrap().send(Event::TransfersThrottled(max));
+                }
+                err
+            }),
             "/sota/chunk"       => self.handle_message::<Chunk>(id, msg),
             "/sota/finish"      => self.handle_message::<Finish>(id, msg),
             "/sota/getpackages" => self.handle_message::<Report>(id, msg),
@@ -75,10 +98,16 @@ impl Services {
 
     /// Parse the message as an `RpcRequest<RviMessage<Parameter>>` then delegate
     /// to the specific `Parameter.handle()` function, forwarding any returned
-    /// `Event` to the `Services` sender.
+    /// `Event` to the `Services` sender. When an HMAC secret is set, messages
+    /// without a valid signature are rejected, as are expired or replayed ones.
     fn handle_message<'de, P>(&self, id: u64, msg: &'de str) -> Result<RpcOk<i32>, RpcErr>
         where P: Parameter + Serialize + Deserialize<'de>
     {
+        if let Some(ref secret) = self.hmac_secret {
+            let now = self.remote.lock().unwrap().clock.now();
+            let mut nonces = self.nonces.lock().unwrap();
+            verify_signature(secret, msg, now, &mut nonces).map_err(|err| RpcErr::unauthorized(id, err))?;
+        }
         let request = json::from_str::<RpcRequest<RviMessage<P>>>(msg)
             .map_err(|err| RpcErr::invalid_params(id, format!("couldn't decode message: {}", err)))?;
         let event = request.params.parameters[0].handle(&self.remote, &self.transfers)
@@ -94,15 +123,24 @@ pub struct RemoteServices {
     pub rvi_client: Url,
     pub local:      Option<LocalServices>,
     pub backend:    Option<BackendServices>,
+    pub clock:      Arc<Clock>,
+    pub http:       Box<Client>,
 }
 
 impl RemoteServices {
     pub fn new(device_id: String, rvi_client: Url) -> RemoteServices {
-        RemoteServices { device_id: device_id, rvi_client: rvi_client, local: None, backend: None }
+        RemoteServices {
+            device_id:  device_id,
+            rvi_client: rvi_client,
+            local:      None,
+            backend:    None,
+            clock:      Arc::new(SystemClock),
+            http:       Box::new(AuthClient::default()),
+        }
     }
 
     fn send_message<S: Serialize>(&self, body: S, addr: &str) -> Result<String, String> {
-        RpcRequest::new("message", RviMessage::new(addr, vec![body], 60)).send(self.rvi_client.clone())
+        RpcRequest::new("message", RviMessage::new(addr, vec![body], 60, self.clock.now())).send_with(&*self.http, self.rvi_client.clone())
     }
 
     pub fn send_download_started(&self, update_id: Uuid) -> Result<String, String> {
@@ -117,10 +155,26 @@ impl RemoteServices {
         self.send_message(chunk, &backend.ack)
     }
 
-    pub fn send_update_report(&self, report: InstallReport) -> Result<String, String> {
+    /// Send the installation report to the backend. A report with results
+    /// from several ECUs is split so that each ECU's result is sent in its own
+    /// report, returning the response to each.
+    pub fn send_update_report(&self, report: InstallReport) -> Result<Vec<String>, String> {
         let backend = self.backend.as_ref().ok_or("BackendServices not set")?;
-        let result  = UpdateReportResult { device: self.device_id.clone(), update_report: report };
-        self.send_message(result, &backend.report)
+        let reports = if report.operation_results.len() > 1 {
+            let update_id = report.update_id;
+            report.operation_results
+                .into_iter()
+                .map(|result| InstallReport::new(update_id.clone(), vec![result]))
+                .collect()
+        } else {
+            vec![report]
+        };
+        reports.into_iter()
+            .map(|report| {
+                let result = UpdateReportResult { device: self.device_id.clone(), update_report: report };
+                self.send_message(result, &backend.report)
+            })
+            .collect()
     }
 
     pub fn send_installed_software(&self, installed: InstalledSoftware) -> Result<String, String> {
@@ -166,15 +220,179 @@ struct InstalledSoftwareResult {
 pub struct RviMessage<S: Serialize> {
     pub service_name: String,
     pub parameters:   Vec<S>,
-    pub timeout:      Option<i64>
+    pub timeout:      Option<i64>,
+    /// A unique value so that a signed message can't be replayed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce:        Option<String>,
+    /// Hex-encoded HMAC-SHA256 of the canonical JSON message fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature:    Option<String>,
 }
 
 impl<S: Serialize> RviMessage<S> {
-    pub fn new(service: &str, parameters: Vec<S>, expire_in: i64) -> RviMessage<S> {
+    /// Create a new message that expires `expire_in` seconds after `now`.
+    pub fn new(service: &str, parameters: Vec<S>, expire_in: i64, now: DateTime<Utc>) -> RviMessage<S> {
         RviMessage {
             service_name: service.to_string(),
             parameters:   parameters,
-            timeout:      Some((time::get_time() + time::Duration::seconds(expire_in)).sec)
+            timeout:      Some(now.timestamp() + expire_in),
+            nonce:        Some(format!("{}", Uuid::new_v4())),
+            signature:    None,
+        }
+    }
+}
+
+
+/// The longest time a signed message may be valid for, bounding how long
+/// each nonce must be remembered.
+const MAX_MESSAGE_SECS: i64 = 3600;
+
+/// The message fields covered by the signature.
+#[derive(Serialize)]
+struct SignedFields<'m> {
+    service_name: &'m str,
+    parameters:   &'m [json::Value],
+    timeout:      i64,
+    nonce:        &'m str,
+}
+
+/// Returns the hex-encoded HMAC-SHA256 of the canonical JSON service name,
+/// parameters, timeout and nonce of the message.
+pub fn sign_message(secret: &str, msg: &RviMessage<json::Value>) -> Result<String, String> {
+    let fields = SignedFields {
+        service_name: &msg.service_name,
+        parameters:   &msg.parameters,
+        timeout:      msg.timeout.ok_or_else(|| "message has no timeout".to_string())?,
+        nonce:        msg.nonce.as_ref().ok_or_else(|| "message has no nonce".to_string())?,
+    };
+    let value = json::to_value(fields).map_err(|err| format!("couldn't encode message: {}", err))?;
+    let data = CanonicalJson::convert(value).map_err(|err| format!("couldn't canonicalize message: {}", err))?;
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(&data);
+    Ok(mac.result().code().to_hex())
+}
+
+/// Verify the signature of an incoming `RpcRequest<RviMessage<_>>` message,
+/// rejecting it when expired or when its nonce was already seen. Nonces are
+/// remembered until their message expires.
+fn verify_signature(secret: &str, msg: &str, now: DateTime<Utc>, nonces: &mut HashMap<String, i64>) -> Result<(), String> {
+    nonces.retain(|_, expiry| *expiry > now.timestamp());
+    let request = json::from_str::<RpcRequest<RviMessage<json::Value>>>(msg)
+        .map_err(|err| format!("couldn't decode message: {}", err))?;
+    let signature = request.params.signature.as_ref().ok_or_else(|| "message not signed".to_string())?;
+    let received = Vec::from_hex(signature).map_err(|err| format!("couldn't decode signature: {}", err))?;
+    let expected = Vec::from_hex(sign_message(secret, &request.params)?).expect("hex signature");
+    if MacResult::new(&received) != MacResult::new(&expected) {
+        return Err("invalid signature".into());
+    }
+
+    let timeout = request.params.timeout.expect("signed timeout");
+    if timeout <= now.timestamp() {
+        return Err("message expired".into());
+    } else if timeout > now.timestamp() + MAX_MESSAGE_SECS {
+        return Err(format!("message timeout more than {}s away", MAX_MESSAGE_SECS));
+    }
+    let nonce = request.params.nonce.clone().expect("signed nonce");
+    if nonces.contains_key(&nonce) {
+        return Err("message replayed".into());
+    }
+    nonces.insert(nonce, timeout);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono;
+
+    use hyper::status::StatusCode;
+    use std::collections::HashMap;
+
+    use datatype::{FakeClock, InstallCode, InstallResult};
+    use http::{Request, Response, ResponseData};
+
+
+    /// Records the body of each request sent to RVI.
+    struct FakeRvi(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Client for FakeRvi {
+        fn chan_request(&self, req: Request, resp_tx: Sender<Response>) {
+            self.0.lock().unwrap().push(req.body.unwrap_or_default());
+            let body = br#"{"jsonrpc": "2.0", "id": 1, "result": null}"#.to_vec();
+            resp_tx.send(Response::Success(ResponseData { code: StatusCode::Ok, url: req.url.to_string(), body: body, headers: HashMap::new() }));
         }
     }
+
+
+    fn message(now: DateTime<Utc>, secret: Option<&str>) -> String {
+        let mut msg = RviMessage::new("/sota/notify", vec![json::Value::String("param".into())], 60, now);
+        msg.signature = secret.map(|secret| sign_message(secret, &msg).unwrap());
+        json::to_string(&RpcRequest::new("message", msg)).unwrap()
+    }
+
+    #[test]
+    fn test_message_timeout() {
+        let clock = FakeClock::new("2020-01-01T00:00:00Z".parse().unwrap());
+        let msg = RviMessage::new("/sota/notify", vec![0], 60, clock.now());
+        assert_eq!(msg.timeout, Some(1577836860));
+        clock.advance(chrono::Duration::seconds(60));
+        assert_eq!(RviMessage::new("/sota/notify", vec![0], 60, clock.now()).timeout, Some(1577836920));
+    }
+
+    #[test]
+    fn test_per_ecu_reports() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut remote = RemoteServices::new("device".into(), "http://localhost:8901".parse().unwrap());
+        remote.http = Box::new(FakeRvi(sent.clone()));
+        remote.backend = Some(BackendServices { start: "start".into(), ack: "ack".into(), report: "report".into(), packages: "packages".into() });
+        let results = vec![
+            InstallResult::new("primary".into(), InstallCode::OK, "".into()),
+            InstallResult::new("secondary".into(), InstallCode::INSTALL_FAILED, "".into()),
+        ];
+        let responses = remote.send_update_report(InstallReport::new("update".into(), results.clone())).expect("send report");
+        assert_eq!(responses.len(), 2);
+
+        let reports = sent.lock().unwrap().iter().map(|body| {
+            let request = json::from_slice::<RpcRequest<RviMessage<UpdateReportResult>>>(body).expect("report request");
+            request.params.parameters.into_iter().next().expect("report").update_report
+        }).collect::<Vec<_>>();
+        assert_eq!(reports, vec![
+            InstallReport::new("update".into(), vec![results[0].clone()]),
+            InstallReport::new("update".into(), vec![results[1].clone()]),
+        ]);
+
+        let single = InstallReport::new("single".into(), vec![results[0].clone()]);
+        assert_eq!(remote.send_update_report(single).expect("send single report").len(), 1);
+        assert_eq!(sent.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let now = Utc::now();
+        let mut nonces = HashMap::new();
+        assert!(verify_signature("secret", &message(now, Some("secret")), now, &mut nonces).is_ok());
+        assert!(verify_signature("other", &message(now, Some("secret")), now, &mut nonces).is_err());
+        assert!(verify_signature("secret", &message(now, None), now, &mut nonces).is_err());
+
+        let mut forged = json::from_str::<RpcRequest<RviMessage<json::Value>>>(&message(now, Some("secret"))).unwrap();
+        forged.params.service_name = "/sota/abort".into();
+        assert!(verify_signature("secret", &json::to_string(&forged).unwrap(), now, &mut nonces).is_err());
+        forged.params.signature = Some("00ff".into());
+        assert!(verify_signature("secret", &json::to_string(&forged).unwrap(), now, &mut nonces).is_err());
+    }
+
+    #[test]
+    fn test_reject_expired_and_replayed() {
+        let clock = FakeClock::new("2020-01-01T00:00:00Z".parse().unwrap());
+        let mut nonces = HashMap::new();
+        let msg = message(clock.now(), Some("secret"));
+        assert!(verify_signature("secret", &msg, clock.now(), &mut nonces).is_ok());
+        assert_eq!(verify_signature("secret", &msg, clock.now(), &mut nonces), Err("message replayed".into()));
+
+        let later = message(clock.now(), Some("secret"));
+        clock.advance(chrono::Duration::seconds(60));
+        assert_eq!(verify_signature("secret", &later, clock.now(), &mut nonces), Err("message expired".into()));
+        assert!(nonces.is_empty());
+    }
 }
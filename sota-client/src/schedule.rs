@@ -0,0 +1,199 @@
+use chan::Sender;
+use chrono::{DateTime, Utc};
+use json;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use datatype::{Clock, Command, Error, Util};
+use interpreter::CommandExec;
+
+
+/// An update to be installed at a future time.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ScheduledInstall {
+    pub update_id: Uuid,
+    pub at:        DateTime<Utc>,
+}
+
+
+/// Installs waiting for their scheduled time, saved to `path` when set so
+/// that they survive a restart, and downloads deferred by the bandwidth rules
+/// until a download may start again. Clones share the same schedule.
+///
+/// A due install stays scheduled until the `CommandInterpreter` starts it, so
+/// that an install deferred while paused or interrupted by a restart is not
+/// lost.
+#[derive(Clone, Debug, Default)]
+pub struct InstallSchedule {
+    path:      Option<String>,
+    pending:   Arc<Mutex<Vec<ScheduledInstall>>>,
+    started:   Arc<Mutex<HashSet<Uuid>>>,
+    downloads: Arc<Mutex<Vec<ScheduledInstall>>>,
+}
+
+impl InstallSchedule {
+    /// Load the schedule saved at `path`, starting empty when there isn't one.
+    pub fn load(path: Option<String>) -> Result<Self, Error> {
+        let pending = match path {
+            Some(ref path) if Path::new(path).exists() => json::from_slice(&Util::read_file(path)?)?,
+            _ => Vec::new()
+        };
+        Ok(InstallSchedule {
+            path:      path,
+            pending:   Arc::new(Mutex::new(pending)),
+            started:   Arc::default(),
+            downloads: Arc::default(),
+        })
+    }
+
+    /// Schedule an update to be installed at this time, replacing any earlier
+    /// schedule for the same update.
+    pub fn add(&self, update_id: Uuid, at: DateTime<Utc>) -> Result<(), Error> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|install| install.update_id != update_id);
+        self.started.lock().unwrap().remove(&update_id);
+        pending.push(ScheduledInstall { update_id: update_id, at: at });
+        pending.sort_by_key(|install| install.at);
+        self.save(&pending)
+    }
+
+    /// Remove any schedule for an update, returning true if it was scheduled.
+    pub fn remove(&self, update_id: &Uuid) -> Result<bool, Error> {
+        let mut pending = self.pending.lock().unwrap();
+        let scheduled = pending.len();
+        pending.retain(|install| install.update_id != *update_id);
+        self.started.lock().unwrap().remove(update_id);
+        if pending.len() == scheduled {
+            return Ok(false);
+        }
+        self.save(&pending).map(|_| true)
+    }
+
+    /// Return the updates due to be installed by `now` that weren't already
+    /// returned. They stay scheduled until removed when the install starts.
+    pub fn take_due(&self, now: DateTime<Utc>) -> Vec<Uuid> {
+        let pending = self.pending.lock().unwrap();
+        let mut started = self.started.lock().unwrap();
+        pending.iter()
+            .filter(|install| install.at <= now && started.insert(install.update_id))
+            .map(|install| install.update_id)
+            .collect()
+    }
+
+    /// Retry a deferred download at this time, replacing any earlier retry.
+    pub fn defer_download(&self, update_id: Uuid, at: DateTime<Utc>) {
+        let mut downloads = self.downloads.lock().unwrap();
+        downloads.retain(|download| download.update_id != update_id);
+        downloads.push(ScheduledInstall { update_id: update_id, at: at });
+    }
+
+    /// Remove and return the deferred downloads due to be retried by `now`.
+    pub fn take_due_downloads(&self, now: DateTime<Utc>) -> Vec<Uuid> {
+        let mut downloads = self.downloads.lock().unwrap();
+        let (due, later): (Vec<_>, Vec<_>) = downloads.drain(..).partition(|download| download.at <= now);
+        *downloads = later;
+        due.into_iter().map(|download| download.update_id).collect()
+    }
+
+    /// Returns the installs waiting for their scheduled time, earliest first.
+    pub fn pending(&self) -> Vec<ScheduledInstall> {
+        self.pending.lock().unwrap().clone()
+    }
+
+    fn save(&self, pending: &[ScheduledInstall]) -> Result<(), Error> {
+        match self.path {
+            Some(ref path) => Util::write_file(path, &json::to_vec(pending)?),
+            None => Ok(())
+        }
+    }
+}
+
+
+/// Check the schedule every `interval`, starting each install once its
+/// scheduled time has passed and retrying each deferred download once due.
+pub fn start_install_scheduler(schedule: InstallSchedule, clock: Arc<Clock>, interval: Duration, ctx: &Sender<CommandExec>) {
+    info!("Checking for scheduled installs every {} seconds.", interval.as_secs());
+    loop {
+        let now = clock.now();
+        for id in schedule.take_due(now) {
+            info!("Starting the scheduled install of {}", id);
+            ctx.send(CommandExec { cmd: Command::StartInstall(id), etx: None });
+        }
+        for id in schedule.take_due_downloads(now) {
+            info!("Retrying the deferred download of {}", id);
+            ctx.send(CommandExec { cmd: Command::StartDownload(id), etx: None });
+        }
+        thread::sleep(interval);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chan;
+    use chrono;
+
+    use datatype::{FakeClock, SystemClock};
+    use pacman::test::TestDir;
+
+
+    #[test]
+    fn test_schedule_persisted() {
+        let dir = TestDir::new("sota-install-schedule");
+        let path = format!("{}/schedule.json", dir.0);
+        let now = Utc::now();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let schedule = InstallSchedule::load(Some(path.clone())).expect("empty schedule");
+        schedule.add(second, now + chrono::Duration::hours(2)).expect("add second");
+        schedule.add(first, now + chrono::Duration::hours(1)).expect("add first");
+        let restarted = InstallSchedule::load(Some(path.clone())).expect("load schedule");
+        assert_eq!(restarted.pending(), schedule.pending());
+        assert_eq!(restarted.pending()[0].update_id, first);
+
+        assert!(restarted.take_due(now).is_empty());
+        assert_eq!(restarted.take_due(now + chrono::Duration::minutes(90)), vec![first]);
+        assert!(restarted.take_due(now + chrono::Duration::minutes(90)).is_empty());
+        let interrupted = InstallSchedule::load(Some(path.clone())).expect("reload schedule");
+        assert_eq!(interrupted.take_due(now + chrono::Duration::minutes(90)), vec![first]);
+
+        assert_eq!(restarted.remove(&first).expect("remove first"), true);
+        let pending = InstallSchedule::load(Some(path)).expect("reload schedule").pending();
+        assert_eq!(pending, vec![ScheduledInstall { update_id: second, at: now + chrono::Duration::hours(2) }]);
+    }
+
+    #[test]
+    fn test_scheduler_starts_install() {
+        let (ctx, crx) = chan::async::<CommandExec>();
+        let id = Uuid::new_v4();
+        let at = Utc::now() + chrono::Duration::milliseconds(200);
+        let schedule = InstallSchedule::default();
+        schedule.add(id, at).expect("add install");
+
+        let shared = schedule.clone();
+        thread::spawn(move || start_install_scheduler(shared, Arc::new(SystemClock), Duration::from_millis(50), &ctx));
+        assert_eq!(crx.recv().map(|exec| exec.cmd), Some(Command::StartInstall(id)));
+        assert_eq!(schedule.pending(), vec![ScheduledInstall { update_id: id, at: at }]);
+    }
+
+    #[test]
+    fn test_scheduler_retries_download() {
+        let (ctx, crx) = chan::async::<CommandExec>();
+        let clock = FakeClock::new(Utc::now());
+        let id = Uuid::new_v4();
+        let schedule = InstallSchedule::default();
+        schedule.defer_download(id, clock.now() + chrono::Duration::hours(1));
+        assert!(schedule.take_due_downloads(clock.now()).is_empty());
+
+        let (shared, now) = (schedule.clone(), Arc::new(clock.clone()));
+        thread::spawn(move || start_install_scheduler(shared, now, Duration::from_millis(10), &ctx));
+        clock.advance(chrono::Duration::hours(1));
+        assert_eq!(crx.recv().map(|exec| exec.cmd), Some(Command::StartDownload(id)));
+        assert!(schedule.take_due_downloads(clock.now()).is_empty());
+    }
+}
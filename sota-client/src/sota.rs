@@ -1,106 +1,575 @@
+use chan::{self, Receiver};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use hyper::status::StatusCode;
 use json;
-use std::fs::{self, File};
-use std::io;
+use serde::Serialize;
+use std::cell::Cell;
+use std::cmp;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Command as ShellCommand, Stdio};
+use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
-use datatype::{Config, DownloadComplete, Error, Package, InstallReport, InstallResult,
-               UpdateRequest, Url};
-use http::{Client, Response};
-use pacman::Credentials;
+use bandwidth::DownloadUsage;
+use datatype::{AbortedUpdates, Clock, Config, DeviceConfig, DownloadComplete, Error, InstallCode, InstallReport,
+               InstallResult, Method, Package, PackageDiff, SystemClock, SystemInfo, UpdateRequest, Url, Util};
+use http::{Client, Request, Response, ResponseData};
+use images::{checksum_path, remove_checksum};
+use install_marker::InstallMarker;
+use metrics::Metrics;
+use pacman::{Credentials, PacMan, run_with_timeout};
 
 
 /// Encapsulate the client configuration and HTTP client used for
 /// software-over-the-air updates.
 pub struct Sota<'c, 'h> {
-    config: &'c Config,
-    client: &'h Client,
+    config:     &'c Config,
+    client:     &'h Client,
+    rate_limit: Option<u64>,
+    clock:      Arc<Clock>,
+    aborted:    AbortedUpdates,
 }
 
 impl<'c, 'h> Sota<'c, 'h> {
     /// Creates a new instance for Sota communication.
     pub fn new(config: &'c Config, client: &'h Client) -> Sota<'c, 'h> {
-        Sota { config, client }
+        Sota { config, client, rate_limit: None, clock: Arc::new(SystemClock), aborted: AbortedUpdates::default() }
     }
 
-    /// When using cert authentication returns an endpoint of: `<tls-server>/core/<path>`
-    /// otherwise returns an endpoint of: `<core-server>/api/v1/mydevice/<device-id>/<path>`.
-    fn endpoint(&self, path: &str) -> Url {
+    /// Read downloads at no more than this many bytes per second.
+    pub fn rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.rate_limit = bytes_per_sec;
+        self
+    }
+
+    /// Record download usage at the time read from this clock.
+    pub fn clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Stop downloads of updates flagged as canceled by the server.
+    pub fn aborted(mut self, aborted: AbortedUpdates) -> Self {
+        self.aborted = aborted;
+        self
+    }
+
+    /// Send a request to an endpoint and wait for the response. The `send`
+    /// function is passed a GET request to the endpoint, limited to
+    /// `network.max_response_bytes`, to adapt and send.
+    ///
+    /// When using cert authentication the endpoint is: `<tls-server>/core/<path>`
+    /// otherwise it is: `<core-server>/api/v1/mydevice/<device-id>/<path>`, with
+    /// each core server tried in turn until one responds.
+    fn request<F>(&self, path: &str, send: F) -> Result<ResponseData, Error>
+        where F: Fn(Request) -> Receiver<Response>
+    {
+        let max_bytes = self.config.network.max_response_bytes;
+        let recv = |url: Url| {
+            let req = Request::new(Method::Get, url.clone(), None).max_bytes(max_bytes);
+            match send(req).recv().expect(&format!("no response from {}", url)) {
+                Response::Success(data) => Ok(data),
+                Response::Failed(data)  => Err(data.into()),
+                Response::Error(err)    => Err(*err)
+            }
+        };
         if let Some(ref tls) = self.config.tls {
-            tls.server.join(&format!("/core/{}", path))
+            recv(tls.server.join(&format!("/core/{}", path)))
         } else {
-            self.config.core.server.join(&format!("/api/v1/mydevice/{}/{}", self.config.device.uuid, path))
+            let path = format!("/api/v1/mydevice/{}/{}", self.config.device.uuid, path);
+            self.config.core.server.try_each(|server| recv(server.join(&path)))
         }
     }
 
     /// Check for any new package updates.
     pub fn get_update_requests(&mut self) -> Result<Vec<UpdateRequest>, Error> {
-        let rx = self.client.get(self.endpoint("updates"), None);
-        match rx.recv().expect("couldn't get update requests") {
-            Response::Success(data) => Ok(json::from_slice::<Vec<UpdateRequest>>(&data.body)?),
-            Response::Failed(data)  => Err(data.into()),
-            Response::Error(err)    => Err(*err)
+        self.poll_update_requests().map(|(updates, _)| updates)
+    }
+
+    /// Check for any new package updates, also returning the size of the
+    /// response body in bytes.
+    pub fn poll_update_requests(&mut self) -> Result<(Vec<UpdateRequest>, u64), Error> {
+        let data = self.request("updates", |req| self.client.send_request(req))?;
+        Ok((json::from_slice::<Vec<UpdateRequest>>(&data.body)?, data.body.len() as u64))
+    }
+
+    /// Returns the server that requests are sent to, which is the last core
+    /// server to respond unless using cert authentication.
+    pub fn server(&self) -> &'c Url {
+        match self.config.tls {
+            Some(ref tls) => &tls.server,
+            None => self.config.core.server.current()
+        }
+    }
+
+    /// Returns the directory that updates for the current package manager are
+    /// downloaded to.
+    pub fn packages_dir(&self) -> &'c str {
+        let device = &self.config.device;
+        device.download_dir(&device.package_manager).unwrap_or(&device.packages_dir)
+    }
+
+    /// Returns the directory that updates are downloaded to and verified in
+    /// before being moved to the `packages_dir` for installation.
+    pub fn staging_dir(&self) -> &'c str {
+        self.config.device.staging_dir.as_ref().map(String::as_str).unwrap_or(self.packages_dir())
+    }
+
+    /// Returns true if the update has already been downloaded.
+    pub fn is_downloaded(&self, update_id: &Uuid) -> bool {
+        Path::new(&format!("{}/{}", self.staging_dir(), update_id)).exists()
+            || Path::new(&format!("{}/{}", self.packages_dir(), update_id)).exists()
+    }
+
+    /// Returns the directory that partial downloads are written to.
+    pub fn partial_dir(&self) -> &'c str {
+        self.config.device.chunks_dir.as_ref().map(String::as_str).unwrap_or(self.staging_dir())
+    }
+
+    /// Remove any partial, staged or installable files of an update, returning
+    /// the number of files removed.
+    pub fn remove_update(&self, update_id: &Uuid) -> Result<usize, Error> {
+        let paths = [
+            format!("{}/{}.part", self.partial_dir(), update_id),
+            format!("{}/{}", self.staging_dir(), update_id),
+            format!("{}/{}", self.packages_dir(), update_id),
+            checksum_path(&format!("{}/{}", self.packages_dir(), update_id)),
+        ];
+        let mut removed = 0;
+        for path in paths.iter().filter(|path| Path::new(path).exists()) {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Returns the artifact of an update left by an earlier install when the
+    /// checksum saved after verifying it matches the expected one, so it
+    /// doesn't need to be downloaded again.
+    pub fn verified_download(&self, update_id: &Uuid, checksum: &str) -> Option<DownloadComplete> {
+        let path = format!("{}/{}", self.packages_dir(), update_id);
+        match Util::read_text(&checksum_path(&path)) {
+            Ok(ref saved) if Path::new(&path).exists() && saved.trim() == checksum.to_lowercase() => {
+                Some(DownloadComplete { update_id: *update_id, update_image: path, signature: "".into() })
+            }
+            _ => None
         }
     }
 
-    /// Download a specific update.
+    /// Download a specific update, resuming from any partially downloaded file.
+    /// The response body is streamed to the partial file, which is kept after
+    /// a short read so that the next attempt resumes from it.
     pub fn download_update(&mut self, update_id: Uuid) -> Result<DownloadComplete, Error> {
-        let rx = self.client.get(self.endpoint(&format!("updates/{}/download", update_id)), None);
-        let data = match rx.recv().expect("couldn't download update") {
-            Response::Success(data) => Ok(data),
-            Response::Failed(data)  => Err(data.into()),
-            Response::Error(err)    => Err(*err)
-        }?;
-
-        let update_image = format!("{}/{}", self.config.device.packages_dir, update_id);
-        let mut file = File::create(&update_image)
-            .map_err(|err| Error::Client(format!("couldn't create path {}: {}", update_image, err)))?;
-        let _ = io::copy(&mut &*data.body, &mut file)?;
+        let config = self.config;
+        let update_image = format!("{}/{}", self.staging_dir(), update_id);
+        let partial_dir = self.partial_dir();
+        let partial = format!("{}/{}.part", partial_dir, update_id);
+        let partial_len = || fs::metadata(&partial).map(|meta| meta.len()).unwrap_or(0);
+
+        let max_bytes = config.network.max_target_bytes;
+        let canceled = self.aborted.flag(update_id);
+        let offset = Cell::new(0);
+        let result = self.request(&format!("updates/{}/download", update_id), |req| {
+            offset.set(partial_len());
+            let req = req.max_bytes(max_bytes)
+                .range(offset.get())
+                .rate_limit(self.rate_limit)
+                .sink(partial.clone())
+                .canceled(canceled.clone());
+            self.client.send_request(req)
+        });
+        if self.aborted.is_canceled(&update_id) {
+            info!("download of {} was canceled by the server", update_id);
+            let _ = fs::remove_file(&partial);
+            return Err(Error::Aborted(format!("download of {}", update_id)));
+        }
+        let data = result?;
+        if let Err(err) = self.check_content_type(&update_id, &data) {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+
+        let (offset, size) = (offset.get(), partial_len());
+        let resumed = data.code == StatusCode::PartialContent && offset > 0;
+        if resumed {
+            match data.content_range() {
+                Some((start, _, _)) if start == offset => debug!("resumed download of {} from byte {}", update_id, offset),
+                _ => {
+                    info!("partial download of {} doesn't match server, restarting", update_id);
+                    fs::remove_file(&partial)?;
+                    return self.download_update(update_id);
+                }
+            }
+        }
+        let written = if resumed { size - offset } else { size };
+        let expected = match (resumed, data.content_range()) {
+            (true, Some((_, _, total))) => Some(total.saturating_sub(offset)),
+            _ => data.content_length()
+        };
+        match expected {
+            Some(len) if len != written => {
+                error!("download of {} expected {} bytes, got {}", update_id, len, written);
+                return Err(Error::Client("short read".into()));
+            }
+            _ => ()
+        }
+        if let Err(err) = Util::check_space(partial_dir, 0, config.device.disk_headroom) {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+        Metrics::update(|metrics| metrics.download_bytes += written);
+        if let Some(ref path) = config.device.download_usage_file {
+            if let Err(err) = DownloadUsage::add(path, written, self.clock.now()) {
+                error!("couldn't record download usage in {}: {}", path, err);
+            }
+        }
+        move_file(&partial, &update_image)?;
         let signature = "".into();
         Ok(DownloadComplete { update_id, update_image, signature })
     }
 
-    /// Install an update using the current package manager.
-    pub fn install_update(&mut self, update_id: &Uuid, creds: &Credentials) -> Result<InstallResult, Error> {
-        let path = format!("{}/{}", self.config.device.packages_dir, update_id);
-        self.config.device
+    /// Fails when `device.check_content_type` is set and the download isn't
+    /// one of the expected types. Responses without a `Content-Type` or sent
+    /// as `application/octet-stream` are accepted.
+    fn check_content_type(&self, update_id: &Uuid, data: &ResponseData) -> Result<(), Error> {
+        let device = &self.config.device;
+        if ! device.check_content_type {
+            return Ok(());
+        }
+        let received = match data.content_type() {
+            Some(received) => received,
+            None => {
+                debug!("download of {} has no content type", update_id);
+                return Ok(());
+            }
+        };
+        let expected = match device.expected_content_type {
+            Some(ref types) => types.split(',').map(|ct| ct.trim().to_lowercase()).filter(|ct| !ct.is_empty()).collect(),
+            None => device.package_manager.content_types().iter().map(|ct| ct.to_string()).collect::<Vec<_>>()
+        };
+        if expected.is_empty() || received == "application/octet-stream" || expected.contains(&received) {
+            Ok(())
+        } else {
+            error!("refusing download of {} with content type {}", update_id, received);
+            Err(Error::Client(format!("unexpected content type {}, expected one of: {}", received, expected.join(", "))))
+        }
+    }
+
+    /// Returns the path to an update's downloaded package, preferring the
+    /// staged download over one already moved to the `packages_dir`.
+    pub fn package_path(&self, update_id: &Uuid) -> String {
+        let staged = format!("{}/{}", self.staging_dir(), update_id);
+        if Path::new(&staged).exists() {
+            staged
+        } else {
+            format!("{}/{}", self.packages_dir(), update_id)
+        }
+    }
+
+    /// Verify an update's downloaded package against its RSASSA-PSS signature
+    /// using the `device.signature_key`. A missing or empty signature is an
+    /// error, as SOTA server downloads are otherwise sent unsigned.
+    pub fn verify_signature(&self, update_id: &Uuid, signature: Option<&str>) -> Result<(), Error> {
+        let signature = match signature {
+            Some(sig) if ! sig.is_empty() => sig,
+            _ => return Err(Error::Image(format!("update {} has no signature to verify", update_id)))
+        };
+        let key = match self.config.device.signature_key {
+            Some(ref path) => Util::read_pem(path)?,
+            None => return Err(Error::Config("device.signature_key needed to verify signatures".into()))
+        };
+        let download = DownloadComplete {
+            update_id:    *update_id,
+            update_image: self.package_path(update_id),
+            signature:    signature.into()
+        };
+        download.verify_signature(&key)
+    }
+
+    /// Install an update using the current package manager, first checking
+    /// the downloaded package against any expected sha256 checksum before
+    /// moving it from the staging directory to the `packages_dir`.
+    pub fn install_update(&mut self, update_id: &Uuid, creds: &Credentials, checksum: Option<&str>) -> Result<InstallResult, Error> {
+        let path = format!("{}/{}", self.packages_dir(), update_id);
+        let source = self.package_path(update_id);
+        if let Some(expected) = checksum {
+            let mut hasher = Sha256::new();
+            hasher.input(&Util::read_file(&source)?);
+            let actual = hasher.result_str();
+            if actual != expected.to_lowercase() {
+                fs::remove_file(&source).unwrap_or_else(|err| error!("couldn't remove package: {}", err));
+                remove_checksum(&source);
+                let text = format!("expected sha256 of `{}`, got `{}`", expected, actual);
+                error!("refusing to install {}: {}", update_id, text);
+                return Ok(InstallResult::new(format!("{}", update_id), InstallCode::GENERAL_ERROR, text));
+            }
+        }
+        if source != path {
+            debug!("moving verified package from {} to {}", source, path);
+            move_file(&source, &path)?;
+        }
+        if let Some(expected) = checksum {
+            Util::write_file(&checksum_path(&path), expected.to_lowercase().as_bytes())?;
+        }
+
+        let marker = self.config.device.install_marker_file.as_ref();
+        if let Some(marker) = marker {
+            InstallMarker::new(*update_id, self.config.device.package_manager.clone(), &path).save(marker)?;
+        }
+
+        let timeout = Duration::from_secs(self.config.device.install_timeout);
+        let installed = self.config.device
             .package_manager
-            .install_package(&path, creds)
-            .and_then(|outcome| {
-                fs::remove_file(&path)
-                    .unwrap_or_else(|err| error!("couldn't remove installed package: {}", err));
-                Ok(outcome.into_result(format!("{}", update_id)))
-            })
+            .install_package(&path, creds, timeout);
+        if let Some(marker) = marker {
+            // an ostree deployment is only checked once its commit has booted
+            let deployed = self.config.device.package_manager == PacMan::Ostree
+                && installed.as_ref().map(|outcome| outcome.is_success()).unwrap_or(false);
+            if ! deployed {
+                InstallMarker::remove(marker);
+            }
+        }
+        installed.and_then(|outcome| {
+            fs::remove_file(&path)
+                .unwrap_or_else(|err| error!("couldn't remove installed package: {}", err));
+            remove_checksum(&path);
+            Ok(outcome.truncate(self.config.device.max_report_output_bytes).into_result(format!("{}", update_id)))
+        })
     }
 
-    /// Send a list of the currently installed packages.
+    /// Send a list of the currently installed packages, serializing each
+    /// package as the request body is streamed.
     pub fn send_installed_packages(&mut self, packages: &[Package]) -> Result<(), Error> {
-        let rx = self.client.put(self.endpoint("installed"), Some(json::to_vec(packages)?));
-        match rx.recv().expect("couldn't send installed packages") {
-            Response::Success(_)   => Ok(()),
-            Response::Failed(data) => Err(data.into()),
-            Response::Error(err)   => Err(*err)
+        self.request("installed", |req| {
+            self.client.send_stream(Request { method: Method::Put, ..req }, &mut JsonArrayReader::new(packages))
+        }).map(|_| ())
+    }
+
+    /// Run `device.post_install_health_cmd` after an update was installed,
+    /// rolling back the update when the check fails. Returns `None` when the
+    /// system is healthy, otherwise the failed result and whether the rollback
+    /// succeeded.
+    pub fn check_health(&self, update_id: &Uuid) -> Option<(InstallResult, bool)> {
+        check_health(&self.config.device, update_id, None)
+    }
+
+    /// Run `device.reboot_cmd` to schedule a reboot of the device.
+    pub fn schedule_reboot(&self) -> Result<(), Error> {
+        let device = &self.config.device;
+        info!("Scheduling a reboot with: {}", device.reboot_cmd);
+        let timeout = Duration::from_secs(device.install_timeout);
+        let output = run_with_timeout(ShellCommand::new("sh").arg("-c").arg(&device.reboot_cmd), timeout)?;
+        if output.code == Some(0) {
+            Ok(())
+        } else {
+            Err(Error::PacMan(format!("reboot command failed: {}", output.stderr.trim())))
         }
     }
 
+    /// Report the installed packages. When `device.package_snapshot` is set
+    /// only the changes since the last report are sent, with the full list
+    /// sent every `device.package_full_every` reports or when the server
+    /// responds with `409 Conflict` to request a resync.
+    pub fn report_installed_packages(&mut self, packages: &[Package]) -> Result<(), Error> {
+        let config = self.config;
+        let path = match config.device.package_snapshot {
+            Some(ref path) => path,
+            None => return self.send_installed_packages(packages)
+        };
+
+        let since_full = match PackageSnapshot::load(path) {
+            Some(ref last) if last.since_full < config.device.package_full_every => {
+                let diff = PackageDiff::new(&last.packages, packages);
+                if diff.is_empty() {
+                    debug!("no changes to the installed packages");
+                    return Ok(());
+                }
+                match self.send_package_diff(&diff) {
+                    Ok(()) => Some(last.since_full + 1),
+                    Err(Error::Http(ref data)) if data.code == StatusCode::Conflict => {
+                        info!("server requested a full list of installed packages");
+                        None
+                    }
+                    Err(err) => return Err(err)
+                }
+            }
+            _ => None
+        };
+
+        let since_full = match since_full {
+            Some(count) => count,
+            None => { self.send_installed_packages(packages)?; 0 }
+        };
+        PackageSnapshot { packages: packages.to_vec(), since_full: since_full }.save(path)
+    }
+
+    /// Send the changes to the installed packages since the last report.
+    pub fn send_package_diff(&mut self, diff: &PackageDiff) -> Result<(), Error> {
+        let body = json::to_vec(diff)?;
+        self.request("installed", |req| {
+            self.client.send_request(Request { method: Method::Patch, body: Some(body.clone()), ..req })
+        }).map(|_| ())
+    }
+
     /// Send the outcome of a package installation.
     pub fn send_install_report(&mut self, report: &InstallReport) -> Result<(), Error> {
-        let url = self.endpoint(&format!("updates/{}", report.update_id));
-        let rx = self.client.post(url, Some(json::to_vec(&report.operation_results)?));
-        match rx.recv().expect("couldn't send update report") {
-            Response::Success(_)   => Ok(()),
-            Response::Failed(data) => Err(data.into()),
-            Response::Error(err)   => Err(*err)
+        let body = json::to_vec(&report.operation_results)?;
+        self.request(&format!("updates/{}", report.update_id), |req| {
+            self.client.send_request(Request { method: Method::Post, body: Some(body.clone()), ..req })
+        }).map(|_| ())
+    }
+
+    /// Send system information from the device. The output of a single
+    /// command is streamed as the request body, while other sources are
+    /// generated in full before sending.
+    pub fn send_system_info(&mut self, info: &SystemInfo) -> Result<(), Error> {
+        let cmd = match *info {
+            SystemInfo::Command(ref cmd) => cmd,
+            _ => {
+                let report = info.report()?;
+                return self.request("system_info", |req| {
+                    self.client.send_stream(Request { method: Method::Put, ..req }, &mut report.as_bytes())
+                }).map(|_| ());
+            }
+        };
+        self.request("system_info", |req| {
+            let mut child = match ShellCommand::new(cmd).stdout(Stdio::piped()).spawn() {
+                Ok(child) => child,
+                Err(err) => return error_response(Error::SystemInfo(err.to_string()))
+            };
+            let resp = {
+                let mut stdout = child.stdout.take().expect("system info stdout");
+                self.client.send_stream(Request { method: Method::Put, ..req }, &mut stdout)
+            };
+            match child.wait() {
+                Ok(ref status) if status.success() => (),
+                Ok(status) => warn!("system info command exited with {}", status),
+                Err(err) => warn!("couldn't wait for system info command: {}", err),
+            }
+            resp
+        }).map(|_| ())
+    }
+}
+
+
+/// The last reported list of installed packages, persisted between restarts.
+#[derive(Serialize, Deserialize)]
+struct PackageSnapshot {
+    packages:   Vec<Package>,
+    since_full: u64,
+}
+
+impl PackageSnapshot {
+    fn load(path: &str) -> Option<PackageSnapshot> {
+        if ! Path::new(path).exists() {
+            return None;
         }
+        Util::read_file(path)
+            .and_then(|buf| Ok(json::from_slice(&buf)?))
+            .map_err(|err| warn!("couldn't load package snapshot {}: {}", path, err))
+            .ok()
+    }
+
+    fn save(&self, path: &str) -> Result<(), Error> {
+        Util::write_file(path, &json::to_vec(self)?)
+    }
+}
+
+
+/// Return a receiver holding a single error response.
+fn error_response(err: Error) -> Receiver<Response> {
+    let (resp_tx, resp_rx) = chan::async::<Response>();
+    resp_tx.send(Response::Error(Box::new(err)));
+    resp_rx
+}
+
+
+/// Run `device.post_install_health_cmd` after an update was installed or its
+/// commit has booted, rolling back when the check fails. Ostree and Uptane
+/// deployments roll back to the `previous` commit. Returns `None` when the
+/// system is healthy, otherwise the failed result and whether the rollback
+/// succeeded.
+pub fn check_health(device: &DeviceConfig, update_id: &Uuid, previous: Option<&str>) -> Option<(InstallResult, bool)> {
+    let cmd = match device.post_install_health_cmd {
+        Some(ref cmd) => cmd,
+        None => return None
+    };
+    let timeout = Duration::from_secs(device.install_timeout);
+    let reason = match run_with_timeout(ShellCommand::new("sh").arg("-c").arg(cmd), timeout) {
+        Ok(ref output) if output.code == Some(0) => return None,
+        Ok(ref output) if output.timed_out => "health check timed out".to_string(),
+        Ok(output) => format!("health check failed: {}", output.stderr.trim()),
+        Err(err) => format!("couldn't run health check: {}", err),
+    };
+
+    error!("{} after installing {}", reason, update_id);
+    let (text, rolled_back) = match device.package_manager.rollback(device.rollback_cmd.as_ref().map(String::as_str), previous, timeout) {
+        Ok(()) => (format!("{}; rolled back", reason), true),
+        Err(err) => (format!("{}; rollback failed: {}", reason, err), false)
+    };
+    Some((InstallResult::new(format!("{}", update_id), InstallCode::INSTALL_FAILED, text), rolled_back))
+}
+
+/// Move a file by renaming it, falling back to copying it alongside the
+/// destination first when on another filesystem so that the destination is
+/// never partially written.
+fn move_file(from: &str, to: &str) -> Result<(), Error> {
+    fs::rename(from, to).or_else(|_| {
+        let tmp = format!("{}.moving", to);
+        fs::copy(from, &tmp)
+            .and_then(|_| fs::rename(&tmp, to))
+            .and_then(|_| fs::remove_file(from))
+    })?;
+    Ok(())
+}
+
+
+/// Serialize a slice as a JSON array one element at a time as it is read.
+struct JsonArrayReader<'a, T: 'a> {
+    items:   slice::Iter<'a, T>,
+    buf:     Vec<u8>,
+    pos:     usize,
+    opened:  bool,
+    started: bool,
+    closed:  bool,
+}
+
+impl<'a, T: Serialize> JsonArrayReader<'a, T> {
+    fn new(items: &'a [T]) -> Self {
+        JsonArrayReader { items: items.iter(), buf: Vec::new(), pos: 0, opened: false, started: false, closed: false }
     }
+}
 
-    /// Send system information from the device.
-    pub fn send_system_info(&mut self, body: Vec<u8>) -> Result<(), Error> {
-        let rx = self.client.put(self.endpoint("system_info"), Some(body));
-        match rx.recv().expect("couldn't send system info") {
-            Response::Success(_)   => Ok(()),
-            Response::Failed(data) => Err(data.into()),
-            Response::Error(err)   => Err(*err)
+impl<'a, T: Serialize> Read for JsonArrayReader<'a, T> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            if ! self.opened {
+                self.opened = true;
+                self.buf.push(b'[');
+            } else if let Some(item) = self.items.next() {
+                if self.started {
+                    self.buf.push(b',');
+                }
+                self.started = true;
+                json::to_writer(&mut self.buf, item).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            } else if ! self.closed {
+                self.closed = true;
+                self.buf.push(b']');
+            } else {
+                return Ok(0);
+            }
         }
+        let len = cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
     }
 }
 
@@ -110,8 +579,13 @@ mod tests {
     use super::*;
     use json;
 
-    use datatype::{Config, Package, UpdateRequest, RequestStatus};
-    use http::TestClient;
+    use bandwidth::DownloadMeter;
+    use chrono::{TimeZone, Utc};
+    use datatype::{Config, FakeClock, InstallCode, InstallResult, Method, Package, UpdateRequest, RequestStatus, Util};
+    use http::{ResponseData, TestClient};
+    use pacman::PacMan;
+    use pacman::test::TestDir;
+    use std::sync::atomic::Ordering;
 
 
     #[test]
@@ -124,15 +598,320 @@ mod tests {
                 version: "0.1.1".to_string()
             },
             installPos: 0,
-            createdAt: "2010-01-01".to_string()
+            createdAt: "2010-01-01".to_string(),
+            checksum: None,
+            signature: None,
         };
 
-        let mut sota = Sota {
-            config: &Config::default(),
-            client: &mut TestClient::from(vec![format!("[{}]", json::to_string(&pend).unwrap()).into_bytes()]),
-        };
+        let config = Config::default();
+        let client = TestClient::from(vec![format!("[{}]", json::to_string(&pend).unwrap()).into_bytes()]);
+        let mut sota = Sota::new(&config, &client);
         let updates: Vec<UpdateRequest> = sota.get_update_requests().unwrap();
         let ids: Vec<Uuid> = updates.iter().map(|p| p.requestId).collect();
         assert_eq!(ids, vec![Uuid::default()])
     }
+
+    #[test]
+    fn test_send_install_report() {
+        let config = Config::default();
+        let report = InstallResult::new("some-id".into(), InstallCode::OK, "".into()).into_report();
+        let url = format!("http://127.0.0.1:8080/api/v1/mydevice/{}/updates/some-id", Uuid::default());
+        let client = TestClient::from(vec![Vec::new()]).expect_requests(vec![(Method::Post, url.parse().unwrap())]);
+        Sota::new(&config, &client).send_install_report(&report).expect("send install report");
+
+        let requests = client.recorded_requests();
+        assert_eq!(requests.len(), 1);
+        let body = requests[0].body.as_ref().expect("report body");
+        assert_eq!(json::from_slice::<Vec<InstallResult>>(body).unwrap(), report.operation_results);
+    }
+
+    #[test]
+    fn test_stream_installed_packages() {
+        let packages = vec![
+            Package { name: "one".into(), version: "1.0".into() },
+            Package { name: "two".into(), version: "2.0".into() },
+        ];
+        let mut streamed = Vec::new();
+        JsonArrayReader::new(&packages).read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, json::to_vec(&packages).unwrap());
+        let mut empty = Vec::new();
+        JsonArrayReader::<Package>::new(&[]).read_to_end(&mut empty).unwrap();
+        assert_eq!(empty, b"[]".to_vec());
+
+        let config = Config::default();
+        let client = TestClient::from(vec![Vec::new()]);
+        Sota::new(&config, &client).send_installed_packages(&packages).expect("send installed packages");
+        let requests = client.recorded_requests();
+        assert_eq!(requests[0].method, Method::Put);
+        assert_eq!(json::from_slice::<Vec<Package>>(requests[0].body.as_ref().unwrap()).unwrap(), packages);
+    }
+
+    #[test]
+    fn test_report_package_diffs() {
+        let dir = TestDir::new("sota-package-diffs");
+        let mut config = Config::default();
+        config.device.package_snapshot = Some(format!("{}/snapshot.json", dir.0));
+        config.device.package_full_every = 2;
+        let one = Package { name: "one".into(), version: "1.0".into() };
+        let two = Package { name: "two".into(), version: "2.0".into() };
+        let two_new = Package { name: "two".into(), version: "2.1".into() };
+
+        let ok = || ResponseData::new(StatusCode::Ok, Vec::new());
+        let conflict = ResponseData::new(StatusCode::Conflict, Vec::new());
+        let client = TestClient::from_responses(vec![ok(), ok(), ok(), ok(), conflict, ok(), ok()]);
+        let report = |packages: &[Package]| Sota::new(&config, &client).report_installed_packages(packages).expect("report");
+
+        report(&[one.clone(), two.clone()]);      // no snapshot: full list
+        report(&[one.clone(), two.clone()]);      // unchanged: nothing sent
+        report(&[one.clone(), two_new.clone()]);  // version change
+        report(&[two_new.clone()]);               // removal
+        report(&[one.clone(), two_new.clone()]);  // periodic full list
+        report(&[two_new.clone()]);               // conflict: resync with full list
+        report(&[one.clone(), two_new.clone()]);  // addition
+
+        let requests = client.recorded_requests();
+        let methods = requests.iter().map(|req| req.method.clone()).collect::<Vec<_>>();
+        assert_eq!(methods, vec![Method::Put, Method::Patch, Method::Patch, Method::Put,
+                                 Method::Patch, Method::Put, Method::Patch]);
+        let diff = |n: usize| json::from_slice::<PackageDiff>(requests[n].body.as_ref().unwrap()).unwrap();
+        assert_eq!(diff(1), PackageDiff { added: vec![], removed: vec![], changed: vec![two_new.clone()] });
+        assert_eq!(diff(2), PackageDiff { added: vec![], removed: vec![one.clone()], changed: vec![] });
+        assert_eq!(diff(6), PackageDiff { added: vec![one.clone()], removed: vec![], changed: vec![] });
+        assert_eq!(json::from_slice::<Vec<Package>>(requests[5].body.as_ref().unwrap()).unwrap(), vec![two_new]);
+    }
+
+    #[test]
+    fn test_stream_system_info() {
+        let config = Config::default();
+        let client = TestClient::from(vec![Vec::new()]);
+        Sota::new(&config, &client).send_system_info(&SystemInfo::Command("pwd".into())).expect("send system info");
+        assert!(! client.recorded_requests()[0].body.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_download_to_package_manager_dir() {
+        let dir = TestDir::new("sota-download-dirs");
+        let mut config = Config::default();
+        config.device.package_manager = PacMan::Deb;
+        config.device.deb_packages_dir = Some(format!("{}/debs", dir.0));
+        config.device.chunks_dir = Some(format!("{}/chunks", dir.0));
+        fs::create_dir_all(format!("{}/debs", dir.0)).unwrap();
+        fs::create_dir_all(format!("{}/chunks", dir.0)).unwrap();
+
+        let client = TestClient::from(vec![b"deb".to_vec()]);
+        let dl = Sota::new(&config, &client).download_update(Uuid::default()).expect("download deb");
+        assert_eq!(dl.update_image, format!("{}/debs/{}", dir.0, Uuid::default()));
+        assert_eq!(Util::read_file(&dl.update_image).unwrap(), b"deb".to_vec());
+        assert!(! Path::new(&format!("{}/chunks/{}.part", dir.0, Uuid::default())).exists());
+    }
+
+    #[test]
+    fn test_server_failover() {
+        let mut config = Config::default();
+        config.core.server = "http://down.local:8080,http://127.0.0.1:8080".parse().unwrap();
+        let url = format!("http://127.0.0.1:8080/api/v1/mydevice/{}/system_info", Uuid::default());
+        let response = |code| ResponseData::new(code, Vec::new());
+        let client = TestClient::from_responses(vec![
+            response(StatusCode::ServiceUnavailable),
+            response(StatusCode::Ok),
+            response(StatusCode::Ok),
+        ]).expect_requests(vec![
+            (Method::Put, url.replace("127.0.0.1", "down.local").parse().unwrap()),
+            (Method::Put, url.parse().unwrap()),
+            (Method::Put, url.parse().unwrap()),
+        ]);
+        Sota::new(&config, &client).send_system_info(&SystemInfo::Command("true".into())).expect("send system info");
+        Sota::new(&config, &client).send_system_info(&SystemInfo::Command("true".into())).expect("send system info");
+
+        let requests = client.recorded_requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].url.0.host_str(), Some("down.local"));
+        assert_eq!(requests[1].url, url.parse().unwrap());
+        assert_eq!(requests[2].url, url.parse().unwrap());
+        assert_eq!(config.core.server.current(), &"http://127.0.0.1:8080".parse().unwrap());
+    }
+
+    #[test]
+    fn test_response_too_large() {
+        let mut config = Config::default();
+        config.core.server = "http://one.local:8080,http://two.local:8080".parse().unwrap();
+        config.network.max_response_bytes = 4;
+        let client = TestClient::from(vec![b"[{}, {}]".to_vec(), b"[]".to_vec()]);
+        match Sota::new(&config, &client).get_update_requests() {
+            Err(Error::TooLarge(_)) => (),
+            other => panic!("expected response too large, got {:?}", other)
+        }
+        assert_eq!(client.recorded_requests().len(), 1);
+        assert_eq!(client.recorded_requests()[0].max_bytes, Some(4));
+    }
+
+    #[test]
+    fn test_resume_download() {
+        let dir = TestDir::new("sota-resume-download");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        Util::write_file(&format!("{}/{}.part", dir.0, Uuid::default()), b"hello ").unwrap();
+
+        let headers = hashmap!{ "content-range".to_string() => "bytes 6-10/11".to_string() };
+        let client = TestClient::from_responses(vec![
+            ResponseData { headers: headers, ..ResponseData::new(StatusCode::PartialContent, b"world".to_vec()) }
+        ]);
+        let dl = Sota::new(&config, &client).download_update(Uuid::default()).expect("download update");
+        assert_eq!(Util::read_text(&dl.update_image).unwrap(), "hello world");
+        assert_eq!(client.recorded_requests()[0].headers.get("Range"), Some(&"bytes=6-".to_string()));
+    }
+
+    #[test]
+    fn test_throttled_download_usage() {
+        let dir = TestDir::new("sota-throttled-download");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.download_usage_file = Some(format!("{}/usage.json", dir.0));
+
+        let clock = Arc::new(FakeClock::new(Utc.ymd(2017, 1, 1).and_hms(12, 0, 0)));
+        let client = TestClient::from(vec![b"hello".to_vec(), b"world".to_vec()]);
+        Sota::new(&config, &client).clock(clock.clone()).rate_limit(Some(65536)).download_update(Uuid::default()).expect("download update");
+        Sota::new(&config, &client).clock(clock.clone()).download_update(Uuid::default()).expect("download update");
+        let requests = client.recorded_requests();
+        assert_eq!(requests[0].rate_limit, Some(65536));
+        assert_eq!(requests[1].rate_limit, None);
+        let usage = DownloadUsage::load(&format!("{}/usage.json", dir.0), clock.now()).expect("load usage");
+        assert_eq!(usage, DownloadUsage { month: "2017-01".into(), bytes: 10 });
+    }
+
+    #[test]
+    fn test_resume_download_restarts() {
+        let dir = TestDir::new("sota-restart-download");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        Util::write_file(&format!("{}/{}.part", dir.0, Uuid::default()), b"stale").unwrap();
+
+        let client = TestClient::from_responses(vec![
+            ResponseData::new(StatusCode::Ok, b"hello world".to_vec())
+        ]);
+        let dl = Sota::new(&config, &client).download_update(Uuid::default()).expect("download update");
+        assert_eq!(Util::read_text(&dl.update_image).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_download_short_read() {
+        let dir = TestDir::new("sota-short-download");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+
+        let headers = hashmap!{ "content-length".to_string() => "11".to_string() };
+        let client = TestClient::from_responses(vec![
+            ResponseData { headers: headers, ..ResponseData::new(StatusCode::Ok, b"hello".to_vec()) }
+        ]);
+        match Sota::new(&config, &client).download_update(Uuid::default()) {
+            Err(Error::Client(err)) => assert_eq!(err, "short read"),
+            other => panic!("expected a short read, got {:?}", other)
+        }
+        let partial = format!("{}/{}.part", dir.0, Uuid::default());
+        assert_eq!(Util::read_text(&partial).unwrap(), "hello");
+        assert!(! Sota::new(&config, &client).is_downloaded(&Uuid::default()));
+
+        let headers = hashmap!{ "content-range".to_string() => "bytes 5-10/11".to_string() };
+        let client = TestClient::from_responses(vec![
+            ResponseData { headers: headers, ..ResponseData::new(StatusCode::PartialContent, b" world".to_vec()) }
+        ]);
+        let dl = Sota::new(&config, &client).download_update(Uuid::default()).expect("resume download");
+        assert_eq!(Util::read_text(&dl.update_image).unwrap(), "hello world");
+        assert_eq!(client.recorded_requests()[0].headers.get("Range"), Some(&"bytes=5-".to_string()));
+    }
+
+    #[test]
+    fn test_download_canceled() {
+        let dir = TestDir::new("sota-canceled-download");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        let aborted = AbortedUpdates::default();
+        aborted.cancel(Uuid::default());
+
+        let client = TestClient::from(vec![b"hello world".to_vec()]);
+        match Sota::new(&config, &client).aborted(aborted).download_update(Uuid::default()) {
+            Err(Error::Aborted(_)) => (),
+            other => panic!("expected an aborted download, got {:?}", other)
+        }
+        assert!(client.recorded_requests()[0].canceled.as_ref().expect("canceled flag").load(Ordering::SeqCst));
+        assert!(! Path::new(&format!("{}/{}.part", dir.0, Uuid::default())).exists());
+    }
+
+    #[test]
+    fn test_download_content_type() {
+        let dir = TestDir::new("sota-download-content-type");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.package_manager = PacMan::Deb;
+        config.device.check_content_type = true;
+
+        let html = || ResponseData {
+            headers: hashmap!{ "content-type".to_string() => "text/html; charset=utf-8".to_string() },
+            ..ResponseData::new(StatusCode::Ok, b"<html>error</html>".to_vec())
+        };
+        let client = TestClient::from_responses(vec![html()]);
+        match Sota::new(&config, &client).download_update(Uuid::default()) {
+            Err(Error::Client(err)) => assert_eq!(err, "unexpected content type text/html, expected one of: \
+                                                        application/vnd.debian.binary-package, application/x-debian-package"),
+            other => panic!("expected an unexpected content type, got {:?}", other)
+        }
+        assert!(! Sota::new(&config, &client).is_downloaded(&Uuid::default()));
+
+        config.device.check_content_type = false;
+        let client = TestClient::from_responses(vec![html()]);
+        let _ = Sota::new(&config, &client).download_update(Uuid::default()).expect("unchecked download");
+    }
+
+    #[test]
+    fn test_install_from_staging_dir() {
+        let dir = TestDir::new("sota-install-staging");
+        let mut config = Config::default();
+        config.device.packages_dir = format!("{}/install", dir.0);
+        config.device.staging_dir = Some(format!("{}/staging", dir.0));
+        config.device.package_manager = PacMan::Test { filename: format!("{}/installed", dir.0), succeeds: true };
+        fs::create_dir_all(format!("{}/install", dir.0)).unwrap();
+        fs::create_dir_all(format!("{}/staging", dir.0)).unwrap();
+        let staged = format!("{}/staging/{}", dir.0, Uuid::default());
+        let package = format!("{}/install/{}", dir.0, Uuid::default());
+        let creds = || Credentials { client: Box::new(TestClient::default()), token: None, ca_file: None, cert_file: None, pkey_file: None, treehub: None, meter: DownloadMeter::default() };
+
+        let client = TestClient::from(vec![b"hello world".to_vec(), b"hello world".to_vec()]);
+        let dl = Sota::new(&config, &client).download_update(Uuid::default()).expect("download update");
+        assert_eq!(dl.update_image, staged);
+        let result = Sota::new(&config, &client).install_update(&Uuid::default(), &creds(), Some("deadbeef")).unwrap();
+        assert_eq!(result.result_code, InstallCode::GENERAL_ERROR);
+        assert!(! Path::new(&staged).exists());
+        assert!(! Path::new(&package).exists());
+        assert!(! Path::new(&format!("{}/installed", dir.0)).exists());
+
+        let _ = Sota::new(&config, &client).download_update(Uuid::default()).expect("download update");
+        let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let result = Sota::new(&config, &client).install_update(&Uuid::default(), &creds(), Some(sha256)).unwrap();
+        assert_eq!(result.result_code, InstallCode::OK);
+        assert!(! Path::new(&staged).exists());
+        assert_eq!(Util::read_text(&format!("{}/installed", dir.0)).unwrap(), format!("{}\n", package));
+    }
+
+    #[test]
+    fn test_install_checksum_mismatch() {
+        let dir = TestDir::new("sota-install-checksum");
+        let mut config = Config::default();
+        config.device.packages_dir = dir.0.clone();
+        config.device.package_manager = PacMan::Test { filename: format!("{}/installed", dir.0), succeeds: true };
+        let package = format!("{}/{}", dir.0, Uuid::default());
+        let creds = || Credentials { client: Box::new(TestClient::default()), token: None, ca_file: None, cert_file: None, pkey_file: None, treehub: None, meter: DownloadMeter::default() };
+        let client = TestClient::default();
+
+        Util::write_file(&package, b"hello world").unwrap();
+        let result = Sota::new(&config, &client).install_update(&Uuid::default(), &creds(), Some("deadbeef")).unwrap();
+        assert_eq!(result.result_code, InstallCode::GENERAL_ERROR);
+        assert!(result.result_text.contains("expected sha256 of `deadbeef`"));
+        assert!(! Path::new(&package).exists());
+        assert!(! Path::new(&format!("{}/installed", dir.0)).exists());
+
+        Util::write_file(&package, b"hello world").unwrap();
+        let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let result = Sota::new(&config, &client).install_update(&Uuid::default(), &creds(), Some(sha256)).unwrap();
+        assert_eq!(result.result_code, InstallCode::OK);
+    }
 }
@@ -0,0 +1,171 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use json;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use datatype::{Error, InstallReport, ReportSummary, Util};
+use metrics::Metrics;
+
+
+/// Store installation reports that couldn't be sent in a directory, one JSON
+/// file per update named by the sha256 of its id, until they are sent
+/// successfully or cleared.
+pub struct ReportSpool {
+    pub dir: String,
+}
+
+impl ReportSpool {
+    /// Create a new spool writing to this directory.
+    pub fn new(dir: String) -> Self {
+        ReportSpool { dir: dir }
+    }
+
+    /// Save a report to be sent later, replacing any earlier one for the update.
+    pub fn save(&self, report: &InstallReport) -> Result<(), Error> {
+        info!("Spooling install report for {}", report.update_id);
+        Util::write_file(&self.path(&report.update_id), &json::to_vec(report)?)?;
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Read every spooled report, skipping any that can't be read.
+    pub fn reports(&self) -> Result<Vec<InstallReport>, Error> {
+        Ok(self.entries()?
+            .into_iter()
+            .filter_map(|(path, _)| Self::read(&path))
+            .collect())
+    }
+
+    /// Summarize each readable spooled report by its update id and age.
+    pub fn pending(&self) -> Result<Vec<ReportSummary>, Error> {
+        Ok(self.entries()?
+            .into_iter()
+            .filter_map(|(path, age_secs)| {
+                Self::read(&path).map(|report| ReportSummary { update_id: report.update_id, age_secs: age_secs })
+            })
+            .collect())
+    }
+
+    /// Remove a spooled report once it has been sent.
+    pub fn remove(&self, update_id: &str) -> Result<(), Error> {
+        let path = self.path(update_id);
+        if Path::new(&path).exists() {
+            fs::remove_file(&path)?;
+            self.update_metrics();
+        }
+        Ok(())
+    }
+
+    /// Discard every spooled report, including unreadable ones, returning a
+    /// summary of the readable reports removed.
+    pub fn clear(&self) -> Result<Vec<ReportSummary>, Error> {
+        let mut cleared = Vec::new();
+        for (path, age_secs) in self.entries()? {
+            if let Some(report) = Self::read(&path) {
+                warn!("Discarding spooled install report for {} ({}s old)", report.update_id, age_secs);
+                cleared.push(ReportSummary { update_id: report.update_id, age_secs: age_secs });
+            }
+            fs::remove_file(&path)?;
+        }
+        self.update_metrics();
+        Ok(cleared)
+    }
+
+    fn read(path: &str) -> Option<InstallReport> {
+        Util::read_file(path)
+            .and_then(|data| Ok(json::from_slice(&data)?))
+            .map_err(|err| warn!("Skipping unreadable spooled report {}: {}", path, err))
+            .ok()
+    }
+
+    fn update_metrics(&self) {
+        if Metrics::is_enabled() {
+            let pending = self.entries().map(|entries| entries.len() as u64).unwrap_or(0);
+            Metrics::update(|metrics| metrics.pending_reports = pending);
+        }
+    }
+
+    fn path(&self, update_id: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input_str(update_id);
+        format!("{}/{}.json", self.dir, hasher.result_str())
+    }
+
+    /// Return the path and age in seconds of each spooled report, oldest first.
+    fn entries(&self) -> Result<Vec<(String, u64)>, Error> {
+        if ! Path::new(&self.dir).exists() {
+            return Ok(Vec::new());
+        }
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            let modified = fs::metadata(&path)?.modified()?;
+            let age = now.duration_since(modified).map(|age| age.as_secs()).unwrap_or(0);
+            entries.push((path.to_string_lossy().into_owned(), age));
+        }
+        entries.sort_by(|a, b| b.1.cmp(&a.1));
+        Ok(entries)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datatype::{InstallCode, InstallResult};
+    use pacman::test::TestDir;
+
+
+    fn report(id: &str) -> InstallReport {
+        InstallResult::new(id.into(), InstallCode::GENERAL_ERROR, "".into()).into_report()
+    }
+
+    #[test]
+    fn test_spool_pending_and_clear() {
+        let dir = TestDir::new("sota-report-spool");
+        let spool = ReportSpool::new(format!("{}/reports", dir.0));
+        assert!(spool.pending().unwrap().is_empty());
+
+        spool.save(&report("first")).unwrap();
+        spool.save(&report("second")).unwrap();
+        spool.save(&report("second")).unwrap();
+        let mut ids = spool.pending().unwrap().into_iter().map(|s| s.update_id).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec!["first", "second"]);
+        assert_eq!(spool.reports().unwrap().len(), 2);
+
+        spool.remove("first").unwrap();
+        assert_eq!(spool.pending().unwrap().len(), 1);
+        assert_eq!(spool.clear().unwrap().len(), 1);
+        assert!(spool.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_spool_unsafe_ids() {
+        let dir = TestDir::new("sota-report-spool-ids");
+        let spool = ReportSpool::new(format!("{}/reports", dir.0));
+        spool.save(&report("../../escaped")).unwrap();
+        assert!(! Path::new(&format!("{}/escaped.json", dir.0)).exists());
+        assert_eq!(spool.pending().unwrap()[0].update_id, "../../escaped");
+        spool.remove("../../escaped").unwrap();
+        assert!(spool.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_spool_skips_corrupt() {
+        let dir = TestDir::new("sota-report-spool-corrupt");
+        let spool = ReportSpool::new(format!("{}/reports", dir.0));
+        spool.save(&report("first")).unwrap();
+        Util::write_file(&format!("{}/reports/corrupt.json", dir.0), b"not json").unwrap();
+        assert_eq!(spool.reports().unwrap().len(), 1);
+        assert_eq!(spool.pending().unwrap().len(), 1);
+        assert_eq!(spool.clear().unwrap().len(), 1);
+        assert_eq!(fs::read_dir(&spool.dir).unwrap().count(), 0);
+    }
+}
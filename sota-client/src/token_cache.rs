@@ -0,0 +1,186 @@
+use chrono::{DateTime, Utc};
+use json;
+use ring::{aead, digest, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use datatype::{AccessToken, AuthConfig, Error, Util};
+
+
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 10_000;
+
+
+/// An access token saved with the time it expires.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct CachedToken {
+    pub token:      AccessToken,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+
+/// Persists the access token between restarts in a file only readable by
+/// the owner. When a device secret is set the token is encrypted with a key
+/// derived from it, otherwise it is stored as plaintext JSON.
+pub struct TokenCache {
+    path:   String,
+    secret: Option<Vec<u8>>,
+}
+
+impl TokenCache {
+    pub fn new(path: String, secret: Option<Vec<u8>>) -> Self {
+        TokenCache { path: path, secret: secret }
+    }
+
+    /// Read the token cache settings, returning `None` when the token isn't
+    /// persisted. The secret is read from `token_cache_key`, which may point
+    /// to the TLS private key or a passphrase file.
+    pub fn from_config(auth: &AuthConfig) -> Result<Option<Self>, Error> {
+        let path = match auth.token_cache {
+            Some(ref path) => path.clone(),
+            None => return Ok(None)
+        };
+        let secret = match auth.token_cache_key {
+            Some(ref key_path) => Some(Util::read_file(key_path)?),
+            None => None
+        };
+        Ok(Some(TokenCache::new(path, secret)))
+    }
+
+    /// Save the access token and when it expires, encrypting it when a
+    /// secret is set.
+    pub fn save(&self, token: &AccessToken, expires_at: Option<DateTime<Utc>>) -> Result<(), Error> {
+        let plain = json::to_vec(&CachedToken { token: token.clone(), expires_at: expires_at })?;
+        let data = match self.secret {
+            Some(ref secret) => encrypt(secret, plain)?,
+            None => plain
+        };
+        write_private(&self.path, &data)
+    }
+
+    /// Load the saved access token, or `None` if there isn't one or it has
+    /// expired by `now`.
+    pub fn load(&self, now: DateTime<Utc>) -> Result<Option<CachedToken>, Error> {
+        if ! Path::new(&self.path).exists() {
+            return Ok(None);
+        }
+        let data = Util::read_file(&self.path)?;
+        let plain = match self.secret {
+            Some(ref secret) => decrypt(secret, data)?,
+            None => data
+        };
+        let cached: CachedToken = json::from_slice(&plain)?;
+        match cached.expires_at {
+            Some(expiry) if expiry <= now => {
+                info!("The saved access token expired at {}", expiry);
+                Ok(None)
+            }
+            _ => Ok(Some(cached))
+        }
+    }
+}
+
+
+/// Write the data to a file created with mode 0600.
+fn write_private(path: &str, data: &[u8]) -> Result<(), Error> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    Ok(file.write_all(data)?)
+}
+
+
+/// Returns the AES-256 key derived from the secret with this salt.
+fn derive_key(secret: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut key = vec![0; aead::AES_256_GCM.key_len()];
+    pbkdf2::derive(&digest::SHA256, PBKDF2_ITERATIONS, salt, secret, &mut key);
+    key
+}
+
+/// Encrypt the data as `<salt><nonce><ciphertext><tag>`.
+fn encrypt(secret: &[u8], plain: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let alg = &aead::AES_256_GCM;
+    let mut header = vec![0; SALT_LEN + alg.nonce_len()];
+    SystemRandom::new().fill(&mut header)?;
+    let key = aead::SealingKey::new(alg, &derive_key(secret, &header[..SALT_LEN]))?;
+
+    let mut in_out = plain;
+    in_out.extend(vec![0; alg.tag_len()]);
+    let len = aead::seal_in_place(&key, &header[SALT_LEN..], &[], &mut in_out, alg.tag_len())?;
+    header.extend(&in_out[..len]);
+    Ok(header)
+}
+
+/// Decrypt data written by `encrypt`, failing if the secret is wrong or the
+/// data was modified.
+fn decrypt(secret: &[u8], data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let alg = &aead::AES_256_GCM;
+    let header_len = SALT_LEN + alg.nonce_len();
+    if data.len() < header_len + alg.tag_len() {
+        return Err(Error::Parse("token cache too short to decrypt".into()));
+    }
+    let (header, sealed) = data.split_at(header_len);
+    let key = aead::OpeningKey::new(alg, &derive_key(secret, &header[..SALT_LEN]))?;
+    let mut in_out = sealed.to_vec();
+    let plain = aead::open_in_place(&key, &header[SALT_LEN..], &[], 0, &mut in_out)
+        .map_err(|_| Error::Parse("couldn't decrypt the token cache with this key".into()))?;
+    Ok(plain.to_vec())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    use pacman::test::TestDir;
+
+
+    fn token() -> AccessToken {
+        AccessToken { access_token: "secret-token".into(), token_type: "bearer".into(), expires_in: 3600, scope: "".into() }
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        let dir = TestDir::new("sota-token-cache");
+        let path = format!("{}/token", dir.0);
+        let cache = TokenCache::new(path.clone(), Some(b"device secret".to_vec()));
+        let now = Utc::now();
+        assert_eq!(cache.load(now).unwrap(), None);
+        cache.save(&token(), None).expect("save token");
+        assert_eq!(cache.load(now).unwrap(), Some(CachedToken { token: token(), expires_at: None }));
+
+        let data = Util::read_file(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains("secret-token"));
+        let plain = TokenCache::new(format!("{}/plain", dir.0), None);
+        plain.save(&token(), None).expect("save plaintext token");
+        assert_eq!(json::from_slice::<CachedToken>(&Util::read_file(&plain.path).unwrap()).unwrap().token, token());
+    }
+
+    #[test]
+    fn test_private_and_expiring() {
+        let dir = TestDir::new("sota-token-cache-expiry");
+        let cache = TokenCache::new(format!("{}/token", dir.0), None);
+        let now = Utc::now();
+        cache.save(&token(), Some(now + Duration::seconds(3600))).expect("save token");
+        assert_eq!(fs::metadata(&cache.path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(cache.load(now).unwrap().is_some());
+        assert_eq!(cache.load(now + Duration::seconds(3600)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_wrong_key_fails() {
+        let dir = TestDir::new("sota-token-cache-key");
+        let path = format!("{}/token", dir.0);
+        TokenCache::new(path.clone(), Some(b"device secret".to_vec())).save(&token(), None).expect("save token");
+        match TokenCache::new(path, Some(b"other secret".to_vec())).load(Utc::now()) {
+            Err(Error::Parse(err)) => assert_eq!(err, "couldn't decrypt the token cache with this key"),
+            other => panic!("expected a decryption error, got {:?}", other)
+        }
+    }
+}